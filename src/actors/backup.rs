@@ -0,0 +1,134 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use async_std::task;
+use futures::{select_biased, FutureExt};
+use log::{info, warn};
+
+use crate::{broker::*, Result, KV_STORAGE};
+
+/// Prefix of the directory name of each backup.
+const BACKUP_PREFIX: &str = "solar-backup-";
+
+/// Configuration files which are copied into each backup (if they exist).
+const CONFIG_FILES: [&str; 5] = [
+    "secret.toml",
+    "replication.toml",
+    "logging.toml",
+    "tokens.toml",
+    "hooks.toml",
+];
+
+/// Register the backup actor with the broker and write a snapshot of the
+/// key-value database and configuration files to the backup folder every
+/// `interval` seconds, retaining the `retention` most recent backups.
+pub async fn actor(
+    base_path: PathBuf,
+    backup_folder: PathBuf,
+    interval: u64,
+    retention: usize,
+) -> Result<()> {
+    // Register the "backup" actor endpoint with the broker.
+    let broker = BROKER.lock().await.register("backup", false).await?;
+    // Fuse internal termination channel with external channel.
+    // This allows termination of the backup loop to be initiated from
+    // outside this function.
+    let mut ch_terminate = broker.ch_terminate.fuse();
+
+    fs::create_dir_all(&backup_folder)?;
+
+    loop {
+        select_biased! {
+            _ = ch_terminate => break,
+            _ = task::sleep(Duration::from_secs(interval)).fuse() => {
+                match backup(&base_path, &backup_folder).await {
+                    Ok(path) => {
+                        info!("wrote backup to {:?}", path);
+                        // Old backups are only deleted once a new one has
+                        // been written in full.
+                        if let Err(err) = rotate(&backup_folder, retention) {
+                            warn!("failed to rotate backups: {}", err)
+                        }
+                    }
+                    Err(err) => warn!("failed to write backup: {}", err),
+                }
+            }
+        }
+    }
+
+    // Send terminated signal back to the broker.
+    let _ = broker.ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+/// Write a single backup into a new, timestamped directory inside the
+/// backup folder and return the path of that directory. The directory is
+/// removed if the backup fails, so that it is not mistaken for a backup.
+async fn backup(base_path: &Path, backup_folder: &Path) -> Result<PathBuf> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let path = backup_folder.join(format!("{BACKUP_PREFIX}{timestamp}"));
+    fs::create_dir_all(&path)?;
+
+    if let Err(err) = write_backup(base_path, &path).await {
+        if let Err(remove_err) = fs::remove_dir_all(&path) {
+            warn!(
+                "failed to remove incomplete backup {:?}: {}",
+                path, remove_err
+            )
+        }
+        return Err(err);
+    }
+
+    Ok(path)
+}
+
+/// Write the snapshot of the database and the configuration files into the
+/// given backup directory.
+async fn write_backup(base_path: &Path, path: &Path) -> Result<()> {
+    // Snapshot the key-value database, holding off writes meanwhile.
+    KV_STORAGE.write().await.backup(&path.join("feeds")).await?;
+
+    // Copy the configuration files.
+    for file in CONFIG_FILES {
+        let config_file = base_path.join(file);
+        if config_file.is_file() {
+            fs::copy(config_file, path.join(file))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Delete the oldest backups until no more than `retention` remain.
+fn rotate(backup_folder: &Path, retention: usize) -> Result<()> {
+    let mut backups = Vec::new();
+    for entry in fs::read_dir(backup_folder)? {
+        let path = entry?.path();
+        let is_backup = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(BACKUP_PREFIX));
+        if path.is_dir() && is_backup {
+            backups.push(path);
+        }
+    }
+
+    // Backup directory names end with a timestamp; sorting by name sorts
+    // from oldest to newest.
+    backups.sort();
+
+    while backups.len() > retention {
+        let oldest = backups.remove(0);
+        info!("removing old backup {:?}", oldest);
+        fs::remove_dir_all(oldest)?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,121 @@
+use std::sync::Arc;
+
+use futures::{select_biased, stream::FusedStream, FutureExt, StreamExt};
+use kuska_ssb::{feed::Feed, keystore::OwnedIdentity};
+use log::{info, warn};
+use serde_json::Value;
+
+use crate::{
+    actors::outbox::publish_or_queue,
+    api::bot::{Command, Ping, BOT_COMMANDS},
+    broker::*,
+    Result, KV_STORAGE,
+};
+
+/// Register the bot actor with the broker and follow the log of appended
+/// messages. Whenever a post mentioning the local identity holds a command
+/// starting with `prefix`, dispatch it to the handler registered for the
+/// command and publish the response of the handler on the local feed.
+///
+/// Commands sent by the local identity or by muted feeds are ignored.
+pub async fn actor(server_id: OwnedIdentity, prefix: String) -> Result<()> {
+    // Register the "bot" actor endpoint with the broker.
+    let ActorEndpoint {
+        ch_terminate,
+        ch_terminated,
+        ..
+    } = BROKER.lock().await.register("bot", false).await?;
+    let mut ch_terminate = ch_terminate.fuse();
+
+    BOT_COMMANDS.register("ping", Arc::new(Ping));
+
+    // Only messages appended from now on are considered, so that commands
+    // are not answered twice after a restart.
+    let mut cursor = KV_STORAGE.read().await.get_latest_global_seq()?;
+    let mut tail = tail_appended(cursor).await?;
+
+    loop {
+        let msg_kvt = select_biased! {
+            _ = ch_terminate => break,
+            item = tail.next() => match item {
+                Some(Ok((global_seq, msg_kvt))) => {
+                    cursor = global_seq;
+                    msg_kvt
+                }
+                Some(Err(err)) => {
+                    warn!("failed to read appended message: {}", err);
+                    continue;
+                }
+                // The stream ends when the database is compacted; follow the
+                // compacted database from the latest message read, since
+                // messages may be appended before the tail is resumed.
+                None => {
+                    tail = tail_appended(cursor).await?;
+                    continue;
+                }
+            },
+        };
+
+        let command = match Command::parse(&prefix, &server_id.id, &msg_kvt) {
+            Some(command) if command.author != server_id.id => command,
+            _ => continue,
+        };
+        if KV_STORAGE.read().await.mutes().is_muted(&command.author)? {
+            continue;
+        }
+
+        let response = match BOT_COMMANDS.dispatch(&command) {
+            Ok(Some(response)) => response,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!(
+                    "failed to handle bot command {} of {}: {}",
+                    command.name, command.msg_ref, err
+                );
+                continue;
+            }
+        };
+        match publish_response(&server_id, response).await {
+            Ok(true) => info!(
+                "answered bot command {} of {}",
+                command.name, command.msg_ref
+            ),
+            Ok(false) => info!(
+                "queued answer to bot command {} of {} in the outbox",
+                command.name, command.msg_ref
+            ),
+            Err(err) => warn!(
+                "failed to answer bot command {} of {}: {}",
+                command.name, command.msg_ref, err
+            ),
+        }
+    }
+
+    // Send terminated signal back to the broker.
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+/// Return a stream of the messages appended after the given global sequence
+/// number.
+async fn tail_appended(
+    after_global_seq: u64,
+) -> Result<impl FusedStream<Item = Result<(u64, Feed)>> + Unpin> {
+    let tail = KV_STORAGE
+        .read()
+        .await
+        .tail_messages(after_global_seq)
+        .await?;
+
+    Ok(Box::pin(tail).fuse())
+}
+
+/// Publish the response to a command on the local feed, or hold it in the
+/// outbox if the database is under maintenance. Returns `false` if it was
+/// queued, to be published once maintenance completes.
+async fn publish_response(server_id: &OwnedIdentity, content: Value) -> Result<bool> {
+    let response = publish_or_queue(server_id, content).await?;
+
+    Ok(response.get("outbox_id").is_none())
+}
@@ -0,0 +1,45 @@
+use std::{path::PathBuf, time::Duration};
+
+use async_std::task;
+use futures::{select_biased, FutureExt};
+use log::{info, warn};
+
+use crate::{broker::*, storage::kv, Result, KV_STORAGE};
+
+/// Register the compaction actor with the broker and compact the key-value
+/// database stored at `feeds_folder` every `interval` seconds. Compaction is
+/// skipped while the database is under maintenance.
+pub async fn actor(feeds_folder: PathBuf, interval: u64) -> Result<()> {
+    // Register the "compaction" actor endpoint with the broker.
+    let broker = BROKER.lock().await.register("compaction", false).await?;
+    // Fuse internal termination channel with external channel.
+    // This allows termination of the compaction loop to be initiated from
+    // outside this function.
+    let mut ch_terminate = broker.ch_terminate.fuse();
+
+    loop {
+        select_biased! {
+            _ = ch_terminate => break,
+            _ = task::sleep(Duration::from_secs(interval)).fuse() => {
+                if kv::is_under_maintenance() {
+                    info!("database under maintenance; skipping compaction");
+                    continue;
+                }
+                match KV_STORAGE.write().await.compact(&feeds_folder).await {
+                    Ok(report) => info!(
+                        "compacted database from {} to {} bytes ({} bytes recovered)",
+                        report.size_before,
+                        report.size_after,
+                        report.recovered()
+                    ),
+                    Err(err) => warn!("failed to compact database: {}", err),
+                }
+            }
+        }
+    }
+
+    // Send terminated signal back to the broker.
+    let _ = broker.ch_terminated.send(Void {});
+
+    Ok(())
+}
@@ -1,4 +1,11 @@
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
 
 use async_std::{
     sync::{Arc, RwLock},
@@ -10,7 +17,7 @@ use kuska_ssb::crypto::ed25519;
 use log::trace;
 use once_cell::sync::Lazy;
 
-use crate::{ActorEndpoint, BROKER};
+use crate::{config::HANDSHAKE_CONFIG, ActorEndpoint, BROKER};
 
 /*
 /// All possible errors while negotiating connections.
@@ -50,6 +57,84 @@ pub enum ConnectionEvent {
     Error(usize, String),
 }
 
+/// Limiter of inbound secret handshakes in progress, overall and per IP
+/// address.
+pub struct HandshakeLimiter {
+    max_in_progress: usize,
+    max_per_ip: usize,
+    /// Number of handshakes in progress with each IP address.
+    in_progress: Mutex<HashMap<IpAddr, usize>>,
+    /// Number of connections dropped for exceeding a limit.
+    refused: AtomicU64,
+}
+
+/// Permission to perform a handshake, released when dropped.
+pub struct HandshakePermit<'a> {
+    limiter: &'a HandshakeLimiter,
+    ip: IpAddr,
+}
+
+/// The limiter of inbound handshakes for the solar node.
+pub static HANDSHAKE_LIMITER: Lazy<HandshakeLimiter> = Lazy::new(|| {
+    let config = HANDSHAKE_CONFIG.get().unwrap();
+    HandshakeLimiter::new(config.max_in_progress, config.max_per_ip)
+});
+
+impl HandshakeLimiter {
+    /// Instantiate a new `HandshakeLimiter` with the given limits.
+    pub fn new(max_in_progress: usize, max_per_ip: usize) -> Self {
+        Self {
+            max_in_progress,
+            max_per_ip,
+            in_progress: Mutex::new(HashMap::new()),
+            refused: AtomicU64::new(0),
+        }
+    }
+
+    /// Attempt to start a handshake with the given IP address. Returns the
+    /// reason for refusing it if a limit has been reached.
+    pub fn try_acquire(
+        &self,
+        ip: IpAddr,
+    ) -> std::result::Result<HandshakePermit<'_>, &'static str> {
+        let mut in_progress = self.in_progress.lock().unwrap();
+
+        let reason = if in_progress.values().sum::<usize>() >= self.max_in_progress {
+            "too many handshakes in progress"
+        } else if in_progress.get(&ip).copied().unwrap_or(0) >= self.max_per_ip {
+            "too many handshakes in progress from this address"
+        } else {
+            *in_progress.entry(ip).or_insert(0) += 1;
+            return Ok(HandshakePermit { limiter: self, ip });
+        };
+        self.refused.fetch_add(1, Ordering::Relaxed);
+
+        Err(reason)
+    }
+
+    /// Query the number of handshakes in progress.
+    pub fn in_progress(&self) -> usize {
+        self.in_progress.lock().unwrap().values().sum()
+    }
+
+    /// Query the number of connections refused since startup.
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for HandshakePermit<'_> {
+    fn drop(&mut self) {
+        let mut in_progress = self.limiter.in_progress.lock().unwrap();
+        if let Some(count) = in_progress.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                in_progress.remove(&self.ip);
+            }
+        }
+    }
+}
+
 /// Connection manager (broker).
 #[derive(Debug)]
 pub struct ConnectionManager {
@@ -295,4 +380,26 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_handshake_limiter() {
+        let limiter = HandshakeLimiter::new(3, 2);
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let other_ip: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = limiter.try_acquire(ip).unwrap();
+        let _second = limiter.try_acquire(ip).unwrap();
+        // The per-address limit is reached.
+        assert!(limiter.try_acquire(ip).is_err());
+        // The overall limit is reached.
+        let _third = limiter.try_acquire(other_ip).unwrap();
+        assert!(limiter.try_acquire(other_ip).is_err());
+        assert_eq!(limiter.in_progress(), 3);
+        assert_eq!(limiter.refused(), 2);
+
+        // A finished handshake makes room for another.
+        drop(first);
+        assert_eq!(limiter.in_progress(), 2);
+        assert!(limiter.try_acquire(ip).is_ok());
+    }
 }
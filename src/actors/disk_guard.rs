@@ -0,0 +1,56 @@
+use std::{path::PathBuf, time::Duration};
+
+use async_std::task;
+use futures::{select_biased, FutureExt};
+use log::{info, warn};
+
+use crate::{broker::*, disk::DISK_GUARD, Result};
+
+/// Interval between checks of the free disk space.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Register the disk guard actor with the broker and check the free space on
+/// the volume holding `base_path` every `CHECK_INTERVAL`, switching the node
+/// to degraded mode while it is below `min_free_space` bytes. A warning is
+/// logged at every check while the space is low.
+pub async fn actor(base_path: PathBuf, min_free_space: u64) -> Result<()> {
+    // Register the "disk-guard" actor endpoint with the broker.
+    let broker = BROKER.lock().await.register("disk-guard", false).await?;
+    // Fuse internal termination channel with external channel.
+    // This allows termination of the check loop to be initiated from
+    // outside this function.
+    let mut ch_terminate = broker.ch_terminate.fuse();
+
+    loop {
+        match fs2::available_space(&base_path) {
+            Ok(free_space) => {
+                let was_low = DISK_GUARD.is_low();
+                if DISK_GUARD.update(free_space, min_free_space) {
+                    warn!(
+                        "free disk space ({} bytes) is below the minimum of {} bytes; \
+                         blob fetching and replication of feeds which are not followed \
+                         are paused",
+                        free_space, min_free_space
+                    );
+                } else if was_low {
+                    info!(
+                        "free disk space ({} bytes) is back above the minimum; \
+                         resuming blob fetching and replication",
+                        free_space
+                    );
+                }
+            }
+            Err(err) => warn!("failed to check free disk space: {}", err),
+        }
+
+        select_biased! {
+            _ = ch_terminate => break,
+            _ = task::sleep(CHECK_INTERVAL).fuse() => (),
+        }
+    }
+
+    // Send terminated signal back to the broker.
+    let _ = broker.ch_terminated.send(Void {});
+
+    Ok(())
+}
@@ -0,0 +1,282 @@
+//! Commands run on appended messages.
+//!
+//! Hooks are listed in `hooks.toml` in the data directory. Each one names a
+//! command, run whenever an appended message matches the filter of the
+//! hook (a content type, an author or both), with the message (as a KVT)
+//! written as JSON to its standard input. The key and author of the message
+//! are also given in the `SOLAR_MSG_KEY` and `SOLAR_MSG_AUTHOR` environment
+//! variables. Hooks run one at a time, in order of appending, and a hook
+//! still running after its timeout is killed. The exit status of a hook has
+//! no effect on the message, which is already stored. Messages by muted
+//! feeds are ignored.
+
+use std::{
+    fs,
+    io::Write,
+    path::Path,
+    process::{Command, ExitStatus, Stdio},
+    sync::{Arc, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use async_std::task;
+use futures::{select_biased, stream::FusedStream, FutureExt, StreamExt};
+use kuska_ssb::feed::Feed;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::{broker::*, error::Error, uri, Result, KV_STORAGE};
+
+/// The hooks loaded from the data directory.
+static EXEC_HOOKS: Lazy<RwLock<Vec<ExecHook>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+/// Interval at which a running hook is checked for completion.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+fn default_timeout() -> u64 {
+    60
+}
+
+/// A command run on the appended messages matching its filter.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ExecHook {
+    /// Path of the executable.
+    pub command: String,
+    /// Arguments given to the executable.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Content type of the messages to run the command on (default: any).
+    /// Private messages have no visible type, so they only match hooks
+    /// without one.
+    #[serde(rename = "type")]
+    pub msg_type: Option<String>,
+    /// Author of the messages to run the command on (default: any).
+    pub author: Option<String>,
+    /// Seconds after which the command is killed (default: 60).
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// Content of `hooks.toml`.
+#[derive(Deserialize)]
+struct HooksConfig {
+    #[serde(default)]
+    hook: Vec<ExecHook>,
+}
+
+impl ExecHook {
+    /// Whether the given message (as a KVT) matches the filter of the hook.
+    pub fn matches(&self, msg_kvt: &Feed) -> bool {
+        let type_matches = match &self.msg_type {
+            Some(msg_type) => msg_kvt.value["content"]["type"] == msg_type.as_str(),
+            None => true,
+        };
+        let author_matches = match &self.author {
+            Some(author) => msg_kvt.value["author"] == author.as_str(),
+            None => true,
+        };
+
+        type_matches && author_matches
+    }
+
+    /// Run the command with the given message (as a KVT) on its standard
+    /// input and wait for it to exit, killing it after the timeout.
+    pub fn run(&self, msg_kvt: &Feed) -> Result<ExitStatus> {
+        let mut input = serde_json::to_vec(msg_kvt)?;
+        input.push(b'\n');
+
+        let mut child = Command::new(&self.command)
+            .args(&self.args)
+            .env("SOLAR_MSG_KEY", &msg_kvt.key)
+            .env(
+                "SOLAR_MSG_AUTHOR",
+                msg_kvt.value["author"].as_str().unwrap_or_default(),
+            )
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .spawn()?;
+        // The command may exit without reading its input.
+        if let Some(mut stdin) = child.stdin.take() {
+            let _ = stdin.write_all(&input);
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(self.timeout);
+        loop {
+            if let Some(status) = child.try_wait()? {
+                return Ok(status);
+            }
+            if Instant::now() >= deadline {
+                child.kill()?;
+                child.wait()?;
+                return Err(Error::Other(format!(
+                    "killed after {} seconds",
+                    self.timeout
+                )));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Load the hooks listed in the TOML file at `path`, if it exists. Returns
+/// the number of hooks loaded.
+pub fn load_config(path: &Path) -> Result<usize> {
+    if !path.is_file() {
+        return Ok(0);
+    }
+
+    let mut config: HooksConfig = toml::from_slice(&fs::read(path)?)?;
+    for hook in &mut config.hook {
+        if hook.command.is_empty() {
+            return Err(Error::Other(format!(
+                "hook without a command in {}",
+                path.display()
+            )));
+        }
+        hook.author = hook.author.as_deref().map(uri::normalize);
+    }
+    let count = config.hook.len();
+    *EXEC_HOOKS.write().unwrap() = config.hook;
+
+    Ok(count)
+}
+
+/// Whether no hook is loaded, in which case the actor need not run.
+pub fn is_empty() -> bool {
+    EXEC_HOOKS.read().unwrap().is_empty()
+}
+
+/// Register the exec hooks actor with the broker and follow the log of
+/// appended messages, running the loaded hooks on the messages matching
+/// them. Only messages appended from now on are considered.
+pub async fn actor() -> Result<()> {
+    // Register the "exec-hooks" actor endpoint with the broker.
+    let ActorEndpoint {
+        ch_terminate,
+        ch_terminated,
+        ..
+    } = BROKER.lock().await.register("exec-hooks", false).await?;
+    let mut ch_terminate = ch_terminate.fuse();
+
+    let hooks = EXEC_HOOKS.read().unwrap().clone();
+    let mut cursor = KV_STORAGE.read().await.get_latest_global_seq()?;
+    let mut tail = tail_appended(cursor).await?;
+
+    loop {
+        let msg_kvt = select_biased! {
+            _ = ch_terminate => break,
+            item = tail.next() => match item {
+                Some(Ok((global_seq, msg_kvt))) => {
+                    cursor = global_seq;
+                    Arc::new(msg_kvt)
+                }
+                Some(Err(err)) => {
+                    warn!("failed to read appended message: {}", err);
+                    continue;
+                }
+                // The stream ends when the database is compacted; follow the
+                // compacted database from the latest message read, since
+                // messages may be appended before the tail is resumed.
+                None => {
+                    tail = tail_appended(cursor).await?;
+                    continue;
+                }
+            },
+        };
+
+        let author = msg_kvt.value["author"].as_str().unwrap_or_default();
+        if KV_STORAGE.read().await.mutes().is_muted(author)? {
+            continue;
+        }
+
+        for hook in hooks.iter().filter(|hook| hook.matches(&msg_kvt)) {
+            let (command, msg) = (hook.clone(), Arc::clone(&msg_kvt));
+            match task::spawn_blocking(move || command.run(&msg)).await {
+                Ok(status) if status.success() => {
+                    debug!("ran hook {} on {}", hook.command, msg_kvt.key)
+                }
+                Ok(status) => warn!(
+                    "hook {} failed on {}: {}",
+                    hook.command, msg_kvt.key, status
+                ),
+                Err(err) => warn!(
+                    "failed to run hook {} on {}: {}",
+                    hook.command, msg_kvt.key, err
+                ),
+            }
+        }
+    }
+
+    // Send terminated signal back to the broker.
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+/// Return a stream of the messages appended after the given global sequence
+/// number.
+async fn tail_appended(
+    after_global_seq: u64,
+) -> Result<impl FusedStream<Item = Result<(u64, Feed)>> + Unpin> {
+    let tail = KV_STORAGE
+        .read()
+        .await
+        .tail_messages(after_global_seq)
+        .await?;
+
+    Ok(Box::pin(tail).fuse())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    const AUTHOR: &str = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+
+    fn msg(content: serde_json::Value) -> Feed {
+        serde_json::from_value(json!({
+            "key": "%R7lJEkz27lNijPhYNDzYoPjM0Fp+bFWzwX0SmNJB/ZE=.sha256",
+            "value": { "author": AUTHOR, "content": content },
+            "timestamp": 0.0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_exec_hooks() -> Result<()> {
+        let config: HooksConfig = toml::from_str(&format!(
+            r#"
+            [[hook]]
+            command = "sh"
+            args = ["-c", "grep -q '\"text\":\"hi\"' && test \"$SOLAR_MSG_AUTHOR\" = '{AUTHOR}'"]
+            type = "post"
+            author = "{AUTHOR}"
+
+            [[hook]]
+            command = "sleep"
+            args = ["10"]
+            timeout = 1
+            "#
+        ))?;
+        let (post_hook, slow_hook) = (&config.hook[0], &config.hook[1]);
+
+        let post = msg(json!({ "type": "post", "text": "hi" }));
+        let private = msg(json!("private.box"));
+        assert!(post_hook.matches(&post));
+        assert!(!post_hook.matches(&msg(json!({ "type": "vote" }))));
+        assert!(!post_hook.matches(&private));
+        assert!(slow_hook.matches(&private));
+
+        assert!(post_hook.run(&post)?.success());
+        assert!(!post_hook
+            .run(&msg(json!({ "type": "post", "text": "bye" })))?
+            .success());
+        assert!(slow_hook.run(&post).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,212 @@
+use std::{path::PathBuf, time::Duration};
+
+use async_std::task;
+use futures::{select_biased, stream::FusedStream, FutureExt, SinkExt, StreamExt};
+use kuska_ssb::{feed::Feed, keystore::OwnedIdentity};
+use log::{info, warn};
+use serde_json::json;
+
+use crate::{
+    actors::{outbox::publish_or_queue, rpc::RpcHistoryStreamEvent},
+    broker::*,
+    config::REPLICATION_CONFIG,
+    storage::kv,
+    Result, KV_STORAGE,
+};
+
+/// Interval in seconds between checks for a pending catch-up with the
+/// followers of the local identity.
+const CATCH_UP_INTERVAL: u64 = 5;
+
+/// Register the follow-back actor with the broker and follow the log of
+/// appended messages. Whenever a peer publishes a contact message about the
+/// local identity and the contact index shows that the peer follows it,
+/// publish a contact message following the peer in return and add the peer
+/// to the replication list.
+///
+/// Peers whom the local identity has explicitly followed or unfollowed in
+/// the past are left alone. Peers seen to follow the local identity while
+/// the database is under maintenance, or before the actor was started, are
+/// followed back once maintenance completes.
+pub async fn actor(server_id: OwnedIdentity, base_path: PathBuf) -> Result<()> {
+    // Register the "follow-back" actor endpoint with the broker.
+    let ActorEndpoint {
+        ch_terminate,
+        ch_terminated,
+        mut ch_broker,
+        ..
+    } = BROKER.lock().await.register("follow-back", false).await?;
+    let mut ch_terminate = ch_terminate.fuse();
+
+    let replication_config_file = base_path.join("replication.toml");
+
+    // Only messages appended from now on are tailed; followers whose
+    // contact messages were appended earlier, or while the database was
+    // under maintenance, are caught up with from the contact index once it
+    // is complete.
+    let mut cursor = KV_STORAGE.read().await.get_latest_global_seq()?;
+    let mut tail = tail_appended(cursor).await?;
+    let mut catch_up = true;
+
+    loop {
+        if catch_up && !kv::is_under_maintenance() {
+            match catch_up_followers(&server_id, &replication_config_file, &mut ch_broker).await {
+                Ok(()) => catch_up = false,
+                Err(err) => warn!("failed to catch up with followers: {}", err),
+            }
+        }
+
+        let msg_kvt = select_biased! {
+            _ = ch_terminate => break,
+            item = tail.next() => match item {
+                Some(Ok((global_seq, msg_kvt))) => {
+                    cursor = global_seq;
+                    msg_kvt
+                }
+                Some(Err(err)) => {
+                    warn!("failed to read appended message: {}", err);
+                    continue;
+                }
+                // The stream ends when the database is compacted; follow the
+                // compacted database from the latest message read, since
+                // messages may be appended before the tail is resumed.
+                None => {
+                    tail = tail_appended(cursor).await?;
+                    continue;
+                }
+            },
+            // Check again for a pending catch-up.
+            _ = task::sleep(Duration::from_secs(CATCH_UP_INTERVAL)).fuse() => continue,
+        };
+
+        let content = &msg_kvt.value["content"];
+        if content["type"].as_str() != Some("contact")
+            || content["contact"].as_str() != Some(server_id.id.as_str())
+        {
+            continue;
+        }
+
+        // The contact index can't be relied upon while the database is
+        // under maintenance, so the follow is caught up with afterwards.
+        if kv::is_under_maintenance() {
+            catch_up = true;
+            continue;
+        }
+        if let Some(peer_pk) = msg_kvt.value["author"].as_str() {
+            follow_back_peer(
+                &server_id,
+                peer_pk,
+                &replication_config_file,
+                &mut ch_broker,
+            )
+            .await;
+        }
+    }
+
+    // Send terminated signal back to the broker.
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+/// Return a stream of the messages appended after the given global sequence
+/// number.
+async fn tail_appended(
+    after_global_seq: u64,
+) -> Result<impl FusedStream<Item = Result<(u64, Feed)>> + Unpin> {
+    let tail = KV_STORAGE
+        .read()
+        .await
+        .tail_messages(after_global_seq)
+        .await?;
+
+    Ok(Box::pin(tail).fuse())
+}
+
+/// Follow back every peer following the local identity, as recorded in the
+/// contact index.
+async fn catch_up_followers(
+    server_id: &OwnedIdentity,
+    replication_config_file: &PathBuf,
+    ch_broker: &mut ChBrokerSend,
+) -> Result<()> {
+    let followers = KV_STORAGE
+        .read()
+        .await
+        .indexes()
+        .get_followers(&server_id.id)?;
+    for peer_pk in followers {
+        follow_back_peer(server_id, &peer_pk, replication_config_file, ch_broker).await;
+    }
+
+    Ok(())
+}
+
+/// Follow back the given peer if need be and, if so, request their feed
+/// from connected peers.
+async fn follow_back_peer(
+    server_id: &OwnedIdentity,
+    peer_pk: &str,
+    replication_config_file: &PathBuf,
+    ch_broker: &mut ChBrokerSend,
+) {
+    match follow_back(server_id, peer_pk, replication_config_file).await {
+        Ok(true) => {
+            info!("followed back peer {}", peer_pk);
+            // Request the feed of the new peer from connected peers.
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    RpcHistoryStreamEvent::Replicate(peer_pk.to_owned()),
+                ))
+                .await
+                .unwrap();
+        }
+        Ok(false) => (),
+        Err(err) => warn!("failed to follow back peer {}: {}", peer_pk, err),
+    }
+}
+
+/// Follow back the given peer if they follow the local identity and the
+/// local identity has never published a contact message about them, nor
+/// queued one in the outbox. Returns `true` if the peer was followed.
+async fn follow_back(
+    server_id: &OwnedIdentity,
+    peer_pk: &str,
+    replication_config_file: &PathBuf,
+) -> Result<bool> {
+    if peer_pk == server_id.id {
+        return Ok(false);
+    }
+
+    let content = json!({ "type": "contact", "contact": peer_pk, "following": true });
+    {
+        let db = KV_STORAGE.read().await;
+        let indexes = db.indexes();
+        if indexes.get_contact(peer_pk, &server_id.id)? != Some(true)
+            || indexes.get_contact(&server_id.id, peer_pk)?.is_some()
+            || db
+                .outbox()
+                .get_queued()?
+                .iter()
+                .any(|(_, queued)| *queued == content)
+        {
+            return Ok(false);
+        }
+    }
+
+    // Publish a contact message following the peer, or hold it in the
+    // outbox if the database has come under maintenance meanwhile.
+    publish_or_queue(server_id, content).await?;
+
+    // Add the peer to the replication list.
+    REPLICATION_CONFIG
+        .get()
+        .unwrap()
+        .write()
+        .await
+        .add_peer(peer_pk, replication_config_file)
+        .await?;
+
+    Ok(true)
+}
@@ -0,0 +1,162 @@
+//! Follower change events.
+//!
+//! The key-value store broadcasts each change in the follow or block state
+//! of another feed for the local identity as the contact message is indexed.
+//! The latest changes are kept in memory, numbered in order, so that clients
+//! can show "X followed you" as it happens by asking for the events after
+//! the latest one they have seen, rather than diffing snapshots of the
+//! follow graph.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use futures::{select_biased, FutureExt, StreamExt};
+use log::info;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{
+    actors::sync_session,
+    broker::*,
+    storage::{
+        indexes::{ContactChange, ContactChangeKind},
+        kv::StoKvEvent,
+    },
+    Result,
+};
+
+/// Maximum number of events kept in memory. Older events are dropped.
+pub const MAX_EVENTS: usize = 1000;
+
+/// The latest follower change events of the solar node.
+pub static FOLLOWER_EVENTS: Lazy<Mutex<FollowerEvents>> =
+    Lazy::new(|| Mutex::new(FollowerEvents::default()));
+
+/// A feed has followed, unfollowed, blocked or unblocked the local identity.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FollowerEvent {
+    /// Position of the event in the order of events, starting at 1.
+    pub id: u64,
+    /// Feed which published the contact message.
+    pub author: String,
+    pub kind: ContactChangeKind,
+    /// ID of the contact message.
+    pub msg_ref: String,
+    /// Milliseconds since the Unix epoch at which the change was indexed.
+    pub timestamp: u64,
+}
+
+/// The latest follower change events, oldest first.
+#[derive(Debug, Default)]
+pub struct FollowerEvents {
+    events: VecDeque<FollowerEvent>,
+    latest_id: u64,
+}
+
+impl FollowerEvents {
+    /// Record the given change, indexed at `now`, dropping the oldest event
+    /// once `MAX_EVENTS` are kept. Returns the event.
+    pub fn push(&mut self, change: ContactChange, now: u64) -> FollowerEvent {
+        self.latest_id += 1;
+        let event = FollowerEvent {
+            id: self.latest_id,
+            author: change.author,
+            kind: change.kind,
+            msg_ref: change.msg_ref,
+            timestamp: now,
+        };
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event.clone());
+
+        event
+    }
+
+    /// Return the kept events which follow the event with the given ID,
+    /// oldest first. Pass 0 for all of them.
+    pub fn after(&self, id: u64) -> Vec<FollowerEvent> {
+        self.events
+            .iter()
+            .filter(|event| event.id > id)
+            .cloned()
+            .collect()
+    }
+}
+
+/// Register the follower events actor with the broker and record the
+/// follower changes broadcast by the key-value store.
+pub async fn actor() -> Result<()> {
+    // Register the "follower-events" actor endpoint with the broker.
+    let ActorEndpoint {
+        ch_terminate,
+        ch_terminated,
+        ch_msg,
+        ..
+    } = BROKER
+        .lock()
+        .await
+        .register("follower-events", true)
+        .await?;
+    let mut ch_terminate = ch_terminate.fuse();
+    let mut ch_msg = ch_msg.unwrap();
+
+    loop {
+        let msg = select_biased! {
+            _ = ch_terminate => break,
+            msg = ch_msg.next().fuse() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
+
+        if let Some(StoKvEvent::FollowerChanged(change)) = msg.downcast_ref::<StoKvEvent>() {
+            let verb = match change.kind {
+                ContactChangeKind::Follow => "followed",
+                ContactChangeKind::Unfollow => "unfollowed",
+                ContactChangeKind::Block => "blocked",
+                ContactChangeKind::Unblock => "unblocked",
+            };
+            info!("{} {} the local identity", change.author, verb);
+            FOLLOWER_EVENTS
+                .lock()
+                .unwrap()
+                .push(change.clone(), sync_session::now());
+        }
+    }
+
+    // Send terminated signal back to the broker.
+    let _ = ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn change(author: &str, kind: ContactChangeKind) -> ContactChange {
+        ContactChange {
+            author: author.to_string(),
+            contact: "@local=.ed25519".to_string(),
+            kind,
+            msg_ref: format!("%{author}"),
+        }
+    }
+
+    #[test]
+    fn test_follower_events() {
+        let mut events = FollowerEvents::default();
+        for i in 0..MAX_EVENTS + 2 {
+            events.push(change(&format!("@{i}"), ContactChangeKind::Follow), 1000);
+        }
+
+        // The oldest events are dropped.
+        let kept = events.after(0);
+        assert_eq!(kept.len(), MAX_EVENTS);
+        assert_eq!(kept[0].id, 3);
+
+        let event = events.push(change("@alice", ContactChangeKind::Block), 2000);
+        assert!(events.after(event.id).is_empty());
+        assert_eq!(events.after(event.id - 1), vec![event]);
+    }
+}
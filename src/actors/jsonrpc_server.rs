@@ -1,29 +1,422 @@
 // src/actors/json_rpc_server.rs
 
+use std::{
+    future::Future,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
 use async_std::task;
-use futures::FutureExt;
+use futures::{
+    future::{self, Either},
+    FutureExt, SinkExt, StreamExt,
+};
 use jsonrpc_http_server::{
-    jsonrpc_core::*, AccessControlAllowOrigin, DomainsValidation, ServerBuilder,
+    hyper::{
+        self,
+        body::HttpBody,
+        header::{HeaderValue, AUTHORIZATION, CACHE_CONTROL, CONTENT_TYPE, WWW_AUTHENTICATE},
+        Body, Method, Request, StatusCode,
+    },
+    jsonrpc_core::*,
+    AccessControlAllowOrigin, DomainsValidation, RequestMiddlewareAction, ServerBuilder,
 };
-use kuska_ssb::{api::dto::content::TypedMessage, feed::Message, keystore::OwnedIdentity};
-use log::{info, warn};
+use kuska_ssb::{feed::Feed as MessageKvt, keystore::OwnedIdentity};
+use log::{debug, info, warn};
 use serde::Deserialize;
 use serde_json::json;
+use url::form_urlencoded;
+
+#[cfg(feature = "blobs")]
+use crate::actors::rpc::{BlobPriority, RpcBlobsGetEvent, BLOB_QUEUE};
+#[cfg(feature = "graphql")]
+use crate::api::graphql;
+#[cfg(feature = "viewer")]
+use crate::api::viewer;
+use crate::{
+    actors::{
+        follower_events::FOLLOWER_EVENTS,
+        outbox::{publish_content, publish_or_queue},
+        peer::PeerBanned,
+        replication_pause::{ReplicationPauseChanged, REPLICATION_PAUSE},
+        rpc::{RpcHistoryStreamEvent, RpcOooEvent},
+        sync_session::{self, SYNC_SESSIONS},
+    },
+    api::{
+        alias, atom,
+        content::{self, Post},
+        frontier,
+        graph::{self, GraphFormat},
+        import, replication, stats,
+        tokens::{tokens_match, Access},
+    },
+    broker::*,
+    config::{
+        ALLOW_LIST_CONFIG, ARCHIVIST_CONFIG, IMPORT_CONFIG, READ_ONLY_CONFIG, REPLICATION_CONFIG,
+        SECRET_CONFIG,
+    },
+    error::Error,
+    health::HEALTH,
+    logging,
+    metafeed::{self, MetafeedTree},
+    private_box,
+    storage::{
+        clock_skew,
+        kv::{self, KvStorage},
+    },
+    uri, Result, KV_STORAGE,
+};
+#[cfg(feature = "activitypub")]
+use crate::{api::activitypub, config::ACTIVITYPUB_CONFIG};
+#[cfg(feature = "blobs")]
+use crate::{storage::blob_fsck, BLOB_STORAGE};
 
-use crate::{broker::*, error::Error, Result, KV_STORAGE};
+/// Maximum size in bytes of the body of a request to the message import
+/// and delta endpoints.
+const MAX_BODY_SIZE: usize = 32 * 1024 * 1024;
+/// Interval at which a comment is sent on an idle event stream, so that
+/// proxies don't close it.
+const EVENTS_KEEPALIVE: Duration = Duration::from_secs(30);
+/// Maximum size of the body of a GraphQL request.
+#[cfg(feature = "graphql")]
+const MAX_QUERY_SIZE: usize = 64 * 1024;
 
 /// Message reference containing the key (sha256 hash) of a message.
 /// Used to parse the key from the parameters supplied to the `message`
 /// endpoint.
 #[derive(Debug, Deserialize)]
 struct MsgRef {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
+    msg_ref: String,
+}
+
+/// Blob reference containing the key (sha256 hash) of a blob.
+#[cfg(feature = "blobs")]
+#[derive(Debug, Deserialize)]
+struct BlobRef {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
+    blob_ref: String,
+}
+
+/// A blob to add to the want-list, with its priority and a peer likely to
+/// hold it.
+#[cfg(feature = "blobs")]
+#[derive(Debug, Deserialize)]
+struct WantBlobArgs {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
+    blob_ref: String,
+    #[serde(default = "default_want_priority")]
+    priority: BlobPriority,
+    hint: Option<String>,
+}
+
+#[cfg(feature = "blobs")]
+fn default_want_priority() -> BlobPriority {
+    BlobPriority::Explicit
+}
+
+/// Feed, message or blob ID, either sigil-encoded or as an SSB URI.
+#[derive(Debug, Deserialize)]
+struct AnyRef {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
+    id: String,
+}
+
+/// Alias to resolve, as its URL or as a `consume-alias` SSB URI.
+#[derive(Debug, Deserialize)]
+struct AliasUri {
+    alias_uri: String,
+}
+
+/// Host name of a room.
+#[derive(Debug, Deserialize)]
+struct RoomHost {
+    host: String,
+}
+
+/// Purpose of a metafeed sub-feed.
+#[derive(Debug, Deserialize)]
+struct SubfeedPurpose {
+    purpose: String,
+}
+
+/// Message reference of the root of a thread.
+#[derive(Debug, Deserialize)]
+struct ThreadRoot {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
+    root: String,
+}
+
+/// Cursor and page size for listing the authors of stored feeds.
+#[derive(Debug, Deserialize)]
+struct AuthorsPage {
+    #[serde(default)]
+    cursor: Option<u32>,
+    #[serde(default = "default_authors_limit")]
+    limit: usize,
+}
+
+fn default_authors_limit() -> usize {
+    100
+}
+
+/// Cursor, page size and maximum follow graph distance for reading the
+/// timeline.
+#[derive(Debug, Deserialize)]
+struct TimelineArgs {
+    #[serde(default)]
+    cursor: Option<u64>,
+    #[serde(default = "default_timeline_limit")]
+    limit: usize,
+    #[serde(default = "default_timeline_hops")]
+    hops: usize,
+}
+
+fn default_timeline_limit() -> usize {
+    50
+}
+
+fn default_timeline_hops() -> usize {
+    1
+}
+
+/// Time range, cursor and page size for listing messages by timestamp.
+#[derive(Debug, Deserialize)]
+struct MessagesBetweenArgs {
+    start: u64,
+    #[serde(default)]
+    end: Option<u64>,
+    #[serde(default)]
+    cursor: Option<String>,
+    #[serde(default = "default_timeline_limit")]
+    limit: usize,
+}
+
+/// Message whose content to delete, and the reason for the deletion.
+#[derive(Debug, Deserialize)]
+struct TombstoneArgs {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
     msg_ref: String,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Number of days of daily message counts and maximum number of hops of
+/// the follow graph included in the network statistics.
+#[derive(Debug, Deserialize)]
+struct NetworkStatsArgs {
+    #[serde(default = "default_stats_days")]
+    days: u32,
+    #[serde(default = "default_stats_hops")]
+    hops: usize,
+}
+
+fn default_stats_days() -> u32 {
+    30
+}
+
+fn default_stats_hops() -> usize {
+    3
+}
+
+/// ID of the latest follower event seen by the client.
+#[derive(Debug, Deserialize)]
+struct FollowerEventsArgs {
+    #[serde(default)]
+    after: u64,
+}
+
+/// Frontier of a peer: latest sequence number of each of its feeds.
+#[derive(Debug, Deserialize)]
+struct FrontierDiffArgs {
+    frontier: frontier::Frontier,
+}
+
+/// Subsystem and log level to set; `None` resets the level to `RUST_LOG`.
+#[derive(Debug, Deserialize)]
+struct LogLevelArgs {
+    subsystem: String,
+    #[serde(default)]
+    level: Option<String>,
+}
+
+/// Cursor and page size for listing notifications.
+#[derive(Debug, Deserialize)]
+struct NotificationsArgs {
+    #[serde(default)]
+    cursor: Option<u64>,
+    #[serde(default = "default_notifications_limit")]
+    limit: usize,
+}
+
+fn default_notifications_limit() -> usize {
+    50
+}
+
+/// Global sequence number of a message.
+#[derive(Debug, Deserialize)]
+struct GlobalSeq {
+    global_seq: u64,
+}
+
+/// Peer with which to pause or resume replication, or every peer if none
+/// is given.
+#[derive(Debug, Deserialize)]
+struct PauseArgs {
+    #[serde(default, deserialize_with = "uri::deserialize_opt_ref")]
+    peer: Option<String>,
+}
+
+/// Peer and number of entries for querying the connection audit log.
+#[derive(Debug, Deserialize)]
+struct ConnectionLogArgs {
+    #[serde(default, deserialize_with = "uri::deserialize_opt_ref")]
+    peer: Option<String>,
+    #[serde(default = "default_connection_log_limit")]
+    limit: usize,
+}
+
+fn default_connection_log_limit() -> usize {
+    100
+}
+
+/// Number of expired messages to list.
+#[derive(Debug, Deserialize)]
+struct ExpiredArgs {
+    #[serde(default = "default_expired_limit")]
+    limit: usize,
+}
+
+fn default_expired_limit() -> usize {
+    100
+}
+
+/// Whether to list only gatherings which have not started yet.
+#[derive(Debug, Default, Deserialize)]
+struct GatheringsArgs {
+    #[serde(default)]
+    upcoming_only: bool,
+}
+
+/// ID of an entry in the outbox.
+#[derive(Debug, Deserialize)]
+struct OutboxId {
+    outbox_id: u64,
+}
+
+/// Format of a contact graph export and whether to anonymize it.
+#[derive(Debug, Deserialize)]
+struct GraphExport {
+    format: GraphFormat,
+    #[serde(default)]
+    anonymize: bool,
+}
+
+/// A feed to mute and whether to stop replicating it.
+#[derive(Debug, Deserialize)]
+struct MuteArgs {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
+    pub_key: String,
+    #[serde(default)]
+    stop_replication: bool,
+}
+
+/// ID of a reported feed or message.
+#[derive(Debug, Deserialize)]
+struct ReportTarget {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
+    target: String,
+}
+
+/// Owner of git-ssb repositories.
+#[derive(Debug, Deserialize)]
+struct RepoOwner {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
+    owner: String,
+}
+
+/// ID of a git-ssb repository.
+#[derive(Debug, Deserialize)]
+struct RepoId {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
+    repo_id: String,
 }
 
 /// The public key (ID) of a peer.
 #[derive(Debug, Deserialize)]
 struct PubKey {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
+    pub_key: String,
+}
+
+/// Feed to read, and the range of its messages to return: up to `limit`
+/// messages from sequence number `start_seq`, or the whole feed.
+#[derive(Debug, Deserialize)]
+struct FeedArgs {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
+    pub_key: String,
+    #[serde(default = "default_start_seq")]
+    start_seq: u64,
+    #[serde(default)]
+    limit: Option<usize>,
+}
+
+fn default_start_seq() -> u64 {
+    1
+}
+
+/// Peer to ban, for how many seconds and why.
+#[derive(Debug, Deserialize)]
+struct BanArgs {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
     pub_key: String,
+    duration: u64,
+    #[serde(default)]
+    reason: Option<String>,
+}
+
+/// Recipients and text of a new private thread, with an optional content
+/// warning.
+#[derive(Debug, Deserialize)]
+struct PrivateThread {
+    #[serde(deserialize_with = "uri::deserialize_refs")]
+    recipients: Vec<String>,
+    text: String,
+    #[serde(default)]
+    content_warning: Option<String>,
+}
+
+/// Key of a private message and the text of a reply to it, with an optional
+/// content warning.
+#[derive(Debug, Deserialize)]
+struct PrivateReply {
+    #[serde(deserialize_with = "uri::deserialize_ref")]
+    root: String,
+    text: String,
+    #[serde(default)]
+    content_warning: Option<String>,
+}
+
+/// Return the metafeed seed of the local identity.
+fn metafeed_seed() -> Result<Vec<u8>> {
+    SECRET_CONFIG
+        .get()
+        .unwrap()
+        .metafeed_seed()
+        .ok_or_else(|| Error::Metafeed("no metafeed seed is stored in secret.toml".to_string()))
+}
+
+/// Serialize a message KVT, adding a `verified` flag which is false if the
+/// message was stored without being fully verified, and the reason why its
+/// content is invalid, if it is.
+fn kvt_to_json(db: &KvStorage, msg_kvt: &MessageKvt) -> Result<Value> {
+    let verified = db.is_msg_verified(&msg_kvt.key)?;
+    let mut value = json!(msg_kvt);
+    value["verified"] = Value::Bool(verified);
+    if let Some(reason) = db.indexes().get_invalid(&msg_kvt.key)? {
+        value["invalid"] = Value::String(reason);
+    }
+
+    Ok(value)
 }
 
 /// Register the JSON-RPC server endpoint, define the JSON-RPC methods
@@ -31,7 +424,7 @@ struct PubKey {
 ///
 /// Listens for a termination signal from the broker. When received, the
 /// JSON-RPC server is closed and a terminated signal is sent to the broker.
-pub async fn actor(server_id: OwnedIdentity, server_addr: String) -> Result<()> {
+pub async fn actor(server_id: OwnedIdentity, server_addr: String, threads: usize) -> Result<()> {
     let broker = BROKER
         .lock()
         .await
@@ -40,161 +433,1947 @@ pub async fn actor(server_id: OwnedIdentity, server_addr: String) -> Result<()>
 
     let ch_terminate = broker.ch_terminate.fuse();
 
-    let mut io = IoHandler::default();
+    let mut io = MetaIoHandler::with_middleware(ScopeCheck);
 
-    // Retrieve a feed by public key.
-    // Returns an array of messages as a KVTs.
-    io.add_sync_method("feed", move |params: Params| {
+    // Return the activity statistics of a feed, or `null` if no message of
+    // the feed is stored.
+    io.add_sync_method("authorStats", |params: Params| {
         task::block_on(async {
-            // Parse the parameter containing the public key.
             let pub_key: PubKey = params.parse()?;
 
-            // Open the primary KV database for reading.
             let db = KV_STORAGE.read().await;
+            let stats = stats::author_stats(&db, &pub_key.pub_key)?;
 
-            // Retrieve the message value for the requested message.
-            let feed = db.get_feed(&pub_key.pub_key)?;
+            Ok(json!(stats))
+        })
+    });
 
-            let response = json!(feed);
+    // Return the feeds which have claimed a timestamp later than the receipt
+    // of their message by more than the maximum clock skew.
+    io.add_sync_method("clockSkew", |_| {
+        task::block_on(async {
+            let db = KV_STORAGE.read().await;
+            let skewed = db
+                .clock_skew()
+                .get_skewed(clock_skew::max_clock_skew() as i64)?;
+
+            Ok(json!(skewed))
+        })
+    });
+
+    // Return a page of the public keys of authors with a stored feed, along
+    // with the total number of such authors and the cursor of the next page.
+    io.add_sync_method("authors", move |params: Params| {
+        task::block_on(async {
+            let page: AuthorsPage = match params {
+                Params::None => AuthorsPage {
+                    cursor: None,
+                    limit: default_authors_limit(),
+                },
+                params => params.parse()?,
+            };
+
+            let db = KV_STORAGE.read().await;
+            let authors = db.authors(page.cursor, page.limit)?;
+
+            let response = json!(authors);
 
             Ok(response)
         })
     });
 
-    // Retrieve a message by key.
-    // Returns the message as a KVT.
-    io.add_sync_method("message", move |params: Params| {
+    // Ban a peer for the given number of seconds, replacing any previous ban
+    // of it. Connections with the peer are refused until the ban expires,
+    // and any open connection is closed. Returns the ban.
+    io.add_sync_method("ban", |params: Params| {
         task::block_on(async {
-            // Parse the parameter containing the message reference (key).
-            let msg_ref: MsgRef = params.parse()?;
+            let args: BanArgs = params.parse()?;
+            let until = sync_session::now().saturating_add(args.duration.saturating_mul(1000));
 
-            // Open the primary KV database for reading.
             let db = KV_STORAGE.read().await;
+            let ban = db.bans().ban(&args.pub_key, until, args.reason)?;
 
-            // Retrieve the message value for the requested message.
-            let msg_val = db.get_msg_val(&msg_ref.msg_ref)?;
+            // Close any open connection with the peer.
+            let mut ch_broker = BROKER.lock().await.create_sender();
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    PeerBanned(ban.pub_key.clone()),
+                ))
+                .await
+                .unwrap();
 
-            // Retrieve the message KVT for the requested message using the
-            // author and sequence fields from the message value.
-            let msg_kvt = if let Some(val) = msg_val {
-                db.get_msg_kvt(val.author(), val.sequence())?
-            } else {
-                None
-            };
+            Ok(json!(ban))
+        })
+    });
 
-            let response = json!(msg_kvt);
+    // Return the peers which are banned, soonest lifted first.
+    io.add_sync_method("bans", |_| {
+        task::block_on(async {
+            let db = KV_STORAGE.read().await;
+            let bans = db.bans().get_all(sync_session::now())?;
 
-            Ok(response)
+            Ok(json!(bans))
         })
     });
 
-    // Retrieve the last message we have of a feed
-    // Returns the message as a KVT.
-    io.add_sync_method("getLatest", move |params: Params| {
+    // Retrieve a feed by public key, optionally a page of it at a time.
+    // Returns an array of messages as a KVTs, which is empty if the feed is
+    // muted.
+    io.add_sync_method("feed", move |params: Params| {
         task::block_on(async {
-            // Parse the parameter containing the feed id
-            let feed_id: PubKey = params.parse()?;
+            // Parse the parameters containing the public key and range.
+            let args: FeedArgs = params.parse()?;
 
             // Open the primary KV database for reading.
             let db = KV_STORAGE.read().await;
 
-            // Retrieve the message value for the requested message.
-            let msg_seq = db.get_latest_seq(&feed_id.pub_key.to_string())?;
-            if let Some(msg_seq) = msg_seq {
-                // Retrieve the message KVT for the requested message using the
-                // author and sequence fields from the message value.
-                let msg_kvt = db.get_msg_kvt(&feed_id.pub_key, msg_seq)?;
-                let response = json!(msg_kvt);
-                Ok(response)
-            } else {
-                // we didn't find the feed locally, but this doesn't constitute an error
-                Ok(json!(r#"{"seq": null}"#))
+            if db.mutes().is_muted(&args.pub_key)? {
+                return Ok(json!([]));
             }
+
+            // Retrieve the messages in the requested range.
+            let feed = db
+                .get_feed_range(
+                    &args.pub_key,
+                    args.start_seq,
+                    args.limit.unwrap_or(usize::MAX),
+                )?
+                .iter()
+                .map(|msg_kvt| kvt_to_json(&db, msg_kvt))
+                .collect::<Result<Vec<Value>>>()?;
+
+            let response = json!(feed);
+
+            Ok(response)
         })
     });
 
-    // Return the public key and latest sequence number for all feeds in the
-    // local database.
-    io.add_sync_method("peers", |_| {
+    // Return the most recent entries of the connection audit log.
+    io.add_sync_method("connectionLog", move |params: Params| {
         task::block_on(async {
+            let args: ConnectionLogArgs = match params {
+                Params::None => ConnectionLogArgs {
+                    peer: None,
+                    limit: default_connection_log_limit(),
+                },
+                params => params.parse()?,
+            };
+
             let db = KV_STORAGE.read().await;
-            let peers = db.get_peers().await?;
+            let entries = db.audit().get_entries(args.peer.as_deref(), args.limit)?;
 
-            let response = json!(peers);
+            let response = json!(entries);
 
             Ok(response)
         })
     });
 
-    // Simple `ping` endpoint.
-    io.add_sync_method("ping", |_| Ok(Value::String("pong!".to_owned())));
+    // Export the follow and block graph of all stored feeds.
+    // Returns the graph as a DOT or GraphML document.
+    io.add_sync_method("contactGraph", move |params: Params| {
+        task::block_on(async {
+            let export: GraphExport = params.parse()?;
 
-    // Clone the local public key (ID) so it can later be captured by the
-    // `whoami` closure.
-    let local_pk = server_id.id.clone();
+            let db = KV_STORAGE.read().await;
+            let contact_graph = db.indexes().get_contact_graph()?;
 
-    // Publish a typed message (raw).
-    // Returns the key (hash) and sequence number of the published message.
-    io.add_sync_method("publish", move |params: Params| {
+            let response = Value::String(graph::export(
+                &contact_graph,
+                export.format,
+                export.anonymize,
+            ));
+
+            Ok(response)
+        })
+    });
+
+    // Deliver messages and blobs to a peer whenever it is seen. Returns
+    // `false` if deliveries to the peer were already enabled.
+    io.add_sync_method("deliver", move |params: Params| {
         task::block_on(async {
-            // Parse the parameter containing the post content.
-            let post_content: TypedMessage = params.parse()?;
+            let pub_key: PubKey = params.parse()?;
 
-            // Open the primary KV database for writing.
-            let db = KV_STORAGE.write().await;
+            let db = KV_STORAGE.read().await;
+            let added = db.deliveries().add(&pub_key.pub_key)?;
+
+            Ok(Value::Bool(added))
+        })
+    });
+
+    // Return the peers to which messages and blobs are delivered, with the
+    // number of messages and the blobs waiting to be delivered to each.
+    io.add_sync_method("deliveries", |_| {
+        task::block_on(async {
+            let db = KV_STORAGE.read().await;
 
-            // Lookup the last message published on the local feed.
-            // Return `None` if no messages have yet been published on the feed.
-            let last_msg = db.get_latest_msg_val(&server_id.id)?;
+            let mut deliveries = Vec::new();
+            for (pub_key, queue) in db.deliveries().get_all()? {
+                let mut pending_msgs = 0;
+                for (feed_id, held) in &queue.feeds {
+                    let latest_seq = db.get_latest_seq(feed_id)?.unwrap_or(0);
+                    pending_msgs += latest_seq.saturating_sub(*held);
+                }
+                deliveries.push(json!({
+                    "pub_key": pub_key,
+                    "feeds": queue.feeds,
+                    "pending_msgs": pending_msgs,
+                    "pending_blobs": queue.blobs,
+                }));
+            }
 
-            // Instantiate and cryptographically-sign a new message using `post`.
-            let msg = Message::sign(last_msg.as_ref(), &server_id, json!(post_content))
-                .map_err(Error::Validation)?;
+            Ok(json!(deliveries))
+        })
+    });
 
-            // Append the signed message to the feed.
-            let seq = db.append_feed(msg.clone()).await?;
+    // Return the messages by other feeds whose expiry hint has passed,
+    // soonest expired first.
+    let expired_id = server_id.id.clone();
+    io.add_sync_method("expired", move |params: Params| {
+        task::block_on(async {
+            let args: ExpiredArgs = match params {
+                Params::None => ExpiredArgs {
+                    limit: default_expired_limit(),
+                },
+                params => params.parse()?,
+            };
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or(0);
 
-            info!(
-                "published message {} with sequence number {}",
-                msg.id().to_string(),
-                seq
-            );
+            let db = KV_STORAGE.read().await;
+            let expired = db.indexes().get_expired(now, &expired_id, args.limit)?;
 
-            let response = json![{ "msg_ref": msg.id().to_string(), "seq_num": seq }];
+            let response = json!(expired);
 
             Ok(response)
         })
     });
 
-    // Return the public key of the local SSB server.
-    io.add_sync_method("whoami", move |_| Ok(Value::String(local_pk.to_owned())));
+    // Return the latest changes in the follow and block states of other
+    // feeds for the local identity which follow the given event, oldest
+    // first.
+    io.add_sync_method("followerEvents", |params: Params| {
+        let args: FollowerEventsArgs = match params {
+            Params::None => FollowerEventsArgs { after: 0 },
+            params => params.parse()?,
+        };
+        let events = FOLLOWER_EVENTS.lock().unwrap().after(args.after);
 
-    let server = ServerBuilder::new(io)
-        .cors(DomainsValidation::AllowOnly(vec![
-            AccessControlAllowOrigin::Null,
-        ]))
-        .start_http(&server_addr.parse()?)?;
+        Ok(json!(events))
+    });
 
-    // Create a close handle to be used when the termination signal is
-    // received.
-    let close_handle = server.close_handle();
+    // Return the latest sequence number of each stored feed, keyed by feed
+    // ID, leaving out muted feeds.
+    io.add_sync_method("frontier", |_| {
+        task::block_on(async {
+            let db = KV_STORAGE.read().await;
+            let frontier = frontier::frontier(&db).await?;
 
-    // Start the JSON-RPC server in a task.
-    // This allows us to listen for the termination signal (without blocking).
-    task::spawn(async {
-        server.wait();
+            Ok(json!(frontier))
+        })
     });
 
-    // Listen for termination signal from broker.
-    if let Err(err) = ch_terminate.await {
-        warn!("ch_terminate sender dropped: {}", err)
-    }
+    // Given the frontier of a peer, return the range of sequence numbers of
+    // the stored messages which the peer lacks in each feed.
+    io.add_sync_method("frontierDiff", |params: Params| {
+        task::block_on(async {
+            let args: FrontierDiffArgs = params.parse()?;
+            let peer: frontier::Frontier = args
+                .frontier
+                .into_iter()
+                .map(|(feed_id, seq)| (uri::normalize(&feed_id), seq))
+                .collect();
 
-    // When received, close (stop) the server.
-    close_handle.close();
+            let db = KV_STORAGE.read().await;
+            let diff = frontier::diff(&db, &peer).await?;
 
-    // Then send terminated signal back to broker.
-    let _ = broker.ch_terminated.send(Void {});
+            Ok(json!(diff))
+        })
+    });
 
-    Ok(())
+    // Return the gatherings (events) known from stored messages, with their
+    // details and attendees, soonest first.
+    io.add_sync_method("gatherings", |params: Params| {
+        task::block_on(async {
+            let args: GatheringsArgs = match params {
+                Params::None => GatheringsArgs::default(),
+                params => params.parse()?,
+            };
+            let after = args.upcoming_only.then(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|duration| duration.as_millis() as u64)
+                    .unwrap_or(0)
+            });
+
+            let db = KV_STORAGE.read().await;
+            let gatherings = db.indexes().get_gatherings(after)?;
+
+            let response = json!(gatherings);
+
+            Ok(response)
+        })
+    });
+
+    // Translate an ID between its sigil encoding and its SSB URI.
+    // Returns both forms; the URI is null if the ID has no URI form.
+    io.add_sync_method("convertRef", move |params: Params| {
+        let any_ref: AnyRef = params.parse()?;
+
+        let response = json!({ "sigil": any_ref.id, "uri": uri::from_sigil(&any_ref.id) });
+
+        Ok(response)
+    });
+
+    // Retrieve a message by key.
+    // Returns the message as a KVT.
+    io.add_sync_method("message", move |params: Params| {
+        task::block_on(async {
+            // Parse the parameter containing the message reference (key).
+            let msg_ref: MsgRef = params.parse()?;
+
+            // Open the primary KV database for reading.
+            let db = KV_STORAGE.read().await;
+
+            // Retrieve the message value for the requested message, unless
+            // its author is muted.
+            let msg_val = match db.get_msg_val(&msg_ref.msg_ref)? {
+                Some(val) if !db.mutes().is_muted(val.author())? => Some(val),
+                _ => None,
+            };
+
+            // Retrieve the message KVT for the requested message using the
+            // author and sequence fields from the message value.
+            let msg_kvt = if let Some(val) = msg_val {
+                db.get_msg_kvt(val.author(), val.sequence())?
+            } else {
+                None
+            };
+            let msg_kvt = msg_kvt
+                .map(|msg_kvt| kvt_to_json(&db, &msg_kvt))
+                .transpose()?;
+
+            let response = json!(msg_kvt);
+
+            Ok(response)
+        })
+    });
+
+    // Retrieve the global sequence number (position in the order in which
+    // messages were received) of a message by key.
+    // Returns `null` if the message is not part of a stored feed.
+    io.add_sync_method("globalSeq", move |params: Params| {
+        task::block_on(async {
+            let msg_ref: MsgRef = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let global_seq = db.get_global_seq(&msg_ref.msg_ref)?;
+
+            let response = json!(global_seq);
+
+            Ok(response)
+        })
+    });
+
+    // Retrieve the last message we have of a feed
+    // Returns the message as a KVT.
+    io.add_sync_method("getLatest", move |params: Params| {
+        task::block_on(async {
+            // Parse the parameter containing the feed id
+            let feed_id: PubKey = params.parse()?;
+
+            // Open the primary KV database for reading.
+            let db = KV_STORAGE.read().await;
+
+            // Retrieve the message value for the requested message. Muted
+            // feeds are treated as unknown.
+            let msg_seq = if db.mutes().is_muted(&feed_id.pub_key)? {
+                None
+            } else {
+                db.get_latest_seq(&feed_id.pub_key.to_string())?
+            };
+            if let Some(msg_seq) = msg_seq {
+                // Retrieve the message KVT for the requested message using the
+                // author and sequence fields from the message value.
+                let msg_kvt = db
+                    .get_msg_kvt(&feed_id.pub_key, msg_seq)?
+                    .map(|msg_kvt| kvt_to_json(&db, &msg_kvt))
+                    .transpose()?;
+                let response = json!(msg_kvt);
+                Ok(response)
+            } else {
+                // we didn't find the feed locally, but this doesn't constitute an error
+                Ok(json!(r#"{"seq": null}"#))
+            }
+        })
+    });
+
+    // Request a blob from connected peers regardless of the blob fetching
+    // policy, ahead of any other queued blobs. Used to fetch blobs which
+    // were skipped.
+    #[cfg(feature = "blobs")]
+    io.add_sync_method("fetchBlob", move |params: Params| {
+        task::block_on(async {
+            let blob_ref: BlobRef = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            db.clear_blob_skipped(&blob_ref.blob_ref)?;
+            BLOB_QUEUE.lock().unwrap().push(
+                db.blob_wants(),
+                &blob_ref.blob_ref,
+                None,
+                BlobPriority::Explicit,
+                0,
+                None,
+            )?;
+
+            let mut ch_broker = BROKER.lock().await.create_sender();
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    RpcBlobsGetEvent::Queued,
+                ))
+                .await
+                .unwrap();
+
+            Ok(Value::Bool(true))
+        })
+    });
+
+    // Fetch the missing messages of a thread from connected peers with
+    // `ooo.get`, including messages authored by peers who are not
+    // replicated: the root and the messages which the stored replies link
+    // to. Fetching happens in the background; returns the messages of the
+    // thread which are already stored locally as KVTs.
+    io.add_sync_method("fetchThread", move |params: Params| {
+        task::block_on(async {
+            let thread_root: ThreadRoot = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let mut missing = Vec::new();
+            // Messages by muted feeds are left out.
+            let root = match db.get_any_msg_val(&thread_root.root)? {
+                Some(msg) if !db.mutes().is_muted(msg.author())? => {
+                    Some(kvt_to_json(&db, &MessageKvt::new(msg))?)
+                }
+                Some(_) => None,
+                None => {
+                    missing.push(thread_root.root.clone());
+                    None
+                }
+            };
+            let mut replies = Vec::new();
+            for msg_id in db.indexes().get_thread(&thread_root.root)? {
+                if let Some(msg) = db.get_any_msg_val(&msg_id)? {
+                    let branches = match &msg.content()["branch"] {
+                        Value::String(branch) => vec![branch.clone()],
+                        Value::Array(branches) => branches
+                            .iter()
+                            .filter_map(|branch| branch.as_str().map(str::to_string))
+                            .collect(),
+                        _ => Vec::new(),
+                    };
+                    for branch in branches {
+                        if db.get_any_msg_val(&branch)?.is_none() {
+                            missing.push(branch);
+                        }
+                    }
+                    if !db.mutes().is_muted(msg.author())? {
+                        replies.push((db.ordering_timestamp(&msg)?, msg));
+                    }
+                }
+            }
+            // Order the replies by their claimed timestamp, unless it is too
+            // far ahead of their receipt.
+            replies.sort_by(|a, b| a.0.total_cmp(&b.0));
+            let replies = replies
+                .into_iter()
+                .map(|(_, msg)| kvt_to_json(&db, &MessageKvt::new(msg)))
+                .collect::<Result<Vec<Value>>>()?;
+            drop(db);
+
+            // Request the missing messages from all connected peers.
+            missing.sort();
+            missing.dedup();
+            let mut ch_broker = BROKER.lock().await.create_sender();
+            for msg_id in missing {
+                ch_broker
+                    .send(BrokerEvent::new(
+                        Destination::Broadcast,
+                        RpcOooEvent::Get(msg_id),
+                    ))
+                    .await
+                    .unwrap();
+            }
+
+            let response = json!({ "root": root, "replies": replies });
+
+            Ok(response)
+        })
+    });
+
+    // Add a feed to the local mute list. No contact message is published.
+    // Returns `true`.
+    io.add_sync_method("mute", move |params: Params| {
+        task::block_on(async {
+            let args: MuteArgs = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            db.mutes().mute(&args.pub_key, args.stop_replication)?;
+
+            Ok(Value::Bool(true))
+        })
+    });
+
+    // Return the feeds in the local mute list.
+    io.add_sync_method("mutes", |_| {
+        task::block_on(async {
+            let db = KV_STORAGE.read().await;
+            let mutes = db.mutes().get_mutes()?;
+
+            let response = json!(mutes);
+
+            Ok(response)
+        })
+    });
+
+    // Return the activity statistics of the stored feeds: daily message
+    // counts, active authors and the distribution of feeds by hops.
+    let stats_id = server_id.id.clone();
+    io.add_sync_method("networkStats", move |params: Params| {
+        task::block_on(async {
+            let args: NetworkStatsArgs = match params {
+                Params::None => NetworkStatsArgs {
+                    days: default_stats_days(),
+                    hops: default_stats_hops(),
+                },
+                params => params.parse()?,
+            };
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or(0);
+
+            let db = KV_STORAGE.read().await;
+            let stats = stats::network_stats(&db, &stats_id, now, args.days, args.hops)?;
+
+            Ok(json!(stats))
+        })
+    });
+
+    // Mark the notifications up to the given global sequence number as read.
+    // Returns `true`.
+    io.add_sync_method("markNotificationsRead", move |params: Params| {
+        task::block_on(async {
+            let global_seq: GlobalSeq = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            db.set_notifications_read(global_seq.global_seq)?;
+
+            Ok(Value::Bool(true))
+        })
+    });
+
+    // Return a page of mentions, replies, votes and follows concerning the
+    // local feed, newest first, along with the cursor of the next page.
+    let notifications_id = server_id.id.clone();
+    io.add_sync_method("notifications", move |params: Params| {
+        task::block_on(async {
+            let args: NotificationsArgs = match params {
+                Params::None => NotificationsArgs {
+                    cursor: None,
+                    limit: default_notifications_limit(),
+                },
+                params => params.parse()?,
+            };
+
+            let db = KV_STORAGE.read().await;
+            let page = db.notifications(&notifications_id, args.cursor, args.limit)?;
+            let notifications = page
+                .notifications
+                .iter()
+                .map(|notification| {
+                    Ok(json!({
+                        "global_seq": notification.global_seq,
+                        "kind": notification.kind,
+                        "read": notification.read,
+                        "message": kvt_to_json(&db, &notification.msg_kvt)?,
+                    }))
+                })
+                .collect::<Result<Vec<Value>>>()?;
+
+            let response = json!({
+                "notifications": notifications,
+                "next_cursor": page.next_cursor,
+            });
+
+            Ok(response)
+        })
+    });
+
+    // Retrieve the status of a message held in the outbox by ID.
+    // Returns `null` if there is no entry with the given ID.
+    io.add_sync_method("outbox", move |params: Params| {
+        task::block_on(async {
+            let outbox_id: OutboxId = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let status = db.outbox().get_status(outbox_id.outbox_id)?;
+
+            let response = json!(status);
+
+            Ok(response)
+        })
+    });
+
+    // Run the self-diagnostic checks.
+    // Returns a report of storage, broker and listener status.
+    io.add_sync_method("health", |_| {
+        task::block_on(async {
+            let report = HEALTH.report().await;
+
+            let response = json!(report);
+
+            Ok(response)
+        })
+    });
+
+    // Pin a feed so that it is replicated regardless of the follow graph.
+    // Returns `true`.
+    io.add_sync_method("pin", move |params: Params| {
+        task::block_on(async {
+            let pub_key: PubKey = params.parse()?;
+
+            KV_STORAGE.read().await.pin_feed(&pub_key.pub_key)?;
+
+            // Start replicating the feed from connected peers.
+            let mut ch_broker = BROKER.lock().await.create_sender();
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    RpcHistoryStreamEvent::Replicate(pub_key.pub_key),
+                ))
+                .await
+                .unwrap();
+
+            Ok(Value::Bool(true))
+        })
+    });
+
+    // Return the public key and latest sequence number for all feeds in the
+    // local database, and whether each is pinned.
+    io.add_sync_method("peers", |_| {
+        task::block_on(async {
+            let db = KV_STORAGE.read().await;
+            let peers = db.get_peers().await?;
+
+            let response = json!(peers);
+
+            Ok(response)
+        })
+    });
+
+    // Drop and reconstruct the global order index from the stored feeds.
+    // Returns the result of checking the index before it was rebuilt.
+    io.add_sync_method("rebuildGlobalOrder", |_| {
+        task::block_on(async {
+            let db = KV_STORAGE.write().await;
+            let report = db.rebuild_global_order()?;
+
+            let response = json!(report);
+
+            Ok(response)
+        })
+    });
+
+    // Look up a room alias and verify its registration.
+    // Returns the feed ID which registered the alias and the address of the
+    // room.
+    io.add_sync_method("resolveAlias", move |params: Params| {
+        task::block_on(async {
+            let alias_uri: AliasUri = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let resolved =
+                alias::resolve_pinned(&db, &alias_uri.alias_uri, sync_session::now()).await?;
+
+            let response = json!(resolved);
+
+            Ok(response)
+        })
+    });
+
+    // Return the IDs of the rooms pinned when an alias was first looked up
+    // on them, in order of host name.
+    io.add_sync_method("roomKeys", |_| {
+        task::block_on(async {
+            let db = KV_STORAGE.read().await;
+            let keys = db.room_keys().get_all()?;
+
+            Ok(json!(keys))
+        })
+    });
+
+    // Forget the ID pinned for a room, so that the next alias lookup on it
+    // pins the ID it returns. Returns `false` if no ID was pinned.
+    io.add_sync_method("forgetRoomKey", |params: Params| {
+        task::block_on(async {
+            let room: RoomHost = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let forgotten = db.room_keys().forget(&room.host)?;
+
+            Ok(Value::Bool(forgotten))
+        })
+    });
+
+    // Create the metafeed of the local identity, holding the main feed and a
+    // sub-feed for the indexes, and announce it on the main feed. Returns
+    // the metafeed tree, as is if the metafeed already exists.
+    let metafeed_id = server_id.clone();
+    io.add_sync_method("createMetafeed", move |_| {
+        task::block_on(async {
+            let seed = metafeed_seed()?;
+
+            // Trees created by earlier versions lack the index feeds.
+            let db = KV_STORAGE.write().await;
+            if let Some(mut tree) = db.get_metafeed()? {
+                if tree.add_index_feeds(&seed)? {
+                    db.set_metafeed(&tree)?;
+                    metafeed::update_indexes(&db, &seed).await?;
+                    db.commit_indexes().await?;
+                }
+                return Ok(json!(tree));
+            }
+            drop(db);
+
+            let tree = MetafeedTree::new(&seed, &metafeed_id.id)?;
+
+            // The seed is backed up on the main feed, encrypted to the local
+            // identity, so that the tree can be recovered from the main feed.
+            let boxed = private_box::box_content(
+                &tree.seed_content(&seed),
+                std::slice::from_ref(&metafeed_id.id),
+            )?;
+            publish_content(&metafeed_id, json!(boxed)).await?;
+            publish_content(&metafeed_id, tree.announce_content()).await?;
+
+            // Index the messages published before the metafeed was created.
+            let db = KV_STORAGE.write().await;
+            db.set_metafeed(&tree)?;
+            metafeed::update_indexes(&db, &seed).await?;
+            db.commit_indexes().await?;
+
+            let response = json!(tree);
+
+            Ok(response)
+        })
+    });
+
+    // Return the metafeed tree of the local identity, or `null` if the
+    // metafeed has not been created.
+    io.add_sync_method("metafeed", move |_| {
+        task::block_on(async {
+            let tree = KV_STORAGE.read().await.get_metafeed()?;
+
+            let response = json!(tree);
+
+            Ok(response)
+        })
+    });
+
+    // Derive a new sub-feed of the metafeed for the given purpose (e.g. an
+    // application). Returns the sub-feed.
+    io.add_sync_method("addSubfeed", move |params: Params| {
+        task::block_on(async {
+            let args: SubfeedPurpose = params.parse()?;
+
+            if *READ_ONLY_CONFIG.get().unwrap() {
+                return Err(Error::ReadOnly.into());
+            }
+
+            let db = KV_STORAGE.read().await;
+            let mut tree = db
+                .get_metafeed()?
+                .ok_or_else(|| Error::Metafeed("the metafeed has not been created".to_string()))?;
+            let subfeed = tree.add_derived(&metafeed_seed()?, &args.purpose)?;
+            db.set_metafeed(&tree)?;
+
+            let response = json!(subfeed);
+
+            Ok(response)
+        })
+    });
+
+    // Return the flags and reports published about a feed or message.
+    io.add_sync_method("reportsAgainst", move |params: Params| {
+        task::block_on(async {
+            let report_target: ReportTarget = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let reports = db.indexes().get_reports(&report_target.target)?;
+
+            let response = json!(reports);
+
+            Ok(response)
+        })
+    });
+
+    // Return the git-ssb repositories owned by a feed.
+    io.add_sync_method("repos", move |params: Params| {
+        task::block_on(async {
+            let repo_owner: RepoOwner = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let repos = db.indexes().get_git_repos(&repo_owner.owner)?;
+
+            let response = json!(repos);
+
+            Ok(response)
+        })
+    });
+
+    // Return the updates pushed to a git-ssb repository, oldest first.
+    io.add_sync_method("repoUpdates", move |params: Params| {
+        task::block_on(async {
+            let repo_id: RepoId = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let updates = db.indexes().get_git_updates(&repo_id.repo_id)?;
+
+            let response = json!(updates);
+
+            Ok(response)
+        })
+    });
+
+    // Return the number of reports about each reported feed or message,
+    // most reported first.
+    io.add_sync_method("reportCounts", |_| {
+        task::block_on(async {
+            let db = KV_STORAGE.read().await;
+            let counts = db.indexes().get_report_counts()?;
+
+            let response = json!(counts);
+
+            Ok(response)
+        })
+    });
+
+    // Return the feeds which are requested from peers, with the reasons why
+    // each one is replicated, its distance from the local identity in the
+    // follow graph and whether it is excluded after all.
+    let plan_id = server_id.id.clone();
+    io.add_sync_method("replicationPlan", move |_| {
+        task::block_on(async {
+            let listed = REPLICATION_CONFIG.get().unwrap().read().await.peers.clone();
+
+            let db = KV_STORAGE.read().await;
+            let plan = replication::plan(
+                &db,
+                &plan_id,
+                &listed,
+                *ARCHIVIST_CONFIG.get().unwrap(),
+                ALLOW_LIST_CONFIG.get().unwrap().as_deref(),
+            )
+            .await?;
+
+            Ok(json!(plan))
+        })
+    });
+
+    // Pause replication with a peer, or with every peer, without closing the
+    // connections. Returns `false` if it was already paused.
+    io.add_sync_method("pauseReplication", |params: Params| {
+        task::block_on(async {
+            let args: PauseArgs = match params {
+                Params::None => PauseArgs { peer: None },
+                params => params.parse()?,
+            };
+
+            let paused = REPLICATION_PAUSE
+                .lock()
+                .unwrap()
+                .pause(args.peer.as_deref());
+            if paused {
+                let mut ch_broker = BROKER.lock().await.create_sender();
+                ch_broker
+                    .send(BrokerEvent::new(
+                        Destination::Broadcast,
+                        ReplicationPauseChanged,
+                    ))
+                    .await
+                    .unwrap();
+            }
+
+            Ok(Value::Bool(paused))
+        })
+    });
+
+    // Resume replication with a peer, or with every peer. Returns `false` if
+    // it was not paused.
+    io.add_sync_method("resumeReplication", |params: Params| {
+        task::block_on(async {
+            let args: PauseArgs = match params {
+                Params::None => PauseArgs { peer: None },
+                params => params.parse()?,
+            };
+
+            let resumed = REPLICATION_PAUSE
+                .lock()
+                .unwrap()
+                .resume(args.peer.as_deref());
+            if resumed {
+                let mut ch_broker = BROKER.lock().await.create_sender();
+                ch_broker
+                    .send(BrokerEvent::new(
+                        Destination::Broadcast,
+                        ReplicationPauseChanged,
+                    ))
+                    .await
+                    .unwrap();
+            }
+
+            Ok(Value::Bool(resumed))
+        })
+    });
+
+    // Return whether replication is paused with every peer, and the peers
+    // with which it is paused.
+    io.add_sync_method("replicationPaused", |_| {
+        let pause = REPLICATION_PAUSE.lock().unwrap().clone();
+
+        Ok(json!(pause))
+    });
+
+    // Set the log level of a subsystem, overriding `RUST_LOG` for its log
+    // targets until the node restarts. Returns the level of each subsystem
+    // for which one is set.
+    io.add_sync_method("setLogLevel", |params: Params| {
+        let args: LogLevelArgs = params.parse()?;
+        let level = args
+            .level
+            .as_deref()
+            .map(logging::parse_level)
+            .transpose()?;
+        logging::set_level(args.subsystem.parse()?, level);
+
+        Ok(json!(logging::levels()))
+    });
+
+    // Return the IDs of all blobs which were not fetched automatically
+    // because they fall outside the blob fetching policy.
+    #[cfg(feature = "blobs")]
+    io.add_sync_method("skippedBlobs", |_| {
+        task::block_on(async {
+            let db = KV_STORAGE.read().await;
+            let blobs = db.get_skipped_blobs()?;
+
+            let response = json!(blobs);
+
+            Ok(response)
+        })
+    });
+
+    // Return the blobs on the want-list, in the order in which they are
+    // fetched.
+    #[cfg(feature = "blobs")]
+    io.add_sync_method("blobWants", |_| {
+        task::block_on(async {
+            let db = KV_STORAGE.read().await;
+            let wants = db.blob_wants().get_all()?;
+
+            Ok(json!(wants))
+        })
+    });
+
+    // Add a blob to the want-list or update its priority, optionally with a
+    // peer likely to hold it. Returns the updated entry of the blob.
+    #[cfg(feature = "blobs")]
+    io.add_sync_method("wantBlob", |params: Params| {
+        task::block_on(async {
+            let args: WantBlobArgs = params.parse()?;
+            let hint = args.hint.as_deref().map(uri::normalize);
+
+            let db = KV_STORAGE.read().await;
+            if args.priority == BlobPriority::Explicit {
+                db.clear_blob_skipped(&args.blob_ref)?;
+            }
+            BLOB_QUEUE.lock().unwrap().push(
+                db.blob_wants(),
+                &args.blob_ref,
+                None,
+                args.priority,
+                0,
+                hint.as_deref(),
+            )?;
+            let want = db.blob_wants().get(&args.blob_ref)?;
+
+            let mut ch_broker = BROKER.lock().await.create_sender();
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    RpcBlobsGetEvent::Queued,
+                ))
+                .await
+                .unwrap();
+
+            Ok(json!(want))
+        })
+    });
+
+    // Remove a blob from the want-list. Returns `false` if it was not
+    // wanted.
+    #[cfg(feature = "blobs")]
+    io.add_sync_method("unwantBlob", |params: Params| {
+        task::block_on(async {
+            let blob_ref: BlobRef = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let removed = BLOB_QUEUE
+                .lock()
+                .unwrap()
+                .finish(db.blob_wants(), &blob_ref.blob_ref)?;
+
+            Ok(Value::Bool(removed))
+        })
+    });
+
+    // Verify the stored blobs against the blob metadata. Blobs whose file
+    // is missing or corrupt are requested again from peers. Returns the
+    // result of the verification.
+    #[cfg(feature = "blobs")]
+    io.add_sync_method("fsckBlobs", |_| {
+        task::block_on(async {
+            let db = KV_STORAGE.read().await;
+            let report = blob_fsck::fsck(&*BLOB_STORAGE.read().await, &db)?;
+
+            for blob_id in report.lost() {
+                db.clear_blob_skipped(blob_id)?;
+                BLOB_QUEUE.lock().unwrap().push(
+                    db.blob_wants(),
+                    blob_id,
+                    None,
+                    BlobPriority::Explicit,
+                    0,
+                    None,
+                )?;
+            }
+            if report.lost().next().is_some() {
+                let mut ch_broker = BROKER.lock().await.create_sender();
+                ch_broker
+                    .send(BrokerEvent::new(
+                        Destination::Broadcast,
+                        RpcBlobsGetEvent::Queued,
+                    ))
+                    .await
+                    .unwrap();
+            }
+
+            Ok(json!(report))
+        })
+    });
+
+    // Return the latest LAN sync session with each peer, latest first.
+    io.add_sync_method("syncSessions", |_| {
+        let sessions = SYNC_SESSIONS.lock().unwrap().list();
+
+        Ok(json!(sessions))
+    });
+
+    // Simple `ping` endpoint.
+    io.add_sync_method("ping", |_| Ok(Value::String("pong!".to_owned())));
+
+    // Clone the local public key (ID) so it can later be captured by the
+    // `whoami` closure.
+    let local_pk = server_id.id.clone();
+
+    // Publish a typed message (raw).
+    // Returns the key (hash) and sequence number of the published message,
+    // or the ID of the outbox entry if the message was queued.
+    let publish_id = server_id.clone();
+    io.add_sync_method("publish", move |params: Params| {
+        task::block_on(async {
+            // Parse the parameter containing the content and check its
+            // shape.
+            let content = content::check_public(params.parse()?)?;
+
+            let response = publish_or_queue(&publish_id, content).await?;
+
+            Ok(response)
+        })
+    });
+
+    // Start a private thread with the given recipients.
+    // The local identity is always included as a recipient so that the
+    // thread remains readable. Returns the key (hash) and sequence number of
+    // the published message, or the ID of the outbox entry if the message
+    // was queued.
+    let thread_id = server_id.clone();
+    io.add_sync_method("createPrivateThread", move |params: Params| {
+        task::block_on(async {
+            let thread: PrivateThread = params.parse()?;
+
+            let mut recps: Vec<String> = Vec::new();
+            for recp in thread.recipients.into_iter().chain([thread_id.id.clone()]) {
+                if !recps.contains(&recp) {
+                    recps.push(recp);
+                }
+            }
+
+            let content = Post::new(thread.text)
+                .recps(recps.clone())
+                .content_warning(thread.content_warning)
+                .build()?;
+            let boxed = private_box::box_content(&content, &recps)?;
+
+            let response = publish_or_queue(&thread_id, json!(boxed)).await?;
+
+            Ok(response)
+        })
+    });
+
+    // Reply to a private message.
+    // The reply is addressed to the recipients of the original message and
+    // links to the root of its thread. Returns the key (hash) and sequence
+    // number of the published message, or the ID of the outbox entry if the
+    // message was queued.
+    let reply_id = server_id.clone();
+    io.add_sync_method("replyPrivate", move |params: Params| {
+        task::block_on(async {
+            let reply: PrivateReply = params.parse()?;
+
+            // Retrieve and decrypt the message being replied to.
+            let msg = KV_STORAGE.read().await.get_msg_val(&reply.root)?;
+            let original = msg
+                .and_then(|msg| private_box::unbox_content(msg.content(), &reply_id))
+                .ok_or_else(|| {
+                    Error::PrivateBox(format!("unable to find or decrypt {}", reply.root))
+                })?;
+
+            // Recipients may be listed as plain IDs or as mention objects.
+            let recps: Vec<String> = original["recps"]
+                .as_array()
+                .map(|recps| {
+                    recps
+                        .iter()
+                        .filter_map(|recp| recp.as_str().or_else(|| recp["link"].as_str()))
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            // Link to the root of the thread, which is the original message
+            // itself unless it is already a reply.
+            let root = original["root"].as_str().unwrap_or(&reply.root);
+
+            let content = Post::new(reply.text)
+                .reply(root, vec![reply.root.clone()])
+                .recps(recps.clone())
+                .content_warning(reply.content_warning)
+                .build()?;
+            let boxed = private_box::box_content(&content, &recps)?;
+
+            let response = publish_or_queue(&reply_id, json!(boxed)).await?;
+
+            Ok(response)
+        })
+    });
+
+    // Return a page of public messages by the local feed and the feeds
+    // within the given hops of it, most recently received first, along with
+    // the cursor of the next page.
+    let timeline_id = server_id.id.clone();
+    io.add_sync_method("timeline", move |params: Params| {
+        task::block_on(async {
+            let args: TimelineArgs = match params {
+                Params::None => TimelineArgs {
+                    cursor: None,
+                    limit: default_timeline_limit(),
+                    hops: default_timeline_hops(),
+                },
+                params => params.parse()?,
+            };
+
+            let db = KV_STORAGE.read().await;
+            let page = db.timeline(&timeline_id, args.hops, args.cursor, args.limit)?;
+            let messages = page
+                .messages
+                .iter()
+                .map(|msg_kvt| kvt_to_json(&db, msg_kvt))
+                .collect::<Result<Vec<Value>>>()?;
+
+            let response = json!({ "messages": messages, "next_cursor": page.next_cursor });
+
+            Ok(response)
+        })
+    });
+
+    // Return a page of public messages whose timestamp falls within the
+    // given range, oldest first, along with the cursor of the next page.
+    io.add_sync_method("messagesBetween", |params: Params| {
+        task::block_on(async {
+            let args: MessagesBetweenArgs = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let page = db.messages_between(
+                args.start,
+                args.end.unwrap_or(u64::MAX),
+                args.cursor.as_deref(),
+                args.limit,
+            )?;
+            let messages = page
+                .messages
+                .iter()
+                .map(|msg_kvt| kvt_to_json(&db, msg_kvt))
+                .collect::<Result<Vec<Value>>>()?;
+
+            let response = json!({ "messages": messages, "next_cursor": page.next_cursor });
+
+            Ok(response)
+        })
+    });
+
+    // Delete the content of a stored message, keeping its metadata so that
+    // its feed can still be replicated. Returns `false` if the message is
+    // not stored or its content was already deleted.
+    io.add_sync_method("tombstone", |params: Params| {
+        task::block_on(async {
+            let args: TombstoneArgs = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let tombstoned = db.tombstone_msg(&args.msg_ref, None, args.reason)?;
+
+            Ok(Value::Bool(tombstoned))
+        })
+    });
+
+    // Unpin a feed. It is still replicated if it is otherwise part of the
+    // replication list. Returns `false` if the feed was not pinned.
+    io.add_sync_method("unpin", move |params: Params| {
+        task::block_on(async {
+            let pub_key: PubKey = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let unpinned = db.unpin_feed(&pub_key.pub_key)?;
+
+            Ok(Value::Bool(unpinned))
+        })
+    });
+
+    // Lift the ban of a peer. Returns `false` if the peer was not banned.
+    io.add_sync_method("unban", |params: Params| {
+        task::block_on(async {
+            let pub_key: PubKey = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let removed = db.bans().unban(&pub_key.pub_key)?;
+
+            Ok(Value::Bool(removed))
+        })
+    });
+
+    // Stop delivering messages and blobs to a peer whenever it is seen.
+    // Returns `false` if deliveries to the peer were not enabled.
+    io.add_sync_method("undeliver", move |params: Params| {
+        task::block_on(async {
+            let pub_key: PubKey = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let removed = db.deliveries().remove(&pub_key.pub_key)?;
+
+            Ok(Value::Bool(removed))
+        })
+    });
+
+    // Remove a feed from the local mute list.
+    // Returns `false` if the feed was not muted.
+    io.add_sync_method("unmute", move |params: Params| {
+        task::block_on(async {
+            let pub_key: PubKey = params.parse()?;
+
+            let db = KV_STORAGE.read().await;
+            let unmuted = db.mutes().unmute(&pub_key.pub_key)?;
+
+            Ok(Value::Bool(unmuted))
+        })
+    });
+
+    // Return the public key of the local SSB server.
+    io.add_sync_method("whoami", move |_| Ok(Value::String(local_pk.to_owned())));
+
+    let server = ServerBuilder::with_meta_extractor(io, |request: &Request<Body>| {
+        Access::of_request(bearer_token(request))
+    })
+    .threads(threads)
+    .cors(DomainsValidation::AllowOnly(vec![
+        AccessControlAllowOrigin::Null,
+    ]))
+    .request_middleware(http_middleware)
+    .start_http(&server_addr.parse()?)?;
+
+    // Create a close handle to be used when the termination signal is
+    // received.
+    let close_handle = server.close_handle();
+
+    // Wait for the JSON-RPC server on the blocking pool.
+    // This allows us to listen for the termination signal (without blocking
+    // an executor thread, which requests may need to complete).
+    task::spawn_blocking(move || {
+        server.wait();
+    });
+
+    // Listen for termination signal from broker.
+    if let Err(err) = ch_terminate.await {
+        warn!("ch_terminate sender dropped: {}", err)
+    }
+
+    // When received, close (stop) the server.
+    close_handle.close();
+
+    // Then send terminated signal back to broker.
+    let _ = broker.ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+impl Metadata for Access {}
+
+/// Middleware rejecting the calls to methods outside the scopes granted to
+/// the API token of the request, if API tokens are configured.
+struct ScopeCheck;
+
+impl Middleware<Access> for ScopeCheck {
+    type Future = middleware::NoopFuture;
+    type CallFuture = middleware::NoopCallFuture;
+
+    fn on_call<F, X>(&self, call: Call, access: Access, next: F) -> Either<Self::CallFuture, X>
+    where
+        F: Fn(Call, Access) -> X + Send + Sync,
+        X: Future<Output = Option<Output>> + Send + 'static,
+    {
+        let denied = match &call {
+            Call::MethodCall(method_call) => access.check(&method_call.method).err().map(|err| {
+                Some(Output::from(
+                    Err(err.into()),
+                    method_call.id.clone(),
+                    method_call.jsonrpc,
+                ))
+            }),
+            // Notifications get no response.
+            Call::Notification(notification) => {
+                access.check(&notification.method).err().map(|_| None)
+            }
+            Call::Invalid { .. } => None,
+        };
+        match denied {
+            Some(output) => Either::Left(Box::pin(future::ready(output))),
+            None => Either::Right(next(call, access)),
+        }
+    }
+}
+
+/// Return the bearer token of a request, if any.
+fn bearer_token(request: &Request<Body>) -> Option<&str> {
+    request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Return the API token of a request for a page or document, which browsers
+/// and feed readers may give as the password of basic authentication rather
+/// than as a bearer token.
+fn page_token(request: &Request<Body>) -> Option<String> {
+    if let Some(token) = bearer_token(request) {
+        return Some(token.to_string());
+    }
+    let credentials = request
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Basic "))
+        .and_then(|encoded| base64::decode(encoded).ok())
+        .and_then(|decoded| String::from_utf8(decoded).ok())?;
+    // The user name is ignored.
+    credentials
+        .split_once(':')
+        .map(|(_, password)| password.to_string())
+}
+
+/// Reject a request for a page or document if API tokens are configured and
+/// the token of the request does not grant the scope of the given method.
+/// Requests without a valid token are asked for one through basic
+/// authentication, which browsers prompt for.
+fn reject_page_request(request: &Request<Body>, method: &str) -> Option<RequestMiddlewareAction> {
+    let access = Access::of_request(page_token(request).as_deref());
+    let err = access.check(method).err()?;
+    let status = match access {
+        Access::Denied => StatusCode::UNAUTHORIZED,
+        _ => StatusCode::FORBIDDEN,
+    };
+    let response = hyper::Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .header(WWW_AUTHENTICATE, "Basic realm=\"solar\"")
+        .body(Body::from(err.to_string()))
+        .expect("valid response");
+
+    Some(response.into())
+}
+
+/// Respond to plain HTTP `GET` requests for the paths served besides the
+/// JSON-RPC API. All other requests are passed on to the JSON-RPC handler.
+/// The ActivityPub documents are public: they only hold the public posts of
+/// the local identity, and the servers fetching them carry no API token.
+fn http_middleware(request: Request<Body>) -> RequestMiddlewareAction {
+    let query = request.uri().query().unwrap_or("").to_string();
+    let path = request.uri().path();
+    let method = match path {
+        "/atom" => Some("atom"),
+        #[cfg(feature = "blobs")]
+        "/blob" => Some("blob"),
+        #[cfg(feature = "viewer")]
+        "/view" | "/view/profile" | "/view/thread" | "/view/channel" => Some("viewer"),
+        _ => None,
+    };
+    if let Some(rejection) = method.and_then(|method| reject_page_request(&request, method)) {
+        return rejection;
+    }
+
+    match path {
+        "/health" => health_response(),
+        "/events" => events_response(request),
+        "/atom" => atom_response(&query),
+        "/import" => import_response(request),
+        "/frontier" => frontier_response(request),
+        "/delta" => delta_response(request),
+        #[cfg(feature = "graphql")]
+        "/graphql" => graphql_response(request),
+        #[cfg(feature = "blobs")]
+        "/blob" => blob_response(&query),
+        #[cfg(feature = "viewer")]
+        path @ ("/view" | "/view/profile" | "/view/thread" | "/view/channel") => {
+            viewer_response(path, &query)
+        }
+        #[cfg(feature = "activitypub")]
+        path @ ("/.well-known/webfinger"
+        | "/ap/actor"
+        | "/ap/outbox"
+        | "/ap/note"
+        | "/ap/inbox") => activitypub_response(path, &query),
+        _ => RequestMiddlewareAction::Proceed {
+            should_continue_on_invalid_cors: false,
+            request,
+        },
+    }
+}
+
+/// Return the value of the given parameter of a query string, which may be
+/// sigil-encoded or an SSB URI.
+fn query_ref(query: &str, name: &str) -> Option<String> {
+    form_urlencoded::parse(query.as_bytes())
+        .find(|(key, _)| key == name)
+        // An unencoded `+` of a sigil-encoded ID is decoded as a space,
+        // which no ID contains.
+        .map(|(_, value)| uri::normalize(&value.replace(' ', "+")))
+}
+
+/// Topics of the server-sent events a client subscribes to.
+#[derive(Debug, Clone, Copy, Default)]
+struct EventTopics {
+    /// Every message received.
+    messages: bool,
+    /// Messages which notify the local identity (see `notifications`).
+    notifications: bool,
+}
+
+impl EventTopics {
+    /// Parse a comma-separated list of topics.
+    fn parse(topics: &str) -> Result<Self> {
+        let mut parsed = EventTopics::default();
+        for topic in topics.split(',') {
+            match topic {
+                "messages" => parsed.messages = true,
+                "notifications" => parsed.notifications = true,
+                _ => return Err(Error::Other(format!("unknown event topic: {topic}"))),
+            }
+        }
+
+        Ok(parsed)
+    }
+}
+
+/// Respond to `GET /events?topics=<topics>` with a stream of server-sent
+/// events, for web clients which cannot keep a socket open otherwise. Each
+/// event carries the global sequence number of its message as its ID, so
+/// that reconnecting clients resume after the last event they received
+/// (`Last-Event-ID`, or `after`). Browsers cannot set the `Authorization`
+/// header on event streams, so the API token may also be given as
+/// `access_token`.
+fn events_response(request: Request<Body>) -> RequestMiddlewareAction {
+    if request.method() != Method::GET {
+        return method_not_allowed(Method::GET);
+    }
+    let query = request.uri().query().unwrap_or("");
+    let param = |name: &str| {
+        form_urlencoded::parse(query.as_bytes())
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.into_owned())
+    };
+    let token = bearer_token(&request)
+        .map(str::to_string)
+        .or_else(|| param("access_token"));
+    if let Err(err) = Access::of_request(token.as_deref()).check("events") {
+        return jsonrpc_http_server::Response {
+            code: StatusCode::FORBIDDEN,
+            content_type: HeaderValue::from_static("text/plain; charset=utf-8"),
+            content: err.to_string(),
+        }
+        .into();
+    }
+    let topics =
+        match EventTopics::parse(&param("topics").unwrap_or_else(|| "notifications".into())) {
+            Ok(topics) => topics,
+            Err(err) => return jsonrpc_http_server::Response::bad_request(err.to_string()).into(),
+        };
+    let after = request
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or_else(|| param("after"))
+        .and_then(|after| after.parse::<u64>().ok());
+
+    let (sender, body) = Body::channel();
+    task::spawn(async move {
+        if let Err(err) = stream_events(sender, topics, after).await {
+            warn!("event stream failed: {}", err);
+        }
+    });
+    let response = hyper::Response::builder()
+        .header(CONTENT_TYPE, "text/event-stream")
+        .header(CACHE_CONTROL, "no-cache")
+        .body(body)
+        .unwrap();
+
+    RequestMiddlewareAction::Respond {
+        should_validate_hosts: true,
+        response: Box::pin(future::ready(Ok(response))),
+    }
+}
+
+/// Send the events of the given topics for the messages received after the
+/// given position in the global order (or from now on), until the client
+/// disconnects or the database is replaced.
+async fn stream_events(
+    mut sender: hyper::body::Sender,
+    topics: EventTopics,
+    after: Option<u64>,
+) -> Result<()> {
+    let local_id = &SECRET_CONFIG.get().unwrap().id;
+    let mut tail = {
+        let db = KV_STORAGE.read().await;
+        let after = match after {
+            Some(after) => after,
+            None => db.get_latest_global_seq()?,
+        };
+        Box::pin(db.tail_messages(after).await?)
+    };
+
+    loop {
+        let (global_seq, msg_kvt) =
+            match async_std::future::timeout(EVENTS_KEEPALIVE, tail.next()).await {
+                Ok(Some(item)) => item?,
+                Ok(None) => break,
+                Err(_) => {
+                    if sender.send_data(": keepalive\n\n".into()).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+        let mut events = String::new();
+        {
+            let db = KV_STORAGE.read().await;
+            if topics.messages {
+                let data = kvt_to_json(&db, &msg_kvt)?;
+                events.push_str(&format!(
+                    "event: message\nid: {global_seq}\ndata: {data}\n\n"
+                ));
+            }
+            if topics.notifications {
+                if let Some(notification) = db.notification(local_id, global_seq, msg_kvt)? {
+                    let data = json!({
+                        "global_seq": notification.global_seq,
+                        "kind": notification.kind,
+                        "read": notification.read,
+                        "message": kvt_to_json(&db, &notification.msg_kvt)?,
+                    });
+                    events.push_str(&format!(
+                        "event: notification\nid: {global_seq}\ndata: {data}\n\n"
+                    ));
+                }
+            }
+        }
+        if !events.is_empty() && sender.send_data(events.into()).await.is_err() {
+            break;
+        }
+    }
+    debug!("event stream closed");
+
+    Ok(())
+}
+
+/// Respond with the self-diagnostic report, for use as a container liveness
+/// probe. Responds with status 200 when healthy and 503 otherwise.
+fn health_response() -> RequestMiddlewareAction {
+    let report = task::block_on(HEALTH.report());
+    let body = json!(report).to_string();
+
+    if report.healthy {
+        jsonrpc_http_server::Response::ok(body).into()
+    } else {
+        jsonrpc_http_server::Response::service_unavailable(body).into()
+    }
+}
+
+/// Respond to `GET /atom?feed=<feed id>` with the latest public posts of the
+/// feed as an Atom document.
+fn atom_response(query: &str) -> RequestMiddlewareAction {
+    let feed_id = match query_ref(query, "feed") {
+        Some(feed_id) => feed_id,
+        None => return jsonrpc_http_server::Response::bad_request("missing feed").into(),
+    };
+
+    let feed = task::block_on(async {
+        let db = KV_STORAGE.read().await;
+        atom::render_feed(&db, &feed_id)
+    });
+    match feed {
+        Ok(Some(feed)) => jsonrpc_http_server::Response {
+            code: StatusCode::OK,
+            content_type: HeaderValue::from_static("application/atom+xml; charset=utf-8"),
+            content: feed,
+        }
+        .into(),
+        Ok(None) => not_found(),
+        Err(err) => jsonrpc_http_server::Response::internal_error(err.to_string()).into(),
+    }
+}
+
+/// Respond to `POST /import` by appending the signed messages in the body
+/// of the request to the feeds of their authors, reporting the number of
+/// messages imported and skipped and the errors. Requests must carry the
+/// configured token as a bearer token.
+fn import_response(request: Request<Body>) -> RequestMiddlewareAction {
+    if let Some(rejection) = reject_sync_request(&request, Method::POST) {
+        return rejection;
+    }
+    if kv::is_under_maintenance() {
+        return jsonrpc_http_server::Response::service_unavailable("database under maintenance")
+            .into();
+    }
+
+    let response = async move {
+        let body = match read_body(request.into_body(), MAX_BODY_SIZE).await {
+            Some(body) => body,
+            None => {
+                return jsonrpc_http_server::Response::too_large("import too large").into();
+            }
+        };
+        let report = task::block_on(async {
+            let msgs = import::parse(&body)?;
+            let db = KV_STORAGE.write().await;
+            import::import_msgs(&db, msgs).await
+        });
+        match report {
+            Ok(report) => {
+                info!(
+                    "imported {} messages ({} skipped, {} rejected)",
+                    report.imported,
+                    report.skipped,
+                    report.errors.len()
+                );
+                jsonrpc_http_server::Response::ok(json!(report).to_string()).into()
+            }
+            Err(Error::SerdeJson(err)) => {
+                jsonrpc_http_server::Response::bad_request(err.to_string()).into()
+            }
+            Err(err) => jsonrpc_http_server::Response::internal_error(err.to_string()).into(),
+        }
+    };
+
+    RequestMiddlewareAction::Respond {
+        should_validate_hosts: true,
+        response: Box::pin(response.map(Ok)),
+    }
+}
+
+/// Respond to `GET /frontier` with the latest sequence number of each
+/// stored feed, keyed by feed ID. Requests must carry the token of the
+/// import endpoint as a bearer token.
+fn frontier_response(request: Request<Body>) -> RequestMiddlewareAction {
+    if let Some(rejection) = reject_sync_request(&request, Method::GET) {
+        return rejection;
+    }
+
+    let frontier = task::block_on(async {
+        let db = KV_STORAGE.read().await;
+        frontier::frontier(&db).await
+    });
+    match frontier {
+        Ok(frontier) => jsonrpc_http_server::Response::ok(json!(frontier).to_string()).into(),
+        Err(err) => jsonrpc_http_server::Response::internal_error(err.to_string()).into(),
+    }
+}
+
+/// Respond to `POST /delta`, whose body is the frontier of a peer, with the
+/// stored messages beyond that frontier as a bundle which the peer can
+/// import. Requests must carry the token of the import endpoint as a bearer
+/// token.
+fn delta_response(request: Request<Body>) -> RequestMiddlewareAction {
+    if let Some(rejection) = reject_sync_request(&request, Method::POST) {
+        return rejection;
+    }
+
+    let response = async move {
+        let peer_frontier = match read_body(request.into_body(), MAX_BODY_SIZE).await {
+            Some(body) => serde_json::from_slice::<frontier::Frontier>(&body),
+            None => {
+                return jsonrpc_http_server::Response::too_large("frontier too large").into();
+            }
+        };
+        let peer_frontier = match peer_frontier {
+            Ok(peer_frontier) => peer_frontier,
+            Err(err) => return jsonrpc_http_server::Response::bad_request(err.to_string()).into(),
+        };
+
+        let bundle = task::block_on(async {
+            let db = KV_STORAGE.read().await;
+            frontier::delta(&db, &peer_frontier, frontier::MAX_BUNDLE_MSGS).await
+        });
+        match bundle {
+            Ok(bundle) => {
+                let mut response = hyper::Response::new(Body::from(bundle));
+                response
+                    .headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+                response
+            }
+            Err(err) => jsonrpc_http_server::Response::internal_error(err.to_string()).into(),
+        }
+    };
+
+    RequestMiddlewareAction::Respond {
+        should_validate_hosts: true,
+        response: Box::pin(response.map(Ok)),
+    }
+}
+
+/// Check a request to the HTTP sync endpoints (import, frontier and delta),
+/// which are only served if the import token is set. Returns the response
+/// rejecting the request if it does not use the given method or does not
+/// carry the token as a bearer token.
+fn reject_sync_request(request: &Request<Body>, method: Method) -> Option<RequestMiddlewareAction> {
+    let token = match IMPORT_CONFIG.get().unwrap() {
+        Some(token) => token,
+        None => return Some(not_found()),
+    };
+    if request.method() != method {
+        return Some(method_not_allowed(method));
+    }
+    let authorized = bearer_token(request).is_some_and(|given| tokens_match(given, token));
+    if !authorized {
+        return Some(
+            jsonrpc_http_server::Response {
+                code: StatusCode::UNAUTHORIZED,
+                content_type: HeaderValue::from_static("text/plain; charset=utf-8"),
+                content: "invalid token".to_string(),
+            }
+            .into(),
+        );
+    }
+
+    None
+}
+
+/// Respond that the given method is required.
+fn method_not_allowed(method: Method) -> RequestMiddlewareAction {
+    jsonrpc_http_server::Response {
+        code: StatusCode::METHOD_NOT_ALLOWED,
+        content_type: HeaderValue::from_static("text/plain; charset=utf-8"),
+        content: format!("{method} is required"),
+    }
+    .into()
+}
+
+/// Respond to `POST /graphql` with the result of the GraphQL request in the
+/// body. The read scope is required if API tokens are configured.
+#[cfg(feature = "graphql")]
+fn graphql_response(request: Request<Body>) -> RequestMiddlewareAction {
+    if request.method() != Method::POST {
+        return method_not_allowed(Method::POST);
+    }
+    if let Err(err) = Access::of_request(bearer_token(&request)).check("graphql") {
+        return jsonrpc_http_server::Response {
+            code: StatusCode::FORBIDDEN,
+            content_type: HeaderValue::from_static("text/plain; charset=utf-8"),
+            content: err.to_string(),
+        }
+        .into();
+    }
+
+    let response = async move {
+        let body = match read_body(request.into_body(), MAX_QUERY_SIZE).await {
+            Some(body) => body,
+            None => return jsonrpc_http_server::Response::too_large("query too large").into(),
+        };
+        let query: async_graphql::Request = match serde_json::from_slice(&body) {
+            Ok(query) => query,
+            Err(err) => return jsonrpc_http_server::Response::bad_request(err.to_string()).into(),
+        };
+        let local_id = &SECRET_CONFIG.get().unwrap().id;
+        let result = task::block_on(async {
+            // Hold the guard for the whole query, so that compaction does not
+            // replace the database while it is being read.
+            let db = KV_STORAGE.read().await;
+            graphql::execute(db.clone(), local_id, query).await
+        });
+
+        jsonrpc_http_server::Response::ok(json!(result).to_string()).into()
+    };
+
+    RequestMiddlewareAction::Respond {
+        should_validate_hosts: true,
+        response: Box::pin(response.map(Ok)),
+    }
+}
+
+/// Read the body of a request, up to `max_size` bytes. Returns `None` if the
+/// body is larger or could not be read.
+async fn read_body(mut body: Body, max_size: usize) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        bytes.extend_from_slice(&chunk.ok()?);
+        if bytes.len() > max_size {
+            return None;
+        }
+    }
+
+    Some(bytes)
+}
+
+/// Respond to `GET /blob?id=<blob id>` with the content of the blob, if it
+/// is stored.
+#[cfg(feature = "blobs")]
+fn blob_response(query: &str) -> RequestMiddlewareAction {
+    let blob_id = match query_ref(query, "id") {
+        Some(blob_id) if blob_id.starts_with('&') && blob_id.ends_with(".sha256") => blob_id,
+        _ => return jsonrpc_http_server::Response::bad_request("invalid blob id").into(),
+    };
+
+    let blob = task::block_on(async {
+        let blobs = BLOB_STORAGE.read().await;
+        match blobs.exists(&blob_id) {
+            true => blobs.get(&blob_id).map(Some),
+            false => Ok(None),
+        }
+    });
+    match blob {
+        Ok(Some(blob)) => hyper::Response::new(Body::from(blob)).into(),
+        Ok(None) => not_found(),
+        Err(err) => jsonrpc_http_server::Response::internal_error(err.to_string()).into(),
+    }
+}
+
+/// Respond to the requests of the ActivityPub bridge: WebFinger lookups of
+/// the actor, and fetches of the actor, its outbox and its notes. Nothing
+/// is accepted by the inbox.
+#[cfg(feature = "activitypub")]
+fn activitypub_response(path: &str, query: &str) -> RequestMiddlewareAction {
+    let config = match ACTIVITYPUB_CONFIG.get().unwrap() {
+        Some(config) if path != "/ap/inbox" => config,
+        Some(_) => return jsonrpc_http_server::Response::method_not_allowed().into(),
+        None => return not_found(),
+    };
+    let local_id = &SECRET_CONFIG.get().unwrap().id;
+
+    let document = task::block_on(async {
+        let db = KV_STORAGE.read().await;
+        match path {
+            "/.well-known/webfinger" => {
+                let resource = form_urlencoded::parse(query.as_bytes())
+                    .find(|(key, _)| key == "resource")
+                    .map(|(_, resource)| resource.into_owned())
+                    .unwrap_or_default();
+                Ok(activitypub::webfinger(config, &resource))
+            }
+            "/ap/actor" => activitypub::actor(config, &db, local_id).map(Some),
+            "/ap/outbox" => activitypub::outbox(config, &db, local_id).map(Some),
+            "/ap/note" => match query_ref(query, "id") {
+                Some(msg_ref) => activitypub::note(config, &db, local_id, &msg_ref),
+                None => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    });
+    let content_type = match path {
+        "/.well-known/webfinger" => "application/jrd+json; charset=utf-8",
+        _ => activitypub::CONTENT_TYPE,
+    };
+    match document {
+        Ok(Some(document)) => jsonrpc_http_server::Response {
+            code: StatusCode::OK,
+            content_type: HeaderValue::from_static(content_type),
+            content: document.to_string(),
+        }
+        .into(),
+        Ok(None) => not_found(),
+        Err(err) => jsonrpc_http_server::Response::internal_error(err.to_string()).into(),
+    }
+}
+
+/// Respond to the requests for the pages of the web viewer: the home page
+/// and the profile of a feed, the thread of a message or a channel.
+#[cfg(feature = "viewer")]
+fn viewer_response(path: &str, query: &str) -> RequestMiddlewareAction {
+    let local_id = &SECRET_CONFIG.get().unwrap().id;
+
+    let page = task::block_on(async {
+        let db = KV_STORAGE.read().await;
+        match (path, query_ref(query, "id")) {
+            ("/view", _) => viewer::index(&db, local_id).map(Some),
+            ("/view/profile", Some(feed_id)) => viewer::profile(&db, &feed_id),
+            ("/view/thread", Some(msg_ref)) => viewer::thread(&db, &msg_ref),
+            ("/view/channel", _) => match query_ref(query, "name") {
+                Some(channel) => viewer::channel(&db, &channel).map(Some),
+                None => Ok(None),
+            },
+            _ => Ok(None),
+        }
+    });
+    match page {
+        Ok(Some(page)) => jsonrpc_http_server::Response {
+            code: StatusCode::OK,
+            content_type: HeaderValue::from_static("text/html; charset=utf-8"),
+            content: page,
+        }
+        .into(),
+        Ok(None) => not_found(),
+        Err(err) => jsonrpc_http_server::Response::internal_error(err.to_string()).into(),
+    }
+}
+
+fn not_found() -> RequestMiddlewareAction {
+    jsonrpc_http_server::Response {
+        code: StatusCode::NOT_FOUND,
+        content_type: HeaderValue::from_static("text/plain; charset=utf-8"),
+        content: "not found".to_string(),
+    }
+    .into()
 }
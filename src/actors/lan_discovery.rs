@@ -4,17 +4,28 @@ use std::time::Duration;
 
 use async_std::{net::UdpSocket, task};
 use futures::{select_biased, FutureExt};
-use kuska_ssb::{discovery::LanBroadcast, keystore::OwnedIdentity};
-use log::warn;
+use kuska_ssb::{crypto::ed25519, discovery::LanBroadcast, keystore::OwnedIdentity};
+use log::{info, warn};
 
-use crate::{broker::*, Result};
+use crate::{
+    actors::{
+        connection_manager::CONNECTION_MANAGER,
+        peer,
+        sync_session::{self, SYNC_SESSIONS},
+    },
+    broker::*,
+    config::REPLICATION_CONFIG,
+    Result, KV_STORAGE,
+};
 
 /// Register the LAN discovery endpoint, send and receive UDP broadcasts and
-/// spawn a peer actor for each successfully parsed broadcast message.
+/// spawn a peer actor for each successfully parsed broadcast message. If
+/// `lan_sync` is set, a sync session is started with known peers.
 pub async fn actor(
     server_id: OwnedIdentity,
     rpc_port: u16,
     selective_replication: bool,
+    lan_sync: bool,
 ) -> Result<()> {
     // Instantiate a new LAN broadcaster with the given public key and port.
     let broadcaster = LanBroadcast::new(&server_id.pk, rpc_port).await?;
@@ -45,7 +56,7 @@ pub async fn actor(
                 // `amt` is the number of bytes read.
                 if let Ok((amt, _)) = recv {
                     // Process the received data. Log any errors.
-                    if let Err(err) = process_broadcast(&server_id, &buf[..amt], selective_replication).await {
+                    if let Err(err) = process_broadcast(&server_id, &buf[..amt], selective_replication, lan_sync).await {
                         warn!("failed to process broadcast: {:?}", err);
                     }
                 }
@@ -75,25 +86,80 @@ async fn process_broadcast(
     server_id: &OwnedIdentity,
     buff: &[u8],
     selective_replication: bool,
+    lan_sync: bool,
 ) -> Result<()> {
     let msg = String::from_utf8_lossy(buff);
 
     // Attempt to parse the IP, port and public key from the received UDP
     // broadcast message.
     if let Some((server, port, peer_pk)) = LanBroadcast::parse(&msg) {
+        let peer_id = peer::peer_id(&peer_pk);
+        let syncing = lan_sync && start_sync_session(&peer_pk, &peer_id).await?;
+
         // Spawn a peer actor with the given connection parameters.
-        Broker::spawn(super::peer::actor(
+        let connection = peer::actor(
             server_id.clone(),
-            super::peer::Connect::TcpServer {
-                server,
-                port,
+            peer::Connect::TcpServer {
+                addresses: vec![format!("{server}:{port}")],
                 peer_pk,
             },
             selective_replication,
-        ));
+        );
+        Broker::spawn(async move {
+            let res = connection.await;
+            // A session which has not completed by the time the connection
+            // ends is interrupted.
+            if syncing {
+                let session = SYNC_SESSIONS
+                    .lock()
+                    .unwrap()
+                    .interrupt(&peer_id, sync_session::now());
+                if session.is_some() {
+                    warn!("LAN sync with peer {} interrupted", peer_id);
+                }
+            }
+            res
+        });
     } else {
         warn!("failed to parse broadcast {}", msg);
     }
 
     Ok(())
 }
+
+/// Start a sync session with the given peer if it is known (listed in the
+/// replication configuration or added with `deliver`), not banned and not
+/// already connected. Returns `false` if no session was started.
+async fn start_sync_session(peer_pk: &ed25519::PublicKey, peer_id: &str) -> Result<bool> {
+    let known = REPLICATION_CONFIG
+        .get()
+        .unwrap()
+        .read()
+        .await
+        .peers
+        .contains_key(peer_id)
+        || KV_STORAGE.read().await.deliveries().contains(peer_id)?;
+    if !known
+        || KV_STORAGE
+            .read()
+            .await
+            .bans()
+            .is_banned(peer_id, sync_session::now())?
+        || CONNECTION_MANAGER
+            .read()
+            .await
+            .contains_connected_peer(peer_pk)
+    {
+        return Ok(false);
+    }
+
+    let started = SYNC_SESSIONS
+        .lock()
+        .unwrap()
+        .start(peer_id, sync_session::now());
+    if started {
+        info!("starting LAN sync with peer {}", peer_id);
+    }
+
+    Ok(started)
+}
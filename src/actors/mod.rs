@@ -1,7 +1,20 @@
+pub mod backup;
+pub mod bot;
+pub mod compaction;
 pub mod connection_manager;
 pub mod ctrlc;
+pub mod disk_guard;
+pub mod exec_hooks;
+pub mod follow_back;
+#[cfg(feature = "jsonrpc")]
+pub mod follower_events;
+#[cfg(feature = "jsonrpc")]
 pub mod jsonrpc_server;
+#[cfg(feature = "lan-discovery")]
 pub mod lan_discovery;
+pub mod outbox;
 pub mod peer;
+pub mod replication_pause;
 pub mod rpc;
+pub mod sync_session;
 pub mod tcp_server;
@@ -0,0 +1,179 @@
+use std::time::Duration;
+
+use async_std::task;
+use futures::{select_biased, FutureExt, SinkExt};
+use kuska_ssb::{feed::Message, keystore::OwnedIdentity};
+use log::{info, warn};
+use serde_json::{json, Value};
+
+#[cfg(feature = "blobs")]
+use crate::actors::rpc::{RpcBlobsWantsEvent, BLOB_REGEX};
+use crate::{
+    broker::*,
+    config::{READ_ONLY_CONFIG, SECRET_CONFIG},
+    error::Error,
+    metafeed,
+    storage::{kv, outbox::OutboxStatus},
+    Result, KV_STORAGE,
+};
+
+/// Interval in seconds between attempts to publish queued messages.
+const OUTBOX_INTERVAL: u64 = 5;
+
+/// Register the outbox actor with the broker and periodically publish the
+/// messages held in the outbox, once the database is no longer undergoing
+/// maintenance.
+pub async fn actor(server_id: OwnedIdentity) -> Result<()> {
+    // Register the "outbox" actor endpoint with the broker.
+    let broker = BROKER.lock().await.register("outbox", false).await?;
+    // Fuse internal termination channel with external channel.
+    // This allows termination of the outbox loop to be initiated from
+    // outside this function.
+    let mut ch_terminate = broker.ch_terminate.fuse();
+
+    loop {
+        select_biased! {
+            _ = ch_terminate => break,
+            _ = task::sleep(Duration::from_secs(OUTBOX_INTERVAL)).fuse() => {
+                if kv::is_under_maintenance() {
+                    continue;
+                }
+                if let Err(err) = publish_queued(&server_id).await {
+                    warn!("failed to publish queued messages: {}", err);
+                }
+            }
+        }
+    }
+
+    // Send terminated signal back to the broker.
+    let _ = broker.ch_terminated.send(Void {});
+
+    Ok(())
+}
+
+/// Publish the queued messages in the order in which they were submitted,
+/// recording the outcome of each in the outbox.
+async fn publish_queued(server_id: &OwnedIdentity) -> Result<()> {
+    // The outbox is looked up for each operation rather than held across
+    // the publishing, since compaction replaces the database in between.
+    let queued = KV_STORAGE.read().await.outbox().get_queued()?;
+
+    for (outbox_id, content) in queued {
+        // Stop if maintenance started again; the remaining messages are
+        // published once it completes.
+        if kv::is_under_maintenance() {
+            break;
+        }
+
+        let status = match publish_content(server_id, content).await {
+            Ok((msg_ref, seq_num)) => {
+                info!("published queued message with outbox id {}", outbox_id);
+                OutboxStatus::Published { msg_ref, seq_num }
+            }
+            Err(err) => {
+                warn!("failed to publish queued message {}: {}", outbox_id, err);
+                OutboxStatus::Failed {
+                    error: err.to_string(),
+                }
+            }
+        };
+        KV_STORAGE
+            .read()
+            .await
+            .outbox()
+            .set_status(outbox_id, status)?;
+    }
+
+    Ok(())
+}
+
+/// Sign the given content as the next message of the local feed and append
+/// it to the feed. Returns the key (hash) and sequence number of the message.
+pub async fn publish_content(server_id: &OwnedIdentity, content: Value) -> Result<(String, u64)> {
+    if *READ_ONLY_CONFIG.get().unwrap() {
+        return Err(Error::ReadOnly);
+    }
+    // Publishing before the local feed is restored would fork it.
+    if kv::is_restoring() {
+        return Err(Error::Restoring);
+    }
+
+    // Open the primary KV database for writing.
+    let db = KV_STORAGE.write().await;
+
+    // Lookup the last message published on the local feed.
+    // Return `None` if no messages have yet been published on the feed.
+    let last_msg = db.get_latest_msg_val(&server_id.id)?;
+
+    // Instantiate and cryptographically-sign a new message using the content.
+    let msg = Message::sign(last_msg.as_ref(), server_id, content).map_err(Error::Validation)?;
+
+    // Append the signed message to the feed.
+    let seq = db.append_feed(msg.clone()).await?;
+
+    // Reference the message from the index feeds of the metafeed, if any.
+    if let Some(seed) = SECRET_CONFIG.get().unwrap().metafeed_seed() {
+        metafeed::update_indexes(&db, &seed).await?;
+    }
+
+    // Make the index updates durable before acknowledging the message.
+    db.commit_indexes().await?;
+
+    info!(
+        "published message {} with sequence number {}",
+        msg.id(),
+        seq
+    );
+
+    // Push any locally stored blobs referenced by the message to connected
+    // peers, rather than waiting for them to request the blobs.
+    #[cfg(feature = "blobs")]
+    {
+        let blob_refs: Vec<String> = BLOB_REGEX
+            .find_iter(&msg.content().to_string())
+            .map(|blob_ref| blob_ref.as_str().to_owned())
+            .collect();
+        db.deliveries().queue_blobs(&server_id.id, &blob_refs)?;
+        if !blob_refs.is_empty() {
+            let mut ch_broker = BROKER.lock().await.create_sender();
+            ch_broker
+                .send(BrokerEvent::new(
+                    Destination::Broadcast,
+                    RpcBlobsWantsEvent::PushHaves(blob_refs),
+                ))
+                .await
+                .unwrap();
+        }
+    }
+
+    Ok((msg.id().to_string(), seq))
+}
+
+/// Publish the given content, or hold it in the outbox if the database is
+/// undergoing maintenance. Content is also held if older entries are still
+/// waiting in the outbox, so that messages are published in the order they
+/// were submitted. Returns the key (hash) and sequence number of the
+/// published message, or the ID of the outbox entry.
+pub async fn publish_or_queue(server_id: &OwnedIdentity, content: Value) -> Result<Value> {
+    // Nothing is queued in read-only mode, since it would never be published.
+    if *READ_ONLY_CONFIG.get().unwrap() {
+        return Err(Error::ReadOnly);
+    }
+
+    {
+        // The guard is released before publishing, which takes the write
+        // lock.
+        let db = KV_STORAGE.read().await;
+        let outbox = db.outbox();
+        if kv::is_under_maintenance() || outbox.has_queued()? {
+            let outbox_id = outbox.push(&content)?;
+            info!("queued message in the outbox with id {}", outbox_id);
+
+            return Ok(json!({ "outbox_id": outbox_id, "status": "queued" }));
+        }
+    }
+
+    let (msg_ref, seq) = publish_content(server_id, content).await?;
+
+    Ok(json!({ "msg_ref": msg_ref, "seq_num": seq }))
+}
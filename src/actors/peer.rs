@@ -1,4 +1,7 @@
-use std::{net::Shutdown, time::Duration};
+use std::{
+    net::Shutdown,
+    time::{Duration, Instant},
+};
 
 use async_std::{
     io::{Read, Write},
@@ -18,23 +21,35 @@ use kuska_ssb::{
 };
 use log::{error, info, trace, warn};
 
+#[cfg(feature = "blobs")]
+use crate::actors::rpc::{BlobsGetHandler, BlobsWantsHandler};
 use crate::{
     actors::{
-        connection_manager::{ConnectionEvent, CONNECTION_MANAGER},
+        connection_manager::{ConnectionEvent, CONNECTION_MANAGER, HANDSHAKE_LIMITER},
+        replication_pause::REPLICATION_PAUSE,
         rpc::{
-            BlobsGetHandler, BlobsWantsHandler, GetHandler, HistoryStreamHandler, RpcHandler,
-            RpcInput, WhoAmIHandler,
+            GetHandler, GossipHandler, HistoryStreamHandler, OooHandler, RpcHandler, RpcInput,
+            WhoAmIHandler,
         },
+        sync_session,
     },
+    allow_list,
     broker::*,
     config::{NETWORK_KEY, REPLICATION_CONFIG},
-    Result,
+    storage::audit::{AuditEntry, AuditEvent, Direction},
+    Result, KV_STORAGE,
 };
 
+/// Broadcast when a peer is banned, so that any connection with it is
+/// closed.
+#[derive(Debug, Clone)]
+pub struct PeerBanned(pub String);
+
 pub enum Connect {
     TcpServer {
-        server: String,
-        port: u16,
+        /// Known addresses of the peer, as `host:port`. Several addresses
+        /// are dialed in parallel and the first to connect is kept.
+        addresses: Vec<String>,
         peer_pk: ed25519::PublicKey,
     },
     ClientStream {
@@ -78,6 +93,73 @@ pub async fn actor(id: OwnedIdentity, connect: Connect, selective_replication: b
     Ok(())
 }
 
+/// Return the SSB ID of the given public key, with the sigil link ('@').
+pub fn peer_id(peer_pk: &ed25519::PublicKey) -> String {
+    let ssb_id = peer_pk.to_ssb_id();
+    if ssb_id.starts_with('@') {
+        ssb_id
+    } else {
+        format!("@{ssb_id}")
+    }
+}
+
+/// Delay before dialing each further address of a peer, giving the
+/// preferred addresses a head start.
+const DIAL_STAGGER: Duration = Duration::from_millis(250);
+
+/// Dial the given addresses of a peer, fastest known first, with the
+/// attempts staggered and running in parallel. The first connection to
+/// succeed is kept and the others are abandoned. The latency of the
+/// successful connection is recorded for future preference.
+async fn dial(addresses: &[String]) -> Result<(TcpStream, String)> {
+    let mut addresses = addresses.to_vec();
+    {
+        // Addresses with no measured latency are tried after the others.
+        let db = KV_STORAGE.read().await;
+        let mut latencies = Vec::new();
+        for address in &addresses {
+            latencies.push(db.get_address_latency(address)?.unwrap_or(u64::MAX));
+        }
+        let mut ranked: Vec<(u64, String)> = latencies.into_iter().zip(addresses).collect();
+        ranked.sort();
+        addresses = ranked.into_iter().map(|(_, address)| address).collect();
+    }
+
+    let attempts = addresses.into_iter().enumerate().map(|(i, address)| {
+        Box::pin(async move {
+            task::sleep(DIAL_STAGGER * i as u32).await;
+            let start = Instant::now();
+            let stream = TcpStream::connect(&address).await?;
+            Ok::<_, std::io::Error>((stream, address, start.elapsed()))
+        })
+    });
+    let ((stream, address, latency), _) = futures::future::select_ok(attempts).await?;
+
+    if let Err(err) = KV_STORAGE
+        .read()
+        .await
+        .record_address_latency(&address, latency.as_millis() as u64)
+    {
+        warn!("failed to record latency of {}: {}", address, err);
+    }
+
+    Ok((stream, address))
+}
+
+/// Record a connection event in the audit log. Failing to record the event
+/// does not affect the connection.
+async fn audit(
+    direction: Direction,
+    address: &Option<String>,
+    peer: Option<String>,
+    event: AuditEvent,
+) {
+    let entry = AuditEntry::new(direction, address.clone(), peer, event);
+    if let Err(err) = KV_STORAGE.read().await.audit().record(&entry) {
+        warn!("failed to record connection event: {}", err);
+    }
+}
+
 /// Handle a TCP connection, update the list of connected peers, register the
 /// peer actor endpoint, spawn the peer loop and report on the connection
 /// outcome.
@@ -113,13 +195,9 @@ pub async fn actor_inner(
         .unwrap();
 
     // Handle a TCP connection event (inbound or outbound).
-    let (stream, handshake) = match connect {
+    let (stream, handshake, direction, address) = match connect {
         // Handle an outbound TCP connection event.
-        Connect::TcpServer {
-            server,
-            port,
-            peer_pk,
-        } => {
+        Connect::TcpServer { addresses, peer_pk } => {
             // TODO: move this check into the scheduler.
             //
             // First check if we are already connected to the selected peer.
@@ -133,10 +211,62 @@ pub async fn actor_inner(
                 return Ok(connection_id);
             }
 
-            // Define the server address and port.
-            let server_port = format!("{server}:{port}");
+            let peer = Some(peer_id(&peer_pk));
+            let attempted = Some(addresses.join(","));
+
+            // Peers outside the allow list are not dialed.
+            if !allow_list::is_allowed(&peer_id(&peer_pk)).await? {
+                info!(
+                    "peer {} is not in the allow list; not connecting",
+                    peer_id(&peer_pk)
+                );
+                let event = AuditEvent::Rejected {
+                    reason: "not in allow list".to_string(),
+                };
+                audit(Direction::Outbound, &attempted, peer, event).await;
+
+                return Ok(connection_id);
+            }
+
+            // Banned peers are not dialed until their ban expires.
+            let ban = KV_STORAGE
+                .read()
+                .await
+                .bans()
+                .get(&peer_id(&peer_pk), sync_session::now())?;
+            if let Some(ban) = ban {
+                info!(
+                    "peer {} is banned until {}; not connecting",
+                    ban.pub_key, ban.until
+                );
+                let event = AuditEvent::Rejected {
+                    reason: "banned".to_string(),
+                };
+                audit(Direction::Outbound, &attempted, peer, event).await;
+
+                return Ok(connection_id);
+            }
+
+            audit(
+                Direction::Outbound,
+                &attempted,
+                peer.clone(),
+                AuditEvent::Attempt,
+            )
+            .await;
+
             // Attempt a TCP connection.
-            let mut stream = TcpStream::connect(server_port).await?;
+            let (mut stream, address) = match dial(&addresses).await {
+                Ok(connected) => connected,
+                Err(err) => {
+                    let event = AuditEvent::Failed {
+                        error: err.to_string(),
+                    };
+                    audit(Direction::Outbound, &attempted, peer, event).await;
+                    return Err(err);
+                }
+            };
+            let address = Some(address);
 
             // Send 'handshaking' connection event message via the broker.
             ch_broker
@@ -148,7 +278,18 @@ pub async fn actor_inner(
                 .unwrap();
 
             // Attempt a secret handshake.
-            let handshake = handshake_client(&mut stream, network_key, pk, sk, peer_pk).await?;
+            let handshake = match handshake_client(&mut stream, network_key, pk, sk, peer_pk).await
+            {
+                Ok(handshake) => handshake,
+                Err(err) => {
+                    let event = AuditEvent::Failed {
+                        error: err.to_string(),
+                    };
+                    audit(Direction::Outbound, &address, peer, event).await;
+                    return Err(err.into());
+                }
+            };
+            audit(Direction::Outbound, &address, peer, AuditEvent::Handshake).await;
 
             info!("💃 connected to peer {}", handshake.peer_pk.to_ssb_id());
 
@@ -161,10 +302,30 @@ pub async fn actor_inner(
                 .await
                 .unwrap();
 
-            (stream, handshake)
+            (stream, handshake, Direction::Outbound, address)
         }
         // Handle an incoming TCP connection event.
         Connect::ClientStream { mut stream } => {
+            let peer_addr = stream.peer_addr()?;
+            let address = Some(peer_addr.to_string());
+            audit(Direction::Inbound, &address, None, AuditEvent::Attempt).await;
+
+            // Drop the connection if too many handshakes are in progress,
+            // overall or with the same IP address.
+            let permit = match HANDSHAKE_LIMITER.try_acquire(peer_addr.ip()) {
+                Ok(permit) => permit,
+                Err(reason) => {
+                    info!("dropping connection from {}: {}", peer_addr, reason);
+                    let event = AuditEvent::Rejected {
+                        reason: reason.to_string(),
+                    };
+                    audit(Direction::Inbound, &address, None, event).await;
+                    stream.shutdown(Shutdown::Both)?;
+
+                    return Ok(connection_id);
+                }
+            };
+
             // Send 'handshaking' connection event message via the broker.
             ch_broker
                 .send(BrokerEvent::new(
@@ -175,7 +336,17 @@ pub async fn actor_inner(
                 .unwrap();
 
             // Attempt a secret handshake.
-            let handshake = handshake_server(&mut stream, network_key, pk, sk).await?;
+            let handshake = match handshake_server(&mut stream, network_key, pk, sk).await {
+                Ok(handshake) => handshake,
+                Err(err) => {
+                    let event = AuditEvent::Failed {
+                        error: err.to_string(),
+                    };
+                    audit(Direction::Inbound, &address, None, event).await;
+                    return Err(err.into());
+                }
+            };
+            drop(permit);
 
             // Send 'connected' connection event message via the broker.
             ch_broker
@@ -187,14 +358,15 @@ pub async fn actor_inner(
                 .unwrap();
 
             // Convert the public key to a `String`.
-            let ssb_id = handshake.peer_pk.to_ssb_id();
-
-            // Add the sigil link ('@') if it's missing.
-            let peer_pk = if ssb_id.starts_with('@') {
-                ssb_id
-            } else {
-                format!("@{ssb_id}")
-            };
+            let peer_pk = peer_id(&handshake.peer_pk);
+            let peer = Some(peer_pk.clone());
+            audit(
+                Direction::Inbound,
+                &address,
+                peer.clone(),
+                AuditEvent::Handshake,
+            )
+            .await;
 
             // Check if we are already connected to the selected peer.
             // If yes, return immediately.
@@ -205,6 +377,10 @@ pub async fn actor_inner(
                 .contains_connected_peer(&handshake.peer_pk)
             {
                 info!("peer {} is already connected", &peer_pk);
+                let event = AuditEvent::Rejected {
+                    reason: "already connected".to_string(),
+                };
+                audit(Direction::Inbound, &address, peer, event).await;
 
                 // Since we already have an active connection to this peer,
                 // we can disconnect the redundant connection.
@@ -223,20 +399,88 @@ pub async fn actor_inner(
 
             info!("💃 received connection from peer {}", &peer_pk);
 
+            // The local identity (e.g. an administration tool using the keys
+            // of the node) is always accepted.
+            let is_local = handshake.peer_pk == pk;
+
+            // Shutdown the connection if the peer is outside the allow list.
+            if !is_local && !allow_list::is_allowed(&peer_pk).await? {
+                info!(
+                    "peer {} is not in the allow list; dropping connection",
+                    peer_pk
+                );
+                let event = AuditEvent::Rejected {
+                    reason: "not in allow list".to_string(),
+                };
+                audit(Direction::Inbound, &address, peer.clone(), event).await;
+
+                // Send connection event message via the broker.
+                ch_broker
+                    .send(BrokerEvent::new(
+                        Destination::Broadcast,
+                        ConnectionEvent::Disconnecting(connection_id),
+                    ))
+                    .await
+                    .unwrap();
+
+                stream.shutdown(Shutdown::Both)?;
+
+                return Ok(connection_id);
+            }
+
+            // Shutdown the connection if the peer is banned.
+            let ban = KV_STORAGE
+                .read()
+                .await
+                .bans()
+                .get(&peer_pk, sync_session::now())?;
+            if let Some(ban) = ban.filter(|_| !is_local) {
+                info!(
+                    "peer {} is banned until {}; dropping connection",
+                    peer_pk, ban.until
+                );
+                let event = AuditEvent::Rejected {
+                    reason: "banned".to_string(),
+                };
+                audit(Direction::Inbound, &address, peer.clone(), event).await;
+
+                // Send connection event message via the broker.
+                ch_broker
+                    .send(BrokerEvent::new(
+                        Destination::Broadcast,
+                        ConnectionEvent::Disconnecting(connection_id),
+                    ))
+                    .await
+                    .unwrap();
+
+                stream.shutdown(Shutdown::Both)?;
+
+                return Ok(connection_id);
+            }
+
             // Shutdown the connection if the peer is not in the list of peers
             // to be replicated, unless replication is set to nonselective.
-            // This ensures we do not replicate with unknown peers.
+            // This ensures we do not replicate with unknown peers. Peers to
+            // which deliveries are enabled are always accepted.
             if selective_replication
-                & !REPLICATION_CONFIG
+                && !is_local
+                && !REPLICATION_CONFIG
                     .get()
                     .unwrap()
+                    .read()
+                    .await
                     .peers
                     .contains_key(&peer_pk)
+                && !KV_STORAGE.read().await.deliveries().contains(&peer_pk)?
             {
                 info!(
                     "peer {} is not in replication list and selective replication is enabled; dropping connection",
                     peer_pk
                 );
+                let event = AuditEvent::Rejected {
+                    reason: "not in replication list".to_string(),
+                };
+                audit(Direction::Inbound, &address, peer, event).await;
 
                 // Send connection event message via the broker.
                 ch_broker
@@ -254,7 +498,7 @@ pub async fn actor_inner(
                 return Ok(connection_id);
             }
 
-            (stream, handshake)
+            (stream, handshake, Direction::Inbound, address)
         }
     };
 
@@ -296,6 +540,10 @@ pub async fn actor_inner(
 
     if let Err(err) = res {
         warn!("💀 client terminated with error {:?}", err);
+        let event = AuditEvent::Failed {
+            error: err.to_string(),
+        };
+        audit(direction, &address, Some(peer_id(&peer_pk)), event).await;
 
         // TODO: Use the `ConnectionError` as the type for `err`.
         //
@@ -309,6 +557,13 @@ pub async fn actor_inner(
             .unwrap();
     } else {
         info!("👋 finished connection with {}", &peer_pk.to_ssb_id());
+        audit(
+            direction,
+            &address,
+            Some(peer_id(&peer_pk)),
+            AuditEvent::Closed,
+        )
+        .await;
 
         // Send 'disconnected' connection event message via the broker.
         ch_broker
@@ -325,6 +580,9 @@ pub async fn actor_inner(
     Ok(connection_id)
 }
 
+/// Run the MUXRPC handlers (replication, blobs etc.) over an authenticated
+/// connection until it is closed or terminated. The loop is independent of
+/// the underlying transport.
 async fn peer_loop<R: Read + Unpin + Send + Sync, W: Write + Unpin + Send + Sync>(
     actor_id: usize,
     reader: R,
@@ -336,6 +594,7 @@ async fn peer_loop<R: Read + Unpin + Send + Sync, W: Write + Unpin + Send + Sync
 ) -> Result<()> {
     // Parse the peer public key from the handshake.
     let peer_ssb_id = handshake.peer_pk.to_ssb_id();
+    let peer_key = peer_id(&handshake.peer_pk);
 
     // Instantiate a box stream and split it into reader and writer streams.
     let (box_stream_read, box_stream_write) =
@@ -347,19 +606,28 @@ async fn peer_loop<R: Read + Unpin + Send + Sync, W: Write + Unpin + Send + Sync
     let mut api = ApiCaller::new(rpc_writer);
 
     // Instantiate the MUXRPC handlers.
-    let mut history_stream_handler = HistoryStreamHandler::new(actor_id);
+    let mut history_stream_handler = HistoryStreamHandler::new(actor_id, &peer_ssb_id);
     let mut whoami_handler = WhoAmIHandler::new(&peer_ssb_id);
     let mut get_handler = GetHandler::default();
-    let mut blobs_get_handler = BlobsGetHandler::default();
-    let mut blobs_wants_handler = BlobsWantsHandler::default();
+    #[cfg(feature = "blobs")]
+    let mut blobs_get_handler = BlobsGetHandler::new(&peer_ssb_id);
+    #[cfg(feature = "blobs")]
+    let mut blobs_wants_handler = BlobsWantsHandler::new(&peer_ssb_id);
+    let mut ooo_handler = OooHandler::default();
+    let mut gossip_handler = GossipHandler::new(&peer_ssb_id);
 
     let mut handlers: Vec<&mut dyn RpcHandler<W>> = vec![
         &mut history_stream_handler,
         &mut whoami_handler,
         &mut get_handler,
-        &mut blobs_get_handler,
-        &mut blobs_wants_handler,
     ];
+    #[cfg(feature = "blobs")]
+    {
+        handlers.push(&mut blobs_get_handler);
+        handlers.push(&mut blobs_wants_handler);
+    }
+    handlers.push(&mut ooo_handler);
+    handlers.push(&mut gossip_handler);
 
     // Create channel to send messages to broker.
     let mut ch_broker = BROKER.lock().await.create_sender();
@@ -407,8 +675,11 @@ async fn peer_loop<R: Read + Unpin + Send + Sync, W: Write + Unpin + Send + Sync
             },
             _ = task::sleep(Duration::from_secs(1)).fuse() => {
                 // Break out of the peer loop if the connection idle timeout
-                // limit has been reached.
-                if timer_counter >= connection_idle_timeout_limit {
+                // limit has been reached. Connections are kept open while
+                // replication with the peer is paused.
+                if REPLICATION_PAUSE.lock().unwrap().is_paused(&peer_ssb_id) {
+                    RpcInput::Timer
+                } else if timer_counter >= connection_idle_timeout_limit {
                     break
                 } else {
                     // Increment the timer counter.
@@ -418,6 +689,16 @@ async fn peer_loop<R: Read + Unpin + Send + Sync, W: Write + Unpin + Send + Sync
             }
         };
 
+        // Close the connection once the peer is banned.
+        if let RpcInput::Message(msg) = &input {
+            if let Some(PeerBanned(banned)) = msg.downcast_ref::<PeerBanned>() {
+                if *banned == peer_key {
+                    info!("peer {} has been banned; closing connection", peer_key);
+                    break;
+                }
+            }
+        }
+
         let mut handled = false;
         for handler in handlers.iter_mut() {
             match handler.handle(&mut api, &input, &mut ch_broker).await {
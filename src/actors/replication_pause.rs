@@ -0,0 +1,97 @@
+//! Pausing of replication.
+//!
+//! Replication may be paused with every peer or with given peers, e.g. on a
+//! metered connection or while the database is being compacted, without
+//! closing the connections. While paused with a peer, the history streams
+//! requested from it are ended, no blob is requested from it and the
+//! messages it requests are held back; the connection is not closed when
+//! idle. On resume, the history streams are requested again from the
+//! latest stored messages and the messages held back are sent.
+
+use std::{collections::BTreeSet, sync::Mutex};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+/// The replication pause state of the solar node.
+pub static REPLICATION_PAUSE: Lazy<Mutex<ReplicationPause>> =
+    Lazy::new(|| Mutex::new(ReplicationPause::default()));
+
+/// Event sent to the peer connections when replication is paused or
+/// resumed.
+pub struct ReplicationPauseChanged;
+
+/// The peers with which replication is paused.
+#[derive(Debug, Default, Clone, PartialEq, Serialize)]
+pub struct ReplicationPause {
+    /// Replication is paused with every peer.
+    pub all: bool,
+    /// Public keys of the peers with which replication is paused.
+    pub peers: BTreeSet<String>,
+}
+
+/// Return the ID of a peer with the leading `@`.
+fn peer_key(peer_id: &str) -> String {
+    if peer_id.starts_with('@') {
+        peer_id.to_string()
+    } else {
+        format!("@{peer_id}")
+    }
+}
+
+impl ReplicationPause {
+    /// Whether replication with the given peer is paused.
+    pub fn is_paused(&self, peer_id: &str) -> bool {
+        self.all || self.peers.contains(&peer_key(peer_id))
+    }
+
+    /// Pause replication with the given peer, or with every peer if none is
+    /// given. Returns `false` if it was already paused.
+    pub fn pause(&mut self, peer_id: Option<&str>) -> bool {
+        match peer_id {
+            Some(peer_id) => !self.all && self.peers.insert(peer_key(peer_id)),
+            None => !std::mem::replace(&mut self.all, true),
+        }
+    }
+
+    /// Resume replication with the given peer, or with every peer if none is
+    /// given. Replication with a single peer cannot be resumed while it is
+    /// paused with every peer. Returns `false` if it was not paused.
+    pub fn resume(&mut self, peer_id: Option<&str>) -> bool {
+        match peer_id {
+            Some(peer_id) => !self.all && self.peers.remove(&peer_key(peer_id)),
+            None => {
+                let paused = self.all || !self.peers.is_empty();
+                *self = ReplicationPause::default();
+                paused
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_replication_pause() {
+        let mut pause = ReplicationPause::default();
+        assert!(!pause.is_paused("peer=.ed25519"));
+
+        // Peer IDs from handshakes have no leading `@`.
+        assert!(pause.pause(Some("@peer=.ed25519")));
+        assert!(!pause.pause(Some("peer=.ed25519")));
+        assert!(pause.is_paused("peer=.ed25519"));
+        assert!(!pause.is_paused("@other=.ed25519"));
+
+        assert!(pause.pause(None));
+        assert!(pause.is_paused("@other=.ed25519"));
+        assert!(!pause.resume(Some("@other=.ed25519")));
+
+        // Resuming every peer also resumes those paused one by one.
+        assert!(pause.resume(None));
+        assert!(!pause.is_paused("peer=.ed25519"));
+        assert!(!pause.resume(None));
+        assert!(!pause.resume(Some("peer=.ed25519")));
+    }
+}
@@ -1,8 +1,9 @@
 #![allow(clippy::single_match)]
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeSet, HashMap, HashSet},
     marker::PhantomData,
+    sync::Mutex,
 };
 
 use async_std::io::Write;
@@ -12,36 +13,327 @@ use kuska_ssb::{
     rpc,
 };
 use log::{info, trace, warn};
+use once_cell::sync::Lazy;
 
 use crate::{
-    actors::rpc::handler::{RpcHandler, RpcInput},
+    actors::{
+        replication_pause::REPLICATION_PAUSE,
+        rpc::handler::{RpcHandler, RpcInput},
+    },
     broker::ChBrokerSend,
-    storage::blob::ToBlobHashId,
-    Result, BLOB_STORAGE,
+    config::{BLOB_CONFIG, DEFAULT_BLOB_FETCH_CONCURRENCY},
+    disk::DISK_GUARD,
+    storage::{
+        blob::ToBlobHashId,
+        blob_wants::{BlobWant, BlobWants},
+    },
+    Result, BLOB_STORAGE, KV_STORAGE,
 };
 
+/// Queue of blobs waiting to be fetched, shared by all peer connections.
+pub static BLOB_QUEUE: Lazy<Mutex<BlobQueue>> = Lazy::new(|| Mutex::new(BlobQueue::default()));
+
+pub use crate::storage::blob_wants::BlobPriority;
+
 pub enum RpcBlobsGetEvent {
-    Get(dto::BlobsGetIn),
+    /// New blobs were added to the blob queue.
+    Queued,
+}
+
+/// Number of times peers may fail to provide a blob before it is no longer
+/// requested, unless requested explicitly again.
+const MAX_BLOB_RETRIES: u32 = 10;
+
+/// Number of queued blobs of the highest priority looked through for one
+/// which the peer is likely to hold.
+const HINT_LOOKAHEAD: usize = 32;
+
+/// Error sent in response to a request for a blob larger than the maximum
+/// size given in the request.
+const ERR_BLOB_TOO_LARGE: &str = "blob.len > max";
+
+/// Whether the given error response to a blob request is `ERR_BLOB_TOO_LARGE`.
+/// Error responses are sent as an error object holding the message.
+fn is_too_large_error(err: &str) -> bool {
+    let message = serde_json::from_str::<serde_json::Value>(err)
+        .ok()
+        .and_then(|err| err["message"].as_str().map(String::from));
+
+    message.as_deref().unwrap_or(err) == ERR_BLOB_TOO_LARGE
+}
+
+/// Return the ID of a peer with the leading `@`, which peer IDs from
+/// handshakes lack.
+fn peer_key(peer_id: &str) -> String {
+    if peer_id.starts_with('@') {
+        peer_id.to_string()
+    } else {
+        format!("@{peer_id}")
+    }
+}
+
+#[derive(Debug)]
+struct QueuedBlob {
+    want: BlobWant,
+    /// Peers which failed to provide the blob since it was queued.
+    tried_by: HashSet<String>,
+}
+
+impl QueuedBlob {
+    /// Key by which the blob is ordered in the queue: most recently
+    /// referenced blobs first within the same priority.
+    fn order_key(&self) -> (BlobPriority, u64, String) {
+        (
+            self.want.priority,
+            self.want.timestamp,
+            self.want.blob_id.clone(),
+        )
+    }
+}
+
+/// Priority queue of the blobs on the want-list. Each blob is requested
+/// from a single peer at a time, preferably from a peer hinted to hold it;
+/// blobs which a peer fails to provide are returned to the queue to be
+/// requested from another peer, until they have failed too many times.
+///
+/// Changes to the wanted blobs are written through to the given want-list,
+/// from which the queue is loaded on startup.
+#[derive(Debug, Default)]
+pub struct BlobQueue {
+    /// Queued blobs by ID.
+    queued: HashMap<String, QueuedBlob>,
+    /// Order of the queued blobs, highest priority last.
+    order: BTreeSet<(BlobPriority, u64, String)>,
+    in_flight: HashMap<String, QueuedBlob>,
+    /// Blobs which each peer failed to provide, queued or in flight.
+    tried: HashMap<String, HashSet<String>>,
+}
+
+impl BlobQueue {
+    /// Replace the queue with the blobs of the want-list which have not
+    /// failed too many times. Returns the number of blobs queued.
+    pub fn load(&mut self, wants: &BlobWants) -> Result<usize> {
+        *self = Self::default();
+        for want in wants.get_all()? {
+            if want.retries < MAX_BLOB_RETRIES {
+                self.enqueue(QueuedBlob {
+                    want,
+                    tried_by: HashSet::new(),
+                });
+            }
+        }
+
+        Ok(self.queued.len())
+    }
+
+    /// Add a blob to the queue.
+    fn enqueue(&mut self, queued: QueuedBlob) {
+        self.order.insert(queued.order_key());
+        self.queued.insert(queued.want.blob_id.clone(), queued);
+    }
+
+    /// Remove a blob from the queue, if it is queued.
+    fn dequeue(&mut self, blob_id: &str) -> Option<QueuedBlob> {
+        let queued = self.queued.remove(blob_id)?;
+        self.order.remove(&queued.order_key());
+
+        Some(queued)
+    }
+
+    /// Forget the peers which failed to provide a blob which is no longer
+    /// queued.
+    fn forget_tried(&mut self, queued: &QueuedBlob) {
+        for peer_id in &queued.tried_by {
+            if let Some(tried) = self.tried.get_mut(peer_id) {
+                tried.remove(&queued.want.blob_id);
+                if tried.is_empty() {
+                    self.tried.remove(peer_id);
+                }
+            }
+        }
+    }
+
+    /// Add a blob to the want-list and to the queue. A blob which is
+    /// already wanted is only updated if the new priority is higher, and
+    /// its retries are only reset by an explicit request. The peer given as
+    /// hint is asked for the blob first.
+    pub fn push(
+        &mut self,
+        wants: &BlobWants,
+        blob_id: &str,
+        max: Option<u64>,
+        priority: BlobPriority,
+        timestamp: u64,
+        hint: Option<&str>,
+    ) -> Result<()> {
+        let update = |want: &mut BlobWant| {
+            if priority > want.priority {
+                want.priority = priority;
+                want.max = max;
+                want.timestamp = timestamp;
+            }
+            if priority == BlobPriority::Explicit {
+                want.retries = 0;
+            }
+            if let Some(peer_id) = hint {
+                want.hints.insert(peer_key(peer_id));
+            }
+        };
+
+        if let Some(queued) = self.in_flight.get_mut(blob_id) {
+            update(&mut queued.want);
+            return wants.insert(&queued.want);
+        }
+
+        let mut queued = match self.dequeue(blob_id) {
+            Some(queued) => queued,
+            None => QueuedBlob {
+                want: wants.get(blob_id)?.unwrap_or_else(|| BlobWant {
+                    blob_id: blob_id.to_owned(),
+                    priority,
+                    max,
+                    timestamp,
+                    hints: BTreeSet::new(),
+                    retries: 0,
+                }),
+                tried_by: HashSet::new(),
+            },
+        };
+        update(&mut queued.want);
+        let result = wants.insert(&queued.want);
+        if queued.want.retries < MAX_BLOB_RETRIES {
+            self.enqueue(queued);
+        } else {
+            self.forget_tried(&queued);
+        }
+
+        result
+    }
+
+    /// Record that the given peer is likely to hold a blob, if the blob is
+    /// queued.
+    pub fn hint(&mut self, wants: &BlobWants, blob_id: &str, peer_id: &str) -> Result<()> {
+        let queued = match self.in_flight.get_mut(blob_id) {
+            Some(queued) => queued,
+            None => match self.queued.get_mut(blob_id) {
+                Some(queued) => queued,
+                None => return Ok(()),
+            },
+        };
+        if queued.want.hints.insert(peer_key(peer_id)) {
+            wants.insert(&queued.want)?;
+        }
+
+        Ok(())
+    }
+
+    /// Take the highest priority blob which has not yet been tried by the
+    /// given peer, marking it as in flight. Among the blobs of that
+    /// priority, one which the peer is hinted to hold is taken first.
+    pub fn pop_for(&mut self, peer_id: &str) -> Option<(String, Option<u64>)> {
+        let hint = peer_key(peer_id);
+        let tried = self.tried.get(peer_id);
+        let is_hinted = |blob_id: &String| self.queued[blob_id].want.hints.contains(&hint);
+
+        let mut untried = self
+            .order
+            .iter()
+            .rev()
+            .filter(|(_, _, blob_id)| !tried.is_some_and(|tried| tried.contains(blob_id)));
+        let (priority, _, first) = untried.next()?;
+        let blob_id = if is_hinted(first) {
+            first
+        } else {
+            untried
+                .take(HINT_LOOKAHEAD)
+                .take_while(|other| other.0 == *priority)
+                .map(|(_, _, blob_id)| blob_id)
+                .find(|blob_id| is_hinted(blob_id))
+                .unwrap_or(first)
+        }
+        .clone();
+
+        let queued = self.dequeue(&blob_id)?;
+        let res = (blob_id.clone(), queued.want.max);
+        self.in_flight.insert(blob_id, queued);
+
+        Some(res)
+    }
+
+    /// Return an in-flight blob to the queue, e.g. when the connection to
+    /// the peer it was requested from closes.
+    pub fn requeue(&mut self, blob_id: &str) {
+        if let Some(queued) = self.in_flight.remove(blob_id) {
+            self.enqueue(queued);
+        }
+    }
+
+    /// Return an in-flight blob which the given peer failed to provide to
+    /// the queue, so that it is requested from another peer, unless it has
+    /// failed too many times.
+    pub fn fail(&mut self, wants: &BlobWants, blob_id: &str, peer_id: &str) -> Result<()> {
+        let mut queued = match self.in_flight.remove(blob_id) {
+            Some(queued) => queued,
+            None => return Ok(()),
+        };
+        queued.tried_by.insert(peer_id.to_owned());
+        self.tried
+            .entry(peer_id.to_owned())
+            .or_default()
+            .insert(blob_id.to_owned());
+        queued.want.retries += 1;
+        let result = wants.insert(&queued.want);
+        if queued.want.retries < MAX_BLOB_RETRIES {
+            self.enqueue(queued);
+        } else {
+            warn!(
+                "Giving up on blob {} after {} failed requests",
+                blob_id, queued.want.retries
+            );
+            self.forget_tried(&queued);
+        }
+
+        result
+    }
+
+    /// Remove a blob from the queue and from the want-list once it has been
+    /// fetched or skipped, or is no longer wanted. Returns `false` if the
+    /// blob was not wanted.
+    pub fn finish(&mut self, wants: &BlobWants, blob_id: &str) -> Result<bool> {
+        let queued = match self.in_flight.remove(blob_id) {
+            Some(queued) => Some(queued),
+            None => self.dequeue(blob_id),
+        };
+        if let Some(queued) = queued {
+            self.forget_tried(&queued);
+        }
+
+        wants.remove(blob_id)
+    }
 }
 
 pub struct BlobsGetHandler<W>
 where
     W: Write + Unpin + Send + Sync,
 {
+    peer_ssb_id: String,
+    /// Maximum number of concurrent outgoing requests.
+    concurrency: usize,
     incoming_reqs: HashSet<i32>,
-    outcoming_reqs: HashMap<i32, String>,
+    /// Outgoing requests with the requested blob ID and maximum size.
+    outcoming_reqs: HashMap<i32, (String, Option<u64>)>,
     phantom: PhantomData<W>,
 }
 
-impl<W> Default for BlobsGetHandler<W>
+impl<W> Drop for BlobsGetHandler<W>
 where
     W: Write + Unpin + Send + Sync,
 {
-    fn default() -> Self {
-        Self {
-            incoming_reqs: HashSet::new(),
-            outcoming_reqs: HashMap::new(),
-            phantom: PhantomData,
+    /// Return unanswered requests to the queue when the connection closes.
+    fn drop(&mut self) {
+        let mut queue = BLOB_QUEUE.lock().unwrap();
+        for (blob_id, _max) in self.outcoming_reqs.values() {
+            queue.requeue(blob_id);
         }
     }
 }
@@ -74,13 +366,21 @@ where
             RpcInput::Network(req_no, rpc::RecvMsg::RpcResponse(_type, res)) => {
                 return self.recv_rpc_response(api, *req_no, res).await;
             }
+            RpcInput::Network(req_no, rpc::RecvMsg::ErrorResponse(err)) => {
+                return self.recv_error_response(api, *req_no, err).await;
+            }
             RpcInput::Message(msg) => {
                 if let Some(get_event) = msg.downcast_ref::<RpcBlobsGetEvent>() {
                     match get_event {
-                        RpcBlobsGetEvent::Get(req) => return self.event_get(api, req).await,
+                        RpcBlobsGetEvent::Queued => {
+                            self.fetch_queued(api).await?;
+                        }
                     }
                 }
             }
+            RpcInput::Timer => {
+                self.fetch_queued(api).await?;
+            }
             _ => {}
         }
 
@@ -92,6 +392,23 @@ impl<W> BlobsGetHandler<W>
 where
     W: Write + Unpin + Send + Sync,
 {
+    pub fn new(peer_ssb_id: &str) -> Self {
+        let concurrency = BLOB_CONFIG
+            .get()
+            .map_or(DEFAULT_BLOB_FETCH_CONCURRENCY, |config| {
+                config.fetch_concurrency
+            })
+            .max(1);
+
+        Self {
+            peer_ssb_id: peer_ssb_id.to_owned(),
+            concurrency,
+            incoming_reqs: HashSet::new(),
+            outcoming_reqs: HashMap::new(),
+            phantom: PhantomData,
+        }
+    }
+
     async fn recv_get(
         &mut self,
         api: &mut ApiCaller<W>,
@@ -117,9 +434,9 @@ where
 
         if let Some(max) = args.max {
             if data.len() > max as usize {
-                trace!(target: "ssb-blob", "not sending blob: blob.len > max");
+                trace!(target: "ssb-blob", "not sending blob: {}", ERR_BLOB_TOO_LARGE);
                 api.rpc()
-                    .send_error(req_no, req.rpc_type, "blob.len > max")
+                    .send_error(req_no, req.rpc_type, ERR_BLOB_TOO_LARGE)
                     .await?;
                 return Ok(true);
             }
@@ -127,6 +444,11 @@ where
 
         api.blobs_get_res_send(req_no, &data).await?;
         self.incoming_reqs.insert(req_no);
+        KV_STORAGE
+            .read()
+            .await
+            .deliveries()
+            .remove_blob(&self.peer_ssb_id, &args.key)?;
 
         info!("Sent blob {}", args.key);
 
@@ -139,33 +461,221 @@ where
 
     async fn recv_rpc_response(
         &mut self,
-        _api: &mut ApiCaller<W>,
+        api: &mut ApiCaller<W>,
         req_no: i32,
         res: &[u8],
     ) -> Result<bool> {
-        if let Some(expected_blob_id) = self.outcoming_reqs.remove(&req_no) {
+        if let Some((expected_blob_id, max)) = self.outcoming_reqs.remove(&req_no) {
             let received_blob_id = res.blob_hash_id();
-            if received_blob_id != expected_blob_id {
+            if max.is_some_and(|max| res.len() as u64 > max) {
+                info!(
+                    "Skipping blob {} larger than {:?} bytes",
+                    expected_blob_id, max
+                );
+                let db = KV_STORAGE.read().await;
+                db.set_blob_skipped(&expected_blob_id)?;
+                BLOB_QUEUE
+                    .lock()
+                    .unwrap()
+                    .finish(db.blob_wants(), &expected_blob_id)?;
+            } else if received_blob_id != expected_blob_id {
                 warn!(
                     "Received a blob with bad hash, received={} expected={}",
                     received_blob_id, expected_blob_id
                 );
+                let db = KV_STORAGE.read().await;
+                BLOB_QUEUE.lock().unwrap().fail(
+                    db.blob_wants(),
+                    &expected_blob_id,
+                    &self.peer_ssb_id,
+                )?;
             } else {
                 info!("Received blob {}", received_blob_id);
                 BLOB_STORAGE.write().await.insert(res).await?;
+                let db = KV_STORAGE.read().await;
+                db.set_blob_retrieved(&received_blob_id, true)?;
+                BLOB_QUEUE
+                    .lock()
+                    .unwrap()
+                    .finish(db.blob_wants(), &expected_blob_id)?;
+            }
+            self.fetch_queued(api).await?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Record blobs which the peer refused to send because they exceed the
+    /// requested maximum size as skipped. Blobs which the peer failed to
+    /// send for other reasons are returned to the queue to be requested
+    /// from another peer.
+    async fn recv_error_response(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        req_no: i32,
+        err: &str,
+    ) -> Result<bool> {
+        if let Some((blob_id, max)) = self.outcoming_reqs.remove(&req_no) {
+            warn!("Failed to get blob {}: {}", blob_id, err);
+            // Any other error may be transient, so the blob is requested
+            // again, from this peer or another.
+            if max.is_some() && is_too_large_error(err) {
+                let db = KV_STORAGE.read().await;
+                db.set_blob_skipped(&blob_id)?;
+                BLOB_QUEUE
+                    .lock()
+                    .unwrap()
+                    .finish(db.blob_wants(), &blob_id)?;
+            } else {
+                let db = KV_STORAGE.read().await;
+                BLOB_QUEUE
+                    .lock()
+                    .unwrap()
+                    .fail(db.blob_wants(), &blob_id, &self.peer_ssb_id)?;
             }
+            self.fetch_queued(api).await?;
             Ok(true)
         } else {
             Ok(false)
         }
     }
 
-    async fn event_get(&mut self, api: &mut ApiCaller<W>, req: &dto::BlobsGetIn) -> Result<bool> {
-        info!("Requesting blob {}", req.key);
+    /// Request queued blobs from the peer until the concurrency limit is
+    /// reached, unless disk space is low.
+    async fn fetch_queued(&mut self, api: &mut ApiCaller<W>) -> Result<()> {
+        // Queued blobs are left for later while disk space is low or
+        // replication with the peer is paused.
+        if DISK_GUARD.is_low()
+            || REPLICATION_PAUSE
+                .lock()
+                .unwrap()
+                .is_paused(&self.peer_ssb_id)
+        {
+            return Ok(());
+        }
 
-        let req_no = api.blobs_get_req_send(req).await?;
-        self.outcoming_reqs.insert(req_no, req.key.clone());
+        while self.outcoming_reqs.len() < self.concurrency {
+            let next = BLOB_QUEUE.lock().unwrap().pop_for(&self.peer_ssb_id);
+            let (blob_id, max) = match next {
+                Some(next) => next,
+                None => break,
+            };
 
-        Ok(true)
+            // The blob may have been fetched since it was queued.
+            if BLOB_STORAGE.read().await.exists(&blob_id) {
+                let db = KV_STORAGE.read().await;
+                BLOB_QUEUE
+                    .lock()
+                    .unwrap()
+                    .finish(db.blob_wants(), &blob_id)?;
+                continue;
+            }
+
+            info!("Requesting blob {}", blob_id);
+
+            let req = dto::BlobsGetIn {
+                key: blob_id.clone(),
+                size: None,
+                max,
+            };
+            let req_no = match api.blobs_get_req_send(&req).await {
+                Ok(req_no) => req_no,
+                Err(err) => {
+                    BLOB_QUEUE.lock().unwrap().requeue(&blob_id);
+                    return Err(err.into());
+                }
+            };
+            self.outcoming_reqs.insert(req_no, (blob_id, max));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temporary_want_list() -> BlobWants {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        BlobWants::open(&db).unwrap()
+    }
+
+    #[test]
+    fn test_is_too_large_error() {
+        assert!(is_too_large_error("blob.len > max"));
+        assert!(is_too_large_error(
+            r#"{"name":"Error","message":"blob.len > max","stack":""}"#
+        ));
+        assert!(!is_too_large_error(
+            r#"{"name":"Error","message":"max connections reached","stack":""}"#
+        ));
+        assert!(!is_too_large_error("blob.len != expected"));
+    }
+
+    #[test]
+    fn test_blob_queue_priority() -> Result<()> {
+        let wants = temporary_want_list();
+        let mut queue = BlobQueue::default();
+        queue.push(&wants, "&old", None, BlobPriority::Follow, 1, None)?;
+        queue.push(&wants, "&new", None, BlobPriority::Follow, 2, None)?;
+        queue.push(&wants, "&other", None, BlobPriority::Other, 3, None)?;
+        queue.push(&wants, "&explicit", None, BlobPriority::Other, 0, None)?;
+        queue.push(&wants, "&explicit", None, BlobPriority::Explicit, 0, None)?;
+
+        let order: Vec<String> = std::iter::from_fn(|| queue.pop_for("@a"))
+            .map(|(blob_id, _max)| blob_id)
+            .collect();
+        assert_eq!(order, vec!["&explicit", "&new", "&old", "&other"]);
+
+        // A failed blob is not requested from the same peer again.
+        queue.fail(&wants, "&new", "@a")?;
+        assert_eq!(queue.pop_for("@a"), None);
+        assert_eq!(queue.pop_for("@b"), Some(("&new".to_string(), None)));
+        assert!(queue.finish(&wants, "&new")?);
+        queue.requeue("&new");
+        assert_eq!(queue.pop_for("@b"), None);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_blob_want_list() -> Result<()> {
+        let wants = temporary_want_list();
+        let mut queue = BlobQueue::default();
+        queue.push(&wants, "&a", None, BlobPriority::Other, 2, None)?;
+        queue.push(&wants, "&b", Some(10), BlobPriority::Other, 1, None)?;
+        queue.hint(&wants, "&b", "@b")?;
+
+        // Peers are asked first for the blobs they are hinted to hold.
+        assert_eq!(queue.pop_for("@b"), Some(("&b".to_string(), Some(10))));
+        assert_eq!(queue.pop_for("@c"), Some(("&a".to_string(), None)));
+        queue.requeue("&a");
+
+        // Blobs which failed too many times are no longer requested.
+        for i in 0..MAX_BLOB_RETRIES {
+            let peer_id = format!("@{i}");
+            assert_eq!(queue.pop_for(&peer_id), Some(("&a".to_string(), None)));
+            queue.fail(&wants, "&a", &peer_id)?;
+        }
+        assert_eq!(queue.pop_for("@c"), None);
+        assert_eq!(wants.get("&a")?.unwrap().retries, MAX_BLOB_RETRIES);
+
+        // The want-list outlives the queue.
+        let want_list = wants.get_all()?;
+        assert_eq!(want_list[0].blob_id, "&a");
+        assert_eq!(want_list[1].hints, BTreeSet::from(["@b".to_string()]));
+        let mut queue = BlobQueue::default();
+        assert_eq!(queue.load(&wants)?, 1);
+
+        // An explicit request resets the retries.
+        queue.push(&wants, "&a", None, BlobPriority::Explicit, 0, None)?;
+        assert_eq!(queue.pop_for("@c"), Some(("&a".to_string(), None)));
+        assert_eq!(wants.get("&a")?.unwrap().retries, 0);
+        assert!(queue.finish(&wants, "&a")?);
+        assert!(!queue.finish(&wants, "&a")?);
+
+        Ok(())
     }
 }
@@ -12,14 +12,22 @@ use kuska_ssb::{
 use log::{trace, warn};
 
 use crate::{
-    actors::rpc::handler::{RpcHandler, RpcInput},
+    actors::rpc::{
+        blobs_get::BLOB_QUEUE,
+        handler::{RpcHandler, RpcInput},
+    },
     broker::{BrokerEvent, ChBrokerSend, Destination},
+    config::{BLOB_CONFIG, SECRET_CONFIG},
+    disk::DISK_GUARD,
     storage::blob::{StoBlobEvent, ToBlobHashId},
-    Result, BLOB_STORAGE,
+    Result, BLOB_STORAGE, KV_STORAGE,
 };
 
-enum RpcBlobsWantsEvent {
+pub enum RpcBlobsWantsEvent {
     BroadcastWants(Vec<(String, i64)>),
+    /// Announce the given blobs to connected peers followed by the local
+    /// identity, without waiting for them to ask.
+    PushHaves(Vec<String>),
 }
 
 #[derive(PartialEq)]
@@ -83,6 +91,7 @@ pub struct BlobsWantsHandler<W>
 where
     W: Write + Unpin + Send + Sync,
 {
+    peer_ssb_id: String,
     initialized: bool,
     peer_wants_req_no: Option<i32>,
     my_wants_req_no: Option<i32>,
@@ -90,12 +99,13 @@ where
     phantom: PhantomData<W>,
 }
 
-impl<W> Default for BlobsWantsHandler<W>
+impl<W> BlobsWantsHandler<W>
 where
     W: Write + Unpin + Send + Sync,
 {
-    fn default() -> Self {
+    pub fn new(peer_ssb_id: &str) -> Self {
         Self {
+            peer_ssb_id: peer_ssb_id.to_string(),
             initialized: false,
             my_wants_req_no: None,
             peer_wants_req_no: None,
@@ -157,6 +167,9 @@ where
                         RpcBlobsWantsEvent::BroadcastWants(ids) => {
                             return self.event_wants_broadcast(api, ids).await
                         }
+                        RpcBlobsWantsEvent::PushHaves(ids) => {
+                            return self.event_push_haves(api, ids).await
+                        }
                     }
                 } else if let Some(stoblob_event) = msg.downcast_ref::<StoBlobEvent>() {
                     match stoblob_event {
@@ -188,13 +201,24 @@ where
 {
     async fn recv_create_wants(
         &mut self,
-        _api: &mut ApiCaller<W>,
+        api: &mut ApiCaller<W>,
         req_no: i32,
         _req: &rpc::Body,
     ) -> Result<bool> {
         if self.peer_wants_req_no.is_none() {
             trace!(target: "ssb-blob", "received create wants");
             self.peer_wants_req_no = Some(req_no);
+
+            // Announce the blobs queued for delivery to the peer right away.
+            let queue = KV_STORAGE
+                .read()
+                .await
+                .deliveries()
+                .get(&self.peer_ssb_id)?;
+            if let Some(queue) = queue {
+                let blob_ids: Vec<String> = queue.blobs.into_iter().collect();
+                self.push_haves(api, &blob_ids).await?;
+            }
         } else {
             trace!(target: "ssb-blob", "peer create wants already received");
         }
@@ -246,6 +270,64 @@ where
         Ok(true)
     }
 
+    /// Return `true` if the peer is followed by the local identity.
+    async fn is_followed(&self) -> Result<bool> {
+        let local_id = &SECRET_CONFIG.get().unwrap().id;
+        let following = KV_STORAGE
+            .read()
+            .await
+            .indexes()
+            .get_contact(local_id, &self.peer_ssb_id)?;
+
+        Ok(following == Some(true))
+    }
+
+    async fn event_push_haves(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        blob_ids: &[String],
+    ) -> Result<bool> {
+        // Only push to peers who are followed by the local identity and who
+        // have opened a wants stream with us.
+        if self.peer_wants_req_no.is_none() || !self.is_followed().await? {
+            return Ok(false);
+        }
+
+        self.push_haves(api, blob_ids).await
+    }
+
+    /// Announce those of the given blobs which are stored locally on the
+    /// wants stream opened by the peer. Returns `false` if none is stored.
+    async fn push_haves(&mut self, api: &mut ApiCaller<W>, blob_ids: &[String]) -> Result<bool> {
+        let peer_wants_req_no = match self.peer_wants_req_no {
+            Some(req_no) => req_no,
+            None => return Ok(false),
+        };
+
+        let mut haves: HashMap<String, u64> = HashMap::new();
+        for blob_id in blob_ids {
+            if let Some(size) = BLOB_STORAGE.read().await.size_of(blob_id)? {
+                haves.insert(blob_id.clone(), size);
+            }
+        }
+        if haves.is_empty() {
+            return Ok(false);
+        }
+
+        trace!(target: "ssb-blob", "pushing haves:{:?} to {}", haves, self.peer_ssb_id);
+
+        api.rpc()
+            .send_response(
+                peer_wants_req_no,
+                rpc::RpcType::Source,
+                rpc::BodyType::JSON,
+                &serde_json::to_vec(&haves)?,
+            )
+            .await?;
+
+        Ok(true)
+    }
+
     async fn recv_wants(
         &mut self,
         api: &mut ApiCaller<W>,
@@ -307,12 +389,42 @@ where
 
         trace!(target: "ssb-blob", "haves:{:?}", haves);
 
-        for (blob_id, _) in haves {
+        // No blobs are fetched while disk space is low.
+        if DISK_GUARD.is_low() {
+            return Ok(true);
+        }
+
+        let max_size = BLOB_CONFIG.get().and_then(|config| config.max_size);
+
+        for (blob_id, size) in haves {
+            // Ask the peer first for the wanted blobs it holds.
+            {
+                let db = KV_STORAGE.read().await;
+                BLOB_QUEUE
+                    .lock()
+                    .unwrap()
+                    .hint(db.blob_wants(), &blob_id, &self.peer_ssb_id)?;
+            }
+
+            // Skip blobs which exceed the maximum size.
+            if max_size.is_some_and(|max| size as u64 > max) {
+                trace!(target: "ssb-blob", "skipping blob {} of size {}", blob_id, size);
+                KV_STORAGE.read().await.set_blob_skipped(&blob_id)?;
+                continue;
+            }
+
             if let Some(wants) = self.peer_wants.get_mut(&blob_id) {
                 let req_no = api
                     .blobs_get_req_send(&dto::BlobsGetIn::new(blob_id.clone()))
                     .await?;
                 *wants = Wants::Requested(req_no);
+            } else if self.is_followed().await? && !BLOB_STORAGE.read().await.exists(&blob_id) {
+                // Accept blobs pushed by peers we follow, even if we did not
+                // ask for them.
+                let req_no = api
+                    .blobs_get_req_send(&dto::BlobsGetIn::new(blob_id.clone()))
+                    .await?;
+                self.peer_wants.insert(blob_id, Wants::Requested(req_no));
             }
         }
 
@@ -342,6 +454,10 @@ where
         }
 
         BLOB_STORAGE.write().await.insert(&data).await?;
+        KV_STORAGE
+            .read()
+            .await
+            .set_blob_retrieved(&current_blob_id, true)?;
         *wants.1 = Wants::Available;
 
         Ok(true)
@@ -0,0 +1,215 @@
+use std::marker::PhantomData;
+
+use async_std::io::Write;
+use async_trait::async_trait;
+use kuska_ssb::{api::ApiCaller, crypto::ToSodiumObject, discovery::LanBroadcast, rpc};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::{
+    actors::{
+        connection_manager::CONNECTION_MANAGER,
+        peer::{self, Connect},
+        rpc::handler::{RpcHandler, RpcInput},
+    },
+    broker::{Broker, ChBrokerSend},
+    config::SECRET_CONFIG,
+    Result,
+};
+
+/// MUXRPC method names of the legacy peer administration requests.
+const GOSSIP_PEERS: [&str; 2] = ["gossip", "peers"];
+const GOSSIP_CONNECT: [&str; 2] = ["gossip", "connect"];
+const CONN_PEERS: [&str; 2] = ["conn", "peers"];
+
+/// Address of the peer to connect to, either as a multiserver address
+/// (`net:<host>:<port>~shs:<key>`) or as an object.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum ConnectArgs {
+    Address(String),
+    Object {
+        host: String,
+        port: u16,
+        key: String,
+    },
+}
+
+/// Gossip handler. Serves the `gossip.peers`, `gossip.connect` and
+/// `conn.peers` requests which legacy tools send to administer pubs, backed
+/// by the connection manager. The requests are only served to the local
+/// identity, as with other SSB servers.
+pub struct GossipHandler<'a, W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    peer_ssb_id: &'a str,
+    phantom: PhantomData<W>,
+}
+
+impl<'a, W> GossipHandler<'a, W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    pub fn new(peer_ssb_id: &'a str) -> Self {
+        Self {
+            peer_ssb_id,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Whether the peer is the local identity, e.g. an administration tool
+    /// using the keys of the node.
+    fn is_local(&self) -> bool {
+        let local_id = &SECRET_CONFIG.get().unwrap().id;
+        self.peer_ssb_id.trim_start_matches('@') == local_id.trim_start_matches('@')
+    }
+}
+
+#[async_trait]
+impl<'a, W> RpcHandler<W> for GossipHandler<'a, W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        "GossipHandler"
+    }
+
+    async fn handle(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        op: &RpcInput,
+        _ch_broker: &mut ChBrokerSend,
+    ) -> Result<bool> {
+        match op {
+            RpcInput::Network(req_no, rpc::RecvMsg::RpcRequest(req))
+                if req.name == GOSSIP_PEERS
+                    || req.name == GOSSIP_CONNECT
+                    || req.name == CONN_PEERS =>
+            {
+                if !self.is_local() {
+                    warn!(
+                        "peer {} is not allowed to call {}",
+                        self.peer_ssb_id,
+                        req.name.join(".")
+                    );
+                    api.rpc()
+                        .send_error(*req_no, req.rpc_type, "method not allowed")
+                        .await?;
+                    return Ok(true);
+                }
+
+                if req.name == GOSSIP_CONNECT {
+                    self.recv_connect(api, *req_no, req).await
+                } else {
+                    self.recv_peers(api, *req_no, req).await
+                }
+            }
+            _ => Ok(false),
+        }
+    }
+}
+
+impl<'a, W> GossipHandler<'a, W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Respond with the connected peers: an array of peers for
+    /// `gossip.peers`, or a single update of `[address, data]` pairs
+    /// followed by the end of the stream for `conn.peers`. Addresses are not
+    /// tracked by the connection manager, so peers are identified by key.
+    async fn recv_peers(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        req_no: i32,
+        req: &rpc::Body,
+    ) -> Result<bool> {
+        let keys: Vec<String> = CONNECTION_MANAGER
+            .read()
+            .await
+            .connected_peers
+            .iter()
+            .map(peer::peer_id)
+            .collect();
+
+        let body = if req.name == GOSSIP_PEERS {
+            let peers: Vec<Value> = keys
+                .iter()
+                .map(|key| json!({ "key": key, "state": "connected" }))
+                .collect();
+            json!(peers)
+        } else {
+            let peers: Vec<Value> = keys
+                .iter()
+                .map(|key| json!([key, { "key": key, "state": "connected" }]))
+                .collect();
+            json!(peers)
+        };
+
+        api.rpc()
+            .send_response(
+                req_no,
+                req.rpc_type,
+                rpc::BodyType::JSON,
+                &serde_json::to_vec(&body)?,
+            )
+            .await?;
+        if req.name == CONN_PEERS {
+            api.rpc().send_stream_eof(req_no).await?;
+        }
+
+        Ok(true)
+    }
+
+    /// Dial the given peer and respond once the connection attempt has
+    /// started.
+    async fn recv_connect(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        req_no: i32,
+        req: &rpc::Body,
+    ) -> Result<bool> {
+        let mut args: Vec<ConnectArgs> = serde_json::from_value(req.args.clone())?;
+
+        let target = match args.pop() {
+            Some(ConnectArgs::Address(address)) => LanBroadcast::parse(&address),
+            Some(ConnectArgs::Object { host, port, key }) => key
+                .trim_start_matches('@')
+                .to_ed25519_pk()
+                .ok()
+                .map(|peer_pk| (host, port, peer_pk)),
+            None => None,
+        };
+
+        match target {
+            Some((server, port, peer_pk)) => {
+                info!("connecting to {}:{} on request", server, port);
+                // Selective replication only applies to inbound connections.
+                Broker::spawn(peer::actor(
+                    SECRET_CONFIG.get().unwrap().owned_identity()?,
+                    Connect::TcpServer {
+                        addresses: vec![format!("{server}:{port}")],
+                        peer_pk,
+                    },
+                    false,
+                ));
+                api.rpc()
+                    .send_response(
+                        req_no,
+                        req.rpc_type,
+                        rpc::BodyType::JSON,
+                        &serde_json::to_vec(&true)?,
+                    )
+                    .await?;
+            }
+            None => {
+                api.rpc()
+                    .send_error(req_no, req.rpc_type, "invalid address")
+                    .await?;
+            }
+        }
+
+        Ok(true)
+    }
+}
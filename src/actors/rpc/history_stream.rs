@@ -1,29 +1,134 @@
-use std::{collections::HashMap, marker::PhantomData, string::ToString};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    marker::PhantomData,
+    string::ToString,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
 
-use async_std::io::Write;
+use async_std::{
+    io::Write,
+    task::{self, JoinHandle},
+};
 use async_trait::async_trait;
-use futures::SinkExt;
+use futures::{stream::FuturesOrdered, FutureExt, SinkExt, StreamExt};
 use kuska_ssb::{
     api::{dto, ApiCaller, ApiMethod},
-    feed::{Feed as MessageKvt, Message},
+    feed::Message,
     rpc,
 };
 use log::{debug, info, warn};
 use once_cell::sync::Lazy;
+#[cfg(feature = "blobs")]
 use regex::Regex;
 
+#[cfg(feature = "blobs")]
 use crate::{
-    actors::rpc::handler::{RpcHandler, RpcInput},
+    actors::rpc::blobs_get::{BlobPriority, RpcBlobsGetEvent, BLOB_QUEUE},
+    config::BLOB_CONFIG,
+    BLOB_STORAGE,
+};
+use crate::{
+    actors::{
+        replication_pause::{ReplicationPauseChanged, REPLICATION_PAUSE},
+        rpc::handler::{RpcHandler, RpcInput},
+        sync_session::{self, SYNC_SESSIONS},
+    },
+    allow_list,
+    api::{forward::FORWARD_HOOKS, replication},
     broker::{BrokerEvent, ChBrokerSend, Destination},
-    config::{REPLICATION_CONFIG, RESYNC_CONFIG, SECRET_CONFIG},
-    storage::kv::StoKvEvent,
-    Result, BLOB_STORAGE, KV_STORAGE,
+    config::{
+        ARCHIVIST_CONFIG, FIRST_SYNC_CONFIG, REPLICATION_CONFIG, RESYNC_CONFIG, SECRET_CONFIG,
+    },
+    disk::DISK_GUARD,
+    health::HEALTH,
+    ingest::{Verdict, INGEST_LIMITER},
+    storage::{
+        audit::{AuditEntry, AuditEvent, Direction},
+        kv::{self, StoKvEvent},
+    },
+    validation, Result, KV_STORAGE,
 };
 
 /// Regex pattern used to match blob references.
+#[cfg(feature = "blobs")]
 pub static BLOB_REGEX: Lazy<Regex> =
     Lazy::new(|| Regex::new(r"(&[0-9A-Za-z/+=]*.sha256)").unwrap());
 
+/// Maximum number of received messages being verified at once, per peer.
+const MAX_VERIFYING: usize = 64;
+
+/// Time for which the distance of an author in the follow graph is cached,
+/// when deciding whether to fetch the blobs referenced by their messages.
+#[cfg(feature = "blobs")]
+const HOPS_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// Number of peers whose stream of the local feed must have ended before the
+/// restore is complete, so that a peer holding an outdated copy of the feed
+/// does not complete it early.
+const RESTORE_MIN_PEERS: usize = 3;
+
+/// Time after the first peer ended its stream of the local feed from which
+/// the restore is complete with fewer peers.
+const RESTORE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Progress of the restore of the local feed, shared by the connections.
+static RESTORE_PROGRESS: Lazy<Mutex<RestoreProgress>> =
+    Lazy::new(|| Mutex::new(RestoreProgress::default()));
+
+/// Peers whose stream of the local feed has ended while it is being
+/// restored, and when the first of them ended.
+#[derive(Default)]
+struct RestoreProgress {
+    peers: HashSet<String>,
+    since: Option<Instant>,
+}
+
+impl RestoreProgress {
+    /// Whether enough peers have ended their stream, or enough time has
+    /// passed since the first did.
+    fn is_complete(&self) -> bool {
+        self.peers.len() >= RESTORE_MIN_PEERS
+            || self
+                .since
+                .is_some_and(|since| since.elapsed() >= RESTORE_TIMEOUT)
+    }
+}
+
+/// Complete the restore of the local feed once enough peers have ended
+/// their stream of it. The messages of every peer extend the same stored
+/// feed, so it holds the latest message sent by any of them.
+async fn finish_restore_if_complete() -> Result<()> {
+    let peers = {
+        let progress = RESTORE_PROGRESS.lock().unwrap();
+        if !progress.is_complete() {
+            return Ok(());
+        }
+        progress.peers.len()
+    };
+
+    let local_id = &SECRET_CONFIG.get().unwrap().id;
+    let db = KV_STORAGE.read().await;
+    if let Some(seq) = db.get_latest_seq(local_id)? {
+        if kv::is_restoring() {
+            db.commit_indexes().await?;
+            db.finish_restore()?;
+            info!(
+                "restored local feed up to sequence number {} from {} peers",
+                seq, peers
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Events which trigger history stream requests.
+pub enum RpcHistoryStreamEvent {
+    /// A peer has been added to the replication list at runtime.
+    Replicate(String),
+}
+
 #[derive(Debug)]
 struct HistoryStreamRequest {
     req_no: i32,
@@ -37,9 +142,29 @@ where
     W: Write + Unpin + Send + Sync,
 {
     initialized: bool,
+    /// Replication with the peer is paused.
+    paused: bool,
     _actor_id: usize,
+    /// Public key of the connected peer.
+    peer_id: String,
     reqs: HashMap<String, HistoryStreamRequest>,
     peers: HashMap<i32, String>,
+    /// IDs of the first-sync requests which have not yet ended.
+    first_sync_reqs: BTreeSet<i32>,
+    /// IDs of the requests of a LAN sync session which have not yet ended.
+    sync_reqs: BTreeSet<i32>,
+    /// ID of the request for the local feed while it is being restored.
+    restore_req: Option<i32>,
+    /// Feeds whose history is requested once the first-sync requests have
+    /// ended.
+    deferred: BTreeSet<String>,
+    /// Received messages being decoded and verified, in the order in which
+    /// they were received, along with their encoded size.
+    verifying: FuturesOrdered<JoinHandle<Result<(Message, usize)>>>,
+    /// Whether authors are within the maximum hops distance for blobs, and
+    /// when this was computed.
+    #[cfg(feature = "blobs")]
+    within_hops: HashMap<String, (bool, Instant)>,
     phantom: PhantomData<W>,
 }
 
@@ -75,11 +200,11 @@ where
             }
             // Handle an incoming MUXRPC 'cancel stream' response.
             RpcInput::Network(req_no, rpc::RecvMsg::CancelStreamRespose()) => {
-                self.recv_cancelstream(api, *req_no).await
+                self.recv_cancelstream(api, ch_broker, *req_no).await
             }
             // Handle an incoming MUXRPC error response.
             RpcInput::Network(req_no, rpc::RecvMsg::ErrorResponse(err)) => {
-                self.recv_error_response(api, *req_no, err).await
+                self.recv_error_response(api, ch_broker, *req_no, err).await
             }
             // Handle a broker message.
             RpcInput::Message(msg) => {
@@ -91,12 +216,30 @@ where
                         StoKvEvent::IdChanged(id) => {
                             return self.recv_storageevent_idchanged(api, id).await
                         }
+                        StoKvEvent::FollowerChanged(_) | StoKvEvent::Replaced => {
+                            return Ok(false)
+                        }
                     }
                 }
+                if let Some(history_event) = msg.downcast_ref::<RpcHistoryStreamEvent>() {
+                    match history_event {
+                        // A peer has been added to the replication list since
+                        // the handler was initialized.
+                        RpcHistoryStreamEvent::Replicate(peer_pk) => {
+                            if self.initialized {
+                                self.send_history_stream_req(api, peer_pk).await?;
+                            }
+                            return Ok(false);
+                        }
+                    }
+                }
+                if msg.downcast_ref::<ReplicationPauseChanged>().is_some() {
+                    self.update_pause(api, ch_broker).await?;
+                }
                 Ok(false)
             }
             // Handle a timer event.
-            RpcInput::Timer => self.on_timer(api).await,
+            RpcInput::Timer => self.on_timer(api, ch_broker).await,
             _ => Ok(false),
         }
     }
@@ -107,13 +250,22 @@ where
     W: Write + Unpin + Send + Sync,
 {
     /// Instantiate a new instance of `HistoryStreamHandler` with the given
-    /// actor ID.
-    pub fn new(actor_id: usize) -> Self {
+    /// actor ID and the public key of the connected peer.
+    pub fn new(actor_id: usize, peer_id: &str) -> Self {
         Self {
             _actor_id: actor_id,
+            peer_id: peer_id.to_string(),
             initialized: false,
+            paused: false,
             peers: HashMap::new(),
             reqs: HashMap::new(),
+            first_sync_reqs: BTreeSet::new(),
+            sync_reqs: BTreeSet::new(),
+            restore_req: None,
+            deferred: BTreeSet::new(),
+            verifying: FuturesOrdered::new(),
+            #[cfg(feature = "blobs")]
+            within_hops: HashMap::new(),
             phantom: PhantomData,
         }
     }
@@ -121,13 +273,45 @@ where
     /// Initialize the history stream handler.
     ///
     /// Calls `create_history_stream` for every peer in the replication list,
-    /// requesting the latest messages.
-    async fn on_timer(&mut self, api: &mut ApiCaller<W>) -> Result<bool> {
-        if !self.initialized {
+    /// requesting the latest messages. Also appends the received messages
+    /// whose verification is still in progress.
+    async fn on_timer(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        ch_broker: &mut ChBrokerSend,
+    ) -> Result<bool> {
+        // Append the messages still being verified once the peer goes quiet,
+        // and commit the index updates batched while they were appended.
+        self.append_ready(api, ch_broker, true).await?;
+        KV_STORAGE.read().await.commit_indexes().await?;
+        self.update_pause(api, ch_broker).await?;
+        if kv::is_restoring() {
+            finish_restore_if_complete().await?;
+        }
+
+        // Replication starts once the indexes built in the background after
+        // startup are ready, since appends would race with the builds.
+        if !self.initialized && !self.paused && kv::indexes_ready() {
             debug!("initializing history stream handler");
 
-            // If local database resync has been selected...
-            if *RESYNC_CONFIG.get().unwrap() {
+            // If the local feed is being restored, or local database resync
+            // has been selected...
+            if kv::is_restoring() {
+                // Request the local feed after the messages restored so far,
+                // e.g. from other peers or before a restart.
+                let local_id = &SECRET_CONFIG.get().unwrap().id;
+                let latest_seq = KV_STORAGE.read().await.get_latest_seq(local_id)?;
+                info!(
+                    "restoring local feed; requesting it from peer {}",
+                    self.peer_id
+                );
+                let args = dto::CreateHistoryStreamIn::new(local_id.clone())
+                    .after_seq(latest_seq.map_or(1, |seq| seq + 1));
+                let req_id = api.create_history_stream_req_send(&args).await?;
+
+                self.peers.insert(req_id, local_id.to_string());
+                self.restore_req = Some(req_id);
+            } else if *RESYNC_CONFIG.get().unwrap() {
                 info!("database resync selected; requesting local feed from peers");
                 // Read the local public key from the secret config file.
                 let local_id = &SECRET_CONFIG.get().unwrap().id;
@@ -141,39 +325,368 @@ where
             }
 
             // Loop through the public keys of all peers in the replication list.
-            for peer_pk in REPLICATION_CONFIG.get().unwrap().peers.keys() {
-                // Instantiate the history stream request args for the given peer.
-                // The `live` arg means: keep the connection open after initial
-                // replication.
-                let mut args = dto::CreateHistoryStreamIn::new(peer_pk.to_string()).live(true);
-
-                // Retrieve the sequence number of the most recent message for
-                // this peer from the local key-value store.
-                if let Some(last_seq) = KV_STORAGE.read().await.get_latest_seq(peer_pk)? {
-                    // Use the latest sequence number to update the request args.
-                    args = args.after_seq(last_seq);
+            let peers: Vec<String> = REPLICATION_CONFIG
+                .get()
+                .unwrap()
+                .read()
+                .await
+                .peers
+                .keys()
+                .cloned()
+                .collect();
+            let mut peers: BTreeSet<String> = peers.into_iter().collect();
+            // Pinned feeds are replicated regardless of the follow graph.
+            peers.extend(KV_STORAGE.read().await.get_pinned()?);
+            if *ARCHIVIST_CONFIG.get().unwrap() {
+                peers.extend(self.archive_feeds().await?);
+            }
+
+            let syncing = SYNC_SESSIONS.lock().unwrap().is_running(&self.peer_id);
+            if syncing {
+                // In a LAN sync session, every known feed is fetched up to
+                // the latest message held by the peer before the history
+                // of any feed is requested.
+                let mut feeds = peers.clone();
+                feeds.extend(self.archive_feeds().await?);
+                for peer_pk in feeds {
+                    self.send_sync_req(api, &peer_pk).await?;
+                }
+                SYNC_SESSIONS
+                    .lock()
+                    .unwrap()
+                    .set_feeds(&self.peer_id, self.sync_reqs.len());
+                if self.sync_reqs.is_empty() {
+                    self.complete_sync();
+                }
+            } else if let Some(limit) = FIRST_SYNC_CONFIG.get().unwrap() {
+                // On first sync, the first messages of direct follows are
+                // fetched before the history of any feed is requested.
+                for peer_pk in self.first_sync_feeds(&peers).await? {
+                    self.send_first_sync_req(api, &peer_pk, *limit).await?;
                 }
+            }
+            if self.first_sync_reqs.is_empty() && self.sync_reqs.is_empty() {
+                for peer_pk in peers {
+                    self.send_history_stream_req(api, &peer_pk).await?;
+                }
+            } else {
+                self.deferred = peers;
+            }
 
-                // Send the history stream request.
-                let id = api.create_history_stream_req_send(&args).await?;
+            self.initialized = true;
+        }
 
-                // Insert the history stream request ID and peer ID
-                // (public key) into the peers hash map.
-                self.peers.insert(id, peer_pk.to_string());
+        Ok(false)
+    }
 
-                info!(
-                    "requesting messages authored by peer {} after {:?}",
-                    peer_pk, args.seq
-                );
+    /// Pause or resume replication with the peer, following the replication
+    /// pause state.
+    ///
+    /// On pause, the messages being verified are appended and the requests
+    /// made to the peer are ended; they are made again on the next timer
+    /// event after resume, from the latest stored messages. On resume, the
+    /// messages appended to the feeds requested by the peer while paused
+    /// are sent.
+    async fn update_pause(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        ch_broker: &mut ChBrokerSend,
+    ) -> Result<()> {
+        let paused = REPLICATION_PAUSE.lock().unwrap().is_paused(&self.peer_id);
+        if paused == self.paused {
+            return Ok(());
+        }
+        self.paused = paused;
+
+        if paused {
+            info!("pausing replication with peer {}", self.peer_id);
+            self.append_ready(api, ch_broker, true).await?;
+            for req_no in self.peers.keys() {
+                api.rpc().send_stream_eof(-req_no).await?;
+            }
+            self.peers.clear();
+            self.first_sync_reqs.clear();
+            self.sync_reqs.clear();
+            self.restore_req = None;
+            self.deferred.clear();
+            self.initialized = false;
+        } else {
+            info!("resuming replication with peer {}", self.peer_id);
+            let ids: Vec<String> = self.reqs.keys().cloned().collect();
+            for id in ids {
+                self.recv_storageevent_idchanged(api, &id).await?;
             }
+        }
 
-            self.initialized = true;
+        Ok(())
+    }
+
+    /// Return the feeds replicated in archivist mode in addition to those in
+    /// the replication list: the connected peer and every feed known from
+    /// the stored messages, except those blocked by the local identity.
+    async fn archive_feeds(&self) -> Result<Vec<String>> {
+        let local_id = &SECRET_CONFIG.get().unwrap().id;
+        let db = KV_STORAGE.read().await;
+
+        let mut archive = replication::archive_feeds(&db, local_id).await?;
+        if self.peer_id != *local_id && !db.indexes().is_blocking(local_id, &self.peer_id)? {
+            archive.insert(self.peer_id.clone());
         }
 
-        Ok(false)
+        Ok(archive.into_iter().collect())
+    }
+
+    /// Return the feeds to be fetched first on first sync: the direct
+    /// follows of the local identity, and the feeds listed in the
+    /// replication configuration, for which no message is stored yet.
+    async fn first_sync_feeds(&self, peers: &BTreeSet<String>) -> Result<Vec<String>> {
+        let local_id = &SECRET_CONFIG.get().unwrap().id;
+        let listed = &REPLICATION_CONFIG.get().unwrap().read().await.peers;
+        let db = KV_STORAGE.read().await;
+
+        let mut feeds = Vec::new();
+        for peer_pk in peers {
+            let followed = listed.contains_key(peer_pk)
+                || db.indexes().get_contact(local_id, peer_pk)? == Some(true);
+            if followed && peer_pk != local_id && db.get_latest_seq(peer_pk)?.is_none() {
+                feeds.push(peer_pk.clone());
+            }
+        }
+
+        Ok(feeds)
+    }
+
+    /// Request the first messages authored by the given peer, which usually
+    /// include its profile and first follows. Legacy replication can only
+    /// fetch a feed in order, so these come before the latest messages.
+    async fn send_first_sync_req(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        peer_pk: &str,
+        limit: u64,
+    ) -> Result<()> {
+        if !allow_list::is_allowed(peer_pk).await?
+            || KV_STORAGE
+                .read()
+                .await
+                .mutes()
+                .is_replication_stopped(peer_pk)?
+        {
+            return Ok(());
+        }
+
+        let args = dto::CreateHistoryStreamIn::new(peer_pk.to_string()).limit(limit);
+        let id = api.create_history_stream_req_send(&args).await?;
+        self.peers.insert(id, peer_pk.to_string());
+        self.first_sync_reqs.insert(id);
+
+        info!(
+            "requesting first {} messages authored by peer {}",
+            limit, peer_pk
+        );
+
+        Ok(())
+    }
+
+    /// Record the end of a first-sync request and, once every first-sync
+    /// request has ended, request the history of the deferred feeds.
+    async fn end_first_sync_req(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        ch_broker: &mut ChBrokerSend,
+        req_no: i32,
+    ) -> Result<bool> {
+        if !self.first_sync_reqs.remove(&req_no) {
+            return Ok(false);
+        }
+        self.peers.remove(&req_no);
+
+        if self.first_sync_reqs.is_empty() {
+            info!("first sync done; requesting full history");
+            // Requests resume after the latest stored messages, so the
+            // messages being verified are appended first.
+            self.append_ready(api, ch_broker, true).await?;
+            for peer_pk in std::mem::take(&mut self.deferred) {
+                self.send_history_stream_req(api, &peer_pk).await?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Request the messages authored by the given peer after the latest
+    /// stored one, up to the latest held by the connected peer, as part of a
+    /// LAN sync session.
+    async fn send_sync_req(&mut self, api: &mut ApiCaller<W>, peer_pk: &str) -> Result<()> {
+        if !allow_list::is_allowed(peer_pk).await?
+            || KV_STORAGE
+                .read()
+                .await
+                .mutes()
+                .is_replication_stopped(peer_pk)?
+        {
+            return Ok(());
+        }
+
+        let mut args = dto::CreateHistoryStreamIn::new(peer_pk.to_string());
+        if let Some(last_seq) = KV_STORAGE.read().await.get_latest_seq(peer_pk)? {
+            args = args.after_seq(last_seq);
+        }
+        let id = api.create_history_stream_req_send(&args).await?;
+        self.peers.insert(id, peer_pk.to_string());
+        self.sync_reqs.insert(id);
+
+        Ok(())
+    }
+
+    /// Record the end of a request of a LAN sync session and, once every
+    /// request of the session has ended, report the session as complete
+    /// and request the history of the deferred feeds.
+    async fn end_sync_req(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        ch_broker: &mut ChBrokerSend,
+        req_no: i32,
+    ) -> Result<bool> {
+        if !self.sync_reqs.remove(&req_no) {
+            return Ok(false);
+        }
+        self.peers.remove(&req_no);
+
+        if self.sync_reqs.is_empty() {
+            // The session is only complete once the received messages are
+            // appended and the index updates committed.
+            self.append_ready(api, ch_broker, true).await?;
+            KV_STORAGE.read().await.commit_indexes().await?;
+            self.complete_sync();
+            for peer_pk in std::mem::take(&mut self.deferred) {
+                self.send_history_stream_req(api, &peer_pk).await?;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Report the LAN sync session with the peer as complete.
+    fn complete_sync(&self) {
+        let session = SYNC_SESSIONS
+            .lock()
+            .unwrap()
+            .complete(&self.peer_id, sync_session::now());
+        if let Some(session) = session {
+            info!(
+                "LAN sync with peer {} complete: received {} messages of {} feeds in {} ms",
+                self.peer_id,
+                session.msgs_received,
+                session.feeds,
+                session.ended.unwrap_or(session.started) - session.started
+            );
+        }
+    }
+
+    /// Record the end of the request for the local feed while it is being
+    /// restored. The restore is complete once the streams of several peers
+    /// have ended, or some time after the first one did, as long as one of
+    /// them held the local feed.
+    async fn end_restore_req(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        ch_broker: &mut ChBrokerSend,
+        req_no: i32,
+    ) -> Result<bool> {
+        if self.restore_req != Some(req_no) {
+            return Ok(false);
+        }
+        self.restore_req = None;
+        self.peers.remove(&req_no);
+
+        // Append the messages still being verified before reading the
+        // restored sequence number.
+        self.append_ready(api, ch_broker, true).await?;
+
+        let local_id = &SECRET_CONFIG.get().unwrap().id;
+        let latest_seq = KV_STORAGE.read().await.get_latest_seq(local_id)?;
+        match latest_seq {
+            Some(seq) if kv::is_restoring() => {
+                {
+                    let mut progress = RESTORE_PROGRESS.lock().unwrap();
+                    progress.peers.insert(self.peer_id.clone());
+                    progress.since.get_or_insert_with(Instant::now);
+                    info!(
+                        "peer {} ended its stream of the local feed at sequence number {} ({} of {} peers)",
+                        self.peer_id,
+                        seq,
+                        progress.peers.len(),
+                        RESTORE_MIN_PEERS
+                    );
+                }
+                finish_restore_if_complete().await?;
+            }
+            Some(_) => (),
+            None => warn!(
+                "peer {} holds none of the local feed; waiting for other peers to restore it",
+                self.peer_id
+            ),
+        }
+
+        Ok(true)
+    }
+
+    /// Request the latest messages authored by the given peer.
+    async fn send_history_stream_req(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        peer_pk: &str,
+    ) -> Result<()> {
+        // Feeds outside the allow list are not requested.
+        if !allow_list::is_allowed(peer_pk).await? {
+            debug!(
+                "not requesting messages of peer {} outside the allow list",
+                peer_pk
+            );
+            return Ok(());
+        }
+
+        // Feeds muted with replication stopped are not requested.
+        if KV_STORAGE
+            .read()
+            .await
+            .mutes()
+            .is_replication_stopped(peer_pk)?
+        {
+            debug!("not requesting messages of muted peer {}", peer_pk);
+            return Ok(());
+        }
+
+        // Instantiate the history stream request args for the given peer.
+        // The `live` arg means: keep the connection open after initial
+        // replication.
+        let mut args = dto::CreateHistoryStreamIn::new(peer_pk.to_string()).live(true);
+
+        // Retrieve the sequence number of the most recent message for
+        // this peer from the local key-value store.
+        if let Some(last_seq) = KV_STORAGE.read().await.get_latest_seq(peer_pk)? {
+            // Use the latest sequence number to update the request args.
+            args = args.after_seq(last_seq);
+        }
+
+        // Send the history stream request.
+        let id = api.create_history_stream_req_send(&args).await?;
+
+        // Insert the history stream request ID and peer ID
+        // (public key) into the peers hash map.
+        self.peers.insert(id, peer_pk.to_string());
+
+        info!(
+            "requesting messages authored by peer {} after {:?}",
+            peer_pk, args.seq
+        );
+
+        Ok(())
     }
 
     /// Extract blob references from post-type messages.
+    #[cfg(feature = "blobs")]
     fn extract_blob_refs(&mut self, msg: &Message) -> Vec<String> {
         let mut refs = Vec::new();
 
@@ -189,73 +702,288 @@ where
         refs
     }
 
+    /// Whether the given author is pinned or within the maximum hops
+    /// distance for blobs of the local identity. The distance is cached for
+    /// a while, since computing it walks the follow graph.
+    #[cfg(feature = "blobs")]
+    async fn is_within_hops(&mut self, local_id: &str, author: &str) -> Result<bool> {
+        let max_hops = match BLOB_CONFIG.get().unwrap().max_hops {
+            Some(max_hops) => max_hops,
+            None => return Ok(true),
+        };
+        if let Some((within_hops, since)) = self.within_hops.get(author) {
+            if since.elapsed() < HOPS_CACHE_TTL {
+                return Ok(*within_hops);
+            }
+        }
+
+        let db = KV_STORAGE.read().await;
+        let within_hops = db.is_pinned(author)?
+            || db
+                .indexes()
+                .get_hops(local_id, author, max_hops)?
+                .is_some();
+        self.within_hops
+            .insert(author.to_string(), (within_hops, Instant::now()));
+
+        Ok(within_hops)
+    }
+
+    /// Queue the blobs referenced by a received message which are not in
+    /// the local blobstore, to be requested from peers.
+    #[cfg(feature = "blobs")]
+    async fn queue_blobs(&mut self, ch_broker: &mut ChBrokerSend, msg: &Message) -> Result<()> {
+        let local_id = &SECRET_CONFIG.get().unwrap().id;
+        let blob_config = BLOB_CONFIG.get().unwrap();
+        let blob_refs = self.extract_blob_refs(msg);
+        if blob_refs.is_empty() {
+            return Ok(());
+        }
+        // The blobs are also queued for delivery to the peers which request
+        // the feed from this node, in case they do not fetch them otherwise.
+        KV_STORAGE
+            .read()
+            .await
+            .deliveries()
+            .queue_blobs(msg.author(), &blob_refs)?;
+        let mut queued = false;
+        for key in blob_refs {
+            if !BLOB_STORAGE.read().await.exists(&key) {
+                // Blobs referenced by authors beyond the maximum hops
+                // distance are recorded as skipped rather than requested.
+                // Blobs referenced by direct follows are queued with a
+                // higher priority, most recent messages first.
+                if !self.is_within_hops(local_id, msg.author()).await? {
+                    KV_STORAGE.read().await.set_blob_skipped(&key)?;
+                    continue;
+                }
+                let db = KV_STORAGE.read().await;
+                let priority = match db.indexes().get_contact(local_id, msg.author())? {
+                    Some(true) => BlobPriority::Follow,
+                    _ => BlobPriority::Other,
+                };
+                // The peer which sent the message is likely to hold the blob.
+                BLOB_QUEUE.lock().unwrap().push(
+                    db.blob_wants(),
+                    &key,
+                    blob_config.max_size,
+                    priority,
+                    msg.timestamp() as u64,
+                    Some(&self.peer_id),
+                )?;
+                queued = true;
+            }
+        }
+        if queued {
+            let broker_msg = BrokerEvent::new(Destination::Broadcast, RpcBlobsGetEvent::Queued);
+            ch_broker.send(broker_msg).await.unwrap();
+        }
+
+        Ok(())
+    }
+
     /// Process an incoming MUXRPC response. The response is expected to
     /// contain an SSB message.
+    ///
+    /// Messages are decoded and their signatures verified on the blocking
+    /// task pool, so that verification of the following messages overlaps
+    /// with the storage of the preceding ones. Verified messages are
+    /// appended in the order in which they were received.
     async fn recv_rpc_response(
         &mut self,
-        _api: &mut ApiCaller<W>,
+        api: &mut ApiCaller<W>,
         ch_broker: &mut ChBrokerSend,
         req_no: i32,
         res: &[u8],
     ) -> Result<bool> {
         // Only handle the response if we made the request.
-        if self.peers.contains_key(&req_no) {
-            // First try to deserialize the response into a message value.
-            // If that fails, try to deserialize into a message KVT and then
-            // convert that into a message value. Return an error if that fails.
-            // This approach allows us to handle the unlikely event that
-            // messages are sent as KVTs and not simply values.
-            let msg = match Message::from_slice(res) {
-                Ok(msg) => msg,
-                Err(_) => MessageKvt::from_slice(res)?.into_message()?,
+        if !self.peers.contains_key(&req_no) {
+            return Ok(false);
+        }
+
+        // Decode the message according to its feed format, verifying its
+        // signature. Messages may be sent either as values or as KVTs.
+        let bytes = res.to_vec();
+        self.verifying.push_back(task::spawn_blocking(move || {
+            validation::decode_msg(&bytes).map(|msg| (msg, bytes.len()))
+        }));
+
+        // Wait for the oldest verification once enough are in progress.
+        if self.verifying.len() > MAX_VERIFYING {
+            if let Some(verified) = self.verifying.next().await {
+                let (msg, size) = verified?;
+                self.append_verified(api, ch_broker, msg, size).await?;
+            }
+        }
+        self.append_ready(api, ch_broker, false).await?;
+
+        Ok(true)
+    }
+
+    /// Append the messages whose verification has completed, in order. If
+    /// `wait` is set, wait for all verifications in progress.
+    async fn append_ready(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        ch_broker: &mut ChBrokerSend,
+        wait: bool,
+    ) -> Result<()> {
+        loop {
+            let verified = if wait {
+                self.verifying.next().await
+            } else {
+                match self.verifying.next().now_or_never() {
+                    Some(verified) => verified,
+                    None => break,
+                }
             };
+            match verified {
+                Some(verified) => {
+                    let (msg, size) = verified?;
+                    self.append_verified(api, ch_broker, msg, size).await?;
+                }
+                None => break,
+            }
+        }
 
-            // Retrieve the sequence number of the most recent message for
-            // the peer that authored the received message.
-            let last_seq = KV_STORAGE
-                .read()
-                .await
-                .get_latest_seq(&msg.author().to_string())?
-                .unwrap_or(0);
+        Ok(())
+    }
+
+    /// Append a verified message of `size` bytes to its feed, if it is the
+    /// next message of the feed, and act on its content.
+    async fn append_verified(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        ch_broker: &mut ChBrokerSend,
+        msg: Message,
+        size: usize,
+    ) -> Result<()> {
+        // Retrieve the sequence number of the most recent message for
+        // the peer that authored the received message.
+        let last_seq = KV_STORAGE
+            .read()
+            .await
+            .get_latest_seq(&msg.author().to_string())?
+            .unwrap_or(0);
 
-            // Validate the sequence number.
-            if msg.sequence() == last_seq + 1 {
-                // Append the message to the feed.
-                KV_STORAGE.write().await.append_feed(msg.clone()).await?;
+        // Validate the sequence number.
+        if msg.sequence() == last_seq + 1 {
+            // Oversized messages are dropped; the rest of the feed cannot
+            // be appended after them.
+            if let Err(err) = validation::check_size(&msg) {
+                warn!("dropping msg from {}: {}", msg.author(), err);
+                return Ok(());
+            }
 
-                info!(
-                    "received msg number {} from {}",
-                    msg.sequence(),
-                    msg.author()
-                );
+            // Messages by feeds which are not followed are subject to
+            // the ingest rate limits. Dropped messages are requested
+            // again the next time the feed is replicated.
+            let local_id = &SECRET_CONFIG.get().unwrap().id;
+
+            // While disk space is low, only messages of the local feed and
+            // of the feeds it follows are appended; others are requested
+            // again once space is available.
+            if DISK_GUARD.is_low()
+                && msg.author() != local_id.as_str()
+                && KV_STORAGE
+                    .read()
+                    .await
+                    .indexes()
+                    .get_contact(local_id, msg.author())?
+                    != Some(true)
+            {
+                debug!("disk space low; dropping msg from feed {}", msg.author());
+                return Ok(());
+            }
 
-                // Extract blob references from the received message and
-                // request those blobs if they are not already in the local
-                // blobstore.
-                for key in self.extract_blob_refs(&msg) {
-                    if !BLOB_STORAGE.read().await.exists(&key) {
-                        let event = super::blobs_get::RpcBlobsGetEvent::Get(dto::BlobsGetIn {
-                            key,
-                            size: None,
-                            max: None,
-                        });
-                        let broker_msg = BrokerEvent::new(Destination::Broadcast, event);
-                        ch_broker.send(broker_msg).await.unwrap();
+            // Messages received in a LAN sync session are not limited.
+            if INGEST_LIMITER.is_enabled()
+                && msg.author() != local_id.as_str()
+                && KV_STORAGE
+                    .read()
+                    .await
+                    .indexes()
+                    .get_contact(local_id, msg.author())?
+                    != Some(true)
+                && !SYNC_SESSIONS.lock().unwrap().is_running(&self.peer_id)
+            {
+                match INGEST_LIMITER.check(msg.author(), size, Instant::now()) {
+                    Verdict::Accept => {}
+                    Verdict::Throttle(reason) => {
+                        warn!("throttling feed {}: {}", msg.author(), reason);
+                        let entry = AuditEntry::new(
+                            Direction::Inbound,
+                            None,
+                            Some(msg.author().to_string()),
+                            AuditEvent::Throttled {
+                                reason: reason.to_string(),
+                            },
+                        );
+                        KV_STORAGE.read().await.audit().record(&entry)?;
+                        return Ok(());
+                    }
+                    Verdict::Throttled => {
+                        debug!("dropping msg from throttled feed {}", msg.author());
+                        return Ok(());
                     }
                 }
-            } else {
-                warn!(
-                    "received out-of-order msg from {}; recv: {} db: {}",
-                    &msg.author().to_string(),
-                    msg.sequence(),
-                    last_seq
-                );
             }
 
-            Ok(true)
+            // Append the message to the feed.
+            KV_STORAGE.write().await.append_feed(msg.clone()).await?;
+            HEALTH.set_replicated();
+            SYNC_SESSIONS.lock().unwrap().record_msg(&self.peer_id);
+
+            info!(
+                "received msg number {} from {}",
+                msg.sequence(),
+                msg.author()
+            );
+
+            #[cfg(feature = "blobs")]
+            self.queue_blobs(ch_broker, &msg).await?;
+
+            // In archivist mode, start replicating feeds as soon as they
+            // are referenced by a contact message.
+            let contact = match msg.content()["type"].as_str() {
+                Some("contact") => msg.content()["contact"].as_str(),
+                _ => None,
+            };
+            if *ARCHIVIST_CONFIG.get().unwrap() {
+                if let Some(contact) = contact.filter(|id| validation::format_for(id).is_ok()) {
+                    let local_id = &SECRET_CONFIG.get().unwrap().id;
+                    let requested = self.peers.values().any(|peer_pk| peer_pk == contact);
+                    let blocked = KV_STORAGE
+                        .read()
+                        .await
+                        .indexes()
+                        .is_blocking(local_id, contact)?;
+                    if !requested && !blocked && contact != local_id {
+                        self.send_history_stream_req(api, contact).await?;
+                    }
+                }
+            }
+
+            // Request the root of the thread to which the received
+            // message belongs if it is not already stored locally. The
+            // root may belong to a feed which is not replicated.
+            if let Some(root) = msg.content()["root"].as_str() {
+                if KV_STORAGE.read().await.get_any_msg_val(root)?.is_none() {
+                    let event = super::ooo::RpcOooEvent::Get(root.to_owned());
+                    let broker_msg = BrokerEvent::new(Destination::Broadcast, event);
+                    ch_broker.send(broker_msg).await.unwrap();
+                }
+            }
         } else {
-            Ok(false)
+            warn!(
+                "received out-of-order msg from {}; recv: {} db: {}",
+                &msg.author().to_string(),
+                msg.sequence(),
+                last_seq
+            );
         }
+
+        Ok(())
     }
 
     /// Process and respond to an incoming history stream request.
@@ -276,8 +1004,11 @@ where
 
         let mut req = HistoryStreamRequest { args, from, req_no };
 
-        // Send the requested messages from the local feed.
-        self.send_history(api, &mut req).await?;
+        // Send the requested messages from the local feed. While replication
+        // is paused, those of live requests are sent on resume.
+        if !self.paused {
+            self.send_history(api, &mut req).await?;
+        }
 
         if req.args.live.unwrap_or(false) {
             // Keep the stream open for communication.
@@ -292,8 +1023,19 @@ where
 
     /// Close the stream and remove the public key of the peer from the list
     /// of active streams (`reqs`).
-    async fn recv_cancelstream(&mut self, api: &mut ApiCaller<W>, req_no: i32) -> Result<bool> {
-        if let Some(key) = self.find_key_by_req_no(req_no) {
+    async fn recv_cancelstream(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        ch_broker: &mut ChBrokerSend,
+        req_no: i32,
+    ) -> Result<bool> {
+        if self.first_sync_reqs.contains(&req_no) {
+            self.end_first_sync_req(api, ch_broker, req_no).await
+        } else if self.sync_reqs.contains(&req_no) {
+            self.end_sync_req(api, ch_broker, req_no).await
+        } else if self.restore_req == Some(req_no) {
+            self.end_restore_req(api, ch_broker, req_no).await
+        } else if let Some(key) = self.find_key_by_req_no(req_no) {
             api.rpc().send_stream_eof(-req_no).await?;
             self.reqs.remove(&key);
             Ok(true)
@@ -306,11 +1048,23 @@ where
     /// list of active streams (`reqs`).
     async fn recv_error_response(
         &mut self,
-        _api: &mut ApiCaller<W>,
+        api: &mut ApiCaller<W>,
+        ch_broker: &mut ChBrokerSend,
         req_no: i32,
         error_msg: &str,
     ) -> Result<bool> {
-        if let Some(key) = self.find_key_by_req_no(req_no) {
+        if self.first_sync_reqs.contains(&req_no) {
+            warn!("MUXRPC error {}", error_msg);
+            self.end_first_sync_req(api, ch_broker, req_no).await
+        } else if self.sync_reqs.contains(&req_no) {
+            warn!("MUXRPC error {}", error_msg);
+            self.end_sync_req(api, ch_broker, req_no).await
+        } else if self.restore_req == Some(req_no) {
+            warn!("MUXRPC error {}", error_msg);
+            self.restore_req = None;
+            self.peers.remove(&req_no);
+            Ok(true)
+        } else if let Some(key) = self.find_key_by_req_no(req_no) {
             warn!("MUXRPC error {}", error_msg);
             self.reqs.remove(&key);
             Ok(true)
@@ -329,6 +1083,11 @@ where
         api: &mut ApiCaller<W>,
         id: &str,
     ) -> Result<bool> {
+        // The new messages are sent on resume.
+        if self.paused {
+            return Ok(false);
+        }
+
         // Attempt to remove the peer from the list of active streams.
         if let Some(mut req) = self.reqs.remove(id) {
             // Send local messages to the peer.
@@ -371,6 +1130,14 @@ where
             .get_latest_seq(&req_id)?
             .unwrap_or(0);
 
+        // Keep track of the messages held by peers to which deliveries are
+        // enabled, as of their request.
+        KV_STORAGE.read().await.deliveries().record_held(
+            &self.peer_id,
+            &req_id,
+            req.from.saturating_sub(1),
+        )?;
+
         // Determine if the messages should be sent as message values or as
         // message KVTs (Key Value Timestamp).
         // Defaults to message values if unset.
@@ -392,23 +1159,84 @@ where
                 req.args.id, requester, req.from, last_seq
             );
 
+            // Sequence number of the latest message sent, which is lower
+            // than `last_seq` if a forwarding hook drops a message or the
+            // content of a message was deleted.
+            let mut sent_seq = last_seq;
+
+            // Messages whose content was deleted no longer verify against
+            // their signature, and the peer cannot append the following
+            // messages without them, so the stream stops before the first.
+            let tombstoned = KV_STORAGE
+                .read()
+                .await
+                .first_tombstoned_seq(&req_id, req.from)?;
+
             // Iterate over the range of requested messages, read them from the
             // local key-value database and send them to the requesting peer.
             // The "to" value (`last_seq`) is exclusive so we need to add one to
             // include it in the range.
             for n in req.from..(last_seq + 1) {
-                let data = KV_STORAGE.read().await.get_msg_kvt(&req_id, n)?.unwrap();
-                // Send either the whole KVT or just the value.
-                let data = if with_keys {
-                    data.to_string()
+                if tombstoned == Some(n) {
+                    debug!(
+                        "not sending {} from deleted sequence {} to {}",
+                        req_id, n, requester
+                    );
+                    sent_seq = n - 1;
+                    break;
+                }
+                // Send either the whole KVT or just the value. Unless
+                // forwarding hooks are registered, the KVT is sent as
+                // stored, without being parsed.
+                if !FORWARD_HOOKS.is_empty() {
+                    let data = KV_STORAGE.read().await.get_msg_kvt(&req_id, n)?.unwrap();
+                    match FORWARD_HOOKS.dispatch(&self.peer_id, data)? {
+                        Some(data) if with_keys => {
+                            api.feed_res_send(req.req_no, &serde_json::to_string(&data)?)
+                                .await?
+                        }
+                        Some(data) => {
+                            api.feed_res_send(req.req_no, &data.value.to_string())
+                                .await?
+                        }
+                        None => {
+                            // The peer cannot append the following messages
+                            // without this one.
+                            debug!(
+                                "forwarding hook held back {} from sequence {} for {}",
+                                req_id, n, requester
+                            );
+                            sent_seq = n - 1;
+                            break;
+                        }
+                    }
+                } else if with_keys {
+                    let raw = KV_STORAGE
+                        .read()
+                        .await
+                        .get_msg_kvt_raw(&req_id, n)?
+                        .unwrap();
+                    api.feed_res_send(req.req_no, &String::from_utf8_lossy(&raw))
+                        .await?;
                 } else {
-                    data.value.to_string()
-                };
-                api.feed_res_send(req.req_no, &data).await?;
+                    let data = KV_STORAGE.read().await.get_msg_kvt(&req_id, n)?.unwrap();
+                    api.feed_res_send(req.req_no, &data.value.to_string())
+                        .await?;
+                }
             }
 
-            // Update the starting sequence number for the request.
-            req.from = last_seq;
+            // Update the starting sequence number for the request. A message
+            // held back by a forwarding hook is offered to it again next time.
+            req.from = if sent_seq < last_seq {
+                sent_seq + 1
+            } else {
+                last_seq
+            };
+            KV_STORAGE
+                .read()
+                .await
+                .deliveries()
+                .record_held(&self.peer_id, &req_id, sent_seq)?;
         }
 
         Ok(())
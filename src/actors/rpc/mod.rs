@@ -1,13 +1,23 @@
+#[cfg(feature = "blobs")]
 mod blobs_get;
+#[cfg(feature = "blobs")]
 mod blobs_wants;
 mod get;
+mod gossip;
 mod handler;
 mod history_stream;
+mod ooo;
 mod whoami;
 
-pub use blobs_get::BlobsGetHandler;
-pub use blobs_wants::BlobsWantsHandler;
+#[cfg(feature = "blobs")]
+pub use blobs_get::{BlobPriority, BlobsGetHandler, RpcBlobsGetEvent, BLOB_QUEUE};
+#[cfg(feature = "blobs")]
+pub use blobs_wants::{BlobsWantsHandler, RpcBlobsWantsEvent};
 pub use get::GetHandler;
+pub use gossip::GossipHandler;
 pub use handler::{RpcHandler, RpcInput};
-pub use history_stream::HistoryStreamHandler;
+#[cfg(feature = "blobs")]
+pub use history_stream::BLOB_REGEX;
+pub use history_stream::{HistoryStreamHandler, RpcHistoryStreamEvent};
+pub use ooo::{OooHandler, RpcOooEvent};
 pub use whoami::WhoAmIHandler;
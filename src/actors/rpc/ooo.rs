@@ -0,0 +1,171 @@
+use std::{collections::HashMap, marker::PhantomData};
+
+use async_std::io::Write;
+use async_trait::async_trait;
+use kuska_ssb::{api::ApiCaller, rpc};
+use log::{info, warn};
+
+use crate::{
+    actors::rpc::handler::{RpcHandler, RpcInput},
+    broker::ChBrokerSend,
+    validation, Result, KV_STORAGE,
+};
+
+/// MUXRPC method name of the out-of-order message fetch.
+const OOO_GET: [&str; 2] = ["ooo", "get"];
+
+/// Events which trigger out-of-order message requests.
+pub enum RpcOooEvent {
+    /// Request the message with the given ID from the peer.
+    Get(String),
+}
+
+/// Out-of-order message handler. Serves `ooo.get` requests for single
+/// messages by ID and requests messages which are referenced locally but
+/// belong to feeds which are not replicated.
+pub struct OooHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Outgoing requests and the ID of the requested message.
+    outgoing_reqs: HashMap<i32, String>,
+    phantom: PhantomData<W>,
+}
+
+impl<W> Default for OooHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    fn default() -> Self {
+        Self {
+            outgoing_reqs: HashMap::new(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+#[async_trait]
+impl<W> RpcHandler<W> for OooHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    fn name(&self) -> &'static str {
+        "OooHandler"
+    }
+
+    async fn handle(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        op: &RpcInput,
+        _ch_broker: &mut ChBrokerSend,
+    ) -> Result<bool> {
+        match op {
+            RpcInput::Network(req_no, rpc::RecvMsg::RpcRequest(req)) if req.name == OOO_GET => {
+                return self.recv_get(api, *req_no, req).await;
+            }
+            RpcInput::Network(req_no, rpc::RecvMsg::RpcResponse(_type, res)) => {
+                return self.recv_rpc_response(*req_no, res).await;
+            }
+            RpcInput::Network(req_no, rpc::RecvMsg::ErrorResponse(err)) => {
+                if let Some(msg_id) = self.outgoing_reqs.remove(req_no) {
+                    warn!("peer failed to provide message {}: {}", msg_id, err);
+                    return Ok(true);
+                }
+            }
+            RpcInput::Message(msg) => {
+                if let Some(ooo_event) = msg.downcast_ref::<RpcOooEvent>() {
+                    match ooo_event {
+                        RpcOooEvent::Get(msg_id) => return self.event_get(api, msg_id).await,
+                    }
+                }
+            }
+            _ => {}
+        }
+
+        Ok(false)
+    }
+}
+
+impl<W> OooHandler<W>
+where
+    W: Write + Unpin + Send + Sync,
+{
+    /// Respond to an incoming request for a single message with the message
+    /// value, whether it belongs to a replicated feed or the out-of-order
+    /// store.
+    async fn recv_get(
+        &mut self,
+        api: &mut ApiCaller<W>,
+        req_no: i32,
+        req: &rpc::Body,
+    ) -> Result<bool> {
+        let args: Vec<String> = serde_json::from_value(req.args.clone())?;
+
+        let msg_val = match args.first() {
+            Some(msg_id) => KV_STORAGE.read().await.get_any_msg_val(msg_id),
+            None => Ok(None),
+        };
+        match msg_val {
+            Ok(Some(msg)) => api.get_res_send(req_no, &msg).await?,
+            Ok(None) => {
+                api.rpc()
+                    .send_error(req_no, req.rpc_type, "not found")
+                    .await?
+            }
+            Err(err) => {
+                let msg = format!("{err}");
+                api.rpc().send_error(req_no, req.rpc_type, &msg).await?
+            }
+        };
+
+        Ok(true)
+    }
+
+    /// Request the message with the given ID from the peer, unless it is
+    /// already stored locally.
+    async fn event_get(&mut self, api: &mut ApiCaller<W>, msg_id: &str) -> Result<bool> {
+        if KV_STORAGE.read().await.get_any_msg_val(msg_id)?.is_some() {
+            return Ok(false);
+        }
+
+        info!("requesting out-of-order message {}", msg_id);
+
+        let req_no = api
+            .rpc()
+            .send_request(&OOO_GET, rpc::RpcType::Async, &[msg_id], &None)
+            .await?;
+        self.outgoing_reqs.insert(req_no, msg_id.to_owned());
+
+        // Let other handlers see the event too.
+        Ok(false)
+    }
+
+    /// Verify the requested message and add it to the out-of-order store.
+    async fn recv_rpc_response(&mut self, req_no: i32, res: &[u8]) -> Result<bool> {
+        if let Some(expected_msg_id) = self.outgoing_reqs.remove(&req_no) {
+            // Decode the message according to its feed format, verifying its
+            // signature. Messages may be sent either as values or as KVTs.
+            let msg = validation::decode_msg(res)?;
+
+            let msg_id = msg.id().to_string();
+            if msg_id != expected_msg_id {
+                warn!(
+                    "received out-of-order message with unexpected id, received={} expected={}",
+                    msg_id, expected_msg_id
+                );
+            } else {
+                let db = KV_STORAGE.read().await;
+                // The message may have arrived via replication in the
+                // meantime.
+                if db.get_msg_val(&expected_msg_id)?.is_none() {
+                    db.set_ooo_msg_val(&msg)?;
+                    info!("received out-of-order message {}", expected_msg_id);
+                }
+            }
+
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
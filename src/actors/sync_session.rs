@@ -0,0 +1,198 @@
+//! LAN sync sessions.
+//!
+//! With LAN sync enabled, discovering a known peer on the local network
+//! (listed in the replication configuration or added with `deliver`) starts
+//! a sync session with it, e.g. when a laptop running solar comes home.
+//! Before the usual live replication, every known feed is requested up to
+//! the latest message held by the peer, bypassing the first-sync and ingest
+//! limits. The session is complete once the peer has sent all of them.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+/// Minimum time between the end of a session with a peer and the start of
+/// the next one. Peers are discovered every few seconds while they remain
+/// on the network.
+pub const SESSION_INTERVAL: Duration = Duration::from_secs(600);
+
+/// The sync sessions of the solar node.
+pub static SYNC_SESSIONS: Lazy<Mutex<SyncSessions>> =
+    Lazy::new(|| Mutex::new(SyncSessions::default()));
+
+/// State of a sync session.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SyncState {
+    /// The messages requested from the peer are being received.
+    Running,
+    /// The peer has sent every requested message.
+    Complete,
+    /// The connection failed or closed before the session was complete.
+    Interrupted,
+}
+
+/// A sync session with a peer.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SyncSession {
+    /// Public key of the peer.
+    pub peer: String,
+    pub state: SyncState,
+    /// Milliseconds since the Unix epoch.
+    pub started: u64,
+    /// Milliseconds since the Unix epoch, once the session has ended.
+    pub ended: Option<u64>,
+    /// Number of feeds requested from the peer.
+    pub feeds: usize,
+    /// Number of messages received from the peer and appended.
+    pub msgs_received: u64,
+}
+
+/// The latest sync session with each peer.
+#[derive(Debug, Default)]
+pub struct SyncSessions {
+    sessions: HashMap<String, SyncSession>,
+}
+
+/// Return the ID of a peer with the leading `@`.
+fn peer_key(peer_id: &str) -> String {
+    if peer_id.starts_with('@') {
+        peer_id.to_string()
+    } else {
+        format!("@{peer_id}")
+    }
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+impl SyncSessions {
+    /// Start a session with the given peer at `now`, unless a session is
+    /// running or the previous one completed less than `SESSION_INTERVAL`
+    /// ago. Returns `false` if no session was started.
+    pub fn start(&mut self, peer_id: &str, now: u64) -> bool {
+        let key = peer_key(peer_id);
+        if let Some(session) = self.sessions.get(&key) {
+            match (session.state, session.ended) {
+                (SyncState::Interrupted, _) => (),
+                (_, Some(ended)) if now >= ended + SESSION_INTERVAL.as_millis() as u64 => (),
+                _ => return false,
+            }
+        }
+
+        self.sessions.insert(
+            key.clone(),
+            SyncSession {
+                peer: key,
+                state: SyncState::Running,
+                started: now,
+                ended: None,
+                feeds: 0,
+                msgs_received: 0,
+            },
+        );
+
+        true
+    }
+
+    /// Whether a session with the given peer is running.
+    pub fn is_running(&self, peer_id: &str) -> bool {
+        self.running(peer_id).is_some()
+    }
+
+    /// Record the number of feeds requested from the peer.
+    pub fn set_feeds(&mut self, peer_id: &str, feeds: usize) {
+        if let Some(session) = self.running_mut(peer_id) {
+            session.feeds = feeds;
+        }
+    }
+
+    /// Record a message received from the peer.
+    pub fn record_msg(&mut self, peer_id: &str) {
+        if let Some(session) = self.running_mut(peer_id) {
+            session.msgs_received += 1;
+        }
+    }
+
+    /// Mark the running session with the given peer as complete. Returns
+    /// the session, if one was running.
+    pub fn complete(&mut self, peer_id: &str, now: u64) -> Option<SyncSession> {
+        self.end(peer_id, SyncState::Complete, now)
+    }
+
+    /// Mark the running session with the given peer as interrupted.
+    /// Returns the session, if one was running.
+    pub fn interrupt(&mut self, peer_id: &str, now: u64) -> Option<SyncSession> {
+        self.end(peer_id, SyncState::Interrupted, now)
+    }
+
+    /// Return the latest session with each peer, latest first.
+    pub fn list(&self) -> Vec<SyncSession> {
+        let mut sessions: Vec<SyncSession> = self.sessions.values().cloned().collect();
+        sessions.sort_by_key(|session| Reverse(session.started));
+
+        sessions
+    }
+
+    fn end(&mut self, peer_id: &str, state: SyncState, now: u64) -> Option<SyncSession> {
+        let session = self.running_mut(peer_id)?;
+        session.state = state;
+        session.ended = Some(now);
+
+        Some(session.clone())
+    }
+
+    fn running(&self, peer_id: &str) -> Option<&SyncSession> {
+        self.sessions
+            .get(&peer_key(peer_id))
+            .filter(|session| session.state == SyncState::Running)
+    }
+
+    fn running_mut(&mut self, peer_id: &str) -> Option<&mut SyncSession> {
+        self.sessions
+            .get_mut(&peer_key(peer_id))
+            .filter(|session| session.state == SyncState::Running)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sync_sessions() {
+        let mut sessions = SyncSessions::default();
+        let interval = SESSION_INTERVAL.as_millis() as u64;
+
+        // Peer IDs from handshakes have no leading `@`.
+        assert!(sessions.start("peer=.ed25519", 1000));
+        assert!(!sessions.start("@peer=.ed25519", 2000));
+        assert!(sessions.is_running("@peer=.ed25519"));
+        sessions.set_feeds("peer=.ed25519", 3);
+        sessions.record_msg("peer=.ed25519");
+
+        let session = sessions.complete("peer=.ed25519", 3000).unwrap();
+        assert_eq!(session.state, SyncState::Complete);
+        assert_eq!((session.feeds, session.msgs_received), (3, 1));
+        assert!(sessions.interrupt("peer=.ed25519", 3000).is_none());
+
+        // The next session starts once the interval has passed.
+        assert!(!sessions.start("peer=.ed25519", 3000 + interval - 1));
+        assert!(sessions.start("peer=.ed25519", 3000 + interval));
+        // An interrupted session is restarted right away.
+        sessions.interrupt("peer=.ed25519", 4000 + interval);
+        assert_eq!(sessions.list()[0].state, SyncState::Interrupted);
+        assert!(sessions.start("peer=.ed25519", 5000 + interval));
+    }
+}
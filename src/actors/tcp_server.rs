@@ -4,19 +4,36 @@ use async_std::{
 };
 use futures::{select_biased, FutureExt};
 use kuska_ssb::keystore::OwnedIdentity;
+use serde_json::json;
 
-use crate::{broker::*, Result};
+use crate::{broker::*, health::HEALTH, Result};
 
 pub async fn actor(
     server_id: OwnedIdentity,
     addr: impl ToSocketAddrs,
     selective_replication: bool,
+    netsim: bool,
 ) -> Result<()> {
     let broker = BROKER.lock().await.register("sbot-listener", false).await?;
 
     let mut ch_terminate = broker.ch_terminate.fuse();
 
     let listener = TcpListener::bind(addr).await?;
+    HEALTH.set_listening(true);
+
+    // Announce the public key and bound port in a machine-readable format
+    // when running in network simulation mode. Simulators wait for this line
+    // before attempting connections.
+    if netsim {
+        println!(
+            "{}",
+            json!({
+                "event": "listening",
+                "id": server_id.id,
+                "port": listener.local_addr()?.port(),
+            })
+        );
+    }
     let mut incoming = listener.incoming();
 
     loop {
@@ -34,6 +51,8 @@ pub async fn actor(
         }
     }
 
+    HEALTH.set_listening(false);
+
     let _ = broker.ch_terminated.send(Void {});
 
     Ok(())
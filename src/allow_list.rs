@@ -0,0 +1,28 @@
+//! Allow-list mode for closed-membership networks.
+//!
+//! When an allow-list admin feed is configured, the node only connects to
+//! and replicates the admin and the feeds which the admin currently follows.
+//! The list is signed, since it is made of the admin's contact messages, and
+//! is updated as those messages are replicated. Combined with a custom
+//! network key this allows a private community to run its own network.
+
+use crate::{config::ALLOW_LIST_CONFIG, storage::kv::KvStorage, Result, KV_STORAGE};
+
+/// Whether the given feed may connect and be replicated. Always true unless
+/// allow-list mode is enabled.
+pub async fn is_allowed(pub_key: &str) -> Result<bool> {
+    let admin = ALLOW_LIST_CONFIG.get().unwrap().as_deref();
+    is_allowed_by(&*KV_STORAGE.read().await, admin, pub_key)
+}
+
+/// Whether the given feed may connect and be replicated when the allow list
+/// is kept by the given admin feed, according to the contact messages of
+/// the admin stored in the given database. Always true without an admin.
+pub fn is_allowed_by(db: &KvStorage, admin: Option<&str>, pub_key: &str) -> Result<bool> {
+    match admin {
+        Some(admin) if admin != pub_key => {
+            Ok(db.indexes().get_contact(admin, pub_key)? == Some(true))
+        }
+        _ => Ok(true),
+    }
+}
@@ -0,0 +1,231 @@
+//! Read-only ActivityPub bridge.
+//!
+//! The local identity is exposed as an ActivityPub actor whose outbox holds
+//! its latest public posts, so that fediverse users can look it up by its
+//! WebFinger address (`@<user>@<domain>`) and read its posts. Nothing is
+//! delivered to other servers and the inbox accepts nothing, so the actor
+//! cannot be followed in the usual sense.
+//!
+//! The public key of the actor is the ed25519 key of the local identity,
+//! tying the actor to the SSB feed.
+
+use serde_json::{json, Value};
+use url::form_urlencoded;
+
+use crate::{
+    api::atom::{self, MAX_ENTRIES},
+    config::ActivityPubConfig,
+    storage::kv::KvStorage,
+    Result,
+};
+
+/// Media type of ActivityPub documents.
+pub const CONTENT_TYPE: &str = "application/activity+json";
+
+/// Audience of public activities.
+const PUBLIC: &str = "https://www.w3.org/ns/activitystreams#Public";
+
+/// DER encoding of the SubjectPublicKeyInfo of an ed25519 key, up to the
+/// key itself.
+const ED25519_SPKI_PREFIX: [u8; 12] = [
+    0x30, 0x2a, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x03, 0x21, 0x00,
+];
+
+/// Base URL of the HTTP server.
+fn base_url(config: &ActivityPubConfig) -> String {
+    format!("https://{}", config.domain)
+}
+
+fn actor_url(config: &ActivityPubConfig) -> String {
+    format!("{}/ap/actor", base_url(config))
+}
+
+fn note_url(config: &ActivityPubConfig, msg_ref: &str) -> String {
+    let msg_uri: String =
+        form_urlencoded::byte_serialize(atom::to_uri(msg_ref).as_bytes()).collect();
+    format!("{}/ap/note?id={}", base_url(config), msg_uri)
+}
+
+/// Respond to a WebFinger query for the given resource, if it is the
+/// address of the actor.
+pub fn webfinger(config: &ActivityPubConfig, resource: &str) -> Option<Value> {
+    let acct = format!("acct:{}@{}", config.user, config.domain);
+    if resource != acct {
+        return None;
+    }
+
+    Some(json!({
+        "subject": acct,
+        "links": [{ "rel": "self", "type": CONTENT_TYPE, "href": actor_url(config) }],
+    }))
+}
+
+/// Return the actor of the local identity, named after the latest name it
+/// gave itself.
+pub fn actor(config: &ActivityPubConfig, db: &KvStorage, local_id: &str) -> Result<Value> {
+    let name = atom::latest_posts(db, local_id)?.and_then(|feed_posts| feed_posts.name);
+    let actor_url = actor_url(config);
+
+    Ok(json!({
+        "@context": ["https://www.w3.org/ns/activitystreams", "https://w3id.org/security/v1"],
+        "id": actor_url,
+        "type": "Person",
+        "preferredUsername": config.user,
+        "name": name.unwrap_or_else(|| config.user.clone()),
+        "summary": format!("Scuttlebutt feed {local_id}"),
+        "inbox": format!("{}/ap/inbox", base_url(config)),
+        "outbox": format!("{}/ap/outbox", base_url(config)),
+        "publicKey": {
+            "id": format!("{actor_url}#main-key"),
+            "owner": actor_url,
+            "publicKeyPem": public_key_pem(local_id),
+        },
+    }))
+}
+
+/// Return the outbox of the actor, holding the creation of its latest
+/// `MAX_ENTRIES` public posts.
+pub fn outbox(config: &ActivityPubConfig, db: &KvStorage, local_id: &str) -> Result<Value> {
+    let posts =
+        atom::latest_posts(db, local_id)?.map_or_else(Vec::new, |feed_posts| feed_posts.posts);
+    let activities: Vec<Value> = posts
+        .iter()
+        .map(|post| {
+            let note = render_note(
+                config,
+                &post.key,
+                &post.value["content"],
+                atom::timestamp(post),
+            );
+            json!({
+                "id": format!("{}#create", note["id"].as_str().unwrap_or_default()),
+                "type": "Create",
+                "actor": actor_url(config),
+                "published": note["published"],
+                "to": [PUBLIC],
+                "object": note,
+            })
+        })
+        .collect();
+
+    Ok(json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": format!("{}/ap/outbox", base_url(config)),
+        "type": "OrderedCollection",
+        "summary": format!("Latest {MAX_ENTRIES} posts"),
+        "totalItems": activities.len(),
+        "orderedItems": activities,
+    }))
+}
+
+/// Return the note of the given public post of the local identity, if it
+/// is stored.
+pub fn note(
+    config: &ActivityPubConfig,
+    db: &KvStorage,
+    local_id: &str,
+    msg_ref: &str,
+) -> Result<Option<Value>> {
+    let msg = match db.get_msg_val(msg_ref)? {
+        Some(msg)
+            if msg.author() == local_id
+                && msg.content()["type"] == "post"
+                && msg.content()["text"].is_string() =>
+        {
+            msg
+        }
+        _ => return Ok(None),
+    };
+
+    let mut note = render_note(config, msg_ref, msg.content(), msg.timestamp());
+    note["@context"] = json!("https://www.w3.org/ns/activitystreams");
+
+    Ok(Some(note))
+}
+
+/// Render a post as a note, with its text converted from markdown to HTML.
+fn render_note(
+    config: &ActivityPubConfig,
+    msg_ref: &str,
+    content: &Value,
+    timestamp: f64,
+) -> Value {
+    let text = content["text"].as_str().unwrap_or("");
+
+    json!({
+        "id": note_url(config, msg_ref),
+        "type": "Note",
+        "attributedTo": actor_url(config),
+        "content": atom::markdown_to_html(text, &base_url(config)),
+        "published": atom::rfc3339(timestamp),
+        "to": [PUBLIC],
+        "url": atom::to_uri(msg_ref),
+    })
+}
+
+/// Encode the ed25519 key of the given feed ID as a PEM public key.
+fn public_key_pem(feed_id: &str) -> String {
+    let key = feed_id
+        .strip_prefix('@')
+        .and_then(|key| key.strip_suffix(".ed25519"))
+        .and_then(|key| base64::decode(key).ok())
+        .unwrap_or_default();
+    let der = [&ED25519_SPKI_PREFIX[..], &key].concat();
+
+    format!(
+        "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+        base64::encode(der)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::testing::TestNode;
+
+    #[async_std::test]
+    async fn test_activitypub() -> Result<()> {
+        let node = TestNode::new()?;
+        let config = ActivityPubConfig {
+            domain: "solar.example.org".to_string(),
+            user: "solar".to_string(),
+        };
+
+        assert_eq!(webfinger(&config, "acct:other@solar.example.org"), None);
+        let webfinger = webfinger(&config, "acct:solar@solar.example.org").unwrap();
+        assert_eq!(
+            webfinger["links"][0]["href"],
+            "https://solar.example.org/ap/actor"
+        );
+
+        node.publish(json!({ "type": "about", "about": node.id.id, "name": "sol" }))
+            .await?;
+        let post = node
+            .publish(json!({ "type": "post", "text": "![sun](&kZpHNwxz0bZTkm7sEzlO9HAXGWLfTnoHP8tiL1EeIWI=.sha256)" }))
+            .await?;
+        let msg_ref = post.id().to_string();
+
+        let actor = actor(&config, &node.kv, &node.id.id)?;
+        assert_eq!(actor["name"], "sol");
+        // The PEM encoding of the key is 60 base64 characters long.
+        let pem = actor["publicKey"]["publicKeyPem"].as_str().unwrap();
+        assert_eq!(pem.lines().nth(1).map(str::len), Some(60));
+
+        let outbox = outbox(&config, &node.kv, &node.id.id)?;
+        assert_eq!(outbox["totalItems"], 1);
+        let note = &outbox["orderedItems"][0]["object"];
+        assert_eq!(note["id"], note_url(&config, &msg_ref));
+        assert!(note["content"].as_str().unwrap().contains(
+            "src=\"https://solar.example.org/blob?id=%26kZpHNwxz0bZTkm7sEzlO9HAXGWLfTnoHP8tiL1EeIWI%3D.sha256\""
+        ));
+
+        assert_eq!(
+            super::note(&config, &node.kv, &node.id.id, &msg_ref)?.unwrap()["id"],
+            note["id"]
+        );
+        assert_eq!(super::note(&config, &node.kv, &node.id.id, "%x")?, None);
+
+        Ok(())
+    }
+}
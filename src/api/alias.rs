@@ -0,0 +1,282 @@
+//! Resolution of room aliases to the feed which registered them, following
+//! the rooms 2.0 alias specification.
+//!
+//! The ID of a room is pinned the first time an alias is looked up on it, and
+//! later lookups on the room must return the same ID. Lookups go over plain
+//! HTTP, so this guards against a man in the middle swapping the room.
+
+use std::time::Duration;
+
+use async_std::{
+    io::{self, ReadExt, WriteExt},
+    net::TcpStream,
+};
+use kuska_ssb::crypto::{ed25519, ToSodiumObject};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{error::Error, storage::kv::KvStorage, Result};
+
+/// Time allowed for the alias lookup request to complete.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A verified alias registration.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ResolvedAlias {
+    pub alias: String,
+    /// ID of the feed which registered the alias.
+    pub feed_id: String,
+    /// ID of the room on which the alias is registered.
+    pub room_id: String,
+    /// Address at which the room (and through it the feed) can be reached.
+    pub multiserver_address: String,
+}
+
+/// An alias registration, as returned by the room or embedded in a
+/// `consume-alias` SSB URI.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AliasRegistration {
+    alias: String,
+    user_id: String,
+    room_id: String,
+    multiserver_address: String,
+    signature: String,
+}
+
+impl AliasRegistration {
+    /// Check the signature of the registration by the feed which claims the
+    /// alias.
+    fn verify(self) -> Result<ResolvedAlias> {
+        let invalid = || Error::Alias(format!("invalid registration of alias {}", self.alias));
+
+        let public_key = self
+            .user_id
+            .strip_prefix('@')
+            .and_then(|user_id| user_id.to_ed25519_pk().ok())
+            .ok_or_else(invalid)?;
+        let signature = self
+            .signature
+            .to_ed25519_signature()
+            .map_err(|_| invalid())?;
+        let signed = format!(
+            "=room-alias-registration:{}:{}:{}",
+            self.room_id, self.user_id, self.alias
+        );
+        if !ed25519::verify_detached(&signature, signed.as_bytes(), &public_key) {
+            return Err(invalid());
+        }
+
+        Ok(ResolvedAlias {
+            alias: self.alias,
+            feed_id: self.user_id,
+            room_id: self.room_id,
+            multiserver_address: self.multiserver_address,
+        })
+    }
+}
+
+/// Resolve an alias given either as its URL (e.g. `http://alice.room.example`)
+/// or as the `consume-alias` SSB URI to which the alias page links, and
+/// verify its registration.
+///
+/// Only plain HTTP lookups are supported, since no TLS client is available;
+/// for rooms served over HTTPS, pass the `consume-alias` URI instead.
+pub async fn resolve(alias_uri: &str) -> Result<ResolvedAlias> {
+    let url = Url::parse(alias_uri).map_err(|err| Error::Alias(err.to_string()))?;
+
+    let registration = match url.scheme() {
+        "ssb" => parse_consume_uri(&url)?,
+        "http" => lookup(&url).await?,
+        "https" => {
+            return Err(Error::Alias(
+                "https alias lookups are not supported; pass the consume-alias SSB URI instead"
+                    .to_string(),
+            ))
+        }
+        scheme => return Err(Error::Alias(format!("unsupported alias scheme {scheme}"))),
+    };
+
+    registration.verify()
+}
+
+/// Resolve an alias like [`resolve`], checking the ID of the room against
+/// the one pinned for it when the alias is looked up on the room. The ID
+/// returned by the first lookup on a room is pinned at `now`. A lookup
+/// returning another ID fails until the pinned key is forgotten, since the
+/// response was tampered with or the room changed its key.
+pub async fn resolve_pinned(db: &KvStorage, alias_uri: &str, now: u64) -> Result<ResolvedAlias> {
+    let resolved = resolve(alias_uri).await?;
+    if let Some(host) = room_host(alias_uri) {
+        check_room_key(db, &host, &resolved.room_id, now)?;
+    }
+
+    Ok(resolved)
+}
+
+/// Return the host name of the room on which the alias at the given URL is
+/// looked up, i.e. the host name of the alias without its first label.
+fn room_host(alias_uri: &str) -> Option<String> {
+    let url = Url::parse(alias_uri).ok()?;
+    if url.scheme() != "http" {
+        return None;
+    }
+
+    url.host_str()?
+        .split_once('.')
+        .map(|(_, room)| room.to_string())
+}
+
+/// Check the ID presented by the room at `host` against the pinned one,
+/// pinning it if none is.
+fn check_room_key(db: &KvStorage, host: &str, room_id: &str, now: u64) -> Result<()> {
+    let pinned = db.room_keys().pin(host, room_id, now)?;
+    if pinned.room_id != room_id {
+        warn!(
+            "room {} presented the ID {} instead of the pinned {}",
+            host, room_id, pinned.room_id
+        );
+        return Err(Error::Alias(format!(
+            "room {host} presented the ID {room_id} instead of the pinned {}; \
+             forget the pinned key with forgetRoomKey if the room changed its key",
+            pinned.room_id
+        )));
+    }
+
+    Ok(())
+}
+
+/// Parse the alias registration held in the query of a `consume-alias` URI.
+fn parse_consume_uri(url: &Url) -> Result<AliasRegistration> {
+    let param = |name: &str| {
+        url.query_pairs()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.to_string())
+            .ok_or_else(|| Error::Alias(format!("alias uri is missing the {name} parameter")))
+    };
+
+    if url.path() != "experimental" || param("action")? != "consume-alias" {
+        return Err(Error::Alias("not a consume-alias uri".to_string()));
+    }
+
+    Ok(AliasRegistration {
+        alias: param("alias")?,
+        user_id: param("userId")?,
+        room_id: param("roomId")?,
+        multiserver_address: param("multiserverAddress")?,
+        signature: param("signature")?,
+    })
+}
+
+/// Request the JSON encoding of the alias page from the room. The alias is
+/// the first label of the host name.
+async fn lookup(url: &Url) -> Result<AliasRegistration> {
+    let host = url
+        .host_str()
+        .ok_or_else(|| Error::Alias("alias url is missing a host".to_string()))?;
+    let port = url.port_or_known_default().unwrap_or(80);
+
+    let request = format!(
+        "GET {}?encoding=json HTTP/1.0\r\nHost: {host}\r\nAccept: application/json\r\n\r\n",
+        url.path()
+    );
+    let response = io::timeout(LOOKUP_TIMEOUT, async {
+        let mut stream = TcpStream::connect((host, port)).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok(response)
+    })
+    .await
+    .map_err(|err| Error::Alias(format!("alias lookup failed: {err}")))?;
+
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| Error::Alias("malformed alias lookup response".to_string()))?;
+    let status_line = head.lines().next().unwrap_or_default();
+    if status_line.split_whitespace().nth(1) != Some("200") {
+        return Err(Error::Alias(format!("alias lookup failed: {status_line}")));
+    }
+
+    let registration: AliasRegistration = serde_json::from_str(body)
+        .map_err(|_| Error::Alias("the room did not return an alias registration".to_string()))?;
+    if host.split('.').next() != Some(registration.alias.as_str()) {
+        return Err(Error::Alias(format!(
+            "the room returned a registration for alias {}",
+            registration.alias
+        )));
+    }
+
+    Ok(registration)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::{config::SecretConfig, testing::TestNode};
+
+    #[async_std::test]
+    async fn test_resolve_consume_uri() -> Result<()> {
+        let user = SecretConfig::create().owned_identity()?;
+        let room_id = "@51w4nYL0k7mRzDGw20KQqCjt35y8qLiopQ1JsOzO0bE=.ed25519";
+        let signed = format!("=room-alias-registration:{room_id}:{}:alice", user.id);
+        let signature = format!(
+            "{}.sig.ed25519",
+            base64::encode(ed25519::sign_detached(signed.as_bytes(), &user.sk).0)
+        );
+
+        let mut url = Url::parse("ssb:experimental").unwrap();
+        url.query_pairs_mut()
+            .append_pair("action", "consume-alias")
+            .append_pair("alias", "alice")
+            .append_pair("userId", &user.id)
+            .append_pair("roomId", room_id)
+            .append_pair("multiserverAddress", "net:room.example:8008~shs:room")
+            .append_pair("signature", &signature);
+
+        let resolved = resolve(url.as_str()).await?;
+        assert_eq!(resolved.feed_id, user.id);
+        assert_eq!(resolved.alias, "alice");
+        assert_eq!(
+            resolved.multiserver_address,
+            "net:room.example:8008~shs:room"
+        );
+
+        // A registration signed for another alias is rejected.
+        let forged = url.as_str().replace("alias=alice", "alias=mallory");
+        assert!(resolve(&forged).await.is_err());
+        assert!(resolve("https://alice.room.example").await.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_room_keys() -> Result<()> {
+        let node = TestNode::new()?;
+        let room_id = "@51w4nYL0k7mRzDGw20KQqCjt35y8qLiopQ1JsOzO0bE=.ed25519";
+        let other_id = "@o8lWpyLeSqV/BJV9pbxFhKpwm6Lw5k+sqexYK+zT9Tc=.ed25519";
+
+        assert_eq!(
+            room_host("http://alice.room.example/").as_deref(),
+            Some("room.example")
+        );
+        assert_eq!(room_host("ssb:experimental?action=consume-alias"), None);
+
+        // The first ID presented by a room is pinned.
+        check_room_key(&node.kv, "room.example", room_id, 1000)?;
+        check_room_key(&node.kv, "room.example", room_id, 2000)?;
+        assert!(check_room_key(&node.kv, "room.example", other_id, 3000).is_err());
+        let pinned = node.kv.room_keys().get("room.example")?.unwrap();
+        assert_eq!((pinned.room_id.as_str(), pinned.pinned_at), (room_id, 1000));
+
+        // Once forgotten, the next ID presented is pinned.
+        assert!(node.kv.room_keys().forget("room.example")?);
+        check_room_key(&node.kv, "room.example", other_id, 4000)?;
+        assert_eq!(node.kv.room_keys().get_all()?.len(), 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+//! Atom rendering of public feeds.
+//!
+//! The latest posts of a feed are rendered as an Atom document, so that
+//! people without an SSB client can follow an author with a feed reader.
+//! Post text is converted from markdown to HTML, with links to blobs
+//! pointing at the `/blob` endpoint of the HTTP server.
+
+use kuska_ssb::feed::Feed as MessageKvt;
+use pulldown_cmark::{html, CowStr, Event, Parser, Tag};
+use url::form_urlencoded;
+
+use crate::{storage::kv::KvStorage, uri, Result};
+
+/// Number of posts included in a rendered feed.
+pub const MAX_ENTRIES: usize = 20;
+
+/// Number of characters of the first line of a post used as its title.
+const TITLE_LEN: usize = 80;
+
+/// The latest public posts of a feed.
+pub struct FeedPosts {
+    /// Latest name the author of the feed gave itself.
+    pub name: Option<String>,
+    /// Up to `MAX_ENTRIES` posts, newest first.
+    pub posts: Vec<MessageKvt>,
+}
+
+/// Return the latest public posts of the given feed. Returns `None` if no
+/// message of the feed is stored or the feed is muted.
+pub fn latest_posts(db: &KvStorage, feed_id: &str) -> Result<Option<FeedPosts>> {
+    let latest_seq = match db.get_latest_seq(feed_id)? {
+        Some(latest_seq) if !db.mutes().is_muted(feed_id)? => latest_seq,
+        _ => return Ok(None),
+    };
+
+    let mut name = None;
+    let mut posts = Vec::new();
+    for seq in (1..=latest_seq).rev() {
+        if posts.len() == MAX_ENTRIES && name.is_some() {
+            break;
+        }
+        let msg_kvt = match db.get_msg_kvt(feed_id, seq)? {
+            Some(msg_kvt) => msg_kvt,
+            None => continue,
+        };
+        // Private messages have encrypted content, and so no type.
+        let content = &msg_kvt.value["content"];
+        match (content["type"].as_str(), content["text"].as_str()) {
+            (Some("post"), Some(_)) if posts.len() < MAX_ENTRIES => posts.push(msg_kvt),
+            (Some("about"), _) if name.is_none() && content["about"] == feed_id => {
+                name = content["name"].as_str().map(str::to_string);
+            }
+            _ => (),
+        }
+    }
+
+    Ok(Some(FeedPosts { name, posts }))
+}
+
+/// The details a feed published about itself in its latest `about`
+/// messages.
+#[cfg(any(feature = "graphql", feature = "viewer"))]
+#[derive(Debug, Default)]
+pub struct Profile {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub image: Option<String>,
+}
+
+/// Return the details the given feed published about itself. If
+/// `name_only` is set, the search stops once the name is found.
+#[cfg(any(feature = "graphql", feature = "viewer"))]
+pub fn profile(db: &KvStorage, feed_id: &str, name_only: bool) -> Result<Profile> {
+    let mut profile = Profile::default();
+    let latest_seq = db.get_latest_seq(feed_id)?.unwrap_or(0);
+
+    for seq in (1..=latest_seq).rev() {
+        if profile.name.is_some()
+            && (name_only || (profile.description.is_some() && profile.image.is_some()))
+        {
+            break;
+        }
+        let msg_kvt = match db.get_msg_kvt(feed_id, seq)? {
+            Some(msg_kvt) => msg_kvt,
+            None => continue,
+        };
+        let content = &msg_kvt.value["content"];
+        if content["type"] != "about" || content["about"] != feed_id {
+            continue;
+        }
+        if profile.name.is_none() {
+            profile.name = content["name"].as_str().map(str::to_string);
+        }
+        if profile.description.is_none() {
+            profile.description = content["description"].as_str().map(str::to_string);
+        }
+        if profile.image.is_none() {
+            // The image is either a blob ID or a link object.
+            let image = &content["image"];
+            profile.image = image
+                .as_str()
+                .or_else(|| image["link"].as_str())
+                .map(str::to_string);
+        }
+    }
+
+    Ok(profile)
+}
+
+/// Render the latest public posts of the given feed as an Atom document.
+/// The feed is named after the latest name its author gave itself. Returns
+/// `None` if no message of the feed is stored or the feed is muted.
+pub fn render_feed(db: &KvStorage, feed_id: &str) -> Result<Option<String>> {
+    let FeedPosts { name, posts } = match latest_posts(db, feed_id)? {
+        Some(feed_posts) => feed_posts,
+        None => return Ok(None),
+    };
+
+    let name = name.unwrap_or_else(|| feed_id.to_string());
+    let updated = posts.first().map_or(0.0, timestamp);
+    let mut feed = String::from("<?xml version=\"1.0\" encoding=\"utf-8\"?>\n");
+    feed.push_str("<feed xmlns=\"http://www.w3.org/2005/Atom\">\n");
+    feed.push_str(&format!("<id>{}</id>\n", escape(&to_uri(feed_id))));
+    feed.push_str(&format!("<title>{}</title>\n", escape(&name)));
+    feed.push_str(&format!(
+        "<author><name>{}</name></author>\n",
+        escape(&name)
+    ));
+    feed.push_str(&format!("<updated>{}</updated>\n", rfc3339(updated)));
+    for post in &posts {
+        feed.push_str(&render_entry(post));
+    }
+    feed.push_str("</feed>\n");
+
+    Ok(Some(feed))
+}
+
+/// The timestamp of a message, in milliseconds since the Unix epoch.
+pub fn timestamp(msg_kvt: &MessageKvt) -> f64 {
+    msg_kvt.value["timestamp"].as_f64().unwrap_or(0.0)
+}
+
+/// The text of a post.
+fn post_text(msg_kvt: &MessageKvt) -> &str {
+    msg_kvt.value["content"]["text"].as_str().unwrap_or("")
+}
+
+/// Render a post as an Atom entry, titled after its first line.
+fn render_entry(post: &MessageKvt) -> String {
+    let text = post_text(post);
+    let first_line = text.lines().map(str::trim).find(|line| !line.is_empty());
+    let mut title: String = first_line.unwrap_or("").chars().take(TITLE_LEN).collect();
+    if first_line.is_some_and(|line| line.chars().count() > TITLE_LEN) {
+        title.push('…');
+    }
+
+    format!(
+        "<entry>\n<id>{}</id>\n<title>{}</title>\n<updated>{}</updated>\n\
+         <content type=\"html\">{}</content>\n</entry>\n",
+        escape(&to_uri(&post.key)),
+        escape(&title),
+        rfc3339(timestamp(post)),
+        escape(&markdown_to_html(text, ""))
+    )
+}
+
+/// Convert markdown to HTML. Raw HTML is escaped rather than passed on,
+/// and links to blobs are rewritten to the `/blob` endpoint at `base_url`
+/// (e.g. `https://example.org`, or empty for relative links).
+pub fn markdown_to_html(text: &str, base_url: &str) -> String {
+    markdown_to_html_with(text, |dest| blob_url(dest, base_url))
+}
+
+/// Convert markdown to HTML, rewriting the target of each link and image
+/// with `link`. Raw HTML is escaped rather than passed on, and links and
+/// images whose rewritten target is not safe to follow (see `is_safe_link`)
+/// are reduced to their text.
+pub fn markdown_to_html_with<F>(text: &str, link: F) -> String
+where
+    F: for<'a> Fn(CowStr<'a>) -> CowStr<'a>,
+{
+    let events = Parser::new(text).filter_map(|event| match event {
+        Event::Start(Tag::Link(kind, dest, title)) => {
+            let dest = link(dest);
+            is_safe_link(&dest).then_some(Event::Start(Tag::Link(kind, dest, title)))
+        }
+        Event::Start(Tag::Image(kind, dest, title)) => {
+            let dest = link(dest);
+            is_safe_link(&dest).then_some(Event::Start(Tag::Image(kind, dest, title)))
+        }
+        // The end of a link or image is dropped along with its start.
+        Event::End(Tag::Link(kind, dest, title)) => {
+            let safe = is_safe_link(&link(dest.clone()));
+            safe.then_some(Event::End(Tag::Link(kind, dest, title)))
+        }
+        Event::End(Tag::Image(kind, dest, title)) => {
+            let safe = is_safe_link(&link(dest.clone()));
+            safe.then_some(Event::End(Tag::Image(kind, dest, title)))
+        }
+        Event::Html(html) => Some(Event::Text(html)),
+        event => Some(event),
+    });
+
+    let mut html = String::new();
+    html::push_html(&mut html, events);
+
+    html
+}
+
+/// Whether a link target may be rendered as a link: an `http`, `https` or
+/// `ssb` URL, or a path on the HTTP server, such as the rewritten links to
+/// blobs. Any other scheme, notably `javascript:`, could act on behalf of
+/// the local identity when followed from a page served by the node.
+pub fn is_safe_link(dest: &str) -> bool {
+    let dest = dest.to_ascii_lowercase();
+
+    dest.starts_with('/')
+        || ["http://", "https://", "ssb:"]
+            .iter()
+            .any(|scheme| dest.starts_with(scheme))
+}
+
+/// Return the URL of the `/blob` endpoint serving the given link target if
+/// it is a blob ID, or the link target otherwise.
+pub fn blob_url<'a>(dest: CowStr<'a>, base_url: &str) -> CowStr<'a> {
+    if !dest.starts_with('&') {
+        return dest;
+    }
+
+    let blob_id: String = form_urlencoded::byte_serialize(dest.as_bytes()).collect();
+    format!("{base_url}/blob?id={blob_id}").into()
+}
+
+/// The SSB URI of an ID, which unlike its sigil encoding is a valid IRI.
+pub fn to_uri(id: &str) -> String {
+    uri::from_sigil(id).unwrap_or_else(|| id.to_string())
+}
+
+/// Escape text for inclusion in an XML or HTML document.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Format a timestamp in milliseconds since the Unix epoch as an RFC 3339
+/// date in UTC.
+pub fn rfc3339(timestamp: f64) -> String {
+    let secs = (timestamp / 1000.0).max(0.0) as u64;
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // Convert days since the epoch to a civil date, counting in eras of 400
+    // years starting on the 1st of March of year 0.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_from_march = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_from_march + 2) / 5 + 1;
+    let month = if month_from_march < 10 {
+        month_from_march + 3
+    } else {
+        month_from_march - 9
+    };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::testing::TestNode;
+
+    #[test]
+    fn test_rfc3339() {
+        assert_eq!(rfc3339(0.0), "1970-01-01T00:00:00Z");
+        assert_eq!(rfc3339(951_782_400_000.0), "2000-02-29T00:00:00Z");
+        assert_eq!(rfc3339(1_700_000_000_123.0), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn test_unsafe_links() {
+        let html = |text| markdown_to_html(text, "");
+
+        // Links with other schemes are reduced to their text.
+        assert_eq!(html("[x](javascript:alert(1))"), "<p>x</p>\n");
+        assert_eq!(html("[x](JavaScript:alert(1))"), "<p>x</p>\n");
+        assert_eq!(html("[x]( javascript:alert(1))"), "<p>x</p>\n");
+        assert_eq!(
+            html("<javascript:alert(1)>"),
+            "<p>javascript:alert(1)</p>\n"
+        );
+        assert_eq!(html("![cat](data:text/html,x)"), "<p>cat</p>\n");
+        assert_eq!(html("[x](%msg.sha256)"), "<p>x</p>\n");
+
+        assert_eq!(
+            html("[x](https://example.org)"),
+            "<p><a href=\"https://example.org\">x</a></p>\n"
+        );
+        assert!(html("[x](ssb:message/sha256/abc)").contains("href=\"ssb:message"));
+        assert!(html("![cat](&abc.sha256)").contains("src=\"/blob?id="));
+    }
+
+    #[async_std::test]
+    async fn test_render_feed() -> Result<()> {
+        let node = TestNode::new()?;
+        assert_eq!(render_feed(&node.kv, &node.id.id)?, None);
+
+        node.publish(json!({ "type": "about", "about": node.id.id, "name": "a&b" }))
+            .await?;
+        node.publish(json!({ "type": "vote", "vote": { "link": "%x", "value": 1 } }))
+            .await?;
+        let post = node
+            .publish(json!({
+                "type": "post",
+                "text": "# Hello <world>\n\n![cat](&kZpHNwxz0bZTkm7sEzlO9HAXGWLfTnoHP8tiL1EeIWI=.sha256)",
+            }))
+            .await?;
+
+        let feed = render_feed(&node.kv, &node.id.id)?.unwrap();
+        assert!(feed.contains("<title>a&amp;b</title>"));
+        assert_eq!(feed.matches("<entry>").count(), 1);
+        assert!(feed.contains(&format!("<id>{}</id>", to_uri(&post.id().to_string()))));
+        assert!(feed.contains("<title># Hello &lt;world&gt;</title>"));
+        // Raw HTML is escaped before the HTML content is escaped in turn.
+        assert!(feed.contains("&lt;h1&gt;Hello &amp;lt;world&amp;gt;&lt;/h1&gt;"));
+        assert!(feed.contains(
+            "src=&quot;/blob?id=%26kZpHNwxz0bZTkm7sEzlO9HAXGWLfTnoHP8tiL1EeIWI%3D.sha256&quot;"
+        ));
+
+        Ok(())
+    }
+}
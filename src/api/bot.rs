@@ -0,0 +1,251 @@
+//! Commands for bots run on the solar node.
+//!
+//! When the bot actor is enabled, posts mentioning the local identity are
+//! scanned for a command: the first word starting with the configured
+//! prefix (e.g. `!ping` for the prefix `!`), followed by its arguments.
+//! Handlers are registered per command name in [`BOT_COMMANDS`] and return
+//! the content to publish in response, usually built with
+//! [`Command::reply`]. A `help` command listing the registered commands is
+//! answered unless a handler is registered for it, and the bot actor
+//! registers [`Ping`] as `ping`.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use kuska_ssb::feed::Feed;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::{
+    api::content::{Mention, Post},
+    Result,
+};
+
+/// The bot commands of the solar node.
+pub static BOT_COMMANDS: Lazy<CommandRegistry> = Lazy::new(CommandRegistry::default);
+
+/// A command addressed to the local identity.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    /// Name of the command, without the prefix.
+    pub name: String,
+    /// Words following the command name.
+    pub args: Vec<String>,
+    /// ID of the feed which sent the command.
+    pub author: String,
+    /// Key of the message holding the command.
+    pub msg_ref: String,
+    /// Key of the first message of the thread holding the command.
+    pub root: String,
+}
+
+impl Command {
+    /// Parse the command of a post which mentions `local_id`, if any. The
+    /// command is the first word of the text starting with `prefix`.
+    pub fn parse(prefix: &str, local_id: &str, msg_kvt: &Feed) -> Option<Self> {
+        let content = &msg_kvt.value["content"];
+        if content["type"].as_str() != Some("post")
+            || !content["mentions"].as_array().is_some_and(|mentions| {
+                mentions
+                    .iter()
+                    .any(|mention| mention["link"].as_str() == Some(local_id))
+            })
+        {
+            return None;
+        }
+
+        let mut words = content["text"]
+            .as_str()?
+            .split_whitespace()
+            .skip_while(|word| !word.starts_with(prefix));
+        let name = words.next()?.strip_prefix(prefix)?;
+        if name.is_empty() {
+            return None;
+        }
+
+        Some(Self {
+            name: name.to_string(),
+            args: words.map(str::to_string).collect(),
+            author: msg_kvt.value["author"].as_str()?.to_string(),
+            msg_ref: msg_kvt.key.clone(),
+            root: content["root"].as_str().unwrap_or(&msg_kvt.key).to_string(),
+        })
+    }
+
+    /// Build a post replying with `text` to the command, in the thread of
+    /// the command and mentioning its sender.
+    pub fn reply(&self, text: impl Into<String>) -> Result<Value> {
+        let mut post = Post::new(text).reply(&self.root, vec![self.msg_ref.clone()]);
+        post.mentions.push(Mention {
+            link: self.author.clone(),
+            name: None,
+        });
+
+        post.build()
+    }
+}
+
+/// Handler of a bot command.
+pub trait CommandHandler: Send + Sync {
+    /// Handle the command and return the content to publish in response,
+    /// if any.
+    fn handle(&self, command: &Command) -> Result<Option<Value>>;
+
+    /// One-line description of the command, listed by `help`.
+    fn description(&self) -> String {
+        String::new()
+    }
+}
+
+/// Replies `pong`, to check that the bot is running.
+pub struct Ping;
+
+impl CommandHandler for Ping {
+    fn handle(&self, command: &Command) -> Result<Option<Value>> {
+        command.reply("pong").map(Some)
+    }
+
+    fn description(&self) -> String {
+        "check that the bot is running".to_string()
+    }
+}
+
+/// Handlers registered per command name.
+#[derive(Default)]
+pub struct CommandRegistry {
+    handlers: RwLock<BTreeMap<String, Arc<dyn CommandHandler>>>,
+}
+
+impl CommandRegistry {
+    /// Register the handler of the command with the given name, replacing
+    /// any handler registered before.
+    pub fn register(&self, name: &str, handler: Arc<dyn CommandHandler>) {
+        self.handlers
+            .write()
+            .unwrap()
+            .insert(name.to_string(), handler);
+    }
+
+    /// Run the handler registered for the command and return the content
+    /// to publish in response, if any. Commands without a handler are
+    /// ignored.
+    pub fn dispatch(&self, command: &Command) -> Result<Option<Value>> {
+        let handler = self.handlers.read().unwrap().get(&command.name).cloned();
+        match handler {
+            Some(handler) => handler.handle(command),
+            None if command.name == "help" => command.reply(self.help()).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// List the registered commands and their descriptions.
+    fn help(&self) -> String {
+        let handlers = self.handlers.read().unwrap();
+        if handlers.is_empty() {
+            return "No commands are available.".to_string();
+        }
+
+        handlers
+            .iter()
+            .map(|(name, handler)| match handler.description() {
+                description if description.is_empty() => format!("- {name}"),
+                description => format!("- {name}: {description}"),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    const LOCAL_ID: &str = "@HEqy940T6uB+T+d9Jaa58aNfRzLx9eRWqkZljBmnkmk=.ed25519";
+    const AUTHOR_ID: &str = "@o8lWpyLeSqV/BJV9pbxFhKpwm6Lw5k+sqexYK+zT9Tc=.ed25519";
+    const ROOT_REF: &str = "%Z1tVZiSJNHMPdXbr8fvsTbmxjhzEEz6eIqg6dc9gXLY=.sha256";
+    const MSG_REF: &str = "%R7lJEkz27lNijPhYNDzYoPjM0Fp+bFWzwX0SmNJB/ZE=.sha256";
+
+    /// Replies with its arguments.
+    struct Echo;
+
+    impl CommandHandler for Echo {
+        fn handle(&self, command: &Command) -> Result<Option<Value>> {
+            command.reply(command.args.join(" ")).map(Some)
+        }
+
+        fn description(&self) -> String {
+            "repeat the given words".to_string()
+        }
+    }
+
+    fn post(content: Value) -> Feed {
+        serde_json::from_value(json!({
+            "key": MSG_REF,
+            "value": { "author": AUTHOR_ID, "content": content },
+            "timestamp": 0.0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_bot_commands() {
+        let mention = json!([{ "link": LOCAL_ID, "name": "solar" }]);
+        let msg = post(json!({
+            "type": "post",
+            "text": "[@solar](@HEqy...) !echo hello  world",
+            "root": ROOT_REF,
+            "mentions": mention,
+        }));
+        let command = Command::parse("!", LOCAL_ID, &msg).unwrap();
+        assert_eq!(
+            command,
+            Command {
+                name: "echo".to_string(),
+                args: vec!["hello".to_string(), "world".to_string()],
+                author: AUTHOR_ID.to_string(),
+                msg_ref: MSG_REF.to_string(),
+                root: ROOT_REF.to_string(),
+            }
+        );
+
+        // Commands must be prefixed, non-empty and addressed to the local
+        // identity.
+        let unprefixed = json!({ "type": "post", "text": "echo", "mentions": mention });
+        assert_eq!(Command::parse("!", LOCAL_ID, &post(unprefixed)), None);
+        let empty = json!({ "type": "post", "text": "! echo", "mentions": mention });
+        assert_eq!(Command::parse("!", LOCAL_ID, &post(empty)), None);
+        let unmentioned = json!({ "type": "post", "text": "!echo" });
+        assert_eq!(Command::parse("!", LOCAL_ID, &post(unmentioned)), None);
+
+        let registry = CommandRegistry::default();
+        let help = json!({ "type": "post", "text": "!help", "mentions": mention });
+        let help = Command::parse("!", LOCAL_ID, &post(help)).unwrap();
+        assert_eq!(help.root, MSG_REF);
+        assert_eq!(
+            registry.dispatch(&help).unwrap().unwrap()["text"],
+            "No commands are available."
+        );
+        assert_eq!(registry.dispatch(&command).unwrap(), None);
+
+        registry.register("echo", Arc::new(Echo));
+        let reply = registry.dispatch(&command).unwrap().unwrap();
+        assert_eq!(
+            reply,
+            json!({
+                "type": "post",
+                "text": "hello world",
+                "root": ROOT_REF,
+                "branch": MSG_REF,
+                "mentions": [{ "link": AUTHOR_ID }],
+            })
+        );
+        assert_eq!(
+            registry.dispatch(&help).unwrap().unwrap()["text"],
+            "- echo: repeat the given words"
+        );
+    }
+}
@@ -0,0 +1,274 @@
+//! Typed message content builders.
+//!
+//! Content built here is checked against the SSB conventions for the shape
+//! of each field before it is published, so that clients can't produce
+//! messages which other implementations fail to interpret.
+
+use kuska_ssb::api::dto::content::TypedMessage;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::{api::schema::SCHEMAS, error::Error, private_box::MAX_RECIPIENTS, Result};
+
+/// A link to a feed, message, blob or channel mentioned in a post.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mention {
+    pub link: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+/// Content of a `post` message.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Post {
+    pub text: String,
+    /// Message ID of the first message of the thread.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub root: Option<String>,
+    /// Message IDs of the latest messages of the thread being replied to.
+    /// Serialized as a single ID if there is only one.
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "serialize_one_or_many",
+        deserialize_with = "deserialize_one_or_many"
+    )]
+    pub branch: Vec<String>,
+    /// Channel name, without the leading `#`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channel: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub mentions: Vec<Mention>,
+    /// Feed IDs of the recipients of a private post.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recps: Vec<String>,
+    /// Warning shown in place of the text until the reader chooses to
+    /// reveal it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content_warning: Option<String>,
+}
+
+impl Post {
+    /// Create a public post with the given text.
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Make the post a reply in the thread starting at `root`, following
+    /// the given messages of the thread.
+    pub fn reply(mut self, root: impl Into<String>, branch: Vec<String>) -> Self {
+        self.root = Some(root.into());
+        self.branch = branch;
+        self
+    }
+
+    /// Address the post to the given recipients.
+    pub fn recps(mut self, recps: Vec<String>) -> Self {
+        self.recps = recps;
+        self
+    }
+
+    /// Hide the text of the post behind the given warning.
+    pub fn content_warning(mut self, content_warning: Option<String>) -> Self {
+        self.content_warning = content_warning;
+        self
+    }
+
+    /// Check the shape of each field.
+    pub fn validate(&self) -> Result<()> {
+        if let Some(root) = &self.root {
+            if !is_msg_ref(root) {
+                return Err(invalid(format!("root is not a message id: {root}")));
+            }
+        }
+        if !self.branch.is_empty() && self.root.is_none() {
+            return Err(invalid("branch is set without a root"));
+        }
+        if let Some(branch) = self.branch.iter().find(|branch| !is_msg_ref(branch)) {
+            return Err(invalid(format!("branch is not a message id: {branch}")));
+        }
+        if let Some(channel) = &self.channel {
+            if !is_channel(channel) {
+                return Err(invalid(format!("invalid channel name: {channel}")));
+            }
+        }
+        for mention in &self.mentions {
+            let link = &mention.link;
+            let valid = match link.strip_prefix('#') {
+                Some(channel) => is_channel(channel),
+                None => is_feed_ref(link) || is_msg_ref(link) || is_blob_ref(link),
+            };
+            if !valid {
+                return Err(invalid(format!("invalid mention link: {link}")));
+            }
+        }
+        if self.recps.len() > MAX_RECIPIENTS {
+            return Err(invalid(format!(
+                "a private post must have at most {MAX_RECIPIENTS} recipients"
+            )));
+        }
+        if let Some(recp) = self.recps.iter().find(|recp| !is_feed_ref(recp)) {
+            return Err(invalid(format!("recipient is not a feed id: {recp}")));
+        }
+        if self
+            .content_warning
+            .as_ref()
+            .is_some_and(|warning| warning.trim().is_empty())
+        {
+            return Err(invalid("content warning is empty"));
+        }
+
+        Ok(())
+    }
+
+    /// Validate the post and return it as message content.
+    pub fn build(&self) -> Result<Value> {
+        self.validate()?;
+
+        let mut content = serde_json::to_value(self)?;
+        content["type"] = Value::String("post".to_string());
+
+        Ok(content)
+    }
+}
+
+/// Check content submitted for publishing on the public feed and return it
+/// in its normalized form. Content must satisfy the schemas registered for
+/// its type. Posts are also checked field by field; other message types are
+/// parsed as typed messages.
+pub fn check_public(content: Value) -> Result<Value> {
+    if let Some(reason) = SCHEMAS.violation(&content) {
+        return Err(invalid(reason));
+    }
+
+    if content["type"] != "post" {
+        return Ok(serde_json::to_value(
+            serde_json::from_value::<TypedMessage>(content)?,
+        )?);
+    }
+
+    let post: Post = serde_json::from_value(content)?;
+    if !post.recps.is_empty() {
+        return Err(invalid(
+            "posts with recipients must be published as private messages",
+        ));
+    }
+
+    post.build()
+}
+
+fn invalid(reason: impl Into<String>) -> Error {
+    Error::InvalidContent(reason.into())
+}
+
+/// Check that `id` consists of the given sigil, a base64 encoded key or
+/// hash and the given suffix.
+fn is_ref(id: &str, sigil: char, suffix: &str) -> bool {
+    id.strip_prefix(sigil)
+        .and_then(|id| id.strip_suffix(suffix))
+        .and_then(|encoded| base64::decode(encoded).ok())
+        .is_some_and(|decoded| decoded.len() == 32)
+}
+
+fn is_feed_ref(id: &str) -> bool {
+    is_ref(id, '@', ".ed25519")
+}
+
+fn is_msg_ref(id: &str) -> bool {
+    is_ref(id, '%', ".sha256")
+}
+
+fn is_blob_ref(id: &str) -> bool {
+    is_ref(id, '&', ".sha256")
+}
+
+/// Channel names are non-empty and contain no whitespace or `#`.
+fn is_channel(name: &str) -> bool {
+    !name.is_empty() && !name.chars().any(|c| c.is_whitespace() || c == '#')
+}
+
+fn serialize_one_or_many<S: Serializer>(
+    ids: &[String],
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match ids {
+        [id] => id.serialize(serializer),
+        ids => ids.serialize(serializer),
+    }
+}
+
+fn deserialize_one_or_many<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(id) => vec![id],
+        OneOrMany::Many(ids) => ids,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::config::SecretConfig;
+
+    #[test]
+    fn test_build_post() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity()?;
+        let root = format!("%{}.sha256", base64::encode([1u8; 32]));
+
+        let content = Post::new("spoilers")
+            .reply(&root, vec![root.clone()])
+            .recps(vec![alice.id.clone()])
+            .content_warning(Some("film plot".to_string()))
+            .build()?;
+        assert_eq!(
+            content,
+            json!({
+                "type": "post",
+                "text": "spoilers",
+                "root": root,
+                "branch": root,
+                "recps": [alice.id],
+                "contentWarning": "film plot",
+            })
+        );
+
+        let post: Post = serde_json::from_value(json!({
+            "type": "post",
+            "text": "#solar",
+            "channel": "solar",
+            "mentions": [{ "link": "#solar" }, { "link": alice.id, "name": "alice" }],
+        }))?;
+        post.validate()?;
+
+        assert!(Post::new("").reply("%abc", vec![]).validate().is_err());
+        assert!(Post::new("")
+            .recps(vec!["alice".to_string()])
+            .validate()
+            .is_err());
+        let mut post = Post::new("");
+        post.channel = Some("#solar".to_string());
+        assert!(post.validate().is_err());
+
+        let contact = json!({ "type": "contact", "contact": alice.id, "following": true });
+        assert_eq!(check_public(contact)?["following"], true);
+        let private = json!({ "type": "post", "text": "psst", "recps": [alice.id] });
+        assert!(check_public(private).is_err());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,241 @@
+//! Export of public messages for research, with consent filtering.
+//!
+//! Only the public messages of authors who have consented are exported.
+//! An author consents by publishing a message of the consent type (e.g.
+//! `{ "type": "research-consent", "consent": true }`); their latest message
+//! of that type counts, so consent is withdrawn by publishing one with
+//! `"consent": false`. References to other feeds, and to messages of other
+//! feeds, are replaced with labels (`@anon-<n>` and `%anon-<n>`), so that
+//! interactions with them are kept without identifying them. Each label
+//! stands for the same ID throughout an export.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::Write,
+};
+
+use kuska_ssb::feed::Feed as MessageKvt;
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{storage::kv::KvStorage, Result};
+
+/// Number of authors read at a time.
+const AUTHORS_PAGE: usize = 1000;
+
+/// Sigil-encoded feed and message IDs.
+static ID_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"[@%][A-Za-z0-9+/]{43}=\.(?:ed25519|sha256)").unwrap());
+
+/// A message in an export.
+#[derive(Debug, Serialize)]
+pub struct DatasetMessage {
+    pub key: String,
+    pub author: String,
+    pub sequence: u64,
+    /// Timestamp claimed by the author, in milliseconds since the Unix
+    /// epoch.
+    pub timestamp: f64,
+    pub content: Value,
+}
+
+/// Labels standing for the IDs of the feeds which have not consented and of
+/// their messages.
+struct Anonymizer<'a> {
+    db: &'a KvStorage,
+    consenting: &'a BTreeSet<String>,
+    labels: HashMap<String, String>,
+}
+
+impl<'a> Anonymizer<'a> {
+    /// Return the label standing for the given ID, or the ID itself if it
+    /// refers to a consenting feed or to one of its messages.
+    fn label(&mut self, id: &str) -> Result<String> {
+        if let Some(label) = self.labels.get(id) {
+            return Ok(label.clone());
+        }
+        let author = match id.starts_with('@') {
+            true => Some(id.to_string()),
+            false => self
+                .db
+                .get_any_msg_val(id)?
+                .map(|msg| msg.author().to_string()),
+        };
+        if author.is_some_and(|author| self.consenting.contains(&author)) {
+            return Ok(id.to_string());
+        }
+
+        let label = format!("{}anon-{}", &id[..1], self.labels.len() + 1);
+        self.labels.insert(id.to_string(), label.clone());
+
+        Ok(label)
+    }
+
+    /// Replace the IDs which are not to be exported in the given content.
+    fn anonymize(&mut self, content: &mut Value) -> Result<()> {
+        match content {
+            Value::String(text) => {
+                let mut result = Ok(());
+                let replaced = ID_REGEX.replace_all(text, |captures: &Captures| {
+                    self.label(&captures[0]).unwrap_or_else(|err| {
+                        result = Err(err);
+                        String::new()
+                    })
+                });
+                *text = replaced.into_owned();
+                result
+            }
+            Value::Array(values) => values
+                .iter_mut()
+                .try_for_each(|value| self.anonymize(value)),
+            Value::Object(fields) => fields
+                .values_mut()
+                .try_for_each(|value| self.anonymize(value)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Return the authors whose latest message of the given consent type
+/// grants consent, leaving out muted feeds.
+pub fn consenting_authors(db: &KvStorage, consent_type: &str) -> Result<BTreeSet<String>> {
+    let mut consenting = BTreeSet::new();
+    let mut cursor = None;
+    loop {
+        let page = db.authors(cursor, AUTHORS_PAGE)?;
+        for author in page.authors {
+            if db.mutes().is_muted(&author)? {
+                continue;
+            }
+            let mut consent = false;
+            for msg_kvt in db.iter_feed(&author)? {
+                let content = &msg_kvt?.value["content"];
+                if content["type"] == consent_type {
+                    consent = content["consent"] == true;
+                }
+            }
+            if consent {
+                consenting.insert(author);
+            }
+        }
+        match page.next_cursor {
+            Some(next_cursor) => cursor = Some(next_cursor),
+            None => break,
+        }
+    }
+
+    Ok(consenting)
+}
+
+/// Write the public messages of the authors who have consented (with the
+/// given consent type) as JSON lines, feed by feed in order of ID. Returns the number of
+/// messages written.
+pub fn export<W: Write>(db: &KvStorage, consent_type: &str, out: &mut W) -> Result<u64> {
+    let consenting = consenting_authors(db, consent_type)?;
+    let mut anonymizer = Anonymizer {
+        db,
+        consenting: &consenting,
+        labels: HashMap::new(),
+    };
+
+    let mut count = 0;
+    for author in &consenting {
+        for msg_kvt in db.iter_feed(author)? {
+            let msg_kvt: MessageKvt = msg_kvt?;
+            // Private messages have encrypted content, and so no type.
+            let mut content = msg_kvt.value["content"].clone();
+            if !content.is_object() {
+                continue;
+            }
+            anonymizer.anonymize(&mut content)?;
+            let msg = DatasetMessage {
+                key: msg_kvt.key.clone(),
+                author: author.clone(),
+                sequence: msg_kvt.value["sequence"].as_u64().unwrap_or_default(),
+                timestamp: msg_kvt.value["timestamp"].as_f64().unwrap_or(0.0),
+                content,
+            };
+            serde_json::to_writer(&mut *out, &msg)?;
+            out.write_all(b"\n")?;
+            count += 1;
+        }
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::testing::TestNode;
+
+    #[async_std::test]
+    async fn test_export_dataset() -> Result<()> {
+        let node = TestNode::new()?;
+        let other = TestNode::new()?;
+        let withdrawn = TestNode::new()?;
+        let (id, other_id) = (&node.id.id, &other.id.id);
+
+        let other_post = other
+            .publish(json!({ "type": "post", "text": "not for research" }))
+            .await?;
+        let other_ref = other_post.id().to_string();
+        node.kv.append_feed(other_post).await?;
+        for msg in [
+            withdrawn
+                .publish(json!({ "type": "research-consent", "consent": true }))
+                .await?,
+            withdrawn
+                .publish(json!({ "type": "research-consent", "consent": false }))
+                .await?,
+        ] {
+            node.kv.append_feed(msg).await?;
+        }
+
+        node.publish(json!({ "type": "research-consent", "consent": true }))
+            .await?;
+        let post = node
+            .publish(json!({ "type": "post", "text": "hi" }))
+            .await?;
+        node.publish(json!({
+            "type": "post",
+            "text": format!("re [@other]({other_id})"),
+            "root": other_ref,
+            "branch": post.id().to_string(),
+            "mentions": [{ "link": other_id }],
+        }))
+        .await?;
+        node.publish(json!({ "type": "contact", "contact": withdrawn.id.id, "following": true }))
+            .await?;
+
+        let consenting = consenting_authors(&node.kv, "research-consent")?;
+        assert_eq!(consenting.into_iter().collect::<Vec<_>>(), vec![id.clone()]);
+
+        let mut out = Vec::new();
+        assert_eq!(export(&node.kv, "research-consent", &mut out)?, 4);
+        let lines: Vec<Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert!(lines.iter().all(|msg| msg["author"] == json!(id)));
+        let reply = &lines[2]["content"];
+        assert_eq!(reply["text"], "re [@other](@anon-1)");
+        assert_eq!(reply["root"], "%anon-2");
+        assert_eq!(reply["branch"], json!(post.id().to_string()));
+        assert_eq!(reply["mentions"][0]["link"], "@anon-1");
+        assert_eq!(lines[3]["content"]["contact"], "@anon-3");
+
+        // Nothing is exported without consent.
+        let mut out = Vec::new();
+        assert_eq!(export(&node.kv, "other-consent", &mut out)?, 0);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,174 @@
+//! Hooks on the messages forwarded to peers.
+//!
+//! Gateways relaying between communities may need to hold back some of the
+//! messages they replicate, depending on the peer they are sent to (e.g. no
+//! private-group content for relays outside the group). Hooks are
+//! registered by name in [`FORWARD_HOOKS`] and see every message sent in
+//! response to a history stream request, before it leaves the node. Each
+//! hook may let the message through, replace it or drop it; hooks run in
+//! order of name, each one seeing the message as left by the previous one.
+//!
+//! Peers cannot append a message without the ones preceding it in its
+//! feed, so once a message is dropped the rest of the feed is held back
+//! from that peer as well, until the hooks let it through.
+
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, RwLock},
+};
+
+use kuska_ssb::feed::Feed;
+use once_cell::sync::Lazy;
+
+use crate::Result;
+
+/// The forwarding hooks of the solar node.
+pub static FORWARD_HOOKS: Lazy<ForwardHookRegistry> = Lazy::new(ForwardHookRegistry::default);
+
+/// What to do with a message about to be sent to a peer.
+// Constructed by the hooks, which are registered in code: none ships with
+// the node.
+#[allow(dead_code)]
+#[derive(Debug)]
+pub enum Forward {
+    /// Send the message as it is.
+    Send,
+    /// Send the given message instead.
+    Replace(Feed),
+    /// Don't send the message.
+    Drop,
+}
+
+/// Hook on the messages sent to peers.
+pub trait ForwardHook: Send + Sync {
+    /// Decide what to do with the message (as a KVT) about to be sent to the
+    /// peer with the given ID.
+    fn forward(&self, peer_id: &str, msg_kvt: &Feed) -> Result<Forward>;
+}
+
+/// Return the ID of a peer with the leading `@`.
+fn peer_key(peer_id: &str) -> String {
+    if peer_id.starts_with('@') {
+        peer_id.to_string()
+    } else {
+        format!("@{peer_id}")
+    }
+}
+
+/// Hooks registered by name.
+#[derive(Default)]
+pub struct ForwardHookRegistry {
+    hooks: RwLock<BTreeMap<String, Arc<dyn ForwardHook>>>,
+}
+
+impl ForwardHookRegistry {
+    /// Register the hook with the given name, replacing any hook registered
+    /// before under that name.
+    #[allow(dead_code)]
+    pub fn register(&self, name: &str, hook: Arc<dyn ForwardHook>) {
+        self.hooks.write().unwrap().insert(name.to_string(), hook);
+    }
+
+    /// Remove the hook with the given name. Returns `false` if no hook was
+    /// registered under that name.
+    #[allow(dead_code)]
+    pub fn unregister(&self, name: &str) -> bool {
+        self.hooks.write().unwrap().remove(name).is_some()
+    }
+
+    /// Whether no hook is registered, in which case messages may be sent
+    /// without being parsed.
+    pub fn is_empty(&self) -> bool {
+        self.hooks.read().unwrap().is_empty()
+    }
+
+    /// Run the registered hooks on the message about to be sent to the
+    /// given peer. Returns the message to send, if any.
+    pub fn dispatch(&self, peer_id: &str, msg_kvt: Feed) -> Result<Option<Feed>> {
+        let peer_id = peer_key(peer_id);
+        let hooks: Vec<Arc<dyn ForwardHook>> =
+            self.hooks.read().unwrap().values().cloned().collect();
+
+        let mut msg_kvt = msg_kvt;
+        for hook in hooks {
+            match hook.forward(&peer_id, &msg_kvt)? {
+                Forward::Send => (),
+                Forward::Replace(replacement) => msg_kvt = replacement,
+                Forward::Drop => return Ok(None),
+            }
+        }
+
+        Ok(Some(msg_kvt))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    const RELAY_ID: &str = "@o8lWpyLeSqV/BJV9pbxFhKpwm6Lw5k+sqexYK+zT9Tc=.ed25519";
+    const MSG_REF: &str = "%R7lJEkz27lNijPhYNDzYoPjM0Fp+bFWzwX0SmNJB/ZE=.sha256";
+
+    /// Drops private messages for the relay.
+    struct NoPrivate;
+
+    impl ForwardHook for NoPrivate {
+        fn forward(&self, peer_id: &str, msg_kvt: &Feed) -> Result<Forward> {
+            match (peer_id, msg_kvt.value["content"].is_string()) {
+                (RELAY_ID, true) => Ok(Forward::Drop),
+                _ => Ok(Forward::Send),
+            }
+        }
+    }
+
+    /// Marks the messages it sees.
+    struct Mark;
+
+    impl ForwardHook for Mark {
+        fn forward(&self, _peer_id: &str, msg_kvt: &Feed) -> Result<Forward> {
+            let mut value = msg_kvt.value.clone();
+            value["marked"] = json!(true);
+            Ok(Forward::Replace(Feed {
+                key: msg_kvt.key.clone(),
+                value,
+                timestamp: msg_kvt.timestamp,
+                rts: msg_kvt.rts,
+            }))
+        }
+    }
+
+    fn msg(content: serde_json::Value) -> Feed {
+        serde_json::from_value(json!({
+            "key": MSG_REF,
+            "value": { "content": content },
+            "timestamp": 0.0,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_forward_hooks() -> Result<()> {
+        let hooks = ForwardHookRegistry::default();
+        assert!(hooks.is_empty());
+        let sent = hooks.dispatch(RELAY_ID, msg(json!({ "type": "post", "text": "hi" })))?;
+        assert_eq!(sent.unwrap().value["marked"], serde_json::Value::Null);
+
+        hooks.register("a-no-private", Arc::new(NoPrivate));
+        hooks.register("b-mark", Arc::new(Mark));
+
+        // Peer IDs from handshakes have no leading `@`.
+        let private = || msg(json!("private.box"));
+        assert!(hooks.dispatch(&RELAY_ID[1..], private())?.is_none());
+        let sent = hooks.dispatch("@member=.ed25519", private())?.unwrap();
+        assert_eq!(sent.value["marked"], json!(true));
+        let sent = hooks.dispatch(RELAY_ID, msg(json!({ "type": "post", "text": "hi" })))?;
+        assert_eq!(sent.unwrap().value["content"]["text"], "hi");
+
+        assert!(hooks.unregister("b-mark"));
+        assert!(!hooks.unregister("b-mark"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,139 @@
+//! Frontier exchange for syncing over HTTP.
+//!
+//! The frontier of a node maps the ID of each stored feed to its latest
+//! sequence number. Given the frontier of a peer, a node computes the delta:
+//! the messages it stores beyond the frontier of the peer, bundled as a JSON
+//! array of KVTs which the peer can import. Two nodes which cannot reach each
+//! other over muxrpc can thus sync with plain HTTP requests. The diff lists
+//! the same messages as ranges of sequence numbers, for sync tools which
+//! fetch them by other means.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{storage::kv::KvStorage, Result};
+
+/// Maximum number of messages in a delta bundle. A peer receiving a full
+/// bundle requests the delta again once it has imported the bundle.
+pub const MAX_BUNDLE_MSGS: usize = 10_000;
+
+/// Latest sequence number of each feed, keyed by feed ID.
+pub type Frontier = BTreeMap<String, u64>;
+
+/// Range of sequence numbers in a feed, both inclusive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SeqRange {
+    pub from: u64,
+    pub to: u64,
+}
+
+/// Return the frontier of the stored feeds, leaving out muted feeds.
+pub async fn frontier(db: &KvStorage) -> Result<Frontier> {
+    let mut frontier = Frontier::new();
+    for peer in db.get_peers().await? {
+        if peer.seq_num > 0 && !db.mutes().is_muted(&peer.pub_key)? {
+            frontier.insert(peer.pub_key, peer.seq_num);
+        }
+    }
+
+    Ok(frontier)
+}
+
+/// Return the range of stored messages beyond the frontier of a peer in
+/// each feed, leaving out the feeds of which the peer holds every stored
+/// message.
+pub async fn diff(db: &KvStorage, peer: &Frontier) -> Result<BTreeMap<String, SeqRange>> {
+    let mut diff = BTreeMap::new();
+    for (feed_id, latest_seq) in frontier(db).await? {
+        let peer_seq = peer.get(&feed_id).copied().unwrap_or(0);
+        if peer_seq < latest_seq {
+            let range = SeqRange {
+                from: peer_seq + 1,
+                to: latest_seq,
+            };
+            diff.insert(feed_id, range);
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Bundle the stored messages beyond the frontier of a peer, up to
+/// `max_msgs`, as a JSON array of KVTs. The messages of each feed are in
+/// order of sequence number, so that the bundle can be imported even if it
+/// is cut short.
+pub async fn delta(db: &KvStorage, peer: &Frontier, max_msgs: usize) -> Result<Vec<u8>> {
+    let mut bundle = vec![b'['];
+    let mut count = 0;
+
+    'feeds: for (feed_id, range) in diff(db, peer).await? {
+        for seq in range.from..=range.to {
+            if count == max_msgs {
+                break 'feeds;
+            }
+            // Messages are bundled as stored, without being parsed.
+            if let Some(msg_kvt) = db.get_msg_kvt_raw(&feed_id, seq)? {
+                if count > 0 {
+                    bundle.push(b',');
+                }
+                bundle.extend_from_slice(&msg_kvt);
+                count += 1;
+            }
+        }
+    }
+    bundle.push(b']');
+
+    Ok(bundle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::{api::import, testing::TestNode};
+
+    #[async_std::test]
+    async fn test_delta() -> Result<()> {
+        let source = TestNode::new()?;
+        let target = TestNode::new()?;
+
+        for text in &["sunrise", "noon", "sunset"] {
+            source
+                .publish(json!({ "type": "post", "text": text }))
+                .await?;
+        }
+        target
+            .publish(json!({ "type": "post", "text": "dawn" }))
+            .await?;
+
+        // The delta is cut short at the maximum number of messages, and
+        // leaves out the messages the target already stores.
+        let bundle = delta(&source.kv, &frontier(&target.kv).await?, 2).await?;
+        let report = import::import_msgs(&target.kv, import::parse(&bundle)?).await?;
+        assert_eq!(report.imported, 2);
+
+        let bundle = delta(&source.kv, &frontier(&target.kv).await?, 2).await?;
+        let report = import::import_msgs(&target.kv, import::parse(&bundle)?).await?;
+        assert_eq!(report.imported, 1);
+
+        let frontier = frontier(&target.kv).await?;
+        assert_eq!(frontier.get(&source.id.id), Some(&3));
+        assert_eq!(frontier.get(&target.id.id), Some(&1));
+        assert_eq!(delta(&source.kv, &frontier, 2).await?, b"[]");
+        assert!(diff(&source.kv, &frontier).await?.is_empty());
+
+        // The target holds a message the source lacks.
+        let source_frontier = super::frontier(&source.kv).await?;
+        let missing = diff(&target.kv, &source_frontier).await?;
+        assert_eq!(missing.len(), 1);
+        assert_eq!(
+            missing.get(&target.id.id),
+            Some(&SeqRange { from: 1, to: 1 })
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//! Export of the contact graph for analysis in external tools.
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Write,
+    str::FromStr,
+};
+
+use rand::seq::SliceRandom;
+use serde::Deserialize;
+
+use crate::{error::Error, storage::indexes::ContactGraph};
+
+/// Format of an exported contact graph.
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GraphFormat {
+    /// Graphviz DOT.
+    Dot,
+    /// GraphML (XML).
+    GraphMl,
+}
+
+impl FromStr for GraphFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format.to_lowercase().as_str() {
+            "dot" => Ok(GraphFormat::Dot),
+            "graphml" => Ok(GraphFormat::GraphMl),
+            _ => Err(Error::Other(format!("unknown graph format: {format}"))),
+        }
+    }
+}
+
+/// Render the follow and block relationships of the contact graph in the
+/// given format. If `anonymize` is set, feed IDs are replaced with labels
+/// assigned in random order, so that the structure of the graph is kept
+/// but no feed can be identified.
+pub fn export(graph: &ContactGraph, format: GraphFormat, anonymize: bool) -> String {
+    let nodes: BTreeSet<&str> = graph
+        .follows
+        .iter()
+        .chain(&graph.blocks)
+        .flat_map(|(author, contact)| [author.as_str(), contact.as_str()])
+        .collect();
+    let mut nodes: Vec<&str> = nodes.into_iter().collect();
+    if anonymize {
+        nodes.shuffle(&mut rand::thread_rng());
+    }
+    let labels: HashMap<&str, String> = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node)| {
+            let label = if anonymize {
+                format!("n{i}")
+            } else {
+                node.to_string()
+            };
+            (*node, label)
+        })
+        .collect();
+
+    let edges = graph
+        .follows
+        .iter()
+        .map(|edge| (edge, "follow"))
+        .chain(graph.blocks.iter().map(|edge| (edge, "block")))
+        .map(|((author, contact), kind)| {
+            (&labels[author.as_str()], &labels[contact.as_str()], kind)
+        });
+
+    // Writing to a `String` never fails.
+    let mut out = String::new();
+    match format {
+        GraphFormat::Dot => {
+            let quote = |id: &str| id.replace('\\', "\\\\").replace('"', "\\\"");
+            out.push_str("digraph contacts {\n");
+            for node in &nodes {
+                let _ = writeln!(out, "  \"{}\";", quote(&labels[node]));
+            }
+            for (author, contact, kind) in edges {
+                let _ = writeln!(
+                    out,
+                    "  \"{}\" -> \"{}\" [type={kind}];",
+                    quote(author),
+                    quote(contact)
+                );
+            }
+            out.push_str("}\n");
+        }
+        GraphFormat::GraphMl => {
+            let escape = |id: &str| {
+                id.replace('&', "&amp;")
+                    .replace('<', "&lt;")
+                    .replace('>', "&gt;")
+                    .replace('"', "&quot;")
+                    .replace('\'', "&apos;")
+            };
+            out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+            out.push_str("<graphml xmlns=\"http://graphml.graphdrawing.org/xmlns\">\n");
+            out.push_str(
+                "  <key id=\"type\" for=\"edge\" attr.name=\"type\" attr.type=\"string\"/>\n",
+            );
+            out.push_str("  <graph id=\"contacts\" edgedefault=\"directed\">\n");
+            for node in &nodes {
+                let _ = writeln!(out, "    <node id=\"{}\"/>", escape(&labels[node]));
+            }
+            for (author, contact, kind) in edges {
+                let _ = writeln!(
+                    out,
+                    "    <edge source=\"{}\" target=\"{}\"><data key=\"type\">{kind}</data></edge>",
+                    escape(author),
+                    escape(contact)
+                );
+            }
+            out.push_str("  </graph>\n</graphml>\n");
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_export_graph() {
+        let graph = ContactGraph {
+            follows: vec![("@alice".to_string(), "@bob".to_string())],
+            blocks: vec![("@bob".to_string(), "@carol".to_string())],
+        };
+
+        let dot = export(&graph, GraphFormat::Dot, false);
+        assert!(dot.contains("\"@alice\" -> \"@bob\" [type=follow];"));
+        assert!(dot.contains("\"@bob\" -> \"@carol\" [type=block];"));
+
+        let graphml = export(&graph, "GraphML".parse().unwrap(), true);
+        assert!(!graphml.contains('@'));
+        assert_eq!(graphml.matches("<node ").count(), 3);
+        assert_eq!(graphml.matches("<edge ").count(), 2);
+
+        assert!("svg".parse::<GraphFormat>().is_err());
+    }
+}
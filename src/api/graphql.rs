@@ -0,0 +1,287 @@
+//! GraphQL API.
+//!
+//! A read-only GraphQL schema over the same indexes as the JSON-RPC API
+//! (messages, threads, profiles, the timeline and channels), served on
+//! `/graphql`. UI developers can fetch what a view needs in one typed query,
+//! selecting only the fields they use, rather than combining several
+//! JSON-RPC calls. Messages are published with the JSON-RPC methods.
+//! Messages by muted feeds are left out, as they are by the JSON-RPC
+//! methods.
+
+use async_graphql::{
+    Context, EmptyMutation, EmptySubscription, Json, Object, Schema, SimpleObject,
+};
+use kuska_ssb::feed::Feed as MessageKvt;
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::{api::atom, storage::kv::KvStorage, uri, Result};
+
+/// Maximum nesting depth of a query.
+const MAX_DEPTH: usize = 8;
+
+/// The GraphQL schema of the node.
+static SCHEMA: Lazy<Schema<Query, EmptyMutation, EmptySubscription>> = Lazy::new(|| {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .limit_depth(MAX_DEPTH)
+        .finish()
+});
+
+/// ID of the local identity, given to the resolvers.
+struct LocalId(String);
+
+/// A message, along with whether its signature has been verified.
+#[derive(SimpleObject)]
+struct Message {
+    key: String,
+    author: String,
+    sequence: u64,
+    /// Timestamp claimed by the author, in milliseconds since the Unix
+    /// epoch.
+    timestamp: f64,
+    content: Json<Value>,
+    verified: bool,
+}
+
+/// A thread: its root message, if stored, and the replies to it, in order
+/// of claimed timestamp (or of receipt, for timestamps too far ahead of it).
+#[derive(SimpleObject)]
+struct Thread {
+    root: Option<Message>,
+    replies: Vec<Message>,
+}
+
+/// The details a feed published about itself, along with its follows and
+/// followers.
+#[derive(SimpleObject)]
+struct Profile {
+    id: String,
+    name: Option<String>,
+    description: Option<String>,
+    image: Option<String>,
+    follows: Vec<String>,
+    followers: Vec<String>,
+}
+
+/// A page of the timeline, along with the cursor of the next page.
+#[derive(SimpleObject)]
+struct TimelinePage {
+    messages: Vec<Message>,
+    next_cursor: Option<u64>,
+}
+
+impl Message {
+    fn new(db: &KvStorage, msg_kvt: &MessageKvt) -> Result<Self> {
+        Ok(Message {
+            key: msg_kvt.key.clone(),
+            author: msg_kvt.value["author"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            sequence: msg_kvt.value["sequence"].as_u64().unwrap_or_default(),
+            timestamp: atom::timestamp(msg_kvt),
+            content: Json(msg_kvt.value["content"].clone()),
+            verified: db.is_msg_verified(&msg_kvt.key)?,
+        })
+    }
+}
+
+/// Return the message with the given ID, including messages of feeds which
+/// are not replicated, unless its author is muted.
+fn any_message(db: &KvStorage, msg_ref: &str) -> Result<Option<Message>> {
+    match db.get_any_msg_val(msg_ref)? {
+        Some(msg) if !db.mutes().is_muted(msg.author())? => {
+            Ok(Some(Message::new(db, &MessageKvt::new(msg))?))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Root of the queries.
+struct Query;
+
+#[Object]
+impl Query {
+    /// The public key of the local node.
+    async fn whoami(&self, ctx: &Context<'_>) -> String {
+        ctx.data_unchecked::<LocalId>().0.clone()
+    }
+
+    /// A stored message, by ID.
+    async fn message(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+    ) -> async_graphql::Result<Option<Message>> {
+        let db = ctx.data_unchecked::<KvStorage>();
+        let msg_val = match db.get_msg_val(&uri::normalize(&id))? {
+            Some(msg_val) if !db.mutes().is_muted(msg_val.author())? => msg_val,
+            _ => return Ok(None),
+        };
+        match db.get_msg_kvt(msg_val.author(), msg_val.sequence())? {
+            Some(msg_kvt) => Ok(Some(Message::new(db, &msg_kvt)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// The messages of a thread which are stored locally.
+    async fn thread(&self, ctx: &Context<'_>, root: String) -> async_graphql::Result<Thread> {
+        let db = ctx.data_unchecked::<KvStorage>();
+        let root = uri::normalize(&root);
+
+        let mut replies = Vec::new();
+        for msg_id in db.indexes().get_thread(&root)? {
+            match db.get_any_msg_val(&msg_id)? {
+                Some(msg) if !db.mutes().is_muted(msg.author())? => {
+                    let timestamp = db.ordering_timestamp(&msg)?;
+                    replies.push((timestamp, Message::new(db, &MessageKvt::new(msg))?));
+                }
+                _ => (),
+            }
+        }
+        replies.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        Ok(Thread {
+            root: any_message(db, &root)?,
+            replies: replies.into_iter().map(|(_, reply)| reply).collect(),
+        })
+    }
+
+    /// The profile of a feed, or `null` if no message of the feed is stored
+    /// or the feed is muted.
+    async fn profile(
+        &self,
+        ctx: &Context<'_>,
+        id: String,
+    ) -> async_graphql::Result<Option<Profile>> {
+        let db = ctx.data_unchecked::<KvStorage>();
+        let id = uri::normalize(&id);
+        if db.get_latest_seq(&id)?.is_none() || db.mutes().is_muted(&id)? {
+            return Ok(None);
+        }
+
+        let about = atom::profile(db, &id, false)?;
+        Ok(Some(Profile {
+            follows: db.indexes().get_follows(&id)?,
+            followers: db.indexes().get_followers(&id)?,
+            id,
+            name: about.name,
+            description: about.description,
+            image: about.image,
+        }))
+    }
+
+    /// A page of public messages by the local feed and the feeds within the
+    /// given hops of it, most recently received first.
+    async fn timeline(
+        &self,
+        ctx: &Context<'_>,
+        cursor: Option<u64>,
+        #[graphql(default = 50)] limit: usize,
+        #[graphql(default = 1)] hops: usize,
+    ) -> async_graphql::Result<TimelinePage> {
+        let db = ctx.data_unchecked::<KvStorage>();
+        let local_id = &ctx.data_unchecked::<LocalId>().0;
+
+        let page = db.timeline(local_id, hops, cursor, limit)?;
+        let messages = page
+            .messages
+            .iter()
+            .map(|msg_kvt| Message::new(db, msg_kvt))
+            .collect::<Result<Vec<Message>>>()?;
+
+        Ok(TimelinePage {
+            messages,
+            next_cursor: page.next_cursor,
+        })
+    }
+
+    /// The latest posts in a channel (without the leading `#`), newest
+    /// first.
+    async fn channel(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        #[graphql(default = 50)] limit: usize,
+    ) -> async_graphql::Result<Vec<Message>> {
+        let db = ctx.data_unchecked::<KvStorage>();
+
+        let mut posts = Vec::new();
+        for msg_id in db.indexes().get_channel(&name, limit)? {
+            if let Some(msg) = any_message(db, &msg_id)? {
+                posts.push(msg);
+            }
+        }
+
+        Ok(posts)
+    }
+}
+
+/// Run a GraphQL request against the given database, on behalf of the
+/// given local identity.
+pub async fn execute(
+    db: KvStorage,
+    local_id: &str,
+    request: async_graphql::Request,
+) -> async_graphql::Response {
+    let request = request.data(db).data(LocalId(local_id.to_string()));
+
+    SCHEMA.execute(request).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::testing::TestNode;
+
+    #[async_std::test]
+    async fn test_graphql() -> Result<()> {
+        let node = TestNode::new()?;
+        let id = &node.id.id;
+        node.publish(json!({ "type": "about", "about": id, "name": "solar" }))
+            .await?;
+        let root = node
+            .publish(json!({ "type": "post", "text": "hi", "channel": "solar" }))
+            .await?;
+        let root_ref = root.id().to_string();
+        node.publish(json!({ "type": "post", "text": "hello", "root": root_ref }))
+            .await?;
+
+        let query = format!(
+            r#"{{
+                whoami
+                profile(id: "{id}") {{ name followers }}
+                thread(root: "{root_ref}") {{ root {{ sequence }} replies {{ content }} }}
+                channel(name: "solar") {{ key }}
+                timeline(limit: 1) {{ messages {{ sequence }} nextCursor }}
+            }}"#
+        );
+        let response = execute(node.kv.clone(), id, query.into()).await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        let data = serde_json::to_value(&response.data)?;
+
+        assert_eq!(data["whoami"], json!(id));
+        assert_eq!(data["profile"], json!({ "name": "solar", "followers": [] }));
+        assert_eq!(data["thread"]["root"]["sequence"], json!(2));
+        assert_eq!(data["thread"]["replies"][0]["content"]["text"], "hello");
+        assert_eq!(data["channel"], json!([{ "key": root_ref }]));
+        assert_eq!(data["timeline"]["messages"], json!([{ "sequence": 3 }]));
+        assert!(!data["timeline"]["nextCursor"].is_null());
+
+        let response = execute(
+            node.kv.clone(),
+            id,
+            "{ profile(id: \"@unknown\") { name } }".into(),
+        )
+        .await;
+        assert_eq!(
+            serde_json::to_value(&response.data)?,
+            json!({ "profile": null })
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,147 @@
+//! Import of signed messages carried over from other devices.
+//!
+//! Messages are accepted as KVTs or as message values (e.g. the output of
+//! the `feed` JSON-RPC method), either as a JSON array or as one message
+//! per line. Each message is verified and appended to the feed of its
+//! author as if it had been received from a peer, so that feeds can be
+//! synced between devices which never connect to each other.
+
+use kuska_ssb::feed::Message as MessageValue;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{storage::kv::KvStorage, validation, Result};
+
+/// Outcome of an import.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ImportReport {
+    /// Number of messages appended.
+    pub imported: usize,
+    /// Number of messages already stored or authored by muted feeds.
+    pub skipped: usize,
+    /// Messages which were rejected, and why.
+    pub errors: Vec<String>,
+}
+
+/// Parse the messages of an import, given as a JSON array or as one JSON
+/// message per line.
+pub fn parse(body: &[u8]) -> Result<Vec<Value>> {
+    match serde_json::from_slice(body) {
+        Ok(Value::Array(msgs)) => Ok(msgs),
+        Ok(msg) => Ok(vec![msg]),
+        Err(_) => body
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .map(|line| Ok(serde_json::from_slice(line)?))
+            .collect(),
+    }
+}
+
+/// Verify the given messages and append them to the feeds of their
+/// authors, in order of sequence number. Messages which are already stored
+/// or authored by muted feeds are skipped; messages which fail
+/// verification, or follow a rejected message of the same feed, are
+/// reported as errors.
+pub async fn import_msgs(db: &KvStorage, msgs: Vec<Value>) -> Result<ImportReport> {
+    let mut report = ImportReport::default();
+
+    let mut decoded: Vec<MessageValue> = Vec::new();
+    for (index, msg) in msgs.into_iter().enumerate() {
+        let bytes = serde_json::to_vec(&msg)?;
+        match validation::decode_msg(&bytes) {
+            Ok(msg) => decoded.push(msg),
+            Err(err) => report.errors.push(format!("message {index}: {err}")),
+        }
+    }
+    decoded.sort_by(|a, b| {
+        a.author()
+            .cmp(b.author())
+            .then(a.sequence().cmp(&b.sequence()))
+    });
+
+    for msg in decoded {
+        let author = msg.author().to_string();
+        if db.mutes().is_muted(&author)? {
+            report.skipped += 1;
+            continue;
+        }
+        if msg.sequence() <= db.get_latest_seq(&author)?.unwrap_or(0) {
+            // A different message at a stored position would fork the feed.
+            match db.get_msg_val(&msg.id().to_string())? {
+                Some(_) => report.skipped += 1,
+                None => report.errors.push(format!(
+                    "{} {}: forks the stored feed",
+                    author,
+                    msg.sequence()
+                )),
+            }
+            continue;
+        }
+
+        match db.append_feed(msg.clone()).await {
+            Ok(_) => report.imported += 1,
+            Err(err) => report
+                .errors
+                .push(format!("{} {}: {}", author, msg.sequence(), err)),
+        }
+    }
+
+    if report.imported > 0 {
+        db.commit_indexes().await?;
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::testing::TestNode;
+
+    #[async_std::test]
+    async fn test_import_msgs() -> Result<()> {
+        let source = TestNode::new()?;
+        let target = TestNode::new()?;
+        let feed_id = &source.id.id;
+
+        for text in &["sunrise", "noon", "sunset"] {
+            source
+                .publish(json!({ "type": "post", "text": text }))
+                .await?;
+        }
+        let mut feed: Vec<Value> = source
+            .kv
+            .get_feed(feed_id)?
+            .into_iter()
+            .map(|msg_kvt| serde_json::to_value(msg_kvt).unwrap())
+            .collect();
+        assert_eq!(feed.len(), 3);
+
+        // Messages are appended in order of sequence number. An altered
+        // message fails verification, and the rest of its feed is rejected.
+        feed.reverse();
+        let mut altered = feed.clone();
+        altered[1]["value"]["content"]["text"] = json!("midnight");
+        let report = import_msgs(&target.kv, altered).await?;
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.errors.len(), 2);
+
+        // Stored messages are skipped.
+        let ndjson: Vec<String> = feed.iter().map(Value::to_string).collect();
+        let report = import_msgs(&target.kv, parse(ndjson.join("\n").as_bytes())?).await?;
+        assert_eq!(
+            report,
+            ImportReport {
+                imported: 2,
+                skipped: 1,
+                errors: Vec::new(),
+            }
+        );
+        assert_eq!(target.kv.get_latest_seq(feed_id)?, Some(3));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,26 @@
+//! Helpers for the operations exposed through the JSON-RPC API.
+
+#[cfg(feature = "activitypub")]
+pub mod activitypub;
+pub mod alias;
+#[cfg(feature = "jsonrpc")]
+pub mod atom;
+pub mod bot;
+pub mod content;
+pub mod dataset;
+pub mod forward;
+#[cfg(feature = "jsonrpc")]
+pub mod frontier;
+pub mod graph;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "jsonrpc")]
+pub mod import;
+pub mod replication;
+pub mod schema;
+#[cfg(feature = "jsonrpc")]
+pub mod stats;
+#[cfg(feature = "jsonrpc")]
+pub mod tokens;
+#[cfg(feature = "viewer")]
+pub mod viewer;
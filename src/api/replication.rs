@@ -0,0 +1,241 @@
+//! Replication scope.
+//!
+//! The feeds which a node requests from its peers are those listed in the
+//! replication configuration, the pinned feeds and, in archivist mode, every
+//! feed known from the stored messages. The replication plan lists these
+//! feeds along with the reasons why each one is replicated, so that the
+//! scope of replication can be checked before data starts flowing.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use serde::Serialize;
+
+use crate::{allow_list, storage::kv::KvStorage, Result};
+
+/// Maximum distance from the local identity in the follow graph reported
+/// for the feeds of the plan.
+pub const MAX_PLAN_HOPS: usize = 3;
+
+/// Why a feed is replicated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PlanReason {
+    /// Listed in the replication configuration.
+    Config,
+    /// Listed in the replication configuration along with the address of
+    /// its node, to which the node connects (e.g. a pub).
+    Pub,
+    /// Pinned with the `pin` method.
+    Pinned,
+    /// Known from the stored messages, in archivist mode.
+    Archive,
+}
+
+/// Why a feed which would otherwise be replicated is not.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Exclusion {
+    /// Muted with replication stopped.
+    ReplicationStopped,
+    /// Not followed by the admin in allow-list mode.
+    NotAllowed,
+}
+
+/// A feed in the replication plan.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PlannedFeed {
+    pub feed_id: String,
+    pub reasons: Vec<PlanReason>,
+    /// Distance from the local identity in the follow graph, up to
+    /// `MAX_PLAN_HOPS`.
+    pub hops: Option<usize>,
+    /// Feed followed by the local identity through which the feed is
+    /// reached in the follow graph, for feeds more than one hop away.
+    pub via: Option<String>,
+    /// Latest stored sequence number.
+    pub latest_seq: Option<u64>,
+    /// Set if the feed is not replicated after all.
+    pub excluded: Option<Exclusion>,
+}
+
+/// Return the feeds replicated in archivist mode in addition to those in
+/// the replication list: every feed known from the stored messages, except
+/// the local feed and those blocked by the local identity.
+pub async fn archive_feeds(db: &KvStorage, local_id: &str) -> Result<BTreeSet<String>> {
+    let mut feeds = BTreeSet::new();
+    for (author, contact) in db.indexes().get_contact_graph()?.follows {
+        feeds.insert(author);
+        feeds.insert(contact);
+    }
+    for peer in db.get_peers().await? {
+        feeds.insert(peer.pub_key);
+    }
+    feeds.remove(local_id);
+
+    let mut archive = BTreeSet::new();
+    for feed in feeds {
+        if !db.indexes().is_blocking(local_id, &feed)? {
+            archive.insert(feed);
+        }
+    }
+
+    Ok(archive)
+}
+
+/// Return the distance of each feed within `MAX_PLAN_HOPS` of `local_id` in
+/// the follow graph, along with the feed followed by `local_id` through
+/// which it is first reached.
+fn hops_from(db: &KvStorage, local_id: &str) -> Result<HashMap<String, (usize, Option<String>)>> {
+    let mut reached = HashMap::new();
+    let mut visited = HashSet::new();
+    visited.insert(local_id.to_string());
+    let mut frontier: Vec<(String, Option<String>)> = vec![(local_id.to_string(), None)];
+
+    for hops in 1..=MAX_PLAN_HOPS {
+        let mut next = Vec::new();
+        for (id, via) in &frontier {
+            for followed in db.indexes().get_follows(id)? {
+                if visited.insert(followed.clone()) {
+                    // Direct follows are reached through themselves.
+                    let via = via.clone().unwrap_or_else(|| followed.clone());
+                    let reported_via = (hops > 1).then(|| via.clone());
+                    reached.insert(followed.clone(), (hops, reported_via));
+                    next.push((followed, Some(via)));
+                }
+            }
+        }
+        frontier = next;
+    }
+
+    Ok(reached)
+}
+
+/// Compute the replication plan of `local_id`, given the peers listed in
+/// the replication configuration (public key and address), whether
+/// archivist mode is enabled and the admin of the allow list, if any.
+/// Feeds are listed in order of ID.
+pub async fn plan(
+    db: &KvStorage,
+    local_id: &str,
+    listed: &HashMap<String, String>,
+    archivist: bool,
+    allow_list_admin: Option<&str>,
+) -> Result<Vec<PlannedFeed>> {
+    let mut reasons: BTreeMap<String, BTreeSet<PlanReason>> = BTreeMap::new();
+    for (feed_id, url) in listed {
+        let reason = match url.is_empty() {
+            true => PlanReason::Config,
+            false => PlanReason::Pub,
+        };
+        reasons.entry(feed_id.clone()).or_default().insert(reason);
+    }
+    for feed_id in db.get_pinned()? {
+        reasons
+            .entry(feed_id)
+            .or_default()
+            .insert(PlanReason::Pinned);
+    }
+    if archivist {
+        for feed_id in archive_feeds(db, local_id).await? {
+            reasons
+                .entry(feed_id)
+                .or_default()
+                .insert(PlanReason::Archive);
+        }
+    }
+
+    let hops = hops_from(db, local_id)?;
+    let mut plan = Vec::new();
+    for (feed_id, reasons) in reasons {
+        let excluded = if db.mutes().is_replication_stopped(&feed_id)? {
+            Some(Exclusion::ReplicationStopped)
+        } else if !allow_list::is_allowed_by(db, allow_list_admin, &feed_id)? {
+            Some(Exclusion::NotAllowed)
+        } else {
+            None
+        };
+        let (hops, via) = match hops.get(&feed_id) {
+            Some((hops, via)) => (Some(*hops), via.clone()),
+            None => (None, None),
+        };
+        plan.push(PlannedFeed {
+            latest_seq: db.get_latest_seq(&feed_id)?,
+            feed_id,
+            reasons: reasons.into_iter().collect(),
+            hops,
+            via,
+            excluded,
+        });
+    }
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::testing::TestNode;
+
+    #[async_std::test]
+    async fn test_plan() -> Result<()> {
+        let node = TestNode::new()?;
+        let friend = TestNode::new()?;
+        let local_id = &node.id.id;
+        let friend_id = &friend.id.id;
+
+        node.publish(json!({ "type": "contact", "contact": friend_id, "following": true }))
+            .await?;
+        let msg = friend
+            .publish(json!({ "type": "contact", "contact": "@fof=.ed25519", "following": true }))
+            .await?;
+        node.kv.append_feed(msg).await?;
+        node.kv.pin_feed("@pinned=.ed25519")?;
+        node.kv.mutes().mute("@pinned=.ed25519", true)?;
+
+        let mut listed = HashMap::new();
+        listed.insert(friend_id.clone(), String::new());
+        listed.insert(
+            "@fof=.ed25519".to_string(),
+            "net:pub:8008~shs:key".to_string(),
+        );
+
+        let scope = plan(&node.kv, local_id, &listed, false, None).await?;
+        assert_eq!(scope.len(), 3);
+        let feed = |id: &str| scope.iter().find(|feed| feed.feed_id == id).unwrap();
+
+        assert_eq!(feed(friend_id).reasons, vec![PlanReason::Config]);
+        assert_eq!(
+            (feed(friend_id).hops, feed(friend_id).latest_seq),
+            (Some(1), Some(1))
+        );
+        assert_eq!(feed("@fof=.ed25519").reasons, vec![PlanReason::Pub]);
+        assert_eq!(feed("@fof=.ed25519").hops, Some(2));
+        assert_eq!(
+            feed("@fof=.ed25519").via.as_deref(),
+            Some(friend_id.as_str())
+        );
+        assert_eq!(
+            feed("@pinned=.ed25519").excluded,
+            Some(Exclusion::ReplicationStopped)
+        );
+
+        // In archivist mode every known feed is replicated; in allow-list
+        // mode only those followed by the admin are.
+        let scope = plan(&node.kv, local_id, &HashMap::new(), true, Some(local_id)).await?;
+        assert!(scope
+            .iter()
+            .all(|feed| feed.reasons.contains(&PlanReason::Archive)
+                || feed.feed_id == "@pinned=.ed25519"));
+        let excluded: Vec<&str> = scope
+            .iter()
+            .filter(|feed| feed.excluded == Some(Exclusion::NotAllowed))
+            .map(|feed| feed.feed_id.as_str())
+            .collect();
+        assert_eq!(excluded, vec!["@fof=.ed25519"]);
+
+        Ok(())
+    }
+}
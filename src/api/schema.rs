@@ -0,0 +1,272 @@
+//! Registry of message content schemas.
+//!
+//! Validators are registered per message type, either as JSON schemas
+//! loaded from the `schemas` directory (`<type>.json`) or as custom
+//! implementations of [`ContentValidator`]. Content published through the
+//! JSON-RPC API must satisfy the validators of its type, and received
+//! messages which don't are tagged as invalid by the indexes.
+//!
+//! Only a subset of JSON Schema is supported: `type`, `enum`, `const`,
+//! `required`, `properties`, `items`, `minLength`, `maxLength`, `minimum`
+//! and `maximum`. Other keywords are ignored.
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, RwLock},
+};
+
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::Result;
+
+/// The content schemas of the solar node.
+pub static SCHEMAS: Lazy<SchemaRegistry> = Lazy::new(SchemaRegistry::default);
+
+/// Check of the content of messages of a given type.
+pub trait ContentValidator: Send + Sync {
+    /// Return the reason why the content is invalid, if it is.
+    fn validate(&self, content: &Value) -> std::result::Result<(), String>;
+}
+
+/// A JSON schema.
+pub struct Schema(Value);
+
+impl Schema {
+    pub fn new(schema: Value) -> Self {
+        Self(schema)
+    }
+}
+
+impl ContentValidator for Schema {
+    fn validate(&self, content: &Value) -> std::result::Result<(), String> {
+        check(&self.0, content, "content")
+    }
+}
+
+/// Check `value`, found at `path`, against `schema`.
+fn check(schema: &Value, value: &Value, path: &str) -> std::result::Result<(), String> {
+    match &schema["type"] {
+        Value::String(expected) if !has_type(value, expected) => {
+            return Err(format!("{path} is not of type {expected}"));
+        }
+        Value::Array(expected)
+            if !expected
+                .iter()
+                .filter_map(Value::as_str)
+                .any(|expected| has_type(value, expected)) =>
+        {
+            return Err(format!(
+                "{path} is not of any of the types {}",
+                schema["type"]
+            ));
+        }
+        _ => (),
+    }
+
+    if let Some(allowed) = schema["enum"].as_array() {
+        if !allowed.contains(value) {
+            return Err(format!("{path} is not one of the allowed values"));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if value != expected {
+            return Err(format!("{path} is not {expected}"));
+        }
+    }
+
+    if let Value::Object(fields) = value {
+        for field in schema["required"].as_array().into_iter().flatten() {
+            if let Some(field) = field.as_str() {
+                if !fields.contains_key(field) {
+                    return Err(format!("{path}.{field} is missing"));
+                }
+            }
+        }
+        if let Some(properties) = schema["properties"].as_object() {
+            for (field, field_schema) in properties {
+                if let Some(field_value) = fields.get(field) {
+                    check(field_schema, field_value, &format!("{path}.{field}"))?;
+                }
+            }
+        }
+    }
+
+    if let Value::Array(items) = value {
+        if schema["items"].is_object() {
+            for (i, item) in items.iter().enumerate() {
+                check(&schema["items"], item, &format!("{path}[{i}]"))?;
+            }
+        }
+    }
+
+    if let Value::String(text) = value {
+        let len = text.chars().count() as u64;
+        if schema["minLength"].as_u64().is_some_and(|min| len < min) {
+            return Err(format!("{path} is too short"));
+        }
+        if schema["maxLength"].as_u64().is_some_and(|max| len > max) {
+            return Err(format!("{path} is too long"));
+        }
+    }
+
+    if let Some(number) = value.as_f64() {
+        if schema["minimum"].as_f64().is_some_and(|min| number < min) {
+            return Err(format!("{path} is less than {}", schema["minimum"]));
+        }
+        if schema["maximum"].as_f64().is_some_and(|max| number > max) {
+            return Err(format!("{path} is greater than {}", schema["maximum"]));
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether `value` is of the JSON schema type `expected`.
+fn has_type(value: &Value, expected: &str) -> bool {
+    match expected {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        _ => true,
+    }
+}
+
+/// Validators registered per message type.
+#[derive(Default)]
+pub struct SchemaRegistry {
+    validators: RwLock<HashMap<String, Vec<Arc<dyn ContentValidator>>>>,
+}
+
+impl SchemaRegistry {
+    /// Register a validator for the content of messages of the given type.
+    /// Content must satisfy every validator registered for its type.
+    pub fn register(&self, msg_type: &str, validator: Arc<dyn ContentValidator>) {
+        self.validators
+            .write()
+            .unwrap()
+            .entry(msg_type.to_string())
+            .or_default()
+            .push(validator);
+    }
+
+    /// Register the JSON schemas stored in the given directory, each in a
+    /// file named after the message type (e.g. `gathering.json`). Returns
+    /// the number of schemas registered.
+    pub fn load_dir(&self, dir: &Path) -> Result<usize> {
+        if !dir.is_dir() {
+            return Ok(0);
+        }
+
+        let mut count = 0;
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            if let Some(msg_type) = path.file_stem().and_then(|stem| stem.to_str()) {
+                let schema = serde_json::from_slice(&fs::read(&path)?)?;
+                self.register(msg_type, Arc::new(Schema::new(schema)));
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Return the reason why the given message content violates a validator
+    /// registered for its type, if it does. Encrypted content and content of
+    /// types without validators is accepted.
+    pub fn violation(&self, content: &Value) -> Option<String> {
+        let msg_type = content["type"].as_str()?;
+        let validators = self.validators.read().unwrap();
+
+        validators
+            .get(msg_type)?
+            .iter()
+            .find_map(|validator| validator.validate(content).err())
+            .map(|reason| format!("{msg_type} message: {reason}"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    /// Rejects content whose text mentions rust.
+    struct NoRust;
+
+    impl ContentValidator for NoRust {
+        fn validate(&self, content: &Value) -> std::result::Result<(), String> {
+            match content["text"].as_str() {
+                Some(text) if text.contains("rust") => Err("mentions rust".to_string()),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_schema_registry() {
+        let registry = SchemaRegistry::default();
+        registry.register(
+            "gathering",
+            Arc::new(Schema::new(json!({
+                "type": "object",
+                "required": ["title", "startDateTime"],
+                "properties": {
+                    "title": { "type": "string", "minLength": 1 },
+                    "startDateTime": {
+                        "type": "object",
+                        "properties": { "epoch": { "type": "integer", "minimum": 0 } },
+                    },
+                    "tags": { "type": "array", "items": { "enum": ["music", "food"] } },
+                },
+            }))),
+        );
+        registry.register("post", Arc::new(NoRust));
+
+        let gathering = json!({
+            "type": "gathering",
+            "title": "picnic",
+            "startDateTime": { "epoch": 1700000000000u64 },
+            "tags": ["food"],
+        });
+        assert_eq!(registry.violation(&gathering), None);
+
+        let untitled = json!({ "type": "gathering", "startDateTime": {} });
+        assert_eq!(
+            registry.violation(&untitled).as_deref(),
+            Some("gathering message: content.title is missing")
+        );
+        let past = json!({
+            "type": "gathering",
+            "title": "picnic",
+            "startDateTime": { "epoch": -1 },
+        });
+        assert!(registry.violation(&past).is_some());
+        let tagged = json!({
+            "type": "gathering",
+            "title": "picnic",
+            "startDateTime": {},
+            "tags": ["sports"],
+        });
+        assert_eq!(
+            registry.violation(&tagged).as_deref(),
+            Some("gathering message: content.tags[0] is not one of the allowed values")
+        );
+
+        assert!(registry
+            .violation(&json!({ "type": "post", "text": "rust" }))
+            .is_some());
+        assert_eq!(registry.violation(&json!({ "type": "vote" })), None);
+        assert_eq!(registry.violation(&json!("encrypted.box")), None);
+    }
+}
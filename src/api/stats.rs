@@ -0,0 +1,225 @@
+//! Activity statistics of feeds and of the network.
+//!
+//! The statistics of a feed are computed from its stored messages and the
+//! follow graph in the indexes, for profile pages and network research. The
+//! statistics of the network are built from daily message and author counts
+//! kept up to date by the indexer, for community health dashboards.
+
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::{api::atom, storage::kv::KvStorage, Result};
+
+/// Activity statistics of a feed.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct AuthorStats {
+    pub feed_id: String,
+    /// Number of stored messages, leaving out those whose content was
+    /// deleted.
+    pub messages: u64,
+    /// Number of messages of each type. Private messages are counted as
+    /// `private`, and messages without a type as `unknown`.
+    pub types: BTreeMap<String, u64>,
+    /// Timestamp claimed by the first and the latest stored message, in
+    /// milliseconds since the Unix epoch.
+    pub first_timestamp: Option<u64>,
+    pub last_timestamp: Option<u64>,
+    /// Number of messages published in each month (UTC), keyed `YYYY-MM`.
+    pub per_month: BTreeMap<String, u64>,
+    /// Number of feeds following the feed.
+    pub followers: usize,
+    /// Number of feeds followed by the feed.
+    pub follows: usize,
+}
+
+/// Number of milliseconds in a day.
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Number of feeds which published a message within each time window.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct ActiveAuthors {
+    /// Today (UTC).
+    pub day: usize,
+    /// The latest 7 days, including today.
+    pub week: usize,
+    /// The latest 30 days, including today.
+    pub month: usize,
+}
+
+/// Activity statistics of the stored feeds.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct NetworkStats {
+    /// Number of stored feeds.
+    pub feeds: u64,
+    /// Number of stored feed messages.
+    pub messages: u64,
+    /// Number of messages published on each of the latest days (UTC), keyed
+    /// `YYYY-MM-DD`. Messages claiming a timestamp later than their receipt
+    /// are counted on the day they were received.
+    pub messages_per_day: BTreeMap<String, u64>,
+    pub active_authors: ActiveAuthors,
+    /// Number of feeds at each distance from the local feed in the follow
+    /// graph, starting with the local feed itself at 0 hops.
+    pub hops: Vec<usize>,
+}
+
+/// Compute the activity statistics of the stored feeds at `now` (in
+/// milliseconds since the Unix epoch), listing the messages of the latest
+/// `days` days and the follow graph up to `max_hops` from `local_id`.
+pub fn network_stats(
+    db: &KvStorage,
+    local_id: &str,
+    now: u64,
+    days: u32,
+    max_hops: usize,
+) -> Result<NetworkStats> {
+    let indexes = db.indexes();
+    let today = (now / MS_PER_DAY) as u32;
+    let since = |days: u32| today.saturating_sub(days.saturating_sub(1));
+
+    let daily_counts = indexes.get_daily_counts(0)?;
+    let mut messages_per_day = BTreeMap::new();
+    for day in since(days)..=today {
+        let date = atom::rfc3339((day as u64 * MS_PER_DAY) as f64)[..10].to_string();
+        messages_per_day.insert(date, daily_counts.get(&day).copied().unwrap_or(0));
+    }
+
+    Ok(NetworkStats {
+        feeds: db.author_count()?,
+        messages: daily_counts.values().sum(),
+        messages_per_day,
+        active_authors: ActiveAuthors {
+            day: indexes.count_active_authors(since(1))?,
+            week: indexes.count_active_authors(since(7))?,
+            month: indexes.count_active_authors(since(30))?,
+        },
+        hops: indexes.get_hops_distribution(local_id, max_hops)?,
+    })
+}
+
+/// Compute the activity statistics of the given feed. Returns `None` if no
+/// message of the feed is stored or the feed is muted.
+pub fn author_stats(db: &KvStorage, feed_id: &str) -> Result<Option<AuthorStats>> {
+    let latest_seq = match db.get_latest_seq(feed_id)? {
+        Some(latest_seq) if !db.mutes().is_muted(feed_id)? => latest_seq,
+        _ => return Ok(None),
+    };
+
+    let mut stats = AuthorStats {
+        feed_id: feed_id.to_string(),
+        ..Default::default()
+    };
+    for seq in 1..=latest_seq {
+        let msg_kvt = match db.get_msg_kvt(feed_id, seq)? {
+            Some(msg_kvt) => msg_kvt,
+            None => continue,
+        };
+        let content = &msg_kvt.value["content"];
+        // The content of the message was deleted.
+        if content.is_null() {
+            continue;
+        }
+        let msg_type = match content.as_object() {
+            Some(content) => content
+                .get("type")
+                .and_then(|msg_type| msg_type.as_str())
+                .unwrap_or("unknown"),
+            None => "private",
+        };
+        *stats.types.entry(msg_type.to_string()).or_default() += 1;
+
+        let timestamp = msg_kvt.value["timestamp"].as_f64().unwrap_or(0.0);
+        let month = atom::rfc3339(timestamp)[..7].to_string();
+        *stats.per_month.entry(month).or_default() += 1;
+
+        let timestamp = timestamp.max(0.0) as u64;
+        stats.first_timestamp.get_or_insert(timestamp);
+        stats.last_timestamp = Some(timestamp);
+        stats.messages += 1;
+    }
+
+    let indexes = db.indexes();
+    stats.followers = indexes.get_followers(feed_id)?.len();
+    stats.follows = indexes.get_follows(feed_id)?.len();
+
+    Ok(Some(stats))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use serde_json::json;
+
+    use crate::testing::TestNode;
+
+    #[async_std::test]
+    async fn test_author_stats() -> Result<()> {
+        let node = TestNode::new()?;
+        let id = &node.id.id;
+        assert_eq!(author_stats(&node.kv, id)?, None);
+
+        node.publish(json!({ "type": "post", "text": "hi" }))
+            .await?;
+        node.publish(json!({ "type": "post", "text": "again" }))
+            .await?;
+        node.publish(
+            json!({ "type": "contact", "contact": "@friend=.ed25519", "following": true }),
+        )
+        .await?;
+        node.publish(json!("private.box")).await?;
+        node.kv.commit_indexes().await?;
+
+        let stats = author_stats(&node.kv, id)?.unwrap();
+        assert_eq!(stats.messages, 4);
+        assert_eq!(stats.types.get("post"), Some(&2));
+        assert_eq!(stats.types.get("private"), Some(&1));
+        assert_eq!(stats.per_month.values().sum::<u64>(), 4);
+        assert!(stats.first_timestamp <= stats.last_timestamp);
+        assert_eq!((stats.follows, stats.followers), (1, 0));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_network_stats() -> Result<()> {
+        let node = TestNode::new()?;
+        let id = &node.id.id;
+
+        node.publish(json!({ "type": "post", "text": "hi" }))
+            .await?;
+        node.publish(
+            json!({ "type": "contact", "contact": "@friend=.ed25519", "following": true }),
+        )
+        .await?;
+        node.kv.commit_indexes().await?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        let stats = network_stats(&node.kv, id, now, 3, 2)?;
+        assert_eq!((stats.feeds, stats.messages), (1, 2));
+        assert_eq!(stats.messages_per_day.len(), 3);
+        assert_eq!(stats.messages_per_day.values().sum::<u64>(), 2);
+        assert_eq!(stats.active_authors.week, 1);
+        assert_eq!(stats.hops, vec![1, 1, 0]);
+
+        // Daily counts are added to those already committed.
+        node.publish(json!({ "type": "post", "text": "again" }))
+            .await?;
+        node.kv.commit_indexes().await?;
+        assert_eq!(network_stats(&node.kv, id, now, 3, 2)?.messages, 3);
+
+        // Messages older than the window are left out of the daily counts.
+        let later = now + 40 * MS_PER_DAY;
+        let stats = network_stats(&node.kv, id, later, 3, 2)?;
+        assert_eq!(stats.messages_per_day.values().sum::<u64>(), 0);
+        assert_eq!(stats.active_authors, ActiveAuthors::default());
+
+        Ok(())
+    }
+}
@@ -0,0 +1,215 @@
+//! Scoped API tokens.
+//!
+//! The JSON-RPC API is open to anyone who can reach it, unless API tokens
+//! are listed in `tokens.toml` in the data directory. Requests must then
+//! carry one of the tokens as a bearer token, and may only call the methods
+//! of the groups (scopes) granted to it: reading, publishing, blob fetching
+//! and administration. Methods not listed in any other scope belong to the
+//! admin scope, so that new methods stay restricted until they are given a
+//! scope.
+
+use std::{collections::BTreeSet, fmt, fs, path::Path, sync::RwLock};
+
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+
+use crate::{error::Error, Result};
+
+/// The API tokens loaded from the data directory.
+static API_TOKENS: Lazy<RwLock<Vec<ApiToken>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+/// A group of JSON-RPC methods to which a token may be granted access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Query the stored messages and the state of the node.
+    Read,
+    /// Publish messages and update the local notifications.
+    Publish,
+    /// Request blobs from peers.
+    Blobs,
+    /// Change the configuration and the indexes of the node.
+    Admin,
+}
+
+impl Scope {
+    /// Return the scope of the given method. Requests to the GraphQL API, to
+    /// the event stream, to the Atom feeds, to the stored blobs and to the
+    /// web viewer, which are read-only, are given as the `graphql`, `events`,
+    /// `atom`, `blob` and `viewer` methods.
+    pub fn of_method(method: &str) -> Self {
+        match method {
+            "atom" | "authorStats" | "authors" | "blob" | "clockSkew" | "contactGraph"
+            | "convertRef" | "deliveries" | "events" | "expired" | "feed" | "fetchThread"
+            | "followerEvents" | "frontier" | "frontierDiff" | "gatherings" | "getLatest"
+            | "globalSeq" | "graphql" | "health" | "message" | "messagesBetween" | "metafeed"
+            | "mutes" | "networkStats" | "notifications" | "outbox" | "peers" | "ping"
+            | "replicationPaused" | "replicationPlan" | "repoUpdates" | "reportCounts"
+            | "reportsAgainst" | "repos" | "resolveAlias" | "roomKeys" | "syncSessions"
+            | "timeline" | "viewer" | "whoami" => Scope::Read,
+            "addSubfeed"
+            | "createMetafeed"
+            | "createPrivateThread"
+            | "markNotificationsRead"
+            | "publish"
+            | "replyPrivate" => Scope::Publish,
+            "blobWants" | "fetchBlob" | "skippedBlobs" | "unwantBlob" | "wantBlob" => Scope::Blobs,
+            _ => Scope::Admin,
+        }
+    }
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Scope::Read => "read",
+            Scope::Publish => "publish",
+            Scope::Blobs => "blobs",
+            Scope::Admin => "admin",
+        };
+        write!(f, "{name}")
+    }
+}
+
+/// An API token along with the scopes granted to it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiToken {
+    pub token: String,
+    pub scopes: BTreeSet<Scope>,
+}
+
+/// Content of `tokens.toml`.
+#[derive(Deserialize)]
+struct TokensConfig {
+    #[serde(default)]
+    token: Vec<ApiToken>,
+}
+
+/// The methods which a request may call, given its bearer token.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Access {
+    /// No token is configured: every method may be called.
+    Open,
+    /// The token of the request grants the given scopes.
+    Scoped(BTreeSet<Scope>),
+    /// The request carries no token or an unknown one.
+    Denied,
+}
+
+impl Access {
+    /// Return the access granted by the given bearer token, out of the
+    /// given API tokens.
+    pub fn of_token(tokens: &[ApiToken], bearer: Option<&str>) -> Self {
+        if tokens.is_empty() {
+            return Access::Open;
+        }
+        let granted = bearer.and_then(|given| {
+            tokens
+                .iter()
+                .find(|token| tokens_match(given, &token.token))
+        });
+        match granted {
+            Some(token) => Access::Scoped(token.scopes.clone()),
+            None => Access::Denied,
+        }
+    }
+
+    /// Return the access granted by the given bearer token, out of the
+    /// loaded API tokens.
+    pub fn of_request(bearer: Option<&str>) -> Self {
+        Access::of_token(&API_TOKENS.read().unwrap(), bearer)
+    }
+
+    /// Check that the given method may be called.
+    pub fn check(&self, method: &str) -> Result<()> {
+        match self {
+            Access::Open => Ok(()),
+            Access::Scoped(scopes) => {
+                let scope = Scope::of_method(method);
+                match scopes.contains(&scope) {
+                    true => Ok(()),
+                    false => Err(Error::Forbidden(format!(
+                        "the {scope} scope is required to call {method}"
+                    ))),
+                }
+            }
+            Access::Denied => Err(Error::Forbidden(
+                "a valid API token is required".to_string(),
+            )),
+        }
+    }
+}
+
+/// Load the API tokens listed in the TOML file at `path`, if it exists.
+/// Returns the number of tokens loaded.
+pub fn load_config(path: &Path) -> Result<usize> {
+    if !path.is_file() {
+        return Ok(0);
+    }
+
+    let config: TokensConfig = toml::from_slice(&fs::read(path)?)?;
+    if config.token.iter().any(|token| token.token.is_empty()) {
+        return Err(Error::Other(format!(
+            "empty API token in {}",
+            path.display()
+        )));
+    }
+    let count = config.token.len();
+    *API_TOKENS.write().unwrap() = config.token;
+
+    Ok(count)
+}
+
+/// Compare a given token to the expected one in constant time.
+pub fn tokens_match(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_scopes() -> Result<()> {
+        let config: TokensConfig = toml::from_str(
+            r#"
+            [[token]]
+            token = "reader"
+            scopes = ["read", "blobs"]
+
+            [[token]]
+            token = "admin"
+            scopes = ["read", "publish", "blobs", "admin"]
+            "#,
+        )?;
+        let tokens = config.token;
+
+        assert_eq!(Access::of_token(&[], None), Access::Open);
+        assert!(Access::of_token(&[], None).check("ban").is_ok());
+
+        let reader = Access::of_token(&tokens, Some("reader"));
+        assert!(reader.check("feed").is_ok());
+        assert!(reader.check("fetchBlob").is_ok());
+        assert!(reader.check("viewer").is_ok());
+        assert!(reader.check("publish").is_err());
+        // Unknown methods require the admin scope.
+        assert!(reader.check("newMethod").is_err());
+        assert!(Access::of_token(&tokens, Some("admin"))
+            .check("newMethod")
+            .is_ok());
+
+        assert_eq!(Access::of_token(&tokens, Some("readers")), Access::Denied);
+        assert_eq!(Access::of_token(&tokens, None), Access::Denied);
+        assert!(
+            toml::from_str::<TokensConfig>("[[token]]\ntoken = \"a\"\nscopes = [\"write\"]")
+                .is_err()
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,311 @@
+//! Built-in web viewer of the local data.
+//!
+//! Profiles, threads and channels are rendered from the stored feeds and
+//! the indexes as plain HTML pages under `/view`, so that the operator of a
+//! headless node (e.g. a pub) can inspect what it stores with a browser.
+//! Private messages are left out, as are messages by muted feeds.
+
+use std::collections::HashMap;
+
+use kuska_ssb::feed::Feed as MessageKvt;
+use pulldown_cmark::CowStr;
+use serde_json::Value;
+use url::form_urlencoded;
+
+use crate::{
+    api::atom::{self, escape},
+    storage::{clock_skew, kv::KvStorage},
+    Result,
+};
+
+/// Maximum number of posts shown on a channel page, and of feeds listed as
+/// follows or followers on a profile page.
+const PAGE_SIZE: usize = 50;
+
+/// Style sheet of the pages.
+const STYLE: &str = "\
+    body { max-width: 50em; margin: auto; padding: 1em; font-family: sans-serif; } \
+    article { border-top: 1px solid #ccc; padding: 0.5em 0; } \
+    header { color: #666; font-size: 0.9em; } \
+    img { max-width: 100%; } \
+    code { word-break: break-all; }";
+
+/// Renderer of the pages, caching the names of the authors of the posts
+/// it renders.
+struct Viewer<'a> {
+    db: &'a KvStorage,
+    names: HashMap<String, String>,
+}
+
+/// Render the home page: the local identity and the channels with posts.
+pub fn index(db: &KvStorage, local_id: &str) -> Result<String> {
+    let mut body = format!(
+        "<h1>solar</h1>\n<p>Local identity: <a href=\"{}\">{}</a></p>\n<p>{} feeds stored</p>\n",
+        view_url("profile", "id", local_id),
+        escape(local_id),
+        db.author_count()?
+    );
+
+    body.push_str("<h2>Channels</h2>\n<ul>\n");
+    for (channel, count) in db.indexes().get_channels()? {
+        body.push_str(&format!(
+            "<li><a href=\"{}\">#{}</a> ({})</li>\n",
+            view_url("channel", "name", &channel),
+            escape(&channel),
+            count
+        ));
+    }
+    body.push_str("</ul>\n");
+
+    Ok(page("solar", &body))
+}
+
+/// Render the profile of a feed, with its follows, followers and latest
+/// posts. Returns `None` if no message of the feed is stored or the feed
+/// is muted.
+pub fn profile(db: &KvStorage, feed_id: &str) -> Result<Option<String>> {
+    let posts = match atom::latest_posts(db, feed_id)? {
+        Some(feed_posts) => feed_posts.posts,
+        None => return Ok(None),
+    };
+    let mut viewer = Viewer::new(db);
+    let profile = atom::profile(db, feed_id, false)?;
+    let name = profile.name.as_deref().unwrap_or(feed_id);
+
+    let mut body = format!("<h1>{}</h1>\n", escape(name));
+    if let Some(image) = &profile.image {
+        body.push_str(&format!(
+            "<img src=\"{}\" alt=\"\" width=\"128\">\n",
+            escape(&atom::blob_url(image.as_str().into(), ""))
+        ));
+    }
+    body.push_str(&format!("<p><code>{}</code></p>\n", escape(feed_id)));
+    if let Some(description) = &profile.description {
+        body.push_str(&atom::markdown_to_html_with(description, link));
+    }
+
+    let indexes = db.indexes();
+    body.push_str(&feed_list("Follows", &indexes.get_follows(feed_id)?));
+    body.push_str(&feed_list("Followers", &indexes.get_followers(feed_id)?));
+
+    body.push_str("<h2>Latest posts</h2>\n");
+    for post in &posts {
+        body.push_str(&viewer.render_post(&post.key, &post.value)?);
+    }
+
+    Ok(Some(page(name, &body)))
+}
+
+/// Render the thread starting at the given message, oldest message first.
+/// Returns `None` if no message of the thread is stored.
+pub fn thread(db: &KvStorage, root: &str) -> Result<Option<String>> {
+    let mut msgs = Vec::new();
+    for msg_ref in std::iter::once(root.to_string()).chain(db.indexes().get_thread(root)?) {
+        if let Some(msg_kvt) = get_msg_kvt(db, &msg_ref)? {
+            msgs.push(msg_kvt);
+        }
+    }
+    if msgs.is_empty() {
+        return Ok(None);
+    }
+    let ordering_timestamp = |msg_kvt: &MessageKvt| {
+        clock_skew::ordering_timestamp(atom::timestamp(msg_kvt), msg_kvt.timestamp)
+    };
+    msgs.sort_by(|a, b| ordering_timestamp(a).total_cmp(&ordering_timestamp(b)));
+
+    let mut viewer = Viewer::new(db);
+    let mut body = String::from("<h1>Thread</h1>\n");
+    if msgs[0].key != root {
+        body.push_str(&format!(
+            "<p>The first message, <code>{}</code>, is not stored.</p>\n",
+            escape(root)
+        ));
+    }
+    for msg_kvt in &msgs {
+        body.push_str(&viewer.render_post(&msg_kvt.key, &msg_kvt.value)?);
+    }
+
+    Ok(Some(page("Thread", &body)))
+}
+
+/// Render the latest posts in the given channel, newest first.
+pub fn channel(db: &KvStorage, channel: &str) -> Result<String> {
+    let channel = channel.trim_start_matches('#');
+    let mut viewer = Viewer::new(db);
+    let mut body = format!("<h1>#{}</h1>\n", escape(channel));
+    for msg_ref in db.indexes().get_channel(channel, PAGE_SIZE)? {
+        if let Some(msg_kvt) = get_msg_kvt(db, &msg_ref)? {
+            body.push_str(&viewer.render_post(&msg_kvt.key, &msg_kvt.value)?);
+        }
+    }
+
+    Ok(page(&format!("#{channel}"), &body))
+}
+
+impl<'a> Viewer<'a> {
+    fn new(db: &'a KvStorage) -> Self {
+        Self {
+            db,
+            names: HashMap::new(),
+        }
+    }
+
+    /// Return the name of the given feed, or its ID if it has none.
+    fn name(&mut self, feed_id: &str) -> Result<String> {
+        if let Some(name) = self.names.get(feed_id) {
+            return Ok(name.clone());
+        }
+
+        let name = atom::profile(self.db, feed_id, true)?
+            .name
+            .unwrap_or_else(|| feed_id.to_string());
+        self.names.insert(feed_id.to_string(), name.clone());
+
+        Ok(name)
+    }
+
+    /// Render the given message if it is a public post by a feed which is
+    /// not muted.
+    fn render_post(&mut self, msg_ref: &str, msg_val: &Value) -> Result<String> {
+        let content = &msg_val["content"];
+        let (author, text) = match (msg_val["author"].as_str(), content["text"].as_str()) {
+            (Some(author), Some(text)) if content["type"] == "post" => (author, text),
+            _ => return Ok(String::new()),
+        };
+        if self.db.mutes().is_muted(author)? {
+            return Ok(String::new());
+        }
+
+        let mut header = format!(
+            "<a href=\"{}\">{}</a> · {}",
+            view_url("profile", "id", author),
+            escape(&self.name(author)?),
+            atom::rfc3339(msg_val["timestamp"].as_f64().unwrap_or(0.0))
+        );
+        if let Some(channel) = content["channel"].as_str() {
+            header.push_str(&format!(
+                " · <a href=\"{}\">#{}</a>",
+                view_url("channel", "name", channel.trim_start_matches('#')),
+                escape(channel.trim_start_matches('#'))
+            ));
+        }
+        let root = content["root"].as_str().unwrap_or(msg_ref);
+        header.push_str(&format!(
+            " · <a href=\"{}\">thread</a>",
+            view_url("thread", "id", root)
+        ));
+
+        Ok(format!(
+            "<article>\n<header>{}</header>\n{}</article>\n",
+            header,
+            atom::markdown_to_html_with(text, link)
+        ))
+    }
+}
+
+/// Return the message with the given ID, if it is stored.
+fn get_msg_kvt(db: &KvStorage, msg_ref: &str) -> Result<Option<MessageKvt>> {
+    match db.get_msg_val(msg_ref)? {
+        Some(msg) => db.get_msg_kvt(msg.author(), msg.sequence()),
+        None => Ok(None),
+    }
+}
+
+/// Render a list of feeds, up to `PAGE_SIZE`.
+fn feed_list(title: &str, feed_ids: &[String]) -> String {
+    let mut list = format!("<h2>{} ({})</h2>\n<ul>\n", title, feed_ids.len());
+    for feed_id in feed_ids.iter().take(PAGE_SIZE) {
+        list.push_str(&format!(
+            "<li><a href=\"{}\"><code>{}</code></a></li>\n",
+            view_url("profile", "id", feed_id),
+            escape(feed_id)
+        ));
+    }
+    list.push_str("</ul>\n");
+
+    list
+}
+
+/// Rewrite a link target in a message to the page showing it: the profile
+/// of a feed, the thread of a message, a channel or a blob.
+fn link(dest: CowStr) -> CowStr {
+    if dest.starts_with('@') {
+        view_url("profile", "id", &dest).into()
+    } else if dest.starts_with('%') {
+        view_url("thread", "id", &dest).into()
+    } else if let Some(channel) = dest.strip_prefix('#') {
+        view_url("channel", "name", channel).into()
+    } else {
+        atom::blob_url(dest, "")
+    }
+}
+
+/// Return the URL of a page of the viewer.
+fn view_url(page: &str, param: &str, value: &str) -> String {
+    let value: String = form_urlencoded::byte_serialize(value.as_bytes()).collect();
+    format!("/view/{page}?{param}={value}")
+}
+
+/// Wrap the body of a page in an HTML document.
+fn page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n\
+         <style>{}</style>\n</head>\n<body>\n<nav><a href=\"/view\">home</a></nav>\n{}</body>\n</html>\n",
+        escape(title),
+        STYLE,
+        body
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::testing::TestNode;
+
+    #[async_std::test]
+    async fn test_viewer() -> Result<()> {
+        let node = TestNode::new()?;
+        let id = &node.id.id;
+
+        node.publish(json!({ "type": "about", "about": id, "name": "<sol>" }))
+            .await?;
+        let root = node
+            .publish(json!({ "type": "post", "text": "hi #solar", "channel": "solar" }))
+            .await?;
+        let root_ref = root.id().to_string();
+        node.publish(json!({
+            "type": "post",
+            "text": format!("[@sol]({id}) replying [here](javascript:alert(1))"),
+            "root": root_ref,
+        }))
+        .await?;
+        node.kv.commit_indexes().await?;
+
+        let index = index(&node.kv, id)?;
+        assert!(index.contains("<a href=\"/view/channel?name=solar\">#solar</a> (1)"));
+
+        let profile = profile(&node.kv, id)?.unwrap();
+        assert!(profile.contains("<h1>&lt;sol&gt;</h1>"));
+        assert_eq!(profile.matches("<article>").count(), 2);
+        assert_eq!(super::profile(&node.kv, "@unknown")?, None);
+
+        let thread = thread(&node.kv, &root_ref)?.unwrap();
+        assert_eq!(thread.matches("<article>").count(), 2);
+        assert!(thread.find("hi").unwrap() < thread.find("replying").unwrap());
+        assert!(thread.contains(&format!(
+            "<a href=\"{}\">@sol</a>",
+            view_url("profile", "id", id)
+        )));
+        // Links which could run code on the page are reduced to their text.
+        assert!(!thread.contains("javascript:"));
+        assert!(thread.contains("replying here"));
+
+        let channel = channel(&node.kv, "#solar")?;
+        assert_eq!(channel.matches("<article>").count(), 1);
+
+        Ok(())
+    }
+}
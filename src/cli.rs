@@ -2,6 +2,8 @@ use std::path::PathBuf;
 
 use structopt::StructOpt;
 
+use crate::api::graph::GraphFormat;
+
 /// Generate a command line parser.
 /// This defines the options that are exposed when running the solar binary.
 #[derive(StructOpt, Debug)]
@@ -36,16 +38,138 @@ pub struct Cli {
     #[structopt(short, long)]
     pub lan: Option<bool>,
 
+    /// Start a sync session with each known peer discovered on the LAN:
+    /// fetch every known feed from the peer before replicating as usual,
+    /// bypassing the first-sync and ingest limits (default: false)
+    #[structopt(long)]
+    pub lan_sync: Option<bool>,
+
     /// Run the JSON-RPC server (default: true)
     #[structopt(short, long)]
     pub jsonrpc: Option<bool>,
 
+    /// Add a feed to the local mute list and exit. Muted feeds are hidden
+    /// from JSON-RPC results; no contact message is published
+    #[structopt(long)]
+    pub mute: Option<String>,
+
+    /// Stop replicating the feed given with --mute (default: false)
+    #[structopt(long)]
+    pub mute_stop_replication: Option<bool>,
+
+    /// Remove a feed from the local mute list and exit
+    #[structopt(long)]
+    pub unmute: Option<String>,
+
+    /// Generate a new keypair for this run without reading or writing
+    /// `secret.toml`, e.g. to start several nodes of a local test network
+    /// from a single data directory template (default: false)
+    #[structopt(long, conflicts_with = "identity-seed")]
+    pub new_identity: Option<bool>,
+
+    /// Derive the keypair from the given 32 byte hex-encoded seed without
+    /// reading or writing `secret.toml`. Takes precedence over
+    /// `SOLAR_IDENTITY_SEED`
+    #[structopt(long)]
+    pub identity_seed: Option<String>,
+
     /// Resync the local database by requesting the local feed from peers
     #[structopt(long)]
     pub resync: Option<bool>,
 
+    /// Restore the local feed from peers, e.g. after restoring `secret.toml`
+    /// from a backup into an empty data directory. Publishing is held until
+    /// a peer has sent the feed, so that it is not forked (default: false)
+    #[structopt(long)]
+    pub restore_identity: Option<bool>,
+
+    /// Replicate and serve queries but refuse to publish or otherwise modify
+    /// the local feed, e.g. for archive mirrors or to inspect a copied data
+    /// directory (default: false)
+    #[structopt(long)]
+    pub read_only: Option<bool>,
+
+    /// Rebuild the global order index of the local database from the stored
+    /// feeds, print a consistency report and exit (default: false)
+    #[structopt(long)]
+    pub rebuild_global_order: Option<bool>,
+
+    /// Compact the local database to reclaim the disk space taken by
+    /// deleted and overwritten entries, print the space recovered and exit
+    /// (default: false)
+    #[structopt(long)]
+    pub compact_db: Option<bool>,
+
+    /// Print the connection audit log of the local database to stdout as
+    /// JSON lines, newest first, and exit (default: false)
+    #[structopt(long)]
+    pub export_connection_log: Option<bool>,
+
+    /// Export the follow and block graph of the local database to stdout in
+    /// the given format (`dot` or `graphml`) and exit
+    #[structopt(long)]
+    pub export_graph: Option<GraphFormat>,
+
+    /// Replace feed IDs with anonymous labels when exporting the graph
+    /// (default: false)
+    #[structopt(long)]
+    pub anonymize_graph: Option<bool>,
+
+    /// Export the public messages of the authors who consented with a
+    /// message of the given type (e.g. `research-consent`) to stdout as JSON
+    /// lines, with references to other feeds anonymized, and exit
+    #[structopt(long)]
+    pub export_dataset: Option<String>,
+
+    /// Import the blobs of a legacy blob store (e.g. `~/.ssb/blobs`),
+    /// verifying their hashes, so that they are not fetched again from
+    /// peers, and exit
+    #[structopt(long, parse(from_os_str))]
+    pub import_blobs: Option<PathBuf>,
+
+    /// Only connect to and replicate the given feed (e.g.
+    /// `@...=.ed25519`) and the feeds it follows, for closed communities
+    /// running on a custom network key
+    #[structopt(long)]
+    pub allow_list: Option<String>,
+
+    /// Run as an archive node: replicate every feed offered by connected
+    /// peers or known from stored messages regardless of the follow graph,
+    /// except those blocked by the local identity. Implies
+    /// `--selective false` (default: false)
+    #[structopt(long)]
+    pub archivist: Option<bool>,
+
     /// Only replicate with peers whose public keys are stored in
     /// `replication.toml` (default: true)
     #[structopt(short, long)]
     pub selective: Option<bool>,
+
+    /// Automatically follow back and replicate peers who follow the local
+    /// identity, as expected of a pub (default: false)
+    #[structopt(long)]
+    pub follow_back: Option<bool>,
+
+    /// Run a bot which answers commands starting with the given prefix
+    /// (e.g. `!`) in posts mentioning the local identity (default: disabled)
+    #[structopt(long)]
+    pub bot_prefix: Option<String>,
+
+    /// On first sync, fetch up to the given number of messages from each
+    /// directly followed feed which has no stored messages, before
+    /// requesting full history of any feed (default: disabled)
+    #[structopt(long)]
+    pub first_sync: Option<u64>,
+
+    /// Start with replication paused with every peer, e.g. on a metered
+    /// connection, until it is resumed with the `resumeReplication`
+    /// JSON-RPC method (default: false)
+    #[structopt(long)]
+    pub pause_replication: Option<bool>,
+
+    /// Run in network simulation mode: disable LAN discovery and print a
+    /// machine-readable line once the TCP server is listening
+    /// (default: false)
+    #[structopt(long)]
+    pub netsim: Option<bool>,
 }
@@ -1,23 +1,34 @@
-use std::{collections::HashMap, env, path::PathBuf};
+use std::{collections::HashMap, env, path::PathBuf, time::Duration};
 
 use async_std::{
     fs::File,
     io::{ReadExt, WriteExt},
+    sync::RwLock,
+};
+use kuska_sodiumoxide::crypto::{
+    auth::Key as NetworkKey,
+    sign::ed25519::{self, Seed},
 };
-use kuska_sodiumoxide::crypto::auth::Key as NetworkKey;
 use kuska_ssb::{
     crypto::{ed25519::PublicKey, ToSodiumObject, ToSsbId},
     discovery,
     keystore::OwnedIdentity,
 };
-use log::{debug, info};
+use log::{debug, info, warn};
 use once_cell::sync::OnceCell;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sled::Config as KvConfig;
 use structopt::StructOpt;
 use url::Url;
 
-use crate::{cli::Cli, Result};
+use crate::{
+    actors::{exec_hooks, replication_pause::REPLICATION_PAUSE},
+    api::{graph::GraphFormat, schema::SCHEMAS},
+    cli::Cli,
+    error::Error,
+    logging, metafeed, uri, Result,
+};
 
 // Define the default IP used for TCP connections (boxstream and MUXRPC).
 const MUXRPC_IP: &str = "0.0.0.0";
@@ -27,11 +38,61 @@ const MUXRPC_PORT: u16 = 8008;
 const JSONRPC_IP: &str = "127.0.0.1";
 // Define the default port used for the JSON-RPC server.
 const JSONRPC_PORT: u16 = 3030;
+// Define the interval in milliseconds between database flushes in archivist
+// mode.
+const ARCHIVIST_FLUSH_INTERVAL_MS: u64 = 5000;
+// Define the default maximum number of concurrent blob requests per peer.
+pub const DEFAULT_BLOB_FETCH_CONCURRENCY: usize = 4;
+// Define the default number of seconds by which the timestamp claimed by a
+// message may be later than its receipt before it is ordered by its receipt.
+pub const DEFAULT_MAX_CLOCK_SKEW: u64 = 600;
+// Define the default maximum number of secret handshakes in progress.
+const DEFAULT_MAX_HANDSHAKES: usize = 32;
+// Define the default maximum number of secret handshakes in progress with a
+// single IP address.
+const DEFAULT_MAX_HANDSHAKES_PER_IP: usize = 4;
+// Define the default number of seconds for which an author exceeding the
+// ingest rate limits is throttled.
+const DEFAULT_INGEST_THROTTLE_SECS: u64 = 600;
+// Define the default minimum free disk space in bytes, below which the node
+// runs in degraded mode.
+const DEFAULT_MIN_FREE_SPACE: u64 = 256 * 1024 * 1024;
+// Define the default user name of the ActivityPub actor.
+const DEFAULT_ACTIVITYPUB_USER: &str = "solar";
 
+// Write once store for the ActivityPub actor of the local identity, if the
+// ActivityPub bridge is enabled.
+pub static ACTIVITYPUB_CONFIG: OnceCell<Option<ActivityPubConfig>> = OnceCell::new();
+// Write once store for the allow-list admin feed, if allow-list mode is
+// enabled.
+pub static ALLOW_LIST_CONFIG: OnceCell<Option<String>> = OnceCell::new();
+// Write once store for the archivist replication profile configuration.
+pub static ARCHIVIST_CONFIG: OnceCell<bool> = OnceCell::new();
+// Write once store for the blob fetching policy.
+pub static BLOB_CONFIG: OnceCell<BlobConfig> = OnceCell::new();
+// Write once store for the number of seconds by which a claimed timestamp may
+// be later than the receipt of its message.
+pub static CLOCK_SKEW_CONFIG: OnceCell<u64> = OnceCell::new();
+// Write once store for the number of messages fetched from each direct
+// follow on first sync, if the first-sync fast path is enabled.
+pub static FIRST_SYNC_CONFIG: OnceCell<Option<u64>> = OnceCell::new();
+// Write once store for the limits on inbound secret handshakes.
+pub static HANDSHAKE_CONFIG: OnceCell<HandshakeConfig> = OnceCell::new();
+// Write once store for the bearer token of the message import endpoint, if
+// the endpoint is enabled.
+pub static IMPORT_CONFIG: OnceCell<Option<String>> = OnceCell::new();
+// Write once store for the per-author ingest rate limits.
+pub static INGEST_CONFIG: OnceCell<IngestConfig> = OnceCell::new();
+// Write once store for the maximum message sizes overriding the defaults of
+// the feed formats, keyed by format name.
+pub static MSG_SIZE_CONFIG: OnceCell<HashMap<String, usize>> = OnceCell::new();
 // Write once store for the network key (aka. SHS key or caps key).
 pub static NETWORK_KEY: OnceCell<NetworkKey> = OnceCell::new();
+// Write once store for the read-only mode configuration.
+pub static READ_ONLY_CONFIG: OnceCell<bool> = OnceCell::new();
 // Write once store for the list of Scuttlebutt peers to replicate.
-pub static REPLICATION_CONFIG: OnceCell<ReplicationConfig> = OnceCell::new();
+// The list itself may be updated at runtime (e.g. by the follow-back policy).
+pub static REPLICATION_CONFIG: OnceCell<RwLock<ReplicationConfig>> = OnceCell::new();
 // Write once store for the database resync configuration.
 pub static RESYNC_CONFIG: OnceCell<bool> = OnceCell::new();
 // Write-once store for the public-private keypair.
@@ -39,32 +100,158 @@ pub static SECRET_CONFIG: OnceCell<SecretConfig> = OnceCell::new();
 
 /// Application configuration for solar.
 pub struct ApplicationConfig {
+    /// Domain and user name under which the local identity is exposed as an
+    /// ActivityPub actor (default: disabled).
+    pub activitypub: Option<ActivityPubConfig>,
+
+    /// Only connect to and replicate this feed and the feeds it follows
+    /// (default: disabled).
+    pub allow_list: Option<String>,
+
+    /// Replace feed IDs with anonymous labels when exporting the contact
+    /// graph (default: false).
+    pub anonymize_graph: bool,
+
+    /// Replicate every feed known from connected peers and stored messages,
+    /// regardless of the follow graph (default: false).
+    pub archivist: bool,
+
+    /// Path to the directory in which backups are stored.
+    pub backup_folder: PathBuf,
+
+    /// Interval in seconds between automatic backups. Backups are disabled
+    /// if this is set to 0 (default: 0).
+    pub backup_interval: u64,
+
+    /// Number of backups to retain before the oldest is deleted (default: 7).
+    pub backup_retention: usize,
+
     /// Root data directory.
     pub base_path: PathBuf,
 
+    /// Maximum number of blobs requested concurrently from a single peer
+    /// (default: 4).
+    pub blob_fetch_concurrency: usize,
+
+    /// Maximum hops distance (in the follow graph) of the author of a
+    /// message for blobs it references to be fetched automatically
+    /// (default: unlimited).
+    pub blob_max_hops: Option<usize>,
+
+    /// Maximum size in bytes of blobs to be fetched automatically
+    /// (default: unlimited).
+    pub blob_max_size: Option<u64>,
+
     /// Path to the blobstore.
     pub blobs_folder: PathBuf,
 
+    /// Prefix of the commands answered by the bot, which is disabled if not
+    /// set.
+    pub bot_prefix: Option<String>,
+
+    /// Compact the database and exit (default: false).
+    pub compact_db: bool,
+
+    /// Interval in seconds between automatic database compactions.
+    /// Compaction is disabled if this is set to 0 (default: 0).
+    pub compact_interval: u64,
+
     /// Peer(s) to connect to over TCP.
     /// Data includes a URL for each peer connection. Multiple URLs may appear
     /// as a comma-separated list (no spaces).
     pub connect: Option<String>,
 
+    /// Print the connection audit log and exit (default: false).
+    pub export_connection_log: bool,
+
+    /// Export the public messages of the authors who consented with a
+    /// message of the given type and exit.
+    pub export_dataset: Option<String>,
+
+    /// Export the contact graph in the given format and exit.
+    pub export_graph: Option<GraphFormat>,
+
+    /// Import the blobs of the legacy blob store in the given directory and
+    /// exit.
+    pub import_blobs: Option<PathBuf>,
+
     /// Path to the feed store.
     pub feeds_folder: PathBuf,
 
+    /// Number of messages fetched from each direct follow with no stored
+    /// messages before full history is requested (default: disabled).
+    pub first_sync: Option<u64>,
+
+    /// Automatically follow back and replicate peers who follow the local
+    /// identity (default: false).
+    pub follow_back: bool,
+
+    /// Hex-encoded seed from which the keypair is derived, instead of
+    /// reading `secret.toml`.
+    pub identity_seed: Option<String>,
+
+    /// Bearer token authorizing requests to the message import endpoint,
+    /// which is disabled if not set.
+    pub import_token: Option<String>,
+
+    /// Maximum number of bytes of messages accepted per hour from a single
+    /// feed which is not followed. No limit if set to 0 (default: 0).
+    pub ingest_bytes_per_hour: u64,
+
+    /// Maximum number of messages accepted per minute from a single feed
+    /// which is not followed. No limit if set to 0 (default: 0).
+    pub ingest_msgs_per_minute: usize,
+
+    /// Number of seconds for which a feed exceeding the ingest rate limits
+    /// is throttled (default: 600).
+    pub ingest_throttle_secs: u64,
+
     /// Run the JSON-RPC server (default: true).
     pub jsonrpc: bool,
 
     /// JSON-RPC IP and port to bind (default: 127.0.0.1:3030).
     pub jsonrpc_addr: String,
 
+    /// Number of threads serving JSON-RPC requests (default: 1).
+    pub jsonrpc_threads: usize,
+
     /// Sled key-value cache capacity.
     pub kv_cache_capacity: u64,
 
+    /// Number of seconds by which the timestamp claimed by a message may be
+    /// later than the time it was received; feeds exceeding it are flagged
+    /// and their messages ordered by their receipt (default: 600).
+    pub max_clock_skew: u64,
+
+    /// Maximum number of inbound secret handshakes in progress (default: 32).
+    pub max_handshakes: usize,
+
+    /// Maximum number of inbound secret handshakes in progress with a single
+    /// IP address (default: 4).
+    pub max_handshakes_per_ip: usize,
+
     /// Run LAN discovery (default: false).
     pub lan_discov: bool,
 
+    /// Start a sync session with known peers discovered on the LAN
+    /// (default: false).
+    pub lan_sync: bool,
+
+    /// Maximum message sizes in bytes, keyed by feed format name, overriding
+    /// the defaults of the formats.
+    pub max_msg_sizes: HashMap<String, usize>,
+
+    /// Free disk space in bytes below which blob fetching and the
+    /// replication of feeds which are not followed are paused. The check is
+    /// disabled if this is set to 0 (default: 256 MiB).
+    pub min_free_space: u64,
+
+    /// Feed to add to the local mute list before exiting.
+    pub mute: Option<String>,
+
+    /// Stop replicating the feed added to the mute list (default: false).
+    pub mute_stop_replication: bool,
+
     /// MUXRPC address (default: 0.0.0.0:8008).
     pub muxrpc_addr: String,
 
@@ -74,19 +261,41 @@ pub struct ApplicationConfig {
     /// MUXRPC port to bind (default: 8008).
     pub muxrpc_port: u16,
 
+    /// Run in network simulation mode (default: false).
+    pub netsim: bool,
+
     /// Secret handshake HMAC key (aka. network key, caps key, SHS key).
     pub network_key: NetworkKey,
 
+    /// Generate a keypair for this run instead of reading `secret.toml`
+    /// (default: false).
+    pub new_identity: bool,
+
+    /// Start with replication paused with every peer (default: false).
+    pub pause_replication: bool,
+
+    /// Refuse to publish or otherwise modify the local feed (default: false).
+    pub read_only: bool,
+
+    /// Rebuild the global order index and exit (default: false).
+    pub rebuild_global_order: bool,
+
     /// List of peers to replicate; "connect" magic word means that peers
     /// specified with --connect are added to the replication list.
     pub replicate: Option<String>,
 
+    /// Restore the local feed from peers before publishing (default: false).
+    pub restore_identity: bool,
+
     /// Resync the local database by requesting the local feed from peers.
     pub resync: bool,
 
     /// Deny replication attempts from peers who are not defined in the
     /// replication configuration (default: true).
     pub selective_replication: bool,
+
+    /// Feed to remove from the local mute list before exiting.
+    pub unmute: Option<String>,
 }
 
 impl ApplicationConfig {
@@ -98,13 +307,40 @@ impl ApplicationConfig {
 
         // Retrieve application configuration parameters from the parsed CLI input.
         // Set defaults if options have not been provided.
-        let lan_discov = cli_args.lan.unwrap_or(false);
+        let netsim = cli_args.netsim.unwrap_or(false);
+        // LAN discovery is always disabled in network simulation mode.
+        let lan_discov = cli_args.lan.unwrap_or(false) && !netsim;
+        let lan_sync = cli_args.lan_sync.unwrap_or(false);
         let muxrpc_ip = cli_args.ip.unwrap_or_else(|| MUXRPC_IP.to_string());
         let muxrpc_port = cli_args.port.unwrap_or(MUXRPC_PORT);
         let muxrpc_addr = format!("{muxrpc_ip}:{muxrpc_port}");
         let jsonrpc = cli_args.jsonrpc.unwrap_or(true);
-        let resync = cli_args.resync.unwrap_or(false);
-        let selective_replication = cli_args.selective.unwrap_or(true);
+        let read_only = cli_args.read_only.unwrap_or(false);
+        // Resyncing writes to the local feed, so it is disabled in read-only
+        // mode.
+        let resync = cli_args.resync.unwrap_or(false) && !read_only;
+        // Likewise for restoring the local feed, which is only published to
+        // once it is complete.
+        let restore_identity = cli_args.restore_identity.unwrap_or(false) && !read_only;
+        let rebuild_global_order = cli_args.rebuild_global_order.unwrap_or(false);
+        let archivist = cli_args.archivist.unwrap_or(false);
+        // An archive node accepts connections from any peer.
+        let selective_replication = cli_args.selective.unwrap_or(true) && !archivist;
+        let follow_back = cli_args.follow_back.unwrap_or(false);
+        // An empty prefix would make every word of a mention a command.
+        let bot_prefix = cli_args.bot_prefix.filter(|prefix| !prefix.is_empty());
+        let new_identity = cli_args.new_identity.unwrap_or(false);
+        // A seed given on the command line takes precedence over the env var.
+        let identity_seed = cli_args
+            .identity_seed
+            .or_else(|| env::var("SOLAR_IDENTITY_SEED").ok());
+        // A first-sync limit of 0 disables the fast path.
+        let first_sync = cli_args.first_sync.filter(|limit| *limit > 0);
+        let anonymize_graph = cli_args.anonymize_graph.unwrap_or(false);
+        let export_connection_log = cli_args.export_connection_log.unwrap_or(false);
+        let compact_db = cli_args.compact_db.unwrap_or(false);
+        let mute_stop_replication = cli_args.mute_stop_replication.unwrap_or(false);
+        let pause_replication = cli_args.pause_replication.unwrap_or(false);
 
         // Set the JSON-RPC server IP address.
         // First check for an env var before falling back to the default.
@@ -119,6 +355,10 @@ impl ApplicationConfig {
             Err(_) => JSONRPC_PORT.to_string(),
         };
         let jsonrpc_addr = format!("{jsonrpc_ip}:{jsonrpc_port}");
+        let jsonrpc_threads: usize = match env::var("SOLAR_JSONRPC_THREADS") {
+            Ok(val) => val.parse().ok().filter(|threads| *threads > 0).unwrap_or(1),
+            Err(_) => 1,
+        };
 
         // Read KV database cache capacity setting from environment variable.
         // Define default value (1 GB) if env var is unset.
@@ -127,6 +367,97 @@ impl ApplicationConfig {
             Err(_) => 1000 * 1000 * 1000,
         };
 
+        // Read the automatic backup settings from environment variables.
+        // Backups are disabled unless an interval has been set.
+        let backup_interval: u64 = match env::var("SOLAR_BACKUP_INTERVAL") {
+            Ok(val) => val.parse().unwrap_or(0),
+            Err(_) => 0,
+        };
+        let backup_retention: usize = match env::var("SOLAR_BACKUP_RETENTION") {
+            Ok(val) => val.parse().unwrap_or(7),
+            Err(_) => 7,
+        };
+
+        // Read the automatic compaction interval from an environment
+        // variable. Compaction is disabled unless an interval has been set.
+        let compact_interval: u64 = match env::var("SOLAR_COMPACT_INTERVAL") {
+            Ok(val) => val.parse().unwrap_or(0),
+            Err(_) => 0,
+        };
+
+        // Read the blob fetching policy from environment variables.
+        // Blobs are fetched regardless of size or distance if unset.
+        let blob_max_size: Option<u64> = env::var("SOLAR_BLOB_MAX_SIZE")
+            .ok()
+            .and_then(|val| val.parse().ok());
+        let blob_max_hops: Option<usize> = env::var("SOLAR_BLOB_MAX_HOPS")
+            .ok()
+            .and_then(|val| val.parse().ok());
+        let max_clock_skew: u64 = match env::var("SOLAR_MAX_CLOCK_SKEW") {
+            Ok(val) => val.parse().unwrap_or(DEFAULT_MAX_CLOCK_SKEW),
+            Err(_) => DEFAULT_MAX_CLOCK_SKEW,
+        };
+        let max_handshakes: usize = match env::var("SOLAR_MAX_HANDSHAKES") {
+            Ok(val) => val.parse().unwrap_or(DEFAULT_MAX_HANDSHAKES),
+            Err(_) => DEFAULT_MAX_HANDSHAKES,
+        };
+        let max_handshakes_per_ip: usize = match env::var("SOLAR_MAX_HANDSHAKES_PER_IP") {
+            Ok(val) => val.parse().unwrap_or(DEFAULT_MAX_HANDSHAKES_PER_IP),
+            Err(_) => DEFAULT_MAX_HANDSHAKES_PER_IP,
+        };
+        // Ingest rate limits are disabled if unset.
+        let ingest_msgs_per_minute: usize = match env::var("SOLAR_INGEST_MSGS_PER_MINUTE") {
+            Ok(val) => val.parse().unwrap_or(0),
+            Err(_) => 0,
+        };
+        let ingest_bytes_per_hour: u64 = match env::var("SOLAR_INGEST_BYTES_PER_HOUR") {
+            Ok(val) => val.parse().unwrap_or(0),
+            Err(_) => 0,
+        };
+        let ingest_throttle_secs: u64 = match env::var("SOLAR_INGEST_THROTTLE_SECS") {
+            Ok(val) => val.parse().unwrap_or(DEFAULT_INGEST_THROTTLE_SECS),
+            Err(_) => DEFAULT_INGEST_THROTTLE_SECS,
+        };
+        // Maximum message sizes are given as a comma-separated list of
+        // `<format>=<bytes>` pairs, e.g. `classic=16384,buttwoo=65536`.
+        let mut max_msg_sizes = HashMap::new();
+        if let Ok(val) = env::var("SOLAR_MAX_MSG_SIZE") {
+            for pair in val.split(',').filter(|pair| !pair.is_empty()) {
+                match pair
+                    .split_once('=')
+                    .map(|(format, size)| (format, size.parse()))
+                {
+                    Some((format, Ok(size))) => {
+                        max_msg_sizes.insert(format.trim().to_string(), size);
+                    }
+                    _ => warn!("ignoring invalid maximum message size {}", pair),
+                }
+            }
+        }
+        let min_free_space: u64 = match env::var("SOLAR_MIN_FREE_SPACE") {
+            Ok(val) => val.parse().unwrap_or(DEFAULT_MIN_FREE_SPACE),
+            Err(_) => DEFAULT_MIN_FREE_SPACE,
+        };
+        let blob_fetch_concurrency: usize = match env::var("SOLAR_BLOB_FETCH_CONCURRENCY") {
+            Ok(val) => val.parse().unwrap_or(DEFAULT_BLOB_FETCH_CONCURRENCY),
+            Err(_) => DEFAULT_BLOB_FETCH_CONCURRENCY,
+        };
+        // The ActivityPub bridge is enabled by setting the public domain of
+        // the HTTP server.
+        let activitypub = match env::var("SOLAR_ACTIVITYPUB_DOMAIN") {
+            Ok(domain) => Some(ActivityPubConfig {
+                domain,
+                user: env::var("SOLAR_ACTIVITYPUB_USER")
+                    .unwrap_or_else(|_| DEFAULT_ACTIVITYPUB_USER.to_string()),
+            }),
+            Err(_) => None,
+        };
+        // The message import endpoint is enabled by setting the token which
+        // authorizes requests to it.
+        let import_token = env::var("SOLAR_IMPORT_TOKEN")
+            .ok()
+            .filter(|token| !token.is_empty());
+
         // Define the default HMAC-SHA-512-256 key for secret handshakes.
         // This is also sometimes known as the SHS key, caps key or network key.
         let network_key = match env::var("SOLAR_NETWORK_KEY") {
@@ -142,25 +473,93 @@ impl ApplicationConfig {
         let base_path = cli_args
             .data
             .unwrap_or(xdg::BaseDirectories::new()?.create_data_directory("solar")?);
+        // The data of networks other than the main SSB network is kept in a
+        // directory of its own, so that switching networks never mixes feeds.
+        // The directory is named after a digest of the network key rather
+        // than the key itself, which is secret on private networks.
+        let base_path = if network_key == discovery::ssb_net_id() {
+            base_path
+        } else {
+            let digest = Sha256::digest(&network_key);
+            let network_path = base_path.join("networks").join(hex::encode(&digest[..8]));
+            // Earlier versions kept the data of every network in the data
+            // directory itself. Which network it belongs to can't be told
+            // before opening it, so rather than starting with a new identity
+            // and an empty database, the operator is asked to move it.
+            if !network_path.exists() && base_path.join("secret.toml").exists() {
+                return Err(Error::LegacyNetworkData {
+                    data_path: base_path,
+                    network_path,
+                });
+            }
+            std::fs::create_dir_all(&network_path)?;
+            network_path
+        };
 
         info!("Base directory is {:?}", base_path);
 
         let app_config = ApplicationConfig {
+            activitypub,
+            allow_list: cli_args.allow_list.as_deref().map(uri::normalize),
+            anonymize_graph,
+            archivist,
+            backup_folder: PathBuf::new(),
+            backup_interval,
+            backup_retention,
             base_path,
+            blob_fetch_concurrency,
+            blob_max_hops,
+            blob_max_size,
             blobs_folder: PathBuf::new(),
+            bot_prefix,
+            compact_db,
+            compact_interval,
             connect: cli_args.connect,
+            export_connection_log,
+            export_dataset: cli_args.export_dataset,
+            export_graph: cli_args.export_graph,
+            import_blobs: cli_args.import_blobs,
             feeds_folder: PathBuf::new(),
+            first_sync,
+            follow_back,
+            identity_seed,
+            import_token,
+            ingest_bytes_per_hour,
+            ingest_msgs_per_minute,
+            ingest_throttle_secs,
             jsonrpc,
             jsonrpc_addr,
+            jsonrpc_threads,
             kv_cache_capacity,
             lan_discov,
+            lan_sync,
+            max_clock_skew,
+            max_handshakes,
+            max_handshakes_per_ip,
+            max_msg_sizes,
+            min_free_space,
+            mute: cli_args.mute.as_deref().map(uri::normalize),
+            mute_stop_replication,
             muxrpc_ip,
             muxrpc_port,
             muxrpc_addr,
+            netsim,
             network_key,
-            replicate: cli_args.replicate,
+            new_identity,
+            pause_replication,
+            read_only,
+            rebuild_global_order,
+            replicate: cli_args.replicate.as_deref().map(|peers| {
+                peers
+                    .split(',')
+                    .map(uri::normalize)
+                    .collect::<Vec<String>>()
+                    .join(",")
+            }),
+            restore_identity,
             resync,
             selective_replication,
+            unmute: cli_args.unmute.as_deref().map(uri::normalize),
         };
 
         Ok(app_config)
@@ -193,13 +592,58 @@ impl ApplicationConfig {
         std::fs::create_dir_all(&feeds_folder)?;
         std::fs::create_dir_all(&blobs_folder)?;
 
+        // Define the directory name for backups.
+        // First check for an env var before falling back to the default.
+        let backup_folder = match env::var("SOLAR_BACKUP_DIR") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => application_config.base_path.join("backups"),
+        };
+
+        // Set the log levels of subsystems configured in the data directory.
+        let log_level_count =
+            logging::load_config(&application_config.base_path.join("logging.toml"))?;
+        if log_level_count > 0 {
+            info!("set the log level of {} subsystems", log_level_count);
+        }
+
+        // Load the JSON-RPC API tokens configured in the data directory.
+        #[cfg(feature = "jsonrpc")]
+        {
+            let token_count =
+                crate::api::tokens::load_config(&application_config.base_path.join("tokens.toml"))?;
+            if token_count > 0 {
+                info!("loaded {} API tokens", token_count);
+            }
+        }
+
+        // Load the commands to run on appended messages.
+        let hook_count =
+            exec_hooks::load_config(&application_config.base_path.join("hooks.toml"))?;
+        if hook_count > 0 {
+            info!("loaded {} exec hooks", hook_count);
+        }
+
+        // Register the message content schemas stored in the data directory.
+        let schema_count = SCHEMAS.load_dir(&application_config.base_path.join("schemas"))?;
+        if schema_count > 0 {
+            info!("registered {} message content schemas", schema_count);
+        }
+
+        application_config.backup_folder = backup_folder;
         application_config.blobs_folder = blobs_folder;
         application_config.feeds_folder = feeds_folder;
 
         // Define configuration parameters for KV database (Sled).
-        let kv_storage_config = KvConfig::new()
+        let mut kv_storage_config = KvConfig::new()
             .path(&application_config.feeds_folder)
             .cache_capacity(application_config.kv_cache_capacity);
+        // An archive grows without bound and is mostly appended to, so favour
+        // disk space over write latency and flush less often.
+        if application_config.archivist {
+            kv_storage_config = kv_storage_config
+                .mode(sled::Mode::LowSpace)
+                .flush_every_ms(Some(ARCHIVIST_FLUSH_INTERVAL_MS));
+        }
 
         // Server, host and public key details for peers to whom a connection
         // will be attempt.
@@ -246,13 +690,69 @@ impl ApplicationConfig {
         // Log the list of public keys identifying peers whose data will be replicated.
         debug!("peers to be replicated are {:?}", &replication_config.peers);
 
-        let secret_config = SecretConfig::configure(secret_key_file).await?;
+        // Generate a keypair for this run, or derive it from a seed if one has
+        // been supplied via CLI or env var. This allows throwaway or
+        // deterministic identities for network simulations and interop tests.
+        // In both cases the secret config file is neither read nor written.
+        let secret_config = if application_config.new_identity {
+            let secret_config = SecretConfig::create();
+            info!(
+                "generated a new identity for this run: {}",
+                secret_config.id
+            );
+            secret_config
+        } else {
+            match &application_config.identity_seed {
+                Some(seed) => SecretConfig::from_seed(
+                    &hex::decode(seed).expect("supplied identity seed is not valid hex"),
+                )
+                .expect("failed to derive a keypair from the supplied seed; check byte length"),
+                None => SecretConfig::configure(secret_key_file).await?,
+            }
+        };
         let owned_identity = secret_config.owned_identity()?;
 
+        // Set the value of the ActivityPub bridge configuration cell.
+        let _err = ACTIVITYPUB_CONFIG.set(application_config.activitypub.clone());
+        // Set the value of the allow-list configuration cell.
+        let _err = ALLOW_LIST_CONFIG.set(application_config.allow_list.clone());
+        // Set the value of the archivist replication profile cell.
+        let _err = ARCHIVIST_CONFIG.set(application_config.archivist);
+        // Set the value of the blob fetching policy cell.
+        let _err = BLOB_CONFIG.set(BlobConfig {
+            fetch_concurrency: application_config.blob_fetch_concurrency,
+            max_hops: application_config.blob_max_hops,
+            max_size: application_config.blob_max_size,
+        });
+        // Set the value of the maximum clock skew cell.
+        let _err = CLOCK_SKEW_CONFIG.set(application_config.max_clock_skew);
+        // Set the value of the first-sync configuration cell.
+        let _err = FIRST_SYNC_CONFIG.set(application_config.first_sync);
+        // Pause replication until it is resumed over JSON-RPC.
+        if application_config.pause_replication {
+            REPLICATION_PAUSE.lock().unwrap().pause(None);
+        }
+        // Set the value of the handshake limits cell.
+        let _err = HANDSHAKE_CONFIG.set(HandshakeConfig {
+            max_in_progress: application_config.max_handshakes,
+            max_per_ip: application_config.max_handshakes_per_ip,
+        });
+        // Set the value of the message import configuration cell.
+        let _err = IMPORT_CONFIG.set(application_config.import_token.clone());
+        // Set the value of the ingest rate limits cell.
+        let _err = INGEST_CONFIG.set(IngestConfig {
+            bytes_per_hour: application_config.ingest_bytes_per_hour,
+            msgs_per_minute: application_config.ingest_msgs_per_minute,
+            throttle: Duration::from_secs(application_config.ingest_throttle_secs),
+        });
+        // Set the value of the maximum message sizes cell.
+        let _err = MSG_SIZE_CONFIG.set(application_config.max_msg_sizes.clone());
         // Set the value of the network key (aka. secret handshake key or caps key).
         let _err = NETWORK_KEY.set(application_config.network_key.to_owned());
+        // Set the value of the read-only mode configuration cell.
+        let _err = READ_ONLY_CONFIG.set(application_config.read_only);
         // Set the value of the replication configuration cell.
-        let _err = REPLICATION_CONFIG.set(replication_config);
+        let _err = REPLICATION_CONFIG.set(RwLock::new(replication_config));
         // Set the value of the resync configuration cell.
         let _err = RESYNC_CONFIG.set(application_config.resync);
         // Set the value of the secret configuration cell.
@@ -269,9 +769,80 @@ impl ApplicationConfig {
     }
 }
 
+/// Size the async executor and its blocking pool from `SOLAR_WORKER_THREADS`
+/// and `SOLAR_BLOCKING_THREADS`, if set. Both are read when the runtime
+/// starts, so this must be called before anything runs on it.
+pub fn configure_executor() {
+    for (var, runtime_var) in [
+        ("SOLAR_WORKER_THREADS", "ASYNC_STD_THREAD_COUNT"),
+        ("SOLAR_BLOCKING_THREADS", "BLOCKING_MAX_THREADS"),
+    ]
+    .iter()
+    .copied()
+    {
+        if let Ok(val) = env::var(var) {
+            match val.parse::<usize>() {
+                Ok(threads) if threads > 0 => env::set_var(runtime_var, threads.to_string()),
+                _ => warn!("ignoring invalid thread count {}={}", var, val),
+            }
+        }
+    }
+}
+
+/// Public identity of the ActivityPub actor exposing the posts of the local
+/// identity.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "activitypub"), allow(dead_code))]
+pub struct ActivityPubConfig {
+    /// Domain at which the HTTP server is publicly reachable over HTTPS
+    /// (e.g. `solar.example.org`).
+    pub domain: String,
+    /// User name of the actor, as in `@<user>@<domain>`.
+    pub user: String,
+}
+
+/// Policy for automatically fetching blobs. Blobs which fall outside the
+/// policy are recorded as skipped and are only fetched on explicit request.
+#[derive(Debug, Default)]
+pub struct BlobConfig {
+    /// Maximum number of concurrent blob requests per peer.
+    pub fetch_concurrency: usize,
+    /// Maximum hops distance of the author of a message referencing a blob.
+    pub max_hops: Option<usize>,
+    /// Maximum size of a blob in bytes.
+    pub max_size: Option<u64>,
+}
+
+/// Limits on inbound secret handshakes, protecting the node from handshake
+/// floods. Connections beyond the limits are dropped before the handshake.
+#[derive(Debug)]
+pub struct HandshakeConfig {
+    /// Maximum number of handshakes in progress.
+    pub max_in_progress: usize,
+    /// Maximum number of handshakes in progress with a single IP address.
+    pub max_per_ip: usize,
+}
+
+/// Per-author ingest rate limits, protecting the node (typically an open
+/// pub) from feed spam. Limits of 0 are disabled.
+#[derive(Debug)]
+pub struct IngestConfig {
+    /// Maximum number of bytes accepted per hour from a single feed.
+    pub bytes_per_hour: u64,
+    /// Maximum number of messages accepted per minute from a single feed.
+    pub msgs_per_minute: usize,
+    /// Time for which a feed exceeding a limit is throttled.
+    pub throttle: Duration,
+}
+
 /// List of peers to be replicated.
 #[derive(Default, Serialize, Deserialize)]
 pub struct ReplicationConfig {
+    /// Public keys of feeds to replicate regardless of the follow graph
+    /// (e.g. a community announcements feed).
+    #[serde(default)]
+    pub pinned: Vec<String>,
+
     /// Peer data. Each entry includes a public key (key) and URL (value).
     /// The URL contains the host, port and public key of the peer's node.
     pub peers: HashMap<String, String>,
@@ -284,8 +855,17 @@ impl ReplicationConfig {
     }
 
     /// Deserialize a TOML byte slice into an instance of `ReplicationConfig`.
+    /// Feeds may be given as SSB URIs and are stored as sigil-encoded IDs.
     pub fn from_toml(s: &[u8]) -> Result<Self> {
-        Ok(toml::from_slice::<ReplicationConfig>(s)?)
+        let mut config = toml::from_slice::<ReplicationConfig>(s)?;
+        config.pinned = config.pinned.iter().map(|id| uri::normalize(id)).collect();
+        config.peers = config
+            .peers
+            .into_iter()
+            .map(|(id, url)| (uri::normalize(&id), url))
+            .collect();
+
+        Ok(config)
     }
 
     /// If the replication config file is not found, generate a new one and
@@ -309,6 +889,25 @@ impl ReplicationConfig {
         }
     }
 
+    /// Add a peer to the replication list and write the updated list to
+    /// file. Returns `false` if the peer was already in the list.
+    pub async fn add_peer(
+        &mut self,
+        peer_pk: &str,
+        replication_config_file: &PathBuf,
+    ) -> Result<bool> {
+        if self.peers.contains_key(peer_pk) {
+            return Ok(false);
+        }
+
+        // A blank `String` stands in place of the peer's URL.
+        self.peers.insert(peer_pk.to_string(), "".to_string());
+        let mut file = File::create(replication_config_file).await?;
+        file.write_all(&self.to_toml()?).await?;
+
+        Ok(true)
+    }
+
     /// Parse a list of peers to be replicated and peer connections to be
     /// attempted. Write the public keys of the replication peers to file
     /// if they are not already stored there.
@@ -378,6 +977,10 @@ pub struct SecretConfig {
     pub id: String,
     /// Private key.
     pub secret: String,
+    /// Hex-encoded seed from which the keys of the metafeed and its
+    /// sub-feeds are derived.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metafeed_seed: Option<String>,
 }
 
 impl SecretConfig {
@@ -388,9 +991,24 @@ impl SecretConfig {
         SecretConfig {
             id,
             secret: sk.to_ssb_id(),
+            metafeed_seed: Some(hex::encode(metafeed::generate_seed())),
         }
     }
 
+    /// Derive a public-private keypair from the given 32 byte seed.
+    /// The same seed always results in the same keypair. No metafeed seed
+    /// is set, since it could not be backed up.
+    pub fn from_seed(seed: &[u8]) -> Option<Self> {
+        let seed = Seed::from_slice(seed)?;
+        let (pk, sk) = ed25519::keypair_from_seed(&seed);
+
+        Some(SecretConfig {
+            id: format!("@{}", pk.to_ssb_id()),
+            secret: sk.to_ssb_id(),
+            metafeed_seed: None,
+        })
+    }
+
     /// Serialize an instance of `SecretConfig` as a TOML byte vector.
     pub fn to_toml(&self) -> Result<Vec<u8>> {
         Ok(toml::to_vec(&self)?)
@@ -401,6 +1019,13 @@ impl SecretConfig {
         Ok(toml::from_slice::<SecretConfig>(s)?)
     }
 
+    /// Decode the metafeed seed, if any.
+    pub fn metafeed_seed(&self) -> Option<Vec<u8>> {
+        self.metafeed_seed
+            .as_ref()
+            .and_then(|seed| hex::decode(seed).ok())
+    }
+
     /// Generate an `OwnedIdentity` from the public-private keypair.
     pub fn owned_identity(&self) -> Result<OwnedIdentity> {
         Ok(OwnedIdentity {
@@ -424,7 +1049,17 @@ impl SecretConfig {
             let mut file = File::open(&secret_key_file).await?;
             let mut raw: Vec<u8> = Vec::new();
             file.read_to_end(&mut raw).await?;
-            SecretConfig::from_toml(&raw)
+            let mut config = SecretConfig::from_toml(&raw)?;
+
+            // Files written by earlier versions have no metafeed seed.
+            if config.metafeed_seed.is_none() {
+                info!("adding a metafeed seed to {secret_key_file:?}");
+                config.metafeed_seed = Some(hex::encode(metafeed::generate_seed()));
+                let mut file = File::create(&secret_key_file).await?;
+                file.write_all(&config.to_toml()?).await?;
+            }
+
+            Ok(config)
         }
     }
 }
@@ -0,0 +1,70 @@
+//! Free disk space guard.
+//!
+//! The free space on the volume holding the data directory is checked
+//! periodically. Below the configured minimum, the node runs in a degraded
+//! mode: blobs are not fetched and messages from feeds which the local
+//! identity does not follow are dropped, keeping the space left for the
+//! local feed and its follows rather than failing mid-write once the volume
+//! is full.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use once_cell::sync::Lazy;
+
+/// The disk space guard for the solar node.
+pub static DISK_GUARD: Lazy<DiskGuard> = Lazy::new(DiskGuard::default);
+
+/// Most recently checked free disk space.
+#[derive(Default)]
+pub struct DiskGuard {
+    /// Free space in bytes. Zero until the first check.
+    free_space: AtomicU64,
+    /// Whether the free space is below the minimum.
+    low: AtomicBool,
+}
+
+impl DiskGuard {
+    /// Record the free space, given the minimum below which it is low.
+    /// Returns whether the free space is low.
+    pub fn update(&self, free_space: u64, min_free_space: u64) -> bool {
+        let low = free_space < min_free_space;
+        self.free_space.store(free_space, Ordering::Relaxed);
+        self.low.store(low, Ordering::Relaxed);
+
+        low
+    }
+
+    /// Whether the free space was below the minimum at the last check, in
+    /// which case the node runs in degraded mode.
+    pub fn is_low(&self) -> bool {
+        self.low.load(Ordering::Relaxed)
+    }
+
+    /// Free space in bytes at the last check, if any.
+    pub fn free_space(&self) -> Option<u64> {
+        match self.free_space.load(Ordering::Relaxed) {
+            0 if !self.is_low() => None,
+            free_space => Some(free_space),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_disk_guard() {
+        let guard = DiskGuard::default();
+        assert!(!guard.is_low());
+        assert_eq!(guard.free_space(), None);
+
+        assert!(guard.update(100, 1000));
+        assert!(guard.is_low());
+        assert_eq!(guard.free_space(), Some(100));
+
+        assert!(!guard.update(1000, 1000));
+        assert!(!guard.is_low());
+        assert_eq!(guard.free_space(), Some(1000));
+    }
+}
@@ -1,7 +1,9 @@
-use std::{fmt, io, net};
+use std::{fmt, io, net, path::PathBuf};
 
+#[cfg(feature = "jsonrpc")]
 use jsonrpc_http_server::jsonrpc_core;
 use kuska_ssb::{api, crypto, discovery, feed, handshake, rpc};
+use sled::transaction::TransactionError;
 use toml::{de, ser};
 
 /// Possible solar errors.
@@ -9,22 +11,64 @@ use toml::{de, ser};
 pub enum Error {
     /// IP address parsing error.
     AddrParse(net::AddrParseError),
+    /// A room alias could not be resolved or its registration is invalid.
+    Alias(String),
     /// xdg::BaseDirectoriesError.
     BaseDirectories(xdg::BaseDirectoriesError),
     /// SSB cryptograpy error.
     Crypto(crypto::Error),
     /// Sled database error.
     Database(sled::Error),
+    /// The database has not been opened.
+    DbUninitialised,
     /// Failed to deserialization TOML.
     DeserializeToml(de::Error),
+    /// The API token of a request is missing or does not grant the scope of
+    /// the method called.
+    Forbidden(String),
+    /// Message content does not follow the SSB conventions.
+    InvalidContent(String),
+    /// Validation error; message does not reference the previous message
+    /// of the feed.
+    InvalidPrevious,
     /// Validation error; invalid message sequence number.
     InvalidSequence,
+    /// A secondary index references data which is not stored, or is
+    /// inconsistent; it can be rebuilt.
+    IndexCorrupt { index: &'static str },
     /// io::Error.
     Io(io::Error),
     /// LAN UDP discovery error.
     LanDiscovery(discovery::Error),
+    /// The data directory holds the data of a node, which may belong to the
+    /// custom network whose data is now kept in a directory of its own.
+    LegacyNetworkData {
+        data_path: PathBuf,
+        network_path: PathBuf,
+    },
     /// SSB RPC error.
     MuxRpc(rpc::Error),
+    /// The database was created for a different network key.
+    NetworkKeyMismatch,
+    /// A message exceeds the maximum size of its feed format.
+    MessageTooLarge {
+        format: &'static str,
+        size: usize,
+        max_size: usize,
+    },
+    /// The metafeed tree of the local identity cannot be created or
+    /// modified.
+    Metafeed(String),
+    /// A message which is expected to be stored is missing.
+    MissingMessage { author: String, seq: u64 },
+    /// Private message (box1) encryption error.
+    PrivateBox(String),
+    /// The node is running in read-only mode and the local feed cannot be
+    /// modified.
+    ReadOnly,
+    /// The local feed is being restored from peers and cannot be published
+    /// to until it is complete.
+    Restoring,
     /// Secret handshake error.
     SecretHandshake(handshake::async_std::Error),
     /// Serde CBOR error.
@@ -35,6 +79,10 @@ pub enum Error {
     SerializeToml(ser::Error),
     /// SSB API error.
     SsbApi(api::Error),
+    /// The feed format of a message is unknown or not yet supported.
+    UnsupportedFeedFormat(String),
+    /// The database was written by a newer version of solar.
+    UnsupportedSchemaVersion(u32),
     /// URL parsing error.
     UrlParse(url::ParseError),
     /// SSB message validation error.
@@ -49,23 +97,68 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Error::AddrParse(err) => write!(f, "failed to parse ip address: {err}"),
+            Error::Alias(err) => write!(f, "alias resolution error: {err}"),
             Error::BaseDirectories(err) => write!(f, "base directory error: {err}"),
             Error::Crypto(err) => write!(f, "ssb cryptographic error: {err}"),
             Error::Database(err) => write!(f, "key-value database error: {err}"),
+            Error::DbUninitialised => write!(f, "the key-value database has not been opened"),
             Error::DeserializeToml(err) => write!(f, "failed to deserialize toml: {err}"),
+            Error::Forbidden(err) => write!(f, "forbidden: {err}"),
+            Error::InvalidContent(err) => write!(f, "invalid message content: {err}"),
             // TODO: Attach context so we know the identity of the offending message.
+            Error::InvalidPrevious => write!(
+                f,
+                "validation error. message does not reference the previous message"
+            ),
             Error::InvalidSequence => write!(
                 f,
                 "validation error. message contains incorrect sequence number"
             ),
+            Error::IndexCorrupt { index } => write!(
+                f,
+                "the {index} index is corrupt; rebuild it to recover"
+            ),
             Error::Io(err) => write!(f, "i/o error: {err}"),
             Error::LanDiscovery(err) => write!(f, "lan udp discovery error: {err}"),
+            Error::LegacyNetworkData {
+                data_path,
+                network_path,
+            } => write!(
+                f,
+                "{data_path:?} holds the data of a node, which may belong to this network; \
+                 move it into {network_path:?} if it does, or create that directory to start \
+                 afresh on this network"
+            ),
+            Error::MessageTooLarge {
+                format,
+                size,
+                max_size,
+            } => write!(
+                f,
+                "message of {size} bytes exceeds the maximum size of {max_size} bytes for {format} feeds"
+            ),
+            Error::Metafeed(err) => write!(f, "metafeed error: {err}"),
+            Error::MissingMessage { author, seq } => {
+                write!(f, "message {seq} of feed {author} is missing")
+            }
             Error::MuxRpc(err) => write!(f, "muxrpc error: {err}"),
+            Error::NetworkKeyMismatch => {
+                write!(f, "the database was created for a different network key")
+            }
+            Error::PrivateBox(err) => write!(f, "private box error: {err}"),
+            Error::ReadOnly => write!(f, "the node is running in read-only mode"),
+            Error::Restoring => write!(f, "the local feed is being restored from peers"),
             Error::SecretHandshake(err) => write!(f, "secret handshake error: {err}"),
             Error::SerdeCbor(err) => write!(f, "serde cbor error: {err}"),
             Error::SerdeJson(err) => write!(f, "serde json error: {err}"),
             Error::SerializeToml(err) => write!(f, "failed to serialize toml: {err}"),
             Error::SsbApi(err) => write!(f, "ssb api error: {err}"),
+            Error::UnsupportedFeedFormat(format) => {
+                write!(f, "unsupported feed format: {format}")
+            }
+            Error::UnsupportedSchemaVersion(version) => {
+                write!(f, "unsupported database schema version: {version}")
+            }
             Error::UrlParse(err) => write!(f, "failed to parse url: {err}"),
             Error::Validation(err) => write!(f, "message validation error: {err}"),
             Error::Other(err) => write!(f, "uncategorized error: {err}"),
@@ -97,6 +190,14 @@ impl From<sled::Error> for Error {
     }
 }
 
+impl From<TransactionError<sled::Error>> for Error {
+    fn from(err: TransactionError<sled::Error>) -> Error {
+        match err {
+            TransactionError::Abort(err) | TransactionError::Storage(err) => Error::Database(err),
+        }
+    }
+}
+
 impl From<de::Error> for Error {
     fn from(err: de::Error) -> Error {
         Error::DeserializeToml(err)
@@ -166,6 +267,7 @@ impl From<feed::Error> for Error {
 // Conversions for errors which occur in the context of a JSON-RPC method call.
 // Crate-local error variants are converted to JSON-RPC errors which are
 // then return to the caller.
+#[cfg(feature = "jsonrpc")]
 impl From<Error> for jsonrpc_core::Error {
     fn from(err: Error) -> Self {
         match &err {
@@ -184,7 +286,73 @@ impl From<Error> for jsonrpc_core::Error {
                 message: err_msg.to_string(),
                 data: None,
             },
-            _ => todo!(),
+            Error::PrivateBox(err_msg) => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::ServerError(-32003),
+                message: err_msg.to_string(),
+                data: None,
+            },
+            Error::InvalidContent(err_msg) => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::ServerError(-32004),
+                message: err_msg.to_string(),
+                data: None,
+            },
+            Error::ReadOnly => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::ServerError(-32005),
+                message: err.to_string(),
+                data: None,
+            },
+            Error::Alias(err_msg) => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::ServerError(-32006),
+                message: err_msg.to_string(),
+                data: None,
+            },
+            Error::Metafeed(err_msg) => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::ServerError(-32007),
+                message: err_msg.to_string(),
+                data: None,
+            },
+            Error::MessageTooLarge { .. } => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::ServerError(-32008),
+                message: err.to_string(),
+                data: None,
+            },
+            Error::MissingMessage { .. } => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::ServerError(-32009),
+                message: err.to_string(),
+                data: None,
+            },
+            Error::IndexCorrupt { .. } => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::ServerError(-32010),
+                message: err.to_string(),
+                data: None,
+            },
+            Error::DbUninitialised => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::ServerError(-32011),
+                message: err.to_string(),
+                data: None,
+            },
+            Error::UnsupportedFeedFormat(_) => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::ServerError(-32012),
+                message: err.to_string(),
+                data: None,
+            },
+            Error::Restoring => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::ServerError(-32013),
+                message: err.to_string(),
+                data: None,
+            },
+            Error::Forbidden(_) => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::ServerError(-32014),
+                message: err.to_string(),
+                data: None,
+            },
+            // Other errors are internal to the node; they are reported with
+            // a single code rather than failing the request handler.
+            _ => jsonrpc_core::Error {
+                code: jsonrpc_core::ErrorCode::InternalError,
+                message: err.to_string(),
+                data: None,
+            },
         }
     }
 }
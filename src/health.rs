@@ -0,0 +1,106 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+
+use crate::{
+    actors::connection_manager::HANDSHAKE_LIMITER, broker::BROKER, disk::DISK_GUARD, storage::kv,
+    KV_STORAGE,
+};
+
+/// Runtime health state, updated by the actors as they run.
+pub static HEALTH: Lazy<Health> = Lazy::new(Health::default);
+
+/// Health state shared between actors.
+#[derive(Default)]
+pub struct Health {
+    /// Whether the TCP server is bound and accepting connections.
+    listening: AtomicBool,
+    /// Unix timestamp (seconds) of the most recent message received from a
+    /// peer. Zero if no message has been received since startup.
+    last_replication: AtomicU64,
+}
+
+/// Self-diagnostic report returned by the `/health` endpoint and the
+/// `health` JSON-RPC method.
+#[derive(Debug, Serialize)]
+pub struct HealthReport {
+    /// True if all checks passed.
+    pub healthy: bool,
+    /// The key-value database accepts writes.
+    pub storage_writable: bool,
+    /// The broker message loop is running.
+    pub broker_alive: bool,
+    /// The TCP server is accepting connections.
+    pub listening: bool,
+    /// The database indexes have been built. Missing indexes are built in
+    /// the background after startup; until then, queries may return
+    /// incomplete results and published messages are held in the outbox.
+    pub ready: bool,
+    /// Error of the latest attempt at building the indexes, if it failed.
+    /// The build is retried until it succeeds.
+    pub index_build_error: Option<String>,
+    /// The local feed is being restored from peers; messages published in
+    /// the meantime are held in the outbox.
+    pub restoring: bool,
+    /// Free disk space in bytes on the volume holding the data directory,
+    /// if it is monitored.
+    pub free_disk_space: Option<u64>,
+    /// Free disk space is below the minimum, so blob fetching and the
+    /// replication of feeds which are not followed are paused.
+    pub disk_space_low: bool,
+    /// Unix timestamp (seconds) of the most recent successful replication.
+    pub last_replication: Option<u64>,
+    /// Number of inbound secret handshakes in progress.
+    pub handshakes_in_progress: usize,
+    /// Number of inbound connections dropped since startup for exceeding
+    /// the handshake limits.
+    pub handshakes_refused: u64,
+}
+
+impl Health {
+    /// Set the listener status of the TCP server.
+    pub fn set_listening(&self, listening: bool) {
+        self.listening.store(listening, Ordering::Relaxed);
+    }
+
+    /// Record the current time as the time of the most recent successful
+    /// replication.
+    pub fn set_replicated(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.last_replication.store(now, Ordering::Relaxed);
+    }
+
+    /// Run the self-diagnostic checks and return a report.
+    pub async fn report(&self) -> HealthReport {
+        let storage_writable = KV_STORAGE.read().await.is_writable();
+        let broker_alive = !BROKER.lock().await.create_sender().is_closed();
+        let listening = self.listening.load(Ordering::Relaxed);
+        let index_build_error = kv::index_build_error();
+        let last_replication = match self.last_replication.load(Ordering::Relaxed) {
+            0 => None,
+            timestamp => Some(timestamp),
+        };
+
+        HealthReport {
+            healthy: storage_writable && broker_alive && listening && index_build_error.is_none(),
+            storage_writable,
+            broker_alive,
+            listening,
+            ready: kv::indexes_ready(),
+            index_build_error,
+            restoring: kv::is_restoring(),
+            free_disk_space: DISK_GUARD.free_space(),
+            disk_space_low: DISK_GUARD.is_low(),
+            last_replication,
+            handshakes_in_progress: HANDSHAKE_LIMITER.in_progress(),
+            handshakes_refused: HANDSHAKE_LIMITER.refused(),
+        }
+    }
+}
@@ -0,0 +1,163 @@
+//! Per-author ingest rate limits.
+//!
+//! Messages received from feeds which the local identity does not follow
+//! are counted per author. An author sending more messages per minute or
+//! more bytes per hour than allowed is throttled for a while: its messages
+//! are dropped and requested again once the throttle lapses.
+
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use once_cell::sync::Lazy;
+
+use crate::config::{IngestConfig, INGEST_CONFIG};
+
+/// Number of tracked authors beyond which idle entries are dropped.
+const MAX_TRACKED_AUTHORS: usize = 10_000;
+
+const MINUTE: Duration = Duration::from_secs(60);
+const HOUR: Duration = Duration::from_secs(60 * 60);
+
+/// Outcome of counting a received message against the limits.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// The message is within the limits.
+    Accept,
+    /// The message exceeds a limit; the author is throttled from now on.
+    Throttle(&'static str),
+    /// The author is already throttled.
+    Throttled,
+}
+
+/// Messages and bytes received from an author in the current windows.
+struct Usage {
+    minute_start: Instant,
+    msgs: usize,
+    hour_start: Instant,
+    bytes: u64,
+    throttled_until: Option<Instant>,
+}
+
+impl Usage {
+    fn new(now: Instant) -> Self {
+        Self {
+            minute_start: now,
+            msgs: 0,
+            hour_start: now,
+            bytes: 0,
+            throttled_until: None,
+        }
+    }
+}
+
+/// Limiter of the rate at which messages are accepted from each author.
+pub struct IngestLimiter {
+    msgs_per_minute: usize,
+    bytes_per_hour: u64,
+    throttle: Duration,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+/// The ingest limiter for the solar node.
+pub static INGEST_LIMITER: Lazy<IngestLimiter> =
+    Lazy::new(|| IngestLimiter::new(INGEST_CONFIG.get().unwrap()));
+
+impl IngestLimiter {
+    /// Instantiate a new `IngestLimiter` with the given limits.
+    pub fn new(config: &IngestConfig) -> Self {
+        Self {
+            msgs_per_minute: config.msgs_per_minute,
+            bytes_per_hour: config.bytes_per_hour,
+            throttle: config.throttle,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether any limit is set.
+    pub fn is_enabled(&self) -> bool {
+        self.msgs_per_minute > 0 || self.bytes_per_hour > 0
+    }
+
+    /// Count a message of `size` bytes by `author` received at `now`.
+    pub fn check(&self, author: &str, size: usize, now: Instant) -> Verdict {
+        let mut usage = self.usage.lock().unwrap();
+
+        if usage.len() >= MAX_TRACKED_AUTHORS {
+            usage.retain(|_, usage| {
+                now.duration_since(usage.hour_start) < HOUR
+                    || usage.throttled_until.is_some_and(|until| now < until)
+            });
+        }
+
+        let usage = usage
+            .entry(author.to_string())
+            .or_insert_with(|| Usage::new(now));
+
+        match usage.throttled_until {
+            Some(until) if now < until => return Verdict::Throttled,
+            Some(_) => *usage = Usage::new(now),
+            None => {}
+        }
+        if now.duration_since(usage.minute_start) >= MINUTE {
+            usage.minute_start = now;
+            usage.msgs = 0;
+        }
+        if now.duration_since(usage.hour_start) >= HOUR {
+            usage.hour_start = now;
+            usage.bytes = 0;
+        }
+        usage.msgs += 1;
+        usage.bytes += size as u64;
+
+        let reason = if self.msgs_per_minute > 0 && usage.msgs > self.msgs_per_minute {
+            "too many messages per minute"
+        } else if self.bytes_per_hour > 0 && usage.bytes > self.bytes_per_hour {
+            "too many bytes per hour"
+        } else {
+            return Verdict::Accept;
+        };
+        usage.throttled_until = Some(now + self.throttle);
+
+        Verdict::Throttle(reason)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ingest_limiter() {
+        let limiter = IngestLimiter::new(&IngestConfig {
+            bytes_per_hour: 1000,
+            msgs_per_minute: 2,
+            throttle: Duration::from_secs(600),
+        });
+        let start = Instant::now();
+
+        assert_eq!(limiter.check("@a", 10, start), Verdict::Accept);
+        assert_eq!(limiter.check("@a", 10, start), Verdict::Accept);
+        assert_eq!(
+            limiter.check("@a", 10, start),
+            Verdict::Throttle("too many messages per minute")
+        );
+        // Other authors are counted separately.
+        assert_eq!(limiter.check("@b", 10, start), Verdict::Accept);
+
+        // The throttle outlasts the rate window, then the author starts
+        // afresh.
+        assert_eq!(limiter.check("@a", 10, start + MINUTE), Verdict::Throttled);
+        let later = start + Duration::from_secs(600);
+        assert_eq!(limiter.check("@a", 10, later), Verdict::Accept);
+
+        // Bytes are counted over the hour.
+        assert_eq!(limiter.check("@b", 900, start + MINUTE), Verdict::Accept);
+        assert_eq!(
+            limiter.check("@b", 100, start + MINUTE * 2),
+            Verdict::Throttle("too many bytes per hour")
+        );
+    }
+}
@@ -0,0 +1,209 @@
+//! Log levels per subsystem.
+//!
+//! Logging is configured with `RUST_LOG` as usual. The level of each
+//! subsystem (storage, replication, network and rpc) may additionally be set
+//! in `logging.toml` in the data directory, or at runtime with the
+//! `setLogLevel` JSON-RPC method, and takes precedence over `RUST_LOG` for
+//! the log targets of that subsystem. This allows tracing a single subsystem
+//! without drowning in the output of all the others.
+
+use std::{collections::BTreeMap, fmt, fs, path::Path, str::FromStr, sync::RwLock};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+
+use crate::{error::Error, Result};
+
+/// Log level of each subsystem for which one has been set.
+static LOG_LEVELS: Lazy<RwLock<BTreeMap<Subsystem, LevelFilter>>> =
+    Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+/// A group of log targets whose level is set as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Subsystem {
+    /// The key-value database, indexes and blob store.
+    Storage,
+    /// Feed and blob replication over MUXRPC.
+    Replication,
+    /// Connections, handshakes and LAN discovery.
+    Network,
+    /// The JSON-RPC and HTTP server.
+    Rpc,
+}
+
+impl Subsystem {
+    /// Prefixes of the log targets belonging to the subsystem. Targets
+    /// default to the module path of the logging statement.
+    fn targets(self) -> &'static [&'static str] {
+        match self {
+            Subsystem::Storage => &["solar::storage"],
+            Subsystem::Replication => &[
+                "solar::actors::rpc",
+                "solar::actors::sync_session",
+                "ssb-blob",
+            ],
+            Subsystem::Network => &[
+                "solar::actors::connection_manager",
+                "solar::actors::lan_discovery",
+                "solar::actors::peer",
+                "solar::actors::tcp_server",
+                "connection-manager",
+                "peer-loop",
+            ],
+            Subsystem::Rpc => &["solar::actors::jsonrpc_server", "solar::api"],
+        }
+    }
+
+    /// Return the subsystem to which the given log target belongs, if any.
+    fn of_target(target: &str) -> Option<Self> {
+        [
+            Subsystem::Storage,
+            Subsystem::Replication,
+            Subsystem::Network,
+            Subsystem::Rpc,
+        ]
+        .iter()
+        .copied()
+        .find(|subsystem| {
+            subsystem.targets().iter().any(|prefix| {
+                target == *prefix
+                    || target
+                        .strip_prefix(prefix)
+                        .is_some_and(|rest| rest.starts_with("::"))
+            })
+        })
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Subsystem::Storage => "storage",
+            Subsystem::Replication => "replication",
+            Subsystem::Network => "network",
+            Subsystem::Rpc => "rpc",
+        };
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for Subsystem {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "storage" => Ok(Subsystem::Storage),
+            "replication" => Ok(Subsystem::Replication),
+            "network" => Ok(Subsystem::Network),
+            "rpc" => Ok(Subsystem::Rpc),
+            _ => Err(Error::Other(format!("unknown log subsystem: {name}"))),
+        }
+    }
+}
+
+/// Parse a log level (`off`, `error`, `warn`, `info`, `debug` or `trace`).
+pub fn parse_level(level: &str) -> Result<LevelFilter> {
+    LevelFilter::from_str(level).map_err(|_| Error::Other(format!("invalid log level: {level}")))
+}
+
+/// Set the log level of the given subsystem, or reset it to the level given
+/// by `RUST_LOG` with `None`.
+pub fn set_level(subsystem: Subsystem, level: Option<LevelFilter>) {
+    let mut levels = LOG_LEVELS.write().unwrap();
+    match level {
+        Some(level) => levels.insert(subsystem, level),
+        None => levels.remove(&subsystem),
+    };
+}
+
+/// Return the log level of each subsystem for which one has been set.
+pub fn levels() -> BTreeMap<String, String> {
+    LOG_LEVELS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|(subsystem, level)| (subsystem.to_string(), level.to_string().to_lowercase()))
+        .collect()
+}
+
+/// Set the log levels given in the TOML file at `path` (e.g.
+/// `replication = "trace"`), if it exists. Returns the number of levels set.
+pub fn load_config(path: &Path) -> Result<usize> {
+    if !path.is_file() {
+        return Ok(0);
+    }
+
+    let config: BTreeMap<String, String> = toml::from_slice(&fs::read(path)?)?;
+    for (subsystem, level) in &config {
+        set_level(subsystem.parse()?, Some(parse_level(level)?));
+    }
+
+    Ok(config.len())
+}
+
+/// Logger applying the level of the subsystem of each record, if one has
+/// been set, and the `RUST_LOG` filters otherwise.
+struct SubsystemLogger {
+    /// Logger filtered by `RUST_LOG`.
+    env: env_logger::Logger,
+    /// Logger writing every record it is given.
+    output: env_logger::Logger,
+}
+
+impl Log for SubsystemLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let level = Subsystem::of_target(metadata.target())
+            .and_then(|subsystem| LOG_LEVELS.read().unwrap().get(&subsystem).copied());
+        match level {
+            Some(level) => metadata.level() <= level,
+            None => self.env.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            self.output.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.output.flush();
+    }
+}
+
+/// Install the logger.
+pub fn init() {
+    let logger = SubsystemLogger {
+        env: env_logger::Builder::from_default_env().build(),
+        output: env_logger::Builder::new()
+            .filter_level(LevelFilter::Trace)
+            .build(),
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(LevelFilter::max());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_subsystem_targets() {
+        assert_eq!(
+            Subsystem::of_target("solar::storage::kv"),
+            Some(Subsystem::Storage)
+        );
+        assert_eq!(
+            Subsystem::of_target("solar::actors::rpc::history_stream"),
+            Some(Subsystem::Replication)
+        );
+        assert_eq!(Subsystem::of_target("peer-loop"), Some(Subsystem::Network));
+        assert_eq!(Subsystem::of_target("solar::api"), Some(Subsystem::Rpc));
+        // Prefixes only match whole path components.
+        assert_eq!(Subsystem::of_target("solar::storage_extra"), None);
+        assert_eq!(Subsystem::of_target("solar::actors::backup"), None);
+        assert!(parse_level("loud").is_err());
+        assert!("database".parse::<Subsystem>().is_err());
+    }
+}
@@ -1,18 +1,36 @@
 #![recursion_limit = "256"]
+// Much of the API is only reachable through the JSON-RPC server and the blob
+// handlers; don't warn about it in builds which leave those features out.
+#![cfg_attr(
+    not(all(feature = "blobs", feature = "jsonrpc")),
+    allow(dead_code, unused_imports)
+)]
 
 use async_std::sync::{Arc, RwLock};
 use once_cell::sync::Lazy;
 
 mod actors;
+mod allow_list;
+mod api;
 mod broker;
 mod cli;
 mod config;
+mod disk;
 mod error;
+mod health;
+mod ingest;
+mod logging;
+mod metafeed;
+mod private_box;
 mod storage;
+#[cfg(test)]
+mod testing;
+mod uri;
+mod validation;
 
 use actors::connection_manager::CONNECTION_MANAGER;
 use broker::*;
-use config::ApplicationConfig;
+use config::{ApplicationConfig, REPLICATION_CONFIG};
 use storage::{blob::BlobStorage, kv::KvStorage};
 
 /// Convenience Result that returns `solar::Error`.
@@ -25,12 +43,17 @@ pub static KV_STORAGE: Lazy<Arc<RwLock<KvStorage>>> =
 pub static BLOB_STORAGE: Lazy<Arc<RwLock<BlobStorage>>> =
     Lazy::new(|| Arc::new(RwLock::new(BlobStorage::default())));
 
-#[async_std::main]
-async fn main() -> Result<()> {
+fn main() -> Result<()> {
     // Initialise the logger.
-    env_logger::init();
-    log::set_max_level(log::LevelFilter::max());
+    logging::init();
 
+    // Size the executor before it starts.
+    config::configure_executor();
+
+    async_std::task::block_on(run())
+}
+
+async fn run() -> Result<()> {
     // Configure the application.
     let (app_config, kv_storage_config, peer_connections, secret_config) =
         ApplicationConfig::configure().await?;
@@ -41,13 +64,154 @@ async fn main() -> Result<()> {
         .write()
         .await
         .open(kv_storage_config, BROKER.lock().await.create_sender())?;
+    KV_STORAGE
+        .read()
+        .await
+        .check_network_key(app_config.network_key.as_ref())?;
+
+    // Pin the feeds listed in the replication configuration.
+    for pub_key in &REPLICATION_CONFIG.get().unwrap().read().await.pinned {
+        KV_STORAGE.read().await.pin_feed(pub_key)?;
+    }
+
+    // Rebuild the global order index and exit if requested in the CLI
+    // arguments. This is a maintenance operation; the node is not started.
+    if app_config.rebuild_global_order {
+        let report = KV_STORAGE.read().await.rebuild_global_order()?;
+        println!("Rebuilt global order index: {report:?}");
+        return Ok(());
+    }
+
+    // Compact the database and exit if requested in the CLI arguments.
+    if app_config.compact_db {
+        let report = KV_STORAGE
+            .write()
+            .await
+            .compact(&app_config.feeds_folder)
+            .await?;
+        println!(
+            "Compacted database from {} to {} bytes ({} bytes recovered)",
+            report.size_before,
+            report.size_after,
+            report.recovered()
+        );
+        return Ok(());
+    }
+
+    // Edit the local mute list and exit if requested in the CLI arguments.
+    if app_config.mute.is_some() || app_config.unmute.is_some() {
+        let db = KV_STORAGE.read().await;
+        if let Some(pub_key) = &app_config.mute {
+            db.mutes().mute(pub_key, app_config.mute_stop_replication)?;
+        }
+        if let Some(pub_key) = &app_config.unmute {
+            db.mutes().unmute(pub_key)?;
+        }
+        for mute in db.mutes().get_mutes()? {
+            println!(
+                "Muted {} (stop replication: {})",
+                mute.pub_key, mute.stop_replication
+            );
+        }
+        return Ok(());
+    }
+
+    // Export the connection audit log and exit if requested in the CLI
+    // arguments.
+    if app_config.export_connection_log {
+        for entry in KV_STORAGE.read().await.audit().get_entries(None, usize::MAX)? {
+            println!("{}", serde_json::to_string(&entry)?);
+        }
+        return Ok(());
+    }
+
+    // Export the contact graph and exit if requested in the CLI arguments.
+    if let Some(format) = app_config.export_graph {
+        let db = KV_STORAGE.read().await;
+        db.build_indexes()?;
+        let graph = db.indexes().get_contact_graph()?;
+        print!(
+            "{}",
+            api::graph::export(&graph, format, app_config.anonymize_graph)
+        );
+        return Ok(());
+    }
+
+    // Export the public messages of the consenting authors and exit if
+    // requested in the CLI arguments.
+    if let Some(consent_type) = &app_config.export_dataset {
+        let db = KV_STORAGE.read().await;
+        let count = api::dataset::export(&db, consent_type, &mut std::io::stdout().lock())?;
+        log::info!("exported {} messages", count);
+        return Ok(());
+    }
+
+    // Hold publishing until the local feed has been restored from peers if
+    // requested in the CLI arguments. An interrupted restore resumes on the
+    // next start regardless.
+    if app_config.restore_identity {
+        KV_STORAGE.read().await.begin_restore()?;
+    }
+
+    // Build any missing indexes in the background, so that the node serves
+    // connections in the meantime. Readiness is reported by the health API,
+    // as is a failed build, which is retried until it succeeds.
+    let db = KV_STORAGE.read().await.clone();
+    async_std::task::spawn_blocking(move || {
+        let mut delay = storage::kv::BUILD_RETRY_DELAY;
+        while let Err(err) = db.build_indexes() {
+            log::error!(
+                "failed to build the database indexes, retrying in {:?}: {}",
+                delay,
+                err
+            );
+            std::thread::sleep(delay);
+            delay = (delay * 2).min(storage::kv::BUILD_RETRY_MAX_DELAY);
+        }
+    });
 
     // Open the blobstore using the given folder path and an unbounded sender
     // channel for message passing.
-    BLOB_STORAGE
+    let migrated = BLOB_STORAGE
         .write()
         .await
-        .open(app_config.blobs_folder, BROKER.lock().await.create_sender());
+        .open(app_config.blobs_folder, BROKER.lock().await.create_sender())?;
+    if migrated > 0 {
+        log::info!("Moved {} blobs into sharded directories", migrated);
+    }
+
+    // Import the blobs of a legacy blob store and exit if requested in the
+    // CLI arguments.
+    if let Some(dir) = &app_config.import_blobs {
+        let report = storage::blob_import::import_dir(
+            &*BLOB_STORAGE.read().await,
+            &*KV_STORAGE.read().await,
+            dir,
+        )?;
+        for path in &report.corrupt {
+            log::warn!("skipped corrupt blob {}", path.display());
+        }
+        println!(
+            "Imported {} blobs ({} already stored, {} corrupt)",
+            report.imported,
+            report.existing,
+            report.corrupt.len()
+        );
+        return Ok(());
+    }
+
+    // Queue the blobs left on the want-list by a previous run.
+    #[cfg(feature = "blobs")]
+    {
+        let db = KV_STORAGE.read().await;
+        let wanted = actors::rpc::BLOB_QUEUE
+            .lock()
+            .unwrap()
+            .load(db.blob_wants())?;
+        if wanted > 0 {
+            log::info!("Queued {} wanted blobs", wanted);
+        }
+    }
 
     // Spawn the ctrlc actor. Listens for SIGINT termination signal.
     Broker::spawn(actors::ctrlc::actor());
@@ -64,6 +228,7 @@ async fn main() -> Result<()> {
         secret_config.clone(),
         app_config.muxrpc_addr,
         app_config.selective_replication,
+        app_config.netsim,
     ));
 
     // Print the network key.
@@ -72,35 +237,128 @@ async fn main() -> Result<()> {
         hex::encode(app_config.network_key)
     );
 
+    // Publishes messages which were queued in the outbox while the database
+    // was undergoing maintenance, whether by JSON-RPC clients, follow-backs
+    // or bot answers. Queued messages are kept for later in read-only mode.
+    if !app_config.read_only {
+        Broker::spawn(actors::outbox::actor(secret_config.clone()));
+    }
+
     // Spawn the JSON-RPC server if the option has been set to true in the
     // CLI arguments. Facilitates operator queries during runtime.
+    #[cfg(feature = "jsonrpc")]
     if app_config.jsonrpc {
         Broker::spawn(actors::jsonrpc_server::actor(
             secret_config.clone(),
             app_config.jsonrpc_addr,
+            app_config.jsonrpc_threads,
         ));
+        // Records the changes in follow and block states of other feeds for
+        // the local identity, for the `followerEvents` method.
+        Broker::spawn(actors::follower_events::actor());
+    }
+    #[cfg(not(feature = "jsonrpc"))]
+    if app_config.jsonrpc {
+        log::warn!(
+            "solar was built without the `jsonrpc` feature; not starting the JSON-RPC server"
+        );
+    }
+    #[cfg(not(feature = "activitypub"))]
+    if app_config.activitypub.is_some() {
+        log::warn!(
+            "solar was built without the `activitypub` feature; not serving the ActivityPub actor"
+        );
     }
 
     // Spawn the LAN discovery actor. Listens for and broadcasts UDP packets
     // to allow LAN-local peer connections.
+    #[cfg(feature = "lan-discovery")]
     if app_config.lan_discov {
         Broker::spawn(actors::lan_discovery::actor(
             secret_config.clone(),
             app_config.muxrpc_port,
             app_config.selective_replication,
+            app_config.lan_sync,
+        ));
+    }
+    #[cfg(not(feature = "lan-discovery"))]
+    if app_config.lan_discov {
+        log::warn!(
+            "solar was built without the `lan-discovery` feature; not starting LAN discovery"
+        );
+    }
+
+    // Spawn the follow-back actor if the option has been set to true in the
+    // CLI arguments. Follows back peers who follow the local identity.
+    // Following back publishes contact messages, so it is disabled in
+    // read-only mode.
+    if app_config.follow_back && !app_config.read_only {
+        Broker::spawn(actors::follow_back::actor(
+            secret_config.clone(),
+            app_config.base_path.clone(),
+        ));
+    }
+
+    // Spawn the bot actor if a command prefix has been set in the CLI
+    // arguments. Answers commands in posts mentioning the local identity.
+    // Answering publishes posts, so it is disabled in read-only mode.
+    if let Some(prefix) = app_config.bot_prefix.clone() {
+        if !app_config.read_only {
+            Broker::spawn(actors::bot::actor(secret_config.clone(), prefix));
+        }
+    }
+
+    // Spawn the exec hooks actor if hooks are listed in `hooks.toml`. Runs
+    // the configured commands on the appended messages matching them.
+    if !actors::exec_hooks::is_empty() {
+        Broker::spawn(actors::exec_hooks::actor());
+    }
+
+    // Spawn the backup actor if an automatic backup interval has been set.
+    // Periodically writes snapshots of the database and configuration.
+    if app_config.backup_interval > 0 {
+        Broker::spawn(actors::backup::actor(
+            app_config.base_path.clone(),
+            app_config.backup_folder,
+            app_config.backup_interval,
+            app_config.backup_retention,
         ));
     }
 
-    // Spawn the peer actor for each set of provided connection parameters.
-    // Facilitates replication.
+    // Spawn the disk guard actor unless the check has been disabled.
+    // Switches to degraded mode while free disk space is low.
+    if app_config.min_free_space > 0 {
+        Broker::spawn(actors::disk_guard::actor(
+            app_config.base_path.clone(),
+            app_config.min_free_space,
+        ));
+    }
+
+    // Spawn the compaction actor if an automatic compaction interval has
+    // been set. Periodically reclaims the space left by deleted entries.
+    if app_config.compact_interval > 0 {
+        Broker::spawn(actors::compaction::actor(
+            app_config.feeds_folder.clone(),
+            app_config.compact_interval,
+        ));
+    }
+
+    // Group the provided connection parameters by peer, so that the
+    // addresses of a peer are dialed together.
+    let mut peer_addresses: Vec<(_, Vec<String>)> = Vec::new();
     for (_url, server, port, peer_pk) in peer_connections {
+        let address = format!("{server}:{port}");
+        match peer_addresses.iter_mut().find(|(pk, _)| *pk == peer_pk) {
+            Some((_, addresses)) => addresses.push(address),
+            None => peer_addresses.push((peer_pk, vec![address])),
+        }
+    }
+
+    // Spawn the peer actor for each peer. Facilitates replication.
+    for (peer_pk, addresses) in peer_addresses {
         Broker::spawn(actors::peer::actor(
             secret_config.clone(),
-            actors::peer::Connect::TcpServer {
-                server,
-                port,
-                peer_pk,
-            },
+            actors::peer::Connect::TcpServer { addresses, peer_pk },
             app_config.selective_replication,
         ));
     }
@@ -0,0 +1,325 @@
+//! Metafeed tree of the local identity, following the SSB meta feeds
+//! specification.
+//!
+//! The keys of the metafeed and of its derived sub-feeds are derived from a
+//! 32 byte seed stored in `secret.toml`, using HKDF-SHA256; the existing
+//! main feed is added to the tree as is. The tree itself is stored in the
+//! key-value database and announced on the main feed.
+//!
+//! Index feeds are classic sub-feeds which reference the public messages of
+//! a given type on the main feed, so that peers can replicate the social
+//! metadata of the local identity without its whole main feed.
+
+use kuska_sodiumoxide::crypto::sign::ed25519::{self, Seed};
+use kuska_ssb::{crypto::ToSsbId, feed::Message, keystore::OwnedIdentity};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use crate::{error::Error, storage::kv::KvStorage, Result};
+
+/// Salt of the key derivation.
+const SALT: &[u8] = b"ssb";
+/// Prefix of the key derivation info.
+const INFO_PREFIX: &str = "ssb-meta-feed-seed-v1:";
+/// Purpose of the existing main feed in the tree.
+pub const MAIN_PURPOSE: &str = "main";
+/// Purpose of the sub-feed holding the index feeds.
+pub const INDEXES_PURPOSE: &str = "indexes";
+/// Prefix of the purpose of index feeds, followed by the indexed type.
+const INDEX_PURPOSE_PREFIX: &str = "index:";
+/// Types of the main feed messages which are indexed, each in its own index
+/// feed.
+const INDEXED_TYPES: [&str; 2] = ["about", "contact"];
+
+/// A feed in the metafeed tree.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Subfeed {
+    pub purpose: String,
+    pub feed_id: String,
+    /// Base64-encoded nonce from which the keys of the feed are derived, or
+    /// `None` for the existing main feed.
+    pub nonce: Option<String>,
+    /// Query selecting the messages referenced by an index feed, as a JSON
+    /// string.
+    #[serde(default)]
+    pub query: Option<String>,
+}
+
+impl Subfeed {
+    /// Return the message type indexed by the feed, if it is an index feed.
+    fn indexed_type(&self) -> Option<&str> {
+        self.purpose.strip_prefix(INDEX_PURPOSE_PREFIX)
+    }
+}
+
+/// The metafeed of the local identity and its sub-feeds.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct MetafeedTree {
+    pub metafeed_id: String,
+    pub subfeeds: Vec<Subfeed>,
+}
+
+/// Generate a new metafeed seed.
+pub fn generate_seed() -> [u8; 32] {
+    rand::thread_rng().gen()
+}
+
+/// HMAC-SHA256 of the given data.
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut block = [0u8; 64];
+    if key.len() > block.len() {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+
+    let mut inner = Sha256::new();
+    inner.update(block.map(|byte| byte ^ 0x36));
+    inner.update(data);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|byte| byte ^ 0x5c));
+    outer.update(inner.finalize());
+
+    outer.finalize().into()
+}
+
+/// HKDF-SHA256 (RFC 5869) of the given input keying material, with an
+/// output of 32 bytes.
+fn hkdf_sha256(ikm: &[u8], salt: &[u8], info: &[u8]) -> [u8; 32] {
+    let prk = hmac_sha256(salt, ikm);
+    let mut block = info.to_vec();
+    block.push(1);
+
+    hmac_sha256(&prk, &block)
+}
+
+/// Derive a keypair from the metafeed seed and the given info, with feed
+/// IDs ending in the given suffix.
+fn derive_identity(seed: &[u8], info: &str, suffix: &str) -> Result<OwnedIdentity> {
+    let derived = hkdf_sha256(seed, SALT, format!("{INFO_PREFIX}{info}").as_bytes());
+    let seed = Seed::from_slice(&derived)
+        .ok_or_else(|| Error::Metafeed("invalid metafeed seed".to_string()))?;
+    let (pk, sk) = ed25519::keypair_from_seed(&seed);
+    let key = pk.to_ssb_id();
+    let id = format!("@{}{suffix}", key.strip_suffix(".ed25519").unwrap_or(&key));
+
+    Ok(OwnedIdentity { id, pk, sk })
+}
+
+/// Derive the keypair of the metafeed, a bendy butt feed.
+pub fn metafeed_identity(seed: &[u8]) -> Result<OwnedIdentity> {
+    derive_identity(seed, "metafeed", ".bbfeed-v1")
+}
+
+/// Derive the keypair of a classic sub-feed from its base64-encoded nonce.
+pub fn subfeed_identity(seed: &[u8], nonce: &str) -> Result<OwnedIdentity> {
+    derive_identity(seed, nonce, ".ed25519")
+}
+
+impl MetafeedTree {
+    /// Create the tree of the given metafeed seed, holding the existing main
+    /// feed, a derived sub-feed for the indexes and the index feeds.
+    pub fn new(seed: &[u8], main_id: &str) -> Result<Self> {
+        let mut tree = MetafeedTree {
+            metafeed_id: metafeed_identity(seed)?.id,
+            subfeeds: vec![Subfeed {
+                purpose: MAIN_PURPOSE.to_string(),
+                feed_id: main_id.to_string(),
+                nonce: None,
+                query: None,
+            }],
+        };
+        tree.add_derived(seed, INDEXES_PURPOSE)?;
+        tree.add_index_feeds(seed)?;
+
+        Ok(tree)
+    }
+
+    /// Derive the index feeds which are missing from the tree, e.g. in trees
+    /// created by earlier versions. Returns `true` if any was added.
+    pub fn add_index_feeds(&mut self, seed: &[u8]) -> Result<bool> {
+        let main_id = match self.get(MAIN_PURPOSE) {
+            Some(main) => main.feed_id.clone(),
+            None => return Err(Error::Metafeed("the main feed is missing".to_string())),
+        };
+
+        let mut added = false;
+        for msg_type in INDEXED_TYPES {
+            let purpose = format!("{INDEX_PURPOSE_PREFIX}{msg_type}");
+            if self.get(&purpose).is_none() {
+                let query = json!({ "author": main_id, "type": msg_type, "private": false });
+                self.derive(seed, &purpose, Some(query.to_string()))?;
+                added = true;
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// Return the sub-feed with the given purpose, if any.
+    pub fn get(&self, purpose: &str) -> Option<&Subfeed> {
+        self.subfeeds
+            .iter()
+            .find(|subfeed| subfeed.purpose == purpose)
+    }
+
+    /// Derive a new sub-feed for the given purpose (e.g. the name of an
+    /// application). Each purpose has at most one sub-feed.
+    pub fn add_derived(&mut self, seed: &[u8], purpose: &str) -> Result<Subfeed> {
+        if purpose.is_empty() {
+            return Err(Error::Metafeed("the purpose is empty".to_string()));
+        }
+        if purpose.starts_with(INDEX_PURPOSE_PREFIX) {
+            return Err(Error::Metafeed(format!(
+                "purposes starting with {INDEX_PURPOSE_PREFIX} are reserved for index feeds"
+            )));
+        }
+
+        self.derive(seed, purpose, None)
+    }
+
+    /// Derive a new sub-feed with the given purpose and query.
+    fn derive(&mut self, seed: &[u8], purpose: &str, query: Option<String>) -> Result<Subfeed> {
+        if self.get(purpose).is_some() {
+            return Err(Error::Metafeed(format!(
+                "a sub-feed with purpose {purpose} already exists"
+            )));
+        }
+
+        let nonce = base64::encode(rand::thread_rng().gen::<[u8; 32]>());
+        let subfeed = Subfeed {
+            purpose: purpose.to_string(),
+            feed_id: subfeed_identity(seed, &nonce)?.id,
+            nonce: Some(nonce),
+            query,
+        };
+        self.subfeeds.push(subfeed.clone());
+
+        Ok(subfeed)
+    }
+
+    /// Content of the message announcing the metafeed on the main feed.
+    pub fn announce_content(&self) -> Value {
+        json!({
+            "type": "metafeed/announce",
+            "metafeed": self.metafeed_id,
+            "tangles": {
+                "metafeed": { "root": null, "previous": null }
+            }
+        })
+    }
+
+    /// Content of the message which backs up the metafeed seed on the main
+    /// feed. It must be encrypted to the local identity before publishing.
+    pub fn seed_content(&self, seed: &[u8]) -> Value {
+        json!({
+            "type": "metafeed/seed",
+            "metafeed": self.metafeed_id,
+            "seed": hex::encode(seed),
+        })
+    }
+}
+
+/// Content of an index message, referencing a message of the main feed.
+fn index_content(msg_ref: &str, sequence: u64) -> Value {
+    json!({
+        "type": "metafeed/index",
+        "indexed": { "key": msg_ref, "sequence": sequence }
+    })
+}
+
+/// Publish on each index feed of the metafeed tree the index messages of the
+/// main feed messages which it does not reference yet. Index feeds resume
+/// after the last message they reference, so this can be called after every
+/// message published on the main feed.
+pub async fn update_indexes(db: &KvStorage, seed: &[u8]) -> Result<()> {
+    let tree = match db.get_metafeed()? {
+        Some(tree) => tree,
+        None => return Ok(()),
+    };
+    let main_id = match tree.get(MAIN_PURPOSE) {
+        Some(main) => &main.feed_id,
+        None => return Ok(()),
+    };
+    let latest_seq = db.get_latest_seq(main_id)?.unwrap_or(0);
+
+    for subfeed in &tree.subfeeds {
+        let (msg_type, nonce) = match (subfeed.indexed_type(), &subfeed.nonce) {
+            (Some(msg_type), Some(nonce)) => (msg_type, nonce),
+            _ => continue,
+        };
+        let identity = subfeed_identity(seed, nonce)?;
+
+        let mut last_msg = db.get_latest_msg_val(&identity.id)?;
+        let indexed_seq = last_msg
+            .as_ref()
+            .and_then(|msg| msg.content()["indexed"]["sequence"].as_u64())
+            .unwrap_or(0);
+
+        for seq in indexed_seq + 1..=latest_seq {
+            let msg = match db.get_msg_kvt(main_id, seq)? {
+                // Messages whose content was deleted are not indexed.
+                Some(msg_kvt) if db.get_tombstone(&msg_kvt.key)?.is_none() => {
+                    msg_kvt.into_message()?
+                }
+                _ => continue,
+            };
+            // The content of private messages is a string, so they are never
+            // indexed.
+            if msg.content()["type"].as_str() != Some(msg_type) {
+                continue;
+            }
+
+            let content = index_content(&msg.id().to_string(), seq);
+            let index_msg =
+                Message::sign(last_msg.as_ref(), &identity, content).map_err(Error::Validation)?;
+            db.append_feed(index_msg.clone()).await?;
+            last_msg = Some(index_msg);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_metafeed_keys() -> Result<()> {
+        // RFC 5869, test case 1 (first 32 bytes of the output).
+        let okm = hkdf_sha256(
+            &[0x0b; 22],
+            &hex::decode("000102030405060708090a0b0c").unwrap(),
+            &hex::decode("f0f1f2f3f4f5f6f7f8f9").unwrap(),
+        );
+        assert_eq!(
+            hex::encode(okm),
+            "3cb25f25faacd57a90434f64d0362f2a2d2d0a90cf1a5a4c5db02d56ecc4c5bf"
+        );
+
+        let seed = generate_seed();
+        let main_id = "@+oaWWDs8g73EZFUMfW37R/ULtFEjwKN/DczvdYI/8TA=.ed25519";
+        let mut tree = MetafeedTree::new(&seed, main_id)?;
+        assert!(tree.metafeed_id.ends_with(".bbfeed-v1"));
+        assert_eq!(tree.get(MAIN_PURPOSE).unwrap().feed_id, main_id);
+
+        // Keys are derived again from the seed and nonce.
+        let app = tree.add_derived(&seed, "chess")?;
+        let identity = subfeed_identity(&seed, app.nonce.as_ref().unwrap())?;
+        assert_eq!(identity.id, app.feed_id);
+        assert_eq!(metafeed_identity(&seed)?.id, tree.metafeed_id);
+        assert!(tree.add_derived(&seed, "chess").is_err());
+        assert!(tree.add_derived(&seed, "index:post").is_err());
+        assert!(!tree.add_index_feeds(&seed)?);
+        assert!(tree.get("index:contact").unwrap().query.is_some());
+        assert_ne!(
+            tree.get(INDEXES_PURPOSE).unwrap().feed_id,
+            tree.get("chess").unwrap().feed_id
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,155 @@
+//! Encryption and decryption of private messages using the `private-box`
+//! (box1) format.
+//!
+//! A boxed message consists of a nonce, a one-time public key, one key
+//! header per recipient and the encrypted body:
+//!
+//! `nonce (24) | one-time public key (32) | headers (49 * n) | body`
+//!
+//! Each header holds the symmetric body key (prefixed with the number of
+//! recipients), encrypted with the shared secret of the one-time keypair and
+//! the recipient's key. Recipients try each header in turn until one opens.
+
+use kuska_sodiumoxide::crypto::{
+    box_, scalarmult::curve25519, secretbox, sign::ed25519,
+};
+use kuska_ssb::{crypto::ToSodiumObject, keystore::OwnedIdentity};
+use serde_json::Value;
+
+use crate::{error::Error, Result};
+
+/// Maximum number of recipients of a boxed message.
+pub const MAX_RECIPIENTS: usize = 7;
+
+/// Suffix of the string encoding of a boxed message.
+const BOX_SUFFIX: &str = ".box";
+
+/// Length of an encrypted key header: the recipient count byte and body key
+/// plus the message authentication code.
+const HEADER_LEN: usize = 1 + secretbox::KEYBYTES + secretbox::MACBYTES;
+
+/// Length of the nonce and one-time public key which precede the headers.
+const PREFIX_LEN: usize = secretbox::NONCEBYTES + box_::PUBLICKEYBYTES;
+
+/// Compute the shared secret of a curve25519 secret key and public key and
+/// return it as a secretbox key.
+fn shared_key(sk: &box_::SecretKey, pk: &box_::PublicKey) -> Option<secretbox::Key> {
+    let scalar = curve25519::Scalar::from_slice(&sk.0)?;
+    let point = curve25519::GroupElement::from_slice(&pk.0)?;
+    let shared = curve25519::scalarmult(&scalar, &point).ok()?;
+    secretbox::Key::from_slice(&shared.0)
+}
+
+/// Encrypt the plaintext for the given recipients (ed25519 public keys).
+pub fn encrypt(plaintext: &[u8], recipients: &[ed25519::PublicKey]) -> Result<Vec<u8>> {
+    if recipients.is_empty() || recipients.len() > MAX_RECIPIENTS {
+        return Err(Error::PrivateBox(format!(
+            "a private message must have between 1 and {MAX_RECIPIENTS} recipients"
+        )));
+    }
+
+    let nonce = secretbox::gen_nonce();
+    let (onetime_pk, onetime_sk) = box_::gen_keypair();
+    let body_key = secretbox::gen_key();
+
+    // The recipient count is encrypted along with the body key so that a
+    // recipient knows where the headers end and the body begins.
+    let mut header_plaintext = vec![recipients.len() as u8];
+    header_plaintext.extend_from_slice(&body_key.0);
+
+    let mut boxed = Vec::new();
+    boxed.extend_from_slice(&nonce.0);
+    boxed.extend_from_slice(&onetime_pk.0);
+
+    for recipient in recipients {
+        let recipient_pk = ed25519::to_curve25519_pk(recipient)
+            .map_err(|_| Error::PrivateBox("invalid recipient public key".to_string()))?;
+        let key = shared_key(&onetime_sk, &recipient_pk)
+            .ok_or_else(|| Error::PrivateBox("failed to derive shared key".to_string()))?;
+        boxed.extend(secretbox::seal(&header_plaintext, &nonce, &key));
+    }
+
+    boxed.extend(secretbox::seal(plaintext, &nonce, &body_key));
+
+    Ok(boxed)
+}
+
+/// Decrypt a boxed message using the given ed25519 secret key. Returns `None`
+/// if the message was not addressed to the key or is malformed.
+pub fn decrypt(boxed: &[u8], sk: &ed25519::SecretKey) -> Option<Vec<u8>> {
+    if boxed.len() < PREFIX_LEN + HEADER_LEN {
+        return None;
+    }
+
+    let nonce = secretbox::Nonce::from_slice(&boxed[..secretbox::NONCEBYTES])?;
+    let onetime_pk = box_::PublicKey::from_slice(&boxed[secretbox::NONCEBYTES..PREFIX_LEN])?;
+    let curve_sk = ed25519::to_curve25519_sk(sk).ok()?;
+    let key = shared_key(&curve_sk, &onetime_pk)?;
+
+    // Try each header in turn; the recipient count is not known until one
+    // of them opens.
+    for header in boxed[PREFIX_LEN..]
+        .chunks_exact(HEADER_LEN)
+        .take(MAX_RECIPIENTS)
+    {
+        if let Ok(header_plaintext) = secretbox::open(header, &nonce, &key) {
+            let num_recipients = header_plaintext[0] as usize;
+            let body_key = secretbox::Key::from_slice(&header_plaintext[1..])?;
+            let body = boxed.get(PREFIX_LEN + num_recipients * HEADER_LEN..)?;
+            return secretbox::open(body, &nonce, &body_key).ok();
+        }
+    }
+
+    None
+}
+
+/// Encrypt message content for the given recipients (SSB IDs) and return
+/// the string encoding used as the content of a private message.
+pub fn box_content(content: &Value, recipients: &[String]) -> Result<String> {
+    let recipient_pks = recipients
+        .iter()
+        .map(|id| id.trim_start_matches('@').to_ed25519_pk())
+        .collect::<std::result::Result<Vec<_>, _>>()?;
+    let boxed = encrypt(content.to_string().as_bytes(), &recipient_pks)?;
+
+    Ok(format!("{}{BOX_SUFFIX}", base64::encode(boxed)))
+}
+
+/// Decrypt the content of a private message using the given identity.
+/// Returns `None` if the content is not boxed, was not addressed to the
+/// identity or is malformed.
+pub fn unbox_content(content: &Value, identity: &OwnedIdentity) -> Option<Value> {
+    let encoded = content.as_str()?.strip_suffix(BOX_SUFFIX)?;
+    let boxed = base64::decode(encoded).ok()?;
+    let plaintext = decrypt(&boxed, &identity.sk)?;
+
+    serde_json::from_slice(&plaintext).ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::config::SecretConfig;
+
+    #[test]
+    fn test_box_unbox() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity()?;
+        let bob = SecretConfig::create().owned_identity()?;
+        let carol = SecretConfig::create().owned_identity()?;
+
+        let content = json!({ "type": "post", "text": "psst", "recps": [alice.id, bob.id] });
+        let boxed = box_content(&content, &[alice.id.clone(), bob.id.clone()])?;
+        assert!(boxed.ends_with(BOX_SUFFIX));
+
+        // Both recipients can read the message but nobody else can.
+        let boxed = Value::String(boxed);
+        assert_eq!(unbox_content(&boxed, &alice), Some(content.clone()));
+        assert_eq!(unbox_content(&boxed, &bob), Some(content));
+        assert_eq!(unbox_content(&boxed, &carol), None);
+
+        Ok(())
+    }
+}
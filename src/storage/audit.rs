@@ -0,0 +1,143 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Name of the sled tree in which the connection audit log is stored.
+const AUDIT_TREE: &str = "audit";
+
+/// Maximum number of entries kept in the audit log. The oldest entries are
+/// dropped once the limit is reached.
+const MAX_AUDIT_ENTRIES: usize = 10_000;
+
+/// Direction of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// Something which happened to a connection.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum AuditEvent {
+    /// A connection was opened or accepted.
+    Attempt,
+    /// The secret handshake succeeded.
+    Handshake,
+    /// The connection was dropped after the handshake.
+    Rejected { reason: String },
+    /// The connection failed, during or after the handshake.
+    Failed { error: String },
+    /// The connection was closed normally.
+    Closed,
+    /// Messages by a feed were dropped for exceeding the ingest rate
+    /// limits; `peer` is the author of the feed.
+    Throttled { reason: String },
+}
+
+/// An entry in the connection audit log.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    pub direction: Direction,
+    /// Remote address of the connection, if known.
+    pub address: Option<String>,
+    /// Public key of the peer, once known.
+    pub peer: Option<String>,
+    #[serde(flatten)]
+    pub event: AuditEvent,
+}
+
+impl AuditEntry {
+    /// Create an entry for an event happening now.
+    pub fn new(
+        direction: Direction,
+        address: Option<String>,
+        peer: Option<String>,
+        event: AuditEvent,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+
+        Self {
+            timestamp,
+            direction,
+            address,
+            peer,
+            event,
+        }
+    }
+}
+
+/// Rolling log of connection attempts, handshake results and rejections,
+/// kept so that operators can investigate abuse after the fact.
+#[derive(Clone)]
+pub struct AuditLog {
+    db: sled::Db,
+    tree: sled::Tree,
+    /// Number of entries in the tree, which sled can only count by scanning.
+    len: Arc<AtomicUsize>,
+}
+
+impl AuditLog {
+    /// Open the audit tree of the given database.
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let tree = db.open_tree(AUDIT_TREE)?;
+        let len = Arc::new(AtomicUsize::new(tree.len()));
+
+        Ok(Self {
+            db: db.clone(),
+            tree,
+            len,
+        })
+    }
+
+    /// Append the given entry, dropping the oldest entries beyond the
+    /// maximum.
+    pub fn record(&self, entry: &AuditEntry) -> Result<()> {
+        // IDs generated by the database are unique and increasing, so the
+        // entries are stored in the order in which they were recorded.
+        let id = self.db.generate_id()?;
+        self.tree
+            .insert(id.to_be_bytes(), serde_cbor::to_vec(entry)?)?;
+
+        if self.len.fetch_add(1, Ordering::SeqCst) + 1 > MAX_AUDIT_ENTRIES
+            && self.tree.pop_min()?.is_some()
+        {
+            self.len.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        Ok(())
+    }
+
+    /// Return up to `limit` entries, newest first, optionally only those
+    /// concerning the given peer.
+    pub fn get_entries(&self, peer: Option<&str>, limit: usize) -> Result<Vec<AuditEntry>> {
+        let mut entries = Vec::new();
+
+        for item in self.tree.iter().rev() {
+            if entries.len() == limit {
+                break;
+            }
+            let (_, value) = item?;
+            let entry: AuditEntry = serde_cbor::from_slice(&value)?;
+            if peer.is_none() || entry.peer.as_deref() == peer {
+                entries.push(entry);
+            }
+        }
+
+        Ok(entries)
+    }
+}
@@ -0,0 +1,189 @@
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionalTree,
+};
+
+use crate::Result;
+
+/// Name of the sled tree in which interned author IDs are stored.
+const AUTHORS_TREE: &str = "authors";
+
+/// Prefix for a key to the interned ID of an author.
+const PREFIX_ID: u8 = 0u8;
+/// Prefix for a key to the author with a given interned ID (the reverse of
+/// `PREFIX_ID`).
+const PREFIX_AUTHOR: u8 = 1u8;
+/// Key to the next interned ID to be assigned.
+const KEY_NEXT_ID: &[u8] = &[2u8];
+/// Prefix for a key marking the author with a given interned ID as having at
+/// least one stored feed message.
+const PREFIX_FEED: u8 = 3u8;
+/// Key to the number of authors with a stored feed.
+const KEY_FEED_COUNT: &[u8] = &[4u8];
+
+/// Table mapping author public keys to compact numeric IDs.
+///
+/// Interned IDs are used in place of the full public key in the keys of the
+/// key-value store and the secondary indexes. IDs are assigned in order and
+/// never reused.
+#[derive(Clone)]
+pub struct Authors {
+    tree: sled::Tree,
+}
+
+impl Authors {
+    /// Open the authors tree of the given database.
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let tree = db.open_tree(AUTHORS_TREE)?;
+
+        Ok(Self { tree })
+    }
+
+    /// Return the authors tree, e.g. to update it in a transaction spanning
+    /// other trees.
+    pub fn tree(&self) -> &sled::Tree {
+        &self.tree
+    }
+
+    fn key_id(author: &str) -> Vec<u8> {
+        let mut key = vec![PREFIX_ID];
+        key.extend_from_slice(author.as_bytes());
+        key
+    }
+
+    fn key_author(id: u32) -> Vec<u8> {
+        let mut key = vec![PREFIX_AUTHOR];
+        key.extend_from_slice(&id.to_be_bytes());
+        key
+    }
+
+    fn key_feed(id: u32) -> Vec<u8> {
+        let mut key = vec![PREFIX_FEED];
+        key.extend_from_slice(&id.to_be_bytes());
+        key
+    }
+
+    fn decode_id(raw: &[u8]) -> u32 {
+        let mut u32_buffer = [0u8; 4];
+        u32_buffer.copy_from_slice(raw);
+        u32::from_be_bytes(u32_buffer)
+    }
+
+    fn decode_count(raw: &[u8]) -> u64 {
+        let mut u64_buffer = [0u8; 8];
+        u64_buffer.copy_from_slice(raw);
+        u64::from_be_bytes(u64_buffer)
+    }
+
+    /// Get the interned ID of the given author, if one has been assigned.
+    pub fn get_id(&self, author: &str) -> Result<Option<u32>> {
+        Ok(self
+            .tree
+            .get(Self::key_id(author))?
+            .map(|raw| Self::decode_id(&raw)))
+    }
+
+    /// Get the interned ID of the given author, assigning a new one if
+    /// necessary.
+    pub fn intern(&self, author: &str) -> Result<u32> {
+        if let Some(id) = self.get_id(author)? {
+            return Ok(id);
+        }
+
+        let id = self.tree.transaction(|tx_tree| {
+            if let Some(raw) = tx_tree.get(Self::key_id(author))? {
+                return Ok(Self::decode_id(&raw));
+            }
+            let id = tx_tree
+                .get(KEY_NEXT_ID)?
+                .map_or(0, |raw| Self::decode_id(&raw));
+            tx_tree.insert(Self::key_id(author), &id.to_be_bytes()[..])?;
+            tx_tree.insert(Self::key_author(id), author.as_bytes())?;
+            tx_tree.insert(KEY_NEXT_ID, &(id + 1).to_be_bytes()[..])?;
+
+            Ok::<u32, ConflictableTransactionError<sled::Error>>(id)
+        })?;
+
+        Ok(id)
+    }
+
+    /// Get the author with the given interned ID.
+    pub fn get_author(&self, id: u32) -> Result<Option<String>> {
+        Ok(self
+            .tree
+            .get(Self::key_author(id))?
+            .map(|raw| String::from_utf8_lossy(&raw).to_string()))
+    }
+
+    /// Record that the author with the given interned ID has a stored feed,
+    /// within a transaction on the authors tree. Returns `false` if it was
+    /// already recorded.
+    pub fn tx_add_feed(
+        tx_tree: &TransactionalTree,
+        id: u32,
+    ) -> ConflictableTransactionResult<bool, sled::Error> {
+        if tx_tree.get(Self::key_feed(id))?.is_some() {
+            return Ok(false);
+        }
+        let count = tx_tree
+            .get(KEY_FEED_COUNT)?
+            .map_or(0, |raw| Self::decode_count(&raw));
+        tx_tree.insert(Self::key_feed(id), &[])?;
+        tx_tree.insert(KEY_FEED_COUNT, &(count + 1).to_be_bytes()[..])?;
+
+        Ok(true)
+    }
+
+    /// Whether the index of authors with a stored feed has been built.
+    pub fn has_feed_index(&self) -> Result<bool> {
+        Ok(self.tree.contains_key(KEY_FEED_COUNT)?)
+    }
+
+    /// Replace the index of authors with a stored feed with the given
+    /// interned IDs.
+    pub fn rebuild_feed_index(&self, ids: &[u32]) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for item in self.tree.scan_prefix([PREFIX_FEED]) {
+            let (key, _) = item?;
+            batch.remove(key);
+        }
+        for id in ids {
+            batch.insert(Self::key_feed(*id), &[]);
+        }
+        batch.insert(KEY_FEED_COUNT, &(ids.len() as u64).to_be_bytes()[..]);
+        self.tree.apply_batch(batch)?;
+
+        Ok(())
+    }
+
+    /// Get the number of authors with a stored feed.
+    pub fn feed_count(&self) -> Result<u64> {
+        Ok(self
+            .tree
+            .get(KEY_FEED_COUNT)?
+            .map_or(0, |raw| Self::decode_count(&raw)))
+    }
+
+    /// Get up to `limit` interned IDs and public keys of authors with a
+    /// stored feed, in order of ID, starting after the given ID.
+    pub fn get_feeds(&self, after: Option<u32>, limit: usize) -> Result<Vec<(u32, String)>> {
+        let start = match after {
+            Some(id) => match id.checked_add(1) {
+                Some(id) => Self::key_feed(id),
+                None => return Ok(Vec::new()),
+            },
+            None => vec![PREFIX_FEED],
+        };
+        let end: &[u8] = &[PREFIX_FEED + 1];
+
+        let mut feeds = Vec::new();
+        for item in self.tree.range(start.as_slice()..end).take(limit) {
+            let (key, _) = item?;
+            let id = Self::decode_id(&key[1..]);
+            if let Some(author) = self.get_author(id)? {
+                feeds.push((id, author));
+            }
+        }
+
+        Ok(feeds)
+    }
+}
@@ -0,0 +1,110 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Name of the sled tree in which the bans are stored.
+const BANS_TREE: &str = "bans";
+
+/// A temporary ban of a peer.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Ban {
+    /// Public key of the peer.
+    pub pub_key: String,
+    /// Milliseconds since the Unix epoch at which the ban expires.
+    pub until: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Peers with which connections are refused until their ban expires.
+///
+/// Bans let an operator cool down a misbehaving peer without blocking it
+/// for good: no contact message is published, and the ban is lifted once it
+/// expires. Expired bans are removed as they are found. Peer IDs are stored
+/// with the leading `@`, which may be left out when calling the methods.
+#[derive(Clone)]
+pub struct Bans {
+    tree: sled::Tree,
+}
+
+/// Return the ID of a peer with the leading `@`.
+fn peer_key(peer_id: &str) -> String {
+    if peer_id.starts_with('@') {
+        peer_id.to_string()
+    } else {
+        format!("@{peer_id}")
+    }
+}
+
+impl Bans {
+    /// Open the bans tree of the given database.
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let tree = db.open_tree(BANS_TREE)?;
+
+        Ok(Self { tree })
+    }
+
+    /// Ban the given peer until the given time (in milliseconds since the
+    /// Unix epoch), replacing any previous ban of it. Returns the ban.
+    pub fn ban(&self, peer_id: &str, until: u64, reason: Option<String>) -> Result<Ban> {
+        let ban = Ban {
+            pub_key: peer_key(peer_id),
+            until,
+            reason,
+        };
+        self.tree
+            .insert(ban.pub_key.as_bytes(), serde_cbor::to_vec(&ban)?)?;
+        self.tree.flush()?;
+
+        Ok(ban)
+    }
+
+    /// Lift the ban of the given peer. Returns `false` if the peer was not
+    /// banned.
+    pub fn unban(&self, peer_id: &str) -> Result<bool> {
+        let removed = self.tree.remove(peer_key(peer_id))?.is_some();
+        self.tree.flush()?;
+
+        Ok(removed)
+    }
+
+    /// Return the ban of the given peer, unless it has expired by `now`.
+    pub fn get(&self, peer_id: &str, now: u64) -> Result<Option<Ban>> {
+        let key = peer_key(peer_id);
+        let raw = match self.tree.get(&key)? {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+        let ban: Ban = serde_cbor::from_slice(&raw)?;
+        if ban.until <= now {
+            // Only remove the ban read, in case it has just been renewed.
+            let _ = self
+                .tree
+                .compare_and_swap(&key, Some(raw), None as Option<&[u8]>)?;
+            return Ok(None);
+        }
+
+        Ok(Some(ban))
+    }
+
+    /// Whether the given peer is banned at `now`.
+    pub fn is_banned(&self, peer_id: &str, now: u64) -> Result<bool> {
+        Ok(self.get(peer_id, now)?.is_some())
+    }
+
+    /// Return the bans which have not expired by `now`, soonest lifted
+    /// first.
+    pub fn get_all(&self, now: u64) -> Result<Vec<Ban>> {
+        let mut bans = Vec::new();
+
+        for item in self.tree.iter() {
+            let (key, _) = item?;
+            if let Some(ban) = self.get(&String::from_utf8_lossy(&key), now)? {
+                bans.push(ban);
+            }
+        }
+        bans.sort_by_key(|ban| ban.until);
+
+        Ok(bans)
+    }
+}
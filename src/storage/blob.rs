@@ -1,6 +1,6 @@
 use std::{
-    fs::File,
-    io::{Read, Result, Write},
+    fs::{self, File},
+    io::{Error, ErrorKind, Read, Result, Write},
     path::{Path, PathBuf},
 };
 
@@ -9,10 +9,28 @@ use sha2::{Digest, Sha256};
 
 use crate::broker::{BrokerEvent, ChBrokerSend, Destination};
 
+/// Name of the directory holding the shards of the blob store, after the
+/// hash function of the blob IDs.
+const SHARDS_DIR: &str = "sha256";
+
 pub enum StoBlobEvent {
     Added(String),
 }
 
+/// Result of verifying a stored blob against its ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobCheck {
+    /// The content of the blob matches its ID.
+    Valid,
+    /// No file is stored for the blob.
+    Missing,
+    /// The content of the blob does not match its ID.
+    Corrupt,
+}
+
+/// Blobs stored on the filesystem, one file per blob, under
+/// `sha256/<first two hex digits of the hash>/<other hex digits>` in the
+/// blobs folder (the layout of `.ssb/blobs`).
 #[derive(Default)]
 pub struct BlobStorage {
     path: Option<PathBuf>,
@@ -32,26 +50,66 @@ impl ToBlobHashId for &[u8] {
 }
 
 impl BlobStorage {
-    pub fn open(&mut self, path: PathBuf, ch_broker: ChBrokerSend) {
+    /// Open the blob store in the given folder, moving any blobs stored
+    /// by earlier versions directly in the folder into their shards.
+    /// Returns the number of blobs moved.
+    pub fn open(&mut self, path: PathBuf, ch_broker: ChBrokerSend) -> Result<usize> {
         self.path = Some(path);
         self.ch_broker = Some(ch_broker);
+
+        self.migrate_flat()
+    }
+
+    /// Move the blobs stored directly in the blobs folder, named after their
+    /// ID with `/` replaced by `_` and no sigil, into their shards.
+    fn migrate_flat(&self) -> Result<usize> {
+        let mut moved = 0;
+        for entry in fs::read_dir(self.path.as_ref().unwrap())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().replace('_', "/");
+            if !entry.file_type()?.is_file() || !name.ends_with(".sha256") {
+                continue;
+            }
+            let path = match self.path_of(&format!("&{name}")) {
+                Ok(path) => path,
+                Err(_) => continue,
+            };
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::rename(entry.path(), path)?;
+            moved += 1;
+        }
+
+        Ok(moved)
     }
-    fn path_of(&self, id: &str) -> PathBuf {
-        let id = id.replace('&', "").replace('/', "_");
-        [self.path.as_ref().unwrap(), Path::new(&id)]
-            .iter()
-            .collect()
+
+    /// Return the path of the file of the blob with the given ID.
+    fn path_of(&self, id: &str) -> Result<PathBuf> {
+        let hash = id
+            .strip_prefix('&')
+            .and_then(|id| id.strip_suffix(".sha256"))
+            .and_then(|hash| base64::decode(hash).ok())
+            .filter(|hash| hash.len() == 32)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("invalid blob id {id}")))?;
+        let hash = hex::encode(hash);
+
+        Ok([
+            self.path.as_ref().unwrap(),
+            Path::new(SHARDS_DIR),
+            Path::new(&hash[..2]),
+            Path::new(&hash[2..]),
+        ]
+        .iter()
+        .collect())
     }
     pub fn size_of(&self, id: &str) -> Result<Option<u64>> {
-        if let Ok(metadata) = std::fs::metadata(self.path_of(id)) {
-            Ok(Some(metadata.len()))
-        } else {
-            Ok(None)
+        match self.path_of(id).and_then(std::fs::metadata) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(_) => Ok(None),
         }
     }
     pub async fn insert<D: AsRef<[u8]>>(&self, content: D) -> Result<String> {
         let id = content.as_ref().blob_hash_id();
-        File::create(self.path_of(&id))?.write_all(content.as_ref())?;
+        self.write(&id, content.as_ref())?;
 
         let broker_msg = BrokerEvent::new(Destination::Broadcast, StoBlobEvent::Added(id.clone()));
 
@@ -64,13 +122,85 @@ impl BlobStorage {
 
         Ok(id)
     }
+
+    /// Write the file of the blob with the given ID, which must be the hash
+    /// of the content. No event is sent to the broker, so that blobs can be
+    /// written before the node is started.
+    pub fn write(&self, id: &str, content: &[u8]) -> Result<()> {
+        let path = self.path_of(id)?;
+        fs::create_dir_all(path.parent().unwrap())?;
+        // Write to a temporary file first, so that an interrupted write
+        // leaves no partial blob behind.
+        let tmp_path = path.with_extension("tmp");
+        File::create(&tmp_path)?.write_all(content)?;
+        fs::rename(tmp_path, path)
+    }
+
     pub fn get(&self, id: &str) -> Result<Vec<u8>> {
-        let mut file = File::open(self.path_of(id))?;
+        let mut file = File::open(self.path_of(id)?)?;
         let mut content = Vec::with_capacity(file.metadata()?.len() as usize);
         file.read_to_end(&mut content)?;
         Ok(content)
     }
     pub fn exists(&self, id: &str) -> bool {
-        self.path_of(id).exists()
+        self.path_of(id).is_ok_and(|path| path.exists())
+    }
+
+    /// Delete the file of the blob with the given ID. Returns `false` if no
+    /// file was stored for it.
+    pub fn remove(&self, id: &str) -> Result<bool> {
+        match fs::remove_file(self.path_of(id)?) {
+            Ok(()) => Ok(true),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Check that the file of the blob with the given ID exists and that its
+    /// content hashes to the ID.
+    pub fn verify(&self, id: &str) -> Result<BlobCheck> {
+        match self.get(id) {
+            Ok(content) if content.as_slice().blob_hash_id() == id => Ok(BlobCheck::Valid),
+            Ok(_) => Ok(BlobCheck::Corrupt),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(BlobCheck::Missing),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Return the IDs of the blobs stored, whether valid or not, in no
+    /// particular order.
+    pub fn ids(&self) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+        let shards_path = self.path.as_ref().unwrap().join(SHARDS_DIR);
+        if !shards_path.is_dir() {
+            return Ok(ids);
+        }
+        for shard in fs::read_dir(shards_path)? {
+            let shard = shard?;
+            let prefix = shard.file_name().to_string_lossy().to_string();
+            if !shard.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(shard.path())? {
+                let name = entry?.file_name().to_string_lossy().to_string();
+                if let Some(id) = shard_id(&prefix, &name) {
+                    ids.push(id);
+                }
+            }
+        }
+
+        Ok(ids)
+    }
+}
+
+/// Return the ID of the blob stored in the given shard directory under the
+/// given file name, if the name is that of a blob file. Temporary files have
+/// an extension, and so are not valid hex.
+pub fn shard_id(shard: &str, name: &str) -> Option<String> {
+    match hex::decode(format!("{shard}{name}")) {
+        Ok(hash) if shard.len() == 2 && hash.len() == 32 => {
+            Some(format!("&{}.sha256", base64::encode(hash)))
+        }
+        _ => None,
     }
 }
@@ -0,0 +1,141 @@
+//! Verification of the blob store against the blob metadata.
+//!
+//! Every blob recorded as stored in the database is checked for a file
+//! whose content hashes to its ID. Blobs whose file is missing, or whose
+//! content does not match, are recorded as no longer stored (corrupt files
+//! are deleted) so that they can be requested again. Valid files which are
+//! not recorded, e.g. blobs stored by earlier versions, are recorded.
+
+use serde::Serialize;
+
+use crate::{
+    storage::{
+        blob::{BlobCheck, BlobStorage},
+        kv::KvStorage,
+    },
+    Result,
+};
+
+/// Result of a verification of the blob store.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct BlobFsckReport {
+    /// Number of blob files found valid.
+    pub valid: u64,
+    /// Blobs recorded as stored with no file.
+    pub missing: Vec<String>,
+    /// Blobs whose file does not match their ID. The files are deleted.
+    pub corrupt: Vec<String>,
+    /// Number of valid blob files which were not recorded as stored.
+    pub untracked: u64,
+}
+
+impl BlobFsckReport {
+    /// Return the blobs to be requested again from peers.
+    pub fn lost(&self) -> impl Iterator<Item = &String> {
+        self.missing.iter().chain(self.corrupt.iter())
+    }
+}
+
+/// Verify every blob recorded in the database or stored on the filesystem,
+/// and update the database to match the files.
+pub fn fsck(blobs: &BlobStorage, db: &KvStorage) -> Result<BlobFsckReport> {
+    let mut report = BlobFsckReport::default();
+
+    let recorded = db.get_retrieved_blobs()?;
+    for blob_id in &recorded {
+        match blobs.verify(blob_id)? {
+            BlobCheck::Valid => report.valid += 1,
+            BlobCheck::Missing => {
+                db.set_blob_retrieved(blob_id, false)?;
+                report.missing.push(blob_id.clone());
+            }
+            BlobCheck::Corrupt => {
+                blobs.remove(blob_id)?;
+                db.set_blob_retrieved(blob_id, false)?;
+                report.corrupt.push(blob_id.clone());
+            }
+        }
+    }
+
+    for blob_id in blobs.ids()? {
+        if recorded.contains(&blob_id) {
+            continue;
+        }
+        match blobs.verify(&blob_id)? {
+            BlobCheck::Valid => {
+                db.set_blob_retrieved(&blob_id, true)?;
+                report.untracked += 1;
+            }
+            BlobCheck::Corrupt => {
+                blobs.remove(&blob_id)?;
+                report.corrupt.push(blob_id);
+            }
+            BlobCheck::Missing => (),
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+
+    use sled::Config as KvConfig;
+
+    use crate::storage::blob::ToBlobHashId;
+
+    #[async_std::test]
+    async fn test_blob_fsck() -> Result<()> {
+        let dir = tempdir::TempDir::new("solarblobs")?;
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let mut db = KvStorage::default();
+        db.open(KvConfig::new().path(dir.path().join("db")), sender.clone())?;
+
+        // A blob stored by an earlier version, directly in the folder.
+        let blobs_path = dir.path().join("blobs");
+        fs::create_dir(&blobs_path)?;
+        let legacy_id = b"legacy".as_slice().blob_hash_id();
+        fs::write(blobs_path.join(legacy_id[1..].replace('/', "_")), b"legacy")?;
+        let mut blobs = BlobStorage::default();
+        assert_eq!(blobs.open(blobs_path.clone(), sender)?, 1);
+        assert_eq!(blobs.get(&legacy_id)?, b"legacy");
+
+        let valid_id = blobs.insert(b"valid").await?;
+        let corrupt_id = blobs.insert(b"corrupt").await?;
+        let missing_id = blobs.insert(b"missing").await?;
+        for blob_id in [&valid_id, &corrupt_id, &missing_id] {
+            db.set_blob_retrieved(blob_id, true)?;
+        }
+        blobs.remove(&missing_id)?;
+        fs::write(
+            blobs_path
+                .join("sha256")
+                .join(&hex_hash(&corrupt_id)[..2])
+                .join(&hex_hash(&corrupt_id)[2..]),
+            b"tampered",
+        )?;
+
+        let report = fsck(&blobs, &db)?;
+        assert_eq!(report.valid, 1);
+        assert_eq!(report.missing, vec![missing_id.clone()]);
+        assert_eq!(report.corrupt, vec![corrupt_id.clone()]);
+        assert_eq!(report.untracked, 1);
+        assert!(!blobs.exists(&corrupt_id));
+        assert!(!db.get_retrieved_blobs()?.contains(&missing_id));
+        assert!(db.get_retrieved_blobs()?.contains(&legacy_id));
+
+        // The store is now consistent.
+        let report = fsck(&blobs, &db)?;
+        assert_eq!(report.valid, 2);
+        assert_eq!(report.lost().count(), 0);
+
+        Ok(())
+    }
+
+    fn hex_hash(blob_id: &str) -> String {
+        hex::encode(base64::decode(&blob_id[1..blob_id.len() - 7]).unwrap())
+    }
+}
@@ -0,0 +1,120 @@
+//! Import of the blobs of a legacy blob store.
+//!
+//! Blob stores of other SSB implementations (e.g. `~/.ssb/blobs`) keep each
+//! blob under `sha256/<first two hex digits of the hash>/<other hex
+//! digits>`, as does the local store. Each blob file found there is checked
+//! against the hash given by its path, copied into the local store and
+//! recorded as retrieved, so that it is not fetched again from peers.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    storage::{
+        blob::{self, BlobCheck, BlobStorage, ToBlobHashId},
+        kv::KvStorage,
+    },
+    Result,
+};
+
+/// Outcome of an import.
+#[derive(Debug, Default, PartialEq)]
+pub struct BlobImportReport {
+    /// Number of blobs copied into the local store.
+    pub imported: u64,
+    /// Number of blobs which were already stored.
+    pub existing: u64,
+    /// Files whose content does not match the hash given by their path.
+    /// They are not imported.
+    pub corrupt: Vec<PathBuf>,
+}
+
+/// Import the blobs stored in the given directory: the `blobs` directory of
+/// a legacy installation, or its `sha256` subdirectory.
+pub fn import_dir(blobs: &BlobStorage, db: &KvStorage, dir: &Path) -> Result<BlobImportReport> {
+    let mut report = BlobImportReport::default();
+    let shards_dir = match dir.join("sha256") {
+        shards_dir if shards_dir.is_dir() => shards_dir,
+        _ => dir.to_path_buf(),
+    };
+
+    for shard in fs::read_dir(shards_dir)? {
+        let shard = shard?;
+        if !shard.file_type()?.is_dir() {
+            continue;
+        }
+        let prefix = shard.file_name().to_string_lossy().to_string();
+        for entry in fs::read_dir(shard.path())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let blob_id = match blob::shard_id(&prefix, &name) {
+                Some(blob_id) if entry.file_type()?.is_file() => blob_id,
+                _ => continue,
+            };
+
+            if blobs.verify(&blob_id)? == BlobCheck::Valid {
+                report.existing += 1;
+            } else {
+                let content = fs::read(entry.path())?;
+                if content.as_slice().blob_hash_id() != blob_id {
+                    report.corrupt.push(entry.path());
+                    continue;
+                }
+                blobs.write(&blob_id, &content)?;
+                report.imported += 1;
+            }
+            db.set_blob_retrieved(&blob_id, true)?;
+            db.blob_wants().remove(&blob_id)?;
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use sha2::Digest;
+    use sled::Config as KvConfig;
+
+    #[test]
+    fn test_import_blobs() -> Result<()> {
+        let dir = tempdir::TempDir::new("solarblobs")?;
+        let (sender, _receiver) = futures::channel::mpsc::unbounded();
+        let mut db = KvStorage::default();
+        db.open(KvConfig::new().path(dir.path().join("db")), sender.clone())?;
+        let mut blobs = BlobStorage::default();
+        fs::create_dir(dir.path().join("blobs"))?;
+        blobs.open(dir.path().join("blobs"), sender)?;
+
+        // A legacy store holding two blobs, one of them corrupt, along with
+        // a file which is not a blob.
+        let legacy_dir = dir.path().join("legacy");
+        let mut files = Vec::new();
+        for content in [b"hello".as_slice(), b"world".as_slice()] {
+            let hash = hex::encode(sha2::Sha256::digest(content));
+            let shard_dir = legacy_dir.join("sha256").join(&hash[..2]);
+            fs::create_dir_all(&shard_dir)?;
+            files.push((shard_dir.join(&hash[2..]), content.blob_hash_id()));
+        }
+        fs::write(&files[0].0, b"hello")?;
+        fs::write(&files[1].0, b"tampered")?;
+        fs::write(files[0].0.with_extension("tmp"), b"partial")?;
+
+        let report = import_dir(&blobs, &db, &legacy_dir)?;
+        assert_eq!(report.imported, 1);
+        assert_eq!(report.corrupt, vec![files[1].0.clone()]);
+        assert_eq!(blobs.get(&files[0].1)?, b"hello");
+        assert!(!blobs.exists(&files[1].1));
+        assert_eq!(db.get_retrieved_blobs()?, vec![files[0].1.clone()]);
+
+        // Importing again, from the shards directory, finds the blob stored.
+        let report = import_dir(&blobs, &db, &legacy_dir.join("sha256"))?;
+        assert_eq!((report.imported, report.existing), (0, 1));
+
+        Ok(())
+    }
+}
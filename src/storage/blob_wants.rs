@@ -0,0 +1,92 @@
+use std::{cmp::Reverse, collections::BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Name of the sled tree in which the wanted blobs are stored.
+const BLOB_WANTS_TREE: &str = "blob_wants";
+
+/// Priority of a wanted blob. Blobs with a higher priority are fetched
+/// first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlobPriority {
+    /// Referenced by a message from a peer we do not follow directly.
+    Other,
+    /// Referenced by a message from a direct follow.
+    Follow,
+    /// Requested explicitly (e.g. with the `fetchBlob` JSON-RPC method).
+    Explicit,
+}
+
+/// A blob to be fetched from peers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BlobWant {
+    pub blob_id: String,
+    pub priority: BlobPriority,
+    /// Maximum size in bytes accepted for the blob, if any.
+    pub max: Option<u64>,
+    /// Timestamp of the message referencing the blob, in milliseconds since
+    /// the Unix epoch. Most recently referenced blobs are fetched first
+    /// within the same priority.
+    pub timestamp: u64,
+    /// Peers likely to hold the blob: the peers which sent the message
+    /// referencing it or announced having it. These are asked first.
+    pub hints: BTreeSet<String>,
+    /// Number of times a peer failed to provide the blob.
+    pub retries: u32,
+}
+
+/// Blobs to be fetched from peers, by blob ID.
+///
+/// The want-list outlives connections and restarts: blobs are removed from
+/// it once they are fetched, skipped by the blob fetching policy or no
+/// longer wanted.
+#[derive(Clone)]
+pub struct BlobWants {
+    tree: sled::Tree,
+}
+
+impl BlobWants {
+    /// Open the blob want-list tree of the given database.
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let tree = db.open_tree(BLOB_WANTS_TREE)?;
+
+        Ok(Self { tree })
+    }
+
+    /// Add the given blob to the want-list, replacing any previous entry.
+    pub fn insert(&self, want: &BlobWant) -> Result<()> {
+        self.tree.insert(&want.blob_id, serde_cbor::to_vec(want)?)?;
+
+        Ok(())
+    }
+
+    /// Return the entry of the given blob, if it is wanted.
+    pub fn get(&self, blob_id: &str) -> Result<Option<BlobWant>> {
+        match self.tree.get(blob_id)? {
+            Some(raw) => Ok(Some(serde_cbor::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Remove the given blob from the want-list. Returns `false` if it was
+    /// not wanted.
+    pub fn remove(&self, blob_id: &str) -> Result<bool> {
+        Ok(self.tree.remove(blob_id)?.is_some())
+    }
+
+    /// Return the wanted blobs, in the order in which they are fetched:
+    /// highest priority first, then most recently referenced first.
+    pub fn get_all(&self) -> Result<Vec<BlobWant>> {
+        let mut wants = Vec::new();
+        for item in self.tree.iter() {
+            let (_, raw) = item?;
+            wants.push(serde_cbor::from_slice::<BlobWant>(&raw)?);
+        }
+        wants.sort_by_key(|want| Reverse((want.priority, want.timestamp)));
+
+        Ok(wants)
+    }
+}
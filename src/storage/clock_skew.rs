@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::{CLOCK_SKEW_CONFIG, DEFAULT_MAX_CLOCK_SKEW},
+    Result,
+};
+
+/// Name of the sled tree in which the clock skew of feeds is recorded.
+const CLOCK_SKEW_TREE: &str = "clock_skew";
+
+/// Return the maximum clock skew in milliseconds: the time by which the
+/// timestamp claimed by a message may be later than its receipt.
+pub fn max_clock_skew() -> f64 {
+    CLOCK_SKEW_CONFIG
+        .get()
+        .copied()
+        .unwrap_or(DEFAULT_MAX_CLOCK_SKEW) as f64
+        * 1000.0
+}
+
+/// Return the timestamp by which a message is ordered, in milliseconds since
+/// the Unix epoch: the timestamp claimed by its author, unless it is later
+/// than the time at which the message was received by more than the maximum
+/// clock skew, in which case the time of receipt. A feed whose clock runs
+/// ahead thus can't keep its messages ahead of the others.
+pub fn ordering_timestamp(claimed: f64, received: f64) -> f64 {
+    if claimed - received > max_clock_skew() {
+        received
+    } else {
+        claimed
+    }
+}
+
+/// The difference between the timestamps claimed by the messages of a feed
+/// and the times at which they were received, in milliseconds. Positive
+/// values mean that the timestamps are later than the receipts, i.e. that
+/// the clock of the author runs ahead.
+///
+/// Messages replicated long after they were published have large negative
+/// differences; only differences in the future point to a skewed clock.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct FeedSkew {
+    pub feed_id: String,
+    /// Number of messages received.
+    pub messages: u64,
+    /// Difference for the latest message received.
+    pub latest_skew: i64,
+    /// Largest difference for any message received.
+    pub max_skew: i64,
+}
+
+impl FeedSkew {
+    /// Add the timestamp claimed by a message of the feed and the time at
+    /// which it was received.
+    fn add(&mut self, claimed: f64, received: f64) {
+        let skew = (claimed - received) as i64;
+        self.max_skew = if self.messages == 0 {
+            skew
+        } else {
+            self.max_skew.max(skew)
+        };
+        self.messages += 1;
+        self.latest_skew = skew;
+    }
+}
+
+/// Clock skew of the feeds whose messages have been received, by feed ID.
+#[derive(Clone)]
+pub struct ClockSkew {
+    tree: sled::Tree,
+}
+
+impl ClockSkew {
+    /// Open the clock skew tree of the given database.
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let tree = db.open_tree(CLOCK_SKEW_TREE)?;
+
+        Ok(Self { tree })
+    }
+
+    /// Record the timestamp claimed by a message of the given feed and the
+    /// time at which it was received, in milliseconds since the Unix epoch.
+    pub fn record(&self, feed_id: &str, claimed: f64, received: f64) -> Result<()> {
+        let mut feed_skew = self.get(feed_id)?.unwrap_or_else(|| FeedSkew {
+            feed_id: feed_id.to_string(),
+            ..Default::default()
+        });
+        feed_skew.add(claimed, received);
+        self.tree.insert(feed_id, serde_cbor::to_vec(&feed_skew)?)?;
+
+        Ok(())
+    }
+
+    /// Replace the recorded clock skew of the given feed with that of the
+    /// given claimed and receipt timestamps of its messages, in feed order,
+    /// e.g. once the content of one of them has been deleted.
+    pub fn recompute(
+        &self,
+        feed_id: &str,
+        timestamps: impl IntoIterator<Item = (f64, f64)>,
+    ) -> Result<()> {
+        let mut feed_skew = FeedSkew {
+            feed_id: feed_id.to_string(),
+            ..Default::default()
+        };
+        for (claimed, received) in timestamps {
+            feed_skew.add(claimed, received);
+        }
+        if feed_skew.messages == 0 {
+            self.tree.remove(feed_id)?;
+        } else {
+            self.tree.insert(feed_id, serde_cbor::to_vec(&feed_skew)?)?;
+        }
+
+        Ok(())
+    }
+
+    /// Return the clock skew of the given feed, if any message of it has
+    /// been received.
+    pub fn get(&self, feed_id: &str) -> Result<Option<FeedSkew>> {
+        match self.tree.get(feed_id)? {
+            Some(raw) => Ok(Some(serde_cbor::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Return the feeds which have claimed a timestamp later than the
+    /// receipt of its message by more than `max_skew` milliseconds, in
+    /// order of feed ID.
+    pub fn get_skewed(&self, max_skew: i64) -> Result<Vec<FeedSkew>> {
+        let mut skewed = Vec::new();
+        for item in self.tree.iter() {
+            let (_, raw) = item?;
+            let feed_skew: FeedSkew = serde_cbor::from_slice(&raw)?;
+            if feed_skew.max_skew > max_skew {
+                skewed.push(feed_skew);
+            }
+        }
+
+        Ok(skewed)
+    }
+
+    /// Forget the recorded clock skew of every feed.
+    pub fn clear(&self) -> Result<()> {
+        self.tree.clear()?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Name of the sled tree in which the delivery queues are stored.
+const DELIVERIES_TREE: &str = "deliveries";
+
+/// What remains to be delivered to a peer.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DeliveryQueue {
+    /// Latest sequence number held by the peer of each feed it has
+    /// requested, as of its latest request or the latest message sent to it.
+    pub feeds: BTreeMap<String, u64>,
+    /// Blobs referenced by messages of those feeds, which the peer has not
+    /// fetched yet.
+    pub blobs: BTreeSet<String>,
+}
+
+/// Peers to which messages and blobs are delivered whenever they are seen.
+///
+/// Meant for mostly-offline friends who connect briefly (e.g. over LAN):
+/// their connections are accepted even with selective replication, and the
+/// blobs they miss are announced to them as soon as they connect rather
+/// than waiting for them to ask. Peer IDs are stored with the leading `@`,
+/// which may be left out when calling the methods.
+#[derive(Clone)]
+pub struct Deliveries {
+    tree: sled::Tree,
+}
+
+/// Return the ID of a peer with the leading `@`.
+fn peer_key(peer_id: &str) -> String {
+    if peer_id.starts_with('@') {
+        peer_id.to_string()
+    } else {
+        format!("@{peer_id}")
+    }
+}
+
+impl Deliveries {
+    /// Open the deliveries tree of the given database.
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let tree = db.open_tree(DELIVERIES_TREE)?;
+
+        Ok(Self { tree })
+    }
+
+    /// Deliver messages and blobs to the given peer whenever it is seen.
+    /// Returns `false` if deliveries to the peer were already enabled.
+    pub fn add(&self, peer_id: &str) -> Result<bool> {
+        let key = peer_key(peer_id);
+        if self.tree.contains_key(&key)? {
+            return Ok(false);
+        }
+        self.set(&key, &DeliveryQueue::default())?;
+
+        Ok(true)
+    }
+
+    /// Stop delivering to the given peer and drop its queue. Returns `false`
+    /// if deliveries to the peer were not enabled.
+    pub fn remove(&self, peer_id: &str) -> Result<bool> {
+        let removed = self.tree.remove(peer_key(peer_id))?.is_some();
+        self.tree.flush()?;
+
+        Ok(removed)
+    }
+
+    /// Whether messages and blobs are delivered to the given peer.
+    pub fn contains(&self, peer_id: &str) -> Result<bool> {
+        Ok(self.tree.contains_key(peer_key(peer_id))?)
+    }
+
+    /// Return the queue of the given peer, if deliveries to it are enabled.
+    pub fn get(&self, peer_id: &str) -> Result<Option<DeliveryQueue>> {
+        match self.tree.get(peer_key(peer_id))? {
+            Some(raw) => Ok(Some(serde_cbor::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Return the peers to which deliveries are enabled, with their queues.
+    pub fn get_all(&self) -> Result<Vec<(String, DeliveryQueue)>> {
+        let mut deliveries = Vec::new();
+
+        for item in self.tree.iter() {
+            let (key, value) = item?;
+            deliveries.push((
+                String::from_utf8_lossy(&key).to_string(),
+                serde_cbor::from_slice(&value)?,
+            ));
+        }
+
+        Ok(deliveries)
+    }
+
+    /// Record that the given peer holds the given feed up to `seq`, unless
+    /// it is already known to hold more of it. Ignored unless deliveries to
+    /// the peer are enabled.
+    pub fn record_held(&self, peer_id: &str, feed_id: &str, seq: u64) -> Result<()> {
+        self.update(&peer_key(peer_id), |queue| match queue.feeds.get(feed_id) {
+            Some(held) if *held >= seq => false,
+            _ => {
+                queue.feeds.insert(feed_id.to_string(), seq);
+                true
+            }
+        })
+    }
+
+    /// Queue the given blobs, referenced by a message of the given feed, for
+    /// delivery to each peer which has requested the feed.
+    pub fn queue_blobs(&self, feed_id: &str, blob_ids: &[String]) -> Result<()> {
+        if blob_ids.is_empty() {
+            return Ok(());
+        }
+
+        for (peer_id, _) in self.get_all()? {
+            self.update(&peer_id, |queue| {
+                if !queue.feeds.contains_key(feed_id) {
+                    return false;
+                }
+                let queued = queue.blobs.len();
+                queue.blobs.extend(blob_ids.iter().cloned());
+                queue.blobs.len() > queued
+            })?;
+        }
+
+        Ok(())
+    }
+
+    /// Record that the given peer has fetched the given blob.
+    pub fn remove_blob(&self, peer_id: &str, blob_id: &str) -> Result<()> {
+        self.update(&peer_key(peer_id), |queue| queue.blobs.remove(blob_id))
+    }
+
+    /// Atomically apply `change` to the queue stored under the given key, if
+    /// any. `change` returns whether it modified the queue.
+    fn update<F>(&self, key: &str, change: F) -> Result<()>
+    where
+        F: Fn(&mut DeliveryQueue) -> bool,
+    {
+        let mut changed = false;
+        self.tree.fetch_and_update(key, |raw| {
+            // Returning `None` would remove the entry, so entries which fail
+            // to decode are left as they are.
+            let raw = raw?;
+            let mut queue: DeliveryQueue = match serde_cbor::from_slice(raw) {
+                Ok(queue) => queue,
+                Err(_) => return Some(raw.to_vec()),
+            };
+            changed = change(&mut queue);
+            match changed {
+                true => serde_cbor::to_vec(&queue).ok(),
+                false => Some(raw.to_vec()),
+            }
+        })?;
+        if changed {
+            self.tree.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn set(&self, key: &str, queue: &DeliveryQueue) -> Result<()> {
+        self.tree.insert(key, serde_cbor::to_vec(queue)?)?;
+        self.tree.flush()?;
+
+        Ok(())
+    }
+}
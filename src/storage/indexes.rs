@@ -0,0 +1,1604 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    mem,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use kuska_ssb::feed::{Feed as MessageKvt, Message as MessageValue};
+use serde::{Deserialize, Serialize};
+use sled::IVec;
+
+use crate::{api::schema::SCHEMAS, storage::authors::Authors, Result};
+
+/// Name of the sled tree in which the indexes are stored.
+const INDEXES_TREE: &str = "indexes";
+
+/// Prefix for a key to the follow state of an author for a contact. Both
+/// are stored as interned author IDs.
+const PREFIX_CONTACT: u8 = 0u8;
+/// Prefix for a key to the follow state of a contact for an author
+/// (the reverse of `PREFIX_CONTACT`).
+const PREFIX_FOLLOWER: u8 = 1u8;
+/// Prefix for a key to a message belonging to the thread with a given root.
+const PREFIX_THREAD: u8 = 2u8;
+/// Prefix for a key to the block state of an author for a contact. Both are
+/// stored as interned author IDs.
+const PREFIX_BLOCK: u8 = 3u8;
+/// Prefix for a key to a flag or report message about a given feed or
+/// message.
+const PREFIX_REPORT: u8 = 4u8;
+/// Prefix for a key to a message with an `expires` hint, ordered by the
+/// expiry timestamp.
+const PREFIX_EXPIRY: u8 = 5u8;
+/// Key of the marker recording that index writes are pending, i.e. that the
+/// indexes may be missing updates if the process stops before they are
+/// committed. It holds the position in the global order of the earliest
+/// feed message whose writes are pending, from which they are replayed, or
+/// nothing if they can only be recovered by reindexing.
+const KEY_PENDING: u8 = 6u8;
+/// Prefix for a key to a message whose content violates the schemas
+/// registered for its type, holding the reason.
+const PREFIX_INVALID: u8 = 7u8;
+/// Prefix for a key to a gathering, holding its details and attendees.
+const PREFIX_GATHERING: u8 = 8u8;
+/// Prefix for a key to a git-ssb repository, holding its owner and name.
+const PREFIX_GIT_REPO: u8 = 9u8;
+/// Prefix for a key to a git-ssb repository owned by a given feed.
+const PREFIX_GIT_OWNER: u8 = 10u8;
+/// Prefix for a key to an update pushed to a git-ssb repository, ordered by
+/// the timestamp of the update.
+const PREFIX_GIT_UPDATE: u8 = 11u8;
+/// Prefix for a key to a post in a given channel, ordered by the timestamp
+/// of the post.
+const PREFIX_CHANNEL: u8 = 12u8;
+/// Prefix for a key to a feed message, ordered by its indexed timestamp
+/// (see `indexed_timestamp`).
+const PREFIX_TIMESTAMP: u8 = 13u8;
+/// Prefix for a key to the number of feed messages indexed on a given day
+/// (UTC), counted in days since the Unix epoch.
+const PREFIX_DAY_COUNT: u8 = 14u8;
+/// Prefix for a key to a feed which published a message on a given day
+/// (UTC), stored as an interned author ID.
+const PREFIX_DAY_AUTHOR: u8 = 15u8;
+
+/// Byte used to separate the components of a compound key.
+const SEPARATOR: u8 = 0u8;
+
+/// Number of pending index writes after which they are committed.
+const BATCH_SIZE: usize = 512;
+/// Time after which pending index writes are committed.
+const BATCH_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Number of milliseconds in a day.
+const MS_PER_DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// The follow and block relationships between feeds, as pairs of author
+/// and contact IDs.
+#[derive(Debug, Default, PartialEq)]
+pub struct ContactGraph {
+    pub follows: Vec<(String, String)>,
+    pub blocks: Vec<(String, String)>,
+}
+
+/// A flag or report published about a feed or message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Report {
+    /// ID of the flag or report message.
+    pub msg_ref: String,
+    /// Feed which published the report.
+    pub author: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+/// Number of reports about a feed or message.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ReportCount {
+    pub target: String,
+    /// Total number of reports.
+    pub reports: usize,
+    /// Number of distinct feeds which published a report.
+    pub reporters: usize,
+}
+
+/// A message which asked to be kept only until the given time.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Expiry {
+    pub msg_ref: String,
+    pub author: String,
+    /// Milliseconds since the Unix epoch.
+    pub expires: u64,
+}
+
+/// Return the expiry hint of the given message content, in milliseconds
+/// since the Unix epoch, following the `expires` content convention.
+pub fn expires_at(content: &serde_json::Value) -> Option<u64> {
+    content["expires"]
+        .as_f64()
+        .filter(|expires| *expires >= 0.0)
+        .map(|expires| expires as u64)
+}
+
+/// Return the target of the given flag or report message content: linked
+/// directly or, in the nested form, from a `flag` object.
+fn report_target(content: &serde_json::Value) -> Option<&str> {
+    content["link"]
+        .as_str()
+        .or_else(|| content["flag"]["link"].as_str())
+}
+
+/// Return the timestamp by which a message is indexed, in milliseconds since
+/// the Unix epoch: the timestamp claimed by its author, unless it is
+/// negative or later than the time at which the message was received, in
+/// which case the time of receipt.
+pub fn indexed_timestamp(claimed: f64, received: f64) -> u64 {
+    if (0.0..=received).contains(&claimed) {
+        claimed as u64
+    } else {
+        received.max(0.0) as u64
+    }
+}
+
+/// Normalize the name of a channel: the leading `#` is dropped and the name
+/// lowercased. Returns `None` if the name is empty or holds the separator
+/// of key components.
+fn channel_name(channel: &str) -> Option<String> {
+    let channel = channel.trim().trim_start_matches('#').to_lowercase();
+    (!channel.is_empty() && !channel.contains('\0')).then_some(channel)
+}
+
+/// A gathering (event), with the details and attendance published about it
+/// in `about` and `gathering-update` messages.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Gathering {
+    /// ID of the `gathering` message.
+    pub msg_ref: String,
+    /// Feed which published the gathering. Unknown until the `gathering`
+    /// message itself is indexed.
+    pub author: Option<String>,
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    /// Blob ID of the image of the gathering.
+    pub image: Option<String>,
+    /// Start time in milliseconds since the Unix epoch.
+    pub start: Option<u64>,
+    /// Feeds which have announced that they are attending.
+    pub attendees: BTreeSet<String>,
+}
+
+impl Gathering {
+    /// Apply the details in the given message content, i.e. those fields
+    /// which are set.
+    fn update_details(&mut self, content: &serde_json::Value) {
+        let text = |field: &str| content[field].as_str().map(String::from);
+        if let Some(title) = text("title") {
+            self.title = Some(title);
+        }
+        if let Some(description) = text("description") {
+            self.description = Some(description);
+        }
+        if let Some(location) = text("location") {
+            self.location = Some(location);
+        }
+        // The image is either a blob ID or an object linking to it.
+        let image = &content["image"];
+        if let Some(image) = image.as_str().or_else(|| image["link"].as_str()) {
+            self.image = Some(image.to_string());
+        }
+        if let Some(start) = content["startDateTime"]["epoch"]
+            .as_f64()
+            .filter(|start| *start >= 0.0)
+        {
+            self.start = Some(start as u64);
+        }
+    }
+}
+
+/// A git-ssb repository, created by a `git-repo` message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GitRepo {
+    /// ID of the `git-repo` message.
+    pub repo_id: String,
+    pub owner: String,
+    /// Name given in the `git-repo` message or by the owner in an `about`
+    /// message.
+    pub name: Option<String>,
+}
+
+/// A commit listed in a `git-update` message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GitCommit {
+    pub sha1: String,
+    pub title: Option<String>,
+}
+
+/// An update pushed to a git-ssb repository by a `git-update` message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GitUpdate {
+    /// ID of the `git-update` message.
+    pub msg_ref: String,
+    pub author: String,
+    /// Milliseconds since the Unix epoch, as claimed by the author.
+    pub timestamp: u64,
+    /// New commit of each updated ref; `None` if the ref was deleted.
+    pub refs: BTreeMap<String, Option<String>>,
+    pub commits: Vec<GitCommit>,
+}
+
+/// A change in the follow or block state of a feed for a contact.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ContactChange {
+    /// Feed which published the contact message.
+    pub author: String,
+    pub contact: String,
+    pub kind: ContactChangeKind,
+    /// ID of the contact message.
+    pub msg_ref: String,
+}
+
+/// Kind of a change in the follow or block state of a feed for a contact.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContactChangeKind {
+    Follow,
+    Unfollow,
+    Block,
+    Unblock,
+}
+
+/// Index writes which have not yet been committed to the tree.
+#[derive(Default)]
+struct PendingWrites {
+    writes: BTreeMap<Vec<u8>, IVec>,
+    /// Time of the oldest pending write.
+    since: Option<Instant>,
+    /// Number of messages indexed on each day since the last commit, added
+    /// to the stored counts when committing.
+    day_counts: BTreeMap<u32, u64>,
+    /// Position in the global order recorded by the pending marker, if any.
+    replay_from: Option<u64>,
+    /// Whether writes which are not replayed from a feed message are
+    /// pending, e.g. those of a reindex.
+    untracked: bool,
+    /// Positions in the global order of the feed messages being indexed.
+    indexing: BTreeSet<u64>,
+}
+
+impl PendingWrites {
+    /// Add a write, recording in the tree that writes are pending. Writes
+    /// made outside the indexing of a feed message are only recovered by
+    /// reindexing.
+    fn stage(&mut self, tree: &sled::Tree, key: Vec<u8>, value: IVec) -> Result<()> {
+        if self.replay_from.is_none() && !self.untracked {
+            tree.insert([KEY_PENDING], &[])?;
+            self.untracked = true;
+        }
+        self.writes.insert(key, value);
+        self.since.get_or_insert_with(Instant::now);
+
+        Ok(())
+    }
+}
+
+/// Iterator over the stored entries in a range of keys, with the staged
+/// writes in the range applied over them.
+struct Overlay {
+    tree: sled::Iter,
+    staged: VecDeque<(IVec, IVec)>,
+    /// Entries taken from either end of the tree iterator, not yet returned.
+    front: Option<(IVec, IVec)>,
+    back: Option<(IVec, IVec)>,
+}
+
+impl Overlay {
+    fn new(tree: sled::Iter, staged: VecDeque<(IVec, IVec)>) -> Self {
+        Self {
+            tree,
+            staged,
+            front: None,
+            back: None,
+        }
+    }
+}
+
+impl Iterator for Overlay {
+    type Item = sled::Result<(IVec, IVec)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.front.is_none() {
+            self.front = match self.tree.next() {
+                Some(Ok(entry)) => Some(entry),
+                Some(Err(err)) => return Some(Err(err)),
+                None => self.back.take(),
+            };
+        }
+        match (&self.front, self.staged.front()) {
+            (Some((key, _)), Some((staged_key, _))) if staged_key <= key => {
+                // A staged write replaces the stored value.
+                if staged_key == key {
+                    self.front = None;
+                }
+                self.staged.pop_front().map(Ok)
+            }
+            (Some(_), _) => self.front.take().map(Ok),
+            (None, _) => self.staged.pop_front().map(Ok),
+        }
+    }
+}
+
+impl DoubleEndedIterator for Overlay {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.back.is_none() {
+            self.back = match self.tree.next_back() {
+                Some(Ok(entry)) => Some(entry),
+                Some(Err(err)) => return Some(Err(err)),
+                None => self.front.take(),
+            };
+        }
+        match (&self.back, self.staged.back()) {
+            (Some((key, _)), Some((staged_key, _))) if staged_key >= key => {
+                if staged_key == key {
+                    self.back = None;
+                }
+                self.staged.pop_back().map(Ok)
+            }
+            (Some(_), _) => self.back.take().map(Ok),
+            (None, _) => self.staged.pop_back().map(Ok),
+        }
+    }
+}
+
+/// Secondary indexes derived from the messages in the key-value store.
+///
+/// The indexes are stored in their own sled tree and are updated each time
+/// a message is appended to a feed. Updates are accumulated in memory and
+/// committed in batches, every `BATCH_SIZE` writes or `BATCH_INTERVAL`,
+/// which greatly reduces write amplification during initial sync. Reads see
+/// the pending writes over the tree, so readers never see stale state. The
+/// pending writes of feed messages lost when the process stops are replayed
+/// from the messages on the next start.
+#[derive(Clone)]
+pub struct Indexes {
+    tree: sled::Tree,
+    authors: Authors,
+    pending: Arc<Mutex<PendingWrites>>,
+}
+
+impl Indexes {
+    /// Open the indexes tree of the given database.
+    pub fn open(db: &sled::Db, authors: Authors) -> Result<Self> {
+        let tree = db.open_tree(INDEXES_TREE)?;
+
+        Ok(Self {
+            tree,
+            authors,
+            pending: Arc::new(Mutex::new(PendingWrites::default())),
+        })
+    }
+
+    /// Remove all entries from the indexes, e.g. before rebuilding them.
+    pub fn clear(&self) -> Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        *pending = PendingWrites {
+            indexing: mem::take(&mut pending.indexing),
+            ..Default::default()
+        };
+        self.tree.clear()?;
+
+        Ok(())
+    }
+
+    /// Commit the pending index writes to the tree.
+    pub fn commit(&self) -> Result<()> {
+        // The lock is held while the batch is applied, so that the pending
+        // marker is not cleared from under a concurrent write.
+        let mut pending = self.pending.lock().unwrap();
+        if pending.writes.is_empty() && pending.replay_from.is_none() && !pending.untracked {
+            return Ok(());
+        }
+        let mut batch = sled::Batch::default();
+        for (key, value) in mem::take(&mut pending.writes) {
+            batch.insert(key, value);
+        }
+        for (day, count) in mem::take(&mut pending.day_counts) {
+            let key = Self::key_day(PREFIX_DAY_COUNT, day);
+            let stored = match self.tree.get(&key)? {
+                Some(raw) => {
+                    let mut u64_buffer = [0u8; 8];
+                    u64_buffer.copy_from_slice(&raw);
+                    u64::from_be_bytes(u64_buffer)
+                }
+                None => 0,
+            };
+            batch.insert(key, &(stored + count).to_be_bytes()[..]);
+        }
+        // The feed messages still being indexed have writes left to stage,
+        // which are replayed from the earliest of them if lost.
+        pending.since = None;
+        pending.untracked = false;
+        pending.replay_from = pending.indexing.iter().next().copied();
+        match pending.replay_from {
+            Some(global_seq) => batch.insert(vec![KEY_PENDING], &global_seq.to_be_bytes()[..]),
+            None => batch.remove(vec![KEY_PENDING]),
+        }
+        self.tree.apply_batch(batch)?;
+
+        Ok(())
+    }
+
+    /// Return the tree, once the pending writes have been committed, e.g.
+    /// to apply writes which are not staged.
+    fn committed_tree(&self) -> Result<&sled::Tree> {
+        self.commit()?;
+
+        Ok(&self.tree)
+    }
+
+    /// Get the value of the given key, staged or stored.
+    fn get(&self, key: &[u8]) -> Result<Option<IVec>> {
+        if let Some(value) = self.pending.lock().unwrap().writes.get(key) {
+            return Ok(Some(value.clone()));
+        }
+
+        Ok(self.tree.get(key)?)
+    }
+
+    /// Iterate over the staged or stored entries whose key starts with the
+    /// given prefix.
+    fn scan_prefix(&self, prefix: impl AsRef<[u8]>) -> Overlay {
+        let prefix = prefix.as_ref();
+        let staged = self
+            .pending
+            .lock()
+            .unwrap()
+            .writes
+            .range(prefix.to_vec()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .map(|(key, value)| (IVec::from(key.as_slice()), value.clone()))
+            .collect();
+
+        Overlay::new(self.tree.scan_prefix(prefix), staged)
+    }
+
+    /// Iterate over the staged or stored entries in the given range of keys.
+    fn range(&self, range: Range<Vec<u8>>) -> Overlay {
+        let staged = self
+            .pending
+            .lock()
+            .unwrap()
+            .writes
+            .range(range.clone())
+            .map(|(key, value)| (IVec::from(key.as_slice()), value.clone()))
+            .collect();
+
+        Overlay::new(self.tree.range(range), staged)
+    }
+
+    /// Whether index writes were left uncommitted when the database was
+    /// last closed, in which case they must be replayed or the indexes
+    /// rebuilt.
+    pub fn is_incomplete(&self) -> Result<bool> {
+        Ok(self.tree.contains_key([KEY_PENDING])?)
+    }
+
+    /// Return the position in the global order from which the feed messages
+    /// are indexed again to replay the writes left uncommitted when the
+    /// database was last closed. Returns `None` if none were left, or if
+    /// they can only be recovered by reindexing.
+    pub fn replay_from(&self) -> Result<Option<u64>> {
+        Ok(self
+            .tree
+            .get([KEY_PENDING])?
+            .filter(|raw| raw.len() == 8)
+            .map(|raw| {
+                let mut u64_buffer = [0u8; 8];
+                u64_buffer.copy_from_slice(&raw);
+                u64::from_be_bytes(u64_buffer)
+            }))
+    }
+
+    /// Add a write to the pending batch.
+    fn stage(&self, key: Vec<u8>, value: impl Into<IVec>) -> Result<()> {
+        let mut pending = self.pending.lock().unwrap();
+        pending.stage(&self.tree, key, value.into())
+    }
+
+    /// Whether the pending writes are due to be committed.
+    fn is_batch_due(&self) -> bool {
+        let pending = self.pending.lock().unwrap();
+        pending.writes.len() >= BATCH_SIZE
+            || pending
+                .since
+                .is_some_and(|since| since.elapsed() >= BATCH_INTERVAL)
+    }
+
+    /// Generate a key made up of the given prefix and components.
+    fn key(prefix: u8, components: &[&str]) -> Vec<u8> {
+        let mut key = vec![prefix];
+        for component in components {
+            key.extend_from_slice(component.as_bytes());
+            key.push(SEPARATOR);
+        }
+        key
+    }
+
+    /// Generate a follow state key made up of the given prefix and interned
+    /// author IDs.
+    fn key_ids(prefix: u8, ids: &[u32]) -> Vec<u8> {
+        let mut key = vec![prefix];
+        for id in ids {
+            key.extend_from_slice(&id.to_be_bytes());
+        }
+        key
+    }
+
+    /// Return the final component of the given key.
+    fn last_component(key: &[u8]) -> String {
+        // Drop the trailing separator and split at the one before it.
+        let key = &key[..key.len() - 1];
+        let start = key
+            .iter()
+            .rposition(|byte| *byte == SEPARATOR)
+            .map_or(1, |pos| pos + 1);
+        String::from_utf8_lossy(&key[start..]).to_string()
+    }
+
+    /// Update the indexes with the content of the given message. Returns the
+    /// changes in follow and block states recorded for a contact message.
+    pub fn index_msg(&self, msg: &MessageValue) -> Result<Vec<ContactChange>> {
+        let content = msg.content();
+        let mut changes = Vec::new();
+
+        if content["type"].as_str() == Some("contact") {
+            if let Some(contact) = content["contact"].as_str() {
+                let mut record = |kind| {
+                    changes.push(ContactChange {
+                        author: msg.author().to_string(),
+                        contact: contact.to_string(),
+                        kind,
+                        msg_ref: msg.id().to_string(),
+                    })
+                };
+                if let Some(following) = content["following"].as_bool() {
+                    if self.index_contact(msg.author(), contact, following)? {
+                        record(match following {
+                            true => ContactChangeKind::Follow,
+                            false => ContactChangeKind::Unfollow,
+                        });
+                    }
+                }
+                if let Some(blocking) = content["blocking"].as_bool() {
+                    if self.index_block(msg.author(), contact, blocking)? {
+                        record(match blocking {
+                            true => ContactChangeKind::Block,
+                            false => ContactChangeKind::Unblock,
+                        });
+                    }
+                }
+            }
+        }
+
+        if matches!(content["type"].as_str(), Some("flag") | Some("report")) {
+            // The target is linked directly or, in the nested form, from a
+            // `flag` object which may also hold the reason.
+            let flag = &content["flag"];
+            if let Some(target) = report_target(content) {
+                let report = Report {
+                    msg_ref: msg.id().to_string(),
+                    author: msg.author().to_string(),
+                    reason: content["reason"]
+                        .as_str()
+                        .or_else(|| flag["reason"].as_str())
+                        .map(String::from),
+                };
+                self.stage(
+                    Self::key(PREFIX_REPORT, &[target, &report.msg_ref]),
+                    serde_cbor::to_vec(&report)?,
+                )?;
+            }
+        }
+
+        if let Some(expires) = expires_at(content) {
+            self.stage(
+                Self::key_expiry(expires, &msg.id().to_string()),
+                msg.author().as_bytes(),
+            )?;
+        }
+
+        if let Some(root) = content["root"].as_str() {
+            self.stage(
+                Self::key(PREFIX_THREAD, &[root, &msg.id().to_string()]),
+                &[],
+            )?;
+        }
+
+        if matches!(
+            content["type"].as_str(),
+            Some("gathering") | Some("gathering-update") | Some("about")
+        ) {
+            self.index_gathering(msg)?;
+        }
+
+        if content["type"].as_str() == Some("post") {
+            if let Some(channel) = content["channel"].as_str().and_then(channel_name) {
+                self.stage(Self::key_channel(&channel, msg), &[])?;
+            }
+        }
+
+        match content["type"].as_str() {
+            Some("git-repo") => self.index_git_repo(msg)?,
+            Some("git-update") => self.index_git_update(msg)?,
+            Some("about") => self.index_git_repo_name(msg)?,
+            _ => (),
+        }
+
+        if let Some(reason) = SCHEMAS.violation(content) {
+            self.stage(
+                Self::key(PREFIX_INVALID, &[&msg.id().to_string()]),
+                reason.as_bytes(),
+            )?;
+        }
+
+        if self.is_batch_due() {
+            self.commit()?;
+        }
+
+        Ok(changes)
+    }
+
+    /// Record the timestamp of a feed message received at `received` (in
+    /// milliseconds since the Unix epoch), and count the message and its
+    /// author in the activity of the day.
+    pub fn index_timestamp(&self, msg: &MessageValue, received: f64) -> Result<()> {
+        let timestamp = indexed_timestamp(msg.timestamp(), received);
+        let day = (timestamp / MS_PER_DAY) as u32;
+        let mut day_key = Self::key_day(PREFIX_DAY_AUTHOR, day);
+        day_key.extend_from_slice(&self.authors.intern(msg.author())?.to_be_bytes());
+
+        // The timestamp is committed along with the day count, which tells
+        // whether the message was counted when replaying it.
+        let mut pending = self.pending.lock().unwrap();
+        pending.stage(
+            &self.tree,
+            Self::key_timestamp(timestamp, &msg.id().to_string()),
+            IVec::default(),
+        )?;
+        pending.stage(&self.tree, day_key, IVec::default())?;
+        *pending.day_counts.entry(day).or_default() += 1;
+
+        Ok(())
+    }
+
+    /// Index a feed message at the given position in the global order,
+    /// received at `received`. Returns the changes in follow and block
+    /// states, as `index_msg`. If the process stops before the writes are
+    /// committed, they are replayed from the message on the next start.
+    pub fn index_feed_msg(
+        &self,
+        msg: &MessageValue,
+        received: f64,
+        global_seq: u64,
+    ) -> Result<Vec<ContactChange>> {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            pending.indexing.insert(global_seq);
+            // The marker records the earliest message with pending writes,
+            // unless writes which cannot be replayed are pending.
+            if !pending.untracked && pending.replay_from.is_none_or(|from| global_seq < from) {
+                self.tree
+                    .insert([KEY_PENDING], &global_seq.to_be_bytes()[..])?;
+                pending.replay_from = Some(global_seq);
+            }
+        }
+
+        let indexed = self.index_msg(msg).and_then(|changes| {
+            self.index_timestamp(msg, received)?;
+            Ok(changes)
+        });
+        self.pending.lock().unwrap().indexing.remove(&global_seq);
+
+        indexed
+    }
+
+    /// Index again a feed message at the given position in the global
+    /// order, whose writes may have been lost when the process stopped. The
+    /// message is only counted in the activity of its day if its timestamp
+    /// was not committed, since the counts are not overwritten but added to.
+    pub fn replay_feed_msg(
+        &self,
+        msg: &MessageValue,
+        received: f64,
+        global_seq: u64,
+    ) -> Result<()> {
+        let timestamp = indexed_timestamp(msg.timestamp(), received);
+        if self
+            .get(&Self::key_timestamp(timestamp, &msg.id().to_string()))?
+            .is_some()
+        {
+            self.index_msg(msg)?;
+        } else {
+            self.index_feed_msg(msg, received, global_seq)?;
+        }
+
+        Ok(())
+    }
+
+    /// Remove the entries recorded for the given message, e.g. once its
+    /// content has been deleted. If the message is part of a feed, received
+    /// at `received`, it is also removed from the count of the messages of
+    /// its day. State derived from several messages is left to `rederive`.
+    pub fn unindex_msg(&self, msg: &MessageValue, received: Option<f64>) -> Result<()> {
+        let content = msg.content();
+        let msg_type = content["type"].as_str();
+        let msg_ref = msg.id().to_string();
+
+        let mut keys = vec![Self::key(PREFIX_INVALID, &[&msg_ref])];
+        if matches!(msg_type, Some("flag") | Some("report")) {
+            if let Some(target) = report_target(content) {
+                keys.push(Self::key(PREFIX_REPORT, &[target, &msg_ref]));
+            }
+        }
+        if let Some(expires) = expires_at(content) {
+            keys.push(Self::key_expiry(expires, &msg_ref));
+        }
+        if let Some(root) = content["root"].as_str() {
+            keys.push(Self::key(PREFIX_THREAD, &[root, &msg_ref]));
+        }
+        if msg_type == Some("post") {
+            if let Some(channel) = content["channel"].as_str().and_then(channel_name) {
+                keys.push(Self::key_channel(&channel, msg));
+            }
+        }
+        match (msg_type, content["repo"].as_str()) {
+            (Some("git-repo"), _) => {
+                keys.push(Self::key(PREFIX_GIT_OWNER, &[msg.author(), &msg_ref]));
+                keys.push(Self::key(PREFIX_GIT_REPO, &[&msg_ref]));
+            }
+            (Some("git-update"), Some(repo_id)) => keys.push(Self::key_git_update(repo_id, msg)),
+            _ => (),
+        }
+
+        // The pending writes are committed first, so that those of the
+        // message are removed as well.
+        let tree = self.committed_tree()?;
+        let mut batch = sled::Batch::default();
+        for key in keys {
+            batch.remove(key);
+        }
+        if let Some(received) = received {
+            let timestamp = indexed_timestamp(msg.timestamp(), received);
+            batch.remove(Self::key_timestamp(timestamp, &msg_ref));
+
+            let key = Self::key_day(PREFIX_DAY_COUNT, (timestamp / MS_PER_DAY) as u32);
+            if let Some(raw) = tree.get(&key)? {
+                let mut u64_buffer = [0u8; 8];
+                u64_buffer.copy_from_slice(&raw);
+                match u64::from_be_bytes(u64_buffer) {
+                    0 | 1 => batch.remove(key),
+                    count => batch.insert(key, &(count - 1).to_be_bytes()[..]),
+                }
+            }
+        }
+        tree.apply_batch(batch)?;
+
+        Ok(())
+    }
+
+    /// Recompute the state derived from several messages to which the given
+    /// message contributed, once its content has been deleted, from the
+    /// `remaining` messages of its author in feed order (those whose content
+    /// is left). Only the feed of the author is read:
+    ///
+    /// - whether the author was active on the day of the message, if it is
+    ///   part of a feed, received at `received`;
+    /// - the follow and block states of the author for the contact of a
+    ///   contact message;
+    /// - the attendance of the author of a gathering and, if they published
+    ///   it, its details. A gathering is dropped along with its own message;
+    /// - the name of a repository owned by the author.
+    pub fn rederive(
+        &self,
+        deleted: &MessageValue,
+        received: Option<f64>,
+        remaining: impl IntoIterator<Item = Result<MessageKvt>>,
+    ) -> Result<()> {
+        let content = deleted.content();
+        let msg_type = content["type"].as_str();
+        let author = deleted.author().as_str();
+        let about = content["about"]
+            .as_str()
+            .filter(|about| about.starts_with('%'));
+
+        let day = received
+            .map(|received| (indexed_timestamp(deleted.timestamp(), received) / MS_PER_DAY) as u32);
+        let contact = match msg_type {
+            Some("contact") => content["contact"].as_str(),
+            _ => None,
+        };
+
+        let tree = self.committed_tree()?;
+        let mut batch = sled::Batch::default();
+        let gathering_ref = match msg_type {
+            Some("gathering") => {
+                batch.remove(Self::key(PREFIX_GATHERING, &[&deleted.id().to_string()]));
+                None
+            }
+            Some("gathering-update") | Some("about") => about,
+            _ => None,
+        };
+        let mut gathering: Option<Gathering> = match gathering_ref {
+            Some(gathering_ref) => match tree.get(Self::key(PREFIX_GATHERING, &[gathering_ref]))? {
+                Some(raw) => Some(serde_cbor::from_slice(&raw)?),
+                None => None,
+            },
+            None => None,
+        };
+        // Until the gathering itself is known, its details may come from
+        // any feed, so they are only recomputed from the feed which
+        // published it.
+        let own_details = gathering
+            .as_ref()
+            .is_some_and(|gathering| gathering.author.as_deref() == Some(author));
+        if let Some(gathering) = gathering.as_mut().filter(|_| own_details) {
+            *gathering = Gathering {
+                msg_ref: gathering.msg_ref.clone(),
+                author: gathering.author.clone(),
+                attendees: mem::take(&mut gathering.attendees),
+                ..Default::default()
+            };
+        }
+        let mut repo: Option<GitRepo> = match (msg_type, about) {
+            (Some("about"), Some(repo_id)) if content["name"].is_string() => {
+                match tree.get(Self::key(PREFIX_GIT_REPO, &[repo_id]))? {
+                    Some(raw) => Some(serde_cbor::from_slice(&raw)?),
+                    None => None,
+                }
+            }
+            _ => None,
+        }
+        .filter(|repo: &GitRepo| repo.owner == author);
+        if let Some(repo) = repo.as_mut() {
+            repo.name = None;
+        }
+
+        let mut active = false;
+        let (mut following, mut blocking, mut attending) = (None, None, None);
+        for msg_kvt in remaining {
+            let msg_kvt = msg_kvt?;
+            let content = &msg_kvt.value["content"];
+            let msg_type = content["type"].as_str();
+            if let Some(day) = day {
+                let claimed = msg_kvt.value["timestamp"].as_f64().unwrap_or(0.0);
+                let timestamp = indexed_timestamp(claimed, msg_kvt.timestamp);
+                active |= (timestamp / MS_PER_DAY) as u32 == day;
+            }
+
+            if msg_type == Some("contact")
+                && contact.is_some()
+                && content["contact"].as_str() == contact
+            {
+                following = content["following"].as_bool().or(following);
+                blocking = content["blocking"].as_bool().or(blocking);
+            }
+
+            if let Some(gathering) = gathering.as_mut() {
+                let is_about = match msg_type {
+                    Some("gathering") => msg_kvt.key == gathering.msg_ref,
+                    Some("gathering-update") | Some("about") => {
+                        content["about"].as_str() == Some(gathering.msg_ref.as_str())
+                    }
+                    _ => false,
+                };
+                if is_about {
+                    if own_details {
+                        gathering.update_details(content);
+                    }
+                    let attendee = &content["attendee"];
+                    if attendee["link"].as_str() == Some(author) {
+                        attending = Some(attendee["remove"].as_bool() != Some(true));
+                    }
+                }
+            }
+
+            if let Some(repo) = repo.as_mut() {
+                let name = content["name"].as_str().map(String::from);
+                match msg_type {
+                    Some("git-repo") if msg_kvt.key == repo.repo_id => repo.name = name,
+                    Some("about")
+                        if name.is_some()
+                            && content["about"].as_str() == Some(repo.repo_id.as_str()) =>
+                    {
+                        repo.name = name
+                    }
+                    _ => (),
+                }
+            }
+        }
+
+        let author_id = self.authors.intern(author)?;
+        if let Some(day) = day.filter(|_| !active) {
+            let mut key = Self::key_day(PREFIX_DAY_AUTHOR, day);
+            key.extend_from_slice(&author_id.to_be_bytes());
+            batch.remove(key);
+        }
+        if let Some(contact) = contact {
+            let contact_id = self.authors.intern(contact)?;
+            let contact_key = Self::key_ids(PREFIX_CONTACT, &[author_id, contact_id]);
+            let follower_key = Self::key_ids(PREFIX_FOLLOWER, &[contact_id, author_id]);
+            match following {
+                Some(following) => {
+                    batch.insert(contact_key, serde_cbor::to_vec(&following)?);
+                    batch.insert(follower_key, serde_cbor::to_vec(&following)?);
+                }
+                None => {
+                    batch.remove(contact_key);
+                    batch.remove(follower_key);
+                }
+            }
+            let block_key = Self::key_ids(PREFIX_BLOCK, &[author_id, contact_id]);
+            match blocking {
+                Some(blocking) => batch.insert(block_key, serde_cbor::to_vec(&blocking)?),
+                None => batch.remove(block_key),
+            }
+        }
+        if let Some(mut gathering) = gathering {
+            match attending {
+                Some(true) => gathering.attendees.insert(author.to_string()),
+                _ => gathering.attendees.remove(author),
+            };
+            batch.insert(
+                Self::key(PREFIX_GATHERING, &[&gathering.msg_ref]),
+                serde_cbor::to_vec(&gathering)?,
+            );
+        }
+        if let Some(repo) = repo {
+            batch.insert(
+                Self::key(PREFIX_GIT_REPO, &[&repo.repo_id]),
+                serde_cbor::to_vec(&repo)?,
+            );
+        }
+        tree.apply_batch(batch)?;
+
+        Ok(())
+    }
+
+    /// Generate a key made up of the given prefix and day.
+    fn key_day(prefix: u8, day: u32) -> Vec<u8> {
+        let mut key = vec![prefix];
+        key.extend_from_slice(&day.to_be_bytes());
+        key
+    }
+
+    /// Generate a key to a message with the given indexed timestamp.
+    fn key_timestamp(timestamp: u64, msg_ref: &str) -> Vec<u8> {
+        let mut key = vec![PREFIX_TIMESTAMP];
+        key.extend_from_slice(&timestamp.to_be_bytes());
+        key.extend_from_slice(msg_ref.as_bytes());
+        key
+    }
+
+    /// Generate a key to a message which expires at the given time.
+    fn key_expiry(expires: u64, msg_ref: &str) -> Vec<u8> {
+        let mut key = vec![PREFIX_EXPIRY];
+        key.extend_from_slice(&expires.to_be_bytes());
+        key.extend_from_slice(msg_ref.as_bytes());
+        key
+    }
+
+    /// Generate a key to the given post in the given (normalized) channel.
+    fn key_channel(channel: &str, msg: &MessageValue) -> Vec<u8> {
+        let mut key = Self::key(PREFIX_CHANNEL, &[channel]);
+        key.extend_from_slice(&(msg.timestamp().max(0.0) as u64).to_be_bytes());
+        key.extend_from_slice(msg.id().to_string().as_bytes());
+        key
+    }
+
+    /// Generate a key to the given update of a git-ssb repository.
+    fn key_git_update(repo_id: &str, msg: &MessageValue) -> Vec<u8> {
+        let mut key = Self::key(PREFIX_GIT_UPDATE, &[repo_id]);
+        key.extend_from_slice(&(msg.timestamp().max(0.0) as u64).to_be_bytes());
+        key.extend_from_slice(msg.id().to_string().as_bytes());
+        key
+    }
+
+    /// Record a gathering, or the details or attendance published about one.
+    /// Details are taken from the author of the gathering, once known, and
+    /// feeds can only announce their own attendance. Updates about a
+    /// gathering which has not been indexed yet are kept if they can only
+    /// be about a gathering, i.e. set its start time or attendance.
+    fn index_gathering(&self, msg: &MessageValue) -> Result<()> {
+        let content = msg.content();
+        let author = msg.author();
+        let is_gathering = content["type"].as_str() == Some("gathering");
+
+        let msg_ref = if is_gathering {
+            msg.id().to_string()
+        } else {
+            match content["about"].as_str() {
+                Some(about) if about.starts_with('%') => about.to_string(),
+                _ => return Ok(()),
+            }
+        };
+
+        let key = Self::key(PREFIX_GATHERING, &[&msg_ref]);
+        let mut gathering: Gathering = match self.get(&key)? {
+            Some(raw) => serde_cbor::from_slice(&raw)?,
+            None if is_gathering
+                || content["type"].as_str() == Some("gathering-update")
+                || content.get("startDateTime").is_some()
+                || content.get("attendee").is_some() =>
+            {
+                Gathering {
+                    msg_ref,
+                    ..Default::default()
+                }
+            }
+            None => return Ok(()),
+        };
+
+        if is_gathering {
+            gathering.author = Some(author.to_string());
+        }
+        if gathering.author.is_none() || gathering.author.as_deref() == Some(author) {
+            gathering.update_details(content);
+        }
+
+        let attendee = &content["attendee"];
+        if attendee["link"].as_str() == Some(author) {
+            if attendee["remove"].as_bool() == Some(true) {
+                gathering.attendees.remove(author);
+            } else {
+                gathering.attendees.insert(author.to_string());
+            }
+        }
+
+        self.stage(key, serde_cbor::to_vec(&gathering)?)
+    }
+
+    /// Record a git-ssb repository, owned by the author of the message.
+    fn index_git_repo(&self, msg: &MessageValue) -> Result<()> {
+        let repo = GitRepo {
+            repo_id: msg.id().to_string(),
+            owner: msg.author().to_string(),
+            name: msg.content()["name"].as_str().map(String::from),
+        };
+
+        self.stage(
+            Self::key(PREFIX_GIT_OWNER, &[&repo.owner, &repo.repo_id]),
+            &[],
+        )?;
+        self.stage(
+            Self::key(PREFIX_GIT_REPO, &[&repo.repo_id]),
+            serde_cbor::to_vec(&repo)?,
+        )
+    }
+
+    /// Record the name given to a git-ssb repository by its owner in an
+    /// `about` message.
+    fn index_git_repo_name(&self, msg: &MessageValue) -> Result<()> {
+        let content = msg.content();
+        let (repo_id, name) = match (content["about"].as_str(), content["name"].as_str()) {
+            (Some(repo_id), Some(name)) if repo_id.starts_with('%') => (repo_id, name),
+            _ => return Ok(()),
+        };
+
+        let key = Self::key(PREFIX_GIT_REPO, &[repo_id]);
+        if let Some(raw) = self.get(&key)? {
+            let mut repo: GitRepo = serde_cbor::from_slice(&raw)?;
+            if &repo.owner == msg.author() {
+                repo.name = Some(name.to_string());
+                self.stage(key, serde_cbor::to_vec(&repo)?)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Record an update pushed to a git-ssb repository.
+    fn index_git_update(&self, msg: &MessageValue) -> Result<()> {
+        let content = msg.content();
+        let repo_id = match content["repo"].as_str() {
+            Some(repo_id) => repo_id,
+            None => return Ok(()),
+        };
+
+        let refs = content["refs"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(name, sha1)| (name.clone(), sha1.as_str().map(String::from)))
+            .collect();
+        let commits = content["commits"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|commit| {
+                Some(GitCommit {
+                    sha1: commit["sha1"].as_str()?.to_string(),
+                    title: commit["title"].as_str().map(String::from),
+                })
+            })
+            .collect();
+        let update = GitUpdate {
+            msg_ref: msg.id().to_string(),
+            author: msg.author().to_string(),
+            timestamp: msg.timestamp().max(0.0) as u64,
+            refs,
+            commits,
+        };
+
+        self.stage(
+            Self::key_git_update(repo_id, msg),
+            serde_cbor::to_vec(&update)?,
+        )
+    }
+
+    /// Record the follow state of `author` for `contact`. Later messages
+    /// overwrite earlier ones. Returns `true` if the contact is newly
+    /// followed, or unfollowed after being followed.
+    fn index_contact(&self, author: &str, contact: &str, following: bool) -> Result<bool> {
+        let author = self.authors.intern(author)?;
+        let contact = self.authors.intern(contact)?;
+        let changed =
+            self.stage_state(Self::key_ids(PREFIX_CONTACT, &[author, contact]), following)?;
+        self.stage(
+            Self::key_ids(PREFIX_FOLLOWER, &[contact, author]),
+            serde_cbor::to_vec(&following)?,
+        )?;
+
+        Ok(changed)
+    }
+
+    /// Record the block state of `author` for `contact`. Later messages
+    /// overwrite earlier ones. Returns `true` if the contact is newly
+    /// blocked, or unblocked after being blocked.
+    fn index_block(&self, author: &str, contact: &str, blocking: bool) -> Result<bool> {
+        let author = self.authors.intern(author)?;
+        let contact = self.authors.intern(contact)?;
+
+        self.stage_state(Self::key_ids(PREFIX_BLOCK, &[author, contact]), blocking)
+    }
+
+    /// Stage a follow or block state. Returns `true` if the state is set
+    /// where it was not, or unset where it was.
+    fn stage_state(&self, key: Vec<u8>, state: bool) -> Result<bool> {
+        let previous = match self.get(&key)? {
+            Some(raw) => serde_cbor::from_slice(&raw)?,
+            None => false,
+        };
+        self.stage(key, serde_cbor::to_vec(&state)?)?;
+
+        Ok(previous != state)
+    }
+
+    /// Get the most recent follow state published by `author` for `contact`.
+    /// Returns `None` if the author has never published a contact message
+    /// about the contact.
+    pub fn get_contact(&self, author: &str, contact: &str) -> Result<Option<bool>> {
+        let (author, contact) = match (self.authors.get_id(author)?, self.authors.get_id(contact)?)
+        {
+            (Some(author), Some(contact)) => (author, contact),
+            _ => return Ok(None),
+        };
+
+        if let Some(raw) = self.get(&Self::key_ids(PREFIX_CONTACT, &[author, contact]))? {
+            Ok(Some(serde_cbor::from_slice(&raw)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Whether `author` currently blocks `contact`.
+    pub fn is_blocking(&self, author: &str, contact: &str) -> Result<bool> {
+        let (author, contact) = match (self.authors.get_id(author)?, self.authors.get_id(contact)?)
+        {
+            (Some(author), Some(contact)) => (author, contact),
+            _ => return Ok(false),
+        };
+
+        match self.get(&Self::key_ids(PREFIX_BLOCK, &[author, contact]))? {
+            Some(raw) => Ok(serde_cbor::from_slice(&raw)?),
+            None => Ok(false),
+        }
+    }
+
+    /// Return the IDs of all feeds currently followed by `author`.
+    pub fn get_follows(&self, author: &str) -> Result<Vec<String>> {
+        match self.authors.get_id(author)? {
+            Some(author) => self.scan_following(Self::key_ids(PREFIX_CONTACT, &[author])),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Return the IDs of all feeds currently following `contact`.
+    pub fn get_followers(&self, contact: &str) -> Result<Vec<String>> {
+        match self.authors.get_id(contact)? {
+            Some(contact) => self.scan_following(Self::key_ids(PREFIX_FOLLOWER, &[contact])),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// Return the distance in the follow graph from `from` to `to`, where
+    /// 0 is `from` itself and 1 is a feed followed by `from`. Returns `None`
+    /// if `to` is further than `max_hops` away.
+    pub fn get_hops(&self, from: &str, to: &str, max_hops: usize) -> Result<Option<usize>> {
+        Ok(self
+            .walk_follows(from, max_hops, Some(to))?
+            .get(to)
+            .copied())
+    }
+
+    /// Return the IDs of all feeds within `max_hops` of `from` in the follow
+    /// graph, including `from` itself.
+    pub fn get_within_hops(&self, from: &str, max_hops: usize) -> Result<HashSet<String>> {
+        Ok(self
+            .walk_follows(from, max_hops, None)?
+            .into_keys()
+            .collect())
+    }
+
+    /// Return the number of feeds at each distance from `from` in the follow
+    /// graph, up to `max_hops`, starting with `from` itself at 0 hops.
+    pub fn get_hops_distribution(&self, from: &str, max_hops: usize) -> Result<Vec<usize>> {
+        let mut distribution = vec![0; max_hops + 1];
+        for hops in self.walk_follows(from, max_hops, None)?.into_values() {
+            distribution[hops] += 1;
+        }
+
+        Ok(distribution)
+    }
+
+    /// Walk the follow graph breadth-first from `from`, up to `max_hops`.
+    /// Returns the distance of each feed reached, `from` itself being at 0.
+    /// The walk stops once `target` is reached, if given.
+    fn walk_follows(
+        &self,
+        from: &str,
+        max_hops: usize,
+        target: Option<&str>,
+    ) -> Result<HashMap<String, usize>> {
+        let mut distances = HashMap::new();
+        distances.insert(from.to_string(), 0);
+        let mut frontier = vec![from.to_string()];
+
+        for hops in 1..=max_hops {
+            if frontier.is_empty() || target.is_some_and(|target| distances.contains_key(target)) {
+                break;
+            }
+            let mut next = Vec::new();
+            for id in &frontier {
+                for followed in self.get_follows(id)? {
+                    if !distances.contains_key(&followed) {
+                        distances.insert(followed.clone(), hops);
+                        next.push(followed);
+                    }
+                }
+            }
+            frontier = next;
+        }
+
+        Ok(distances)
+    }
+
+    /// Return the number of feed messages indexed on each day (UTC) from
+    /// `from_day` on, keyed by days since the Unix epoch. Days without
+    /// messages are left out.
+    pub fn get_daily_counts(&self, from_day: u32) -> Result<BTreeMap<u32, u64>> {
+        let mut counts = BTreeMap::new();
+
+        let start = Self::key_day(PREFIX_DAY_COUNT, from_day);
+        for item in self.tree.range(start..vec![PREFIX_DAY_COUNT + 1]) {
+            let (key, value) = item?;
+            let mut u32_buffer = [0u8; 4];
+            u32_buffer.copy_from_slice(&key[1..5]);
+            let mut u64_buffer = [0u8; 8];
+            u64_buffer.copy_from_slice(&value);
+            counts.insert(
+                u32::from_be_bytes(u32_buffer),
+                u64::from_be_bytes(u64_buffer),
+            );
+        }
+        // The counts of the pending writes are added when committing.
+        for (day, count) in self.pending.lock().unwrap().day_counts.range(from_day..) {
+            *counts.entry(*day).or_default() += count;
+        }
+
+        Ok(counts)
+    }
+
+    /// Return the number of distinct feeds which published a message on a
+    /// day (UTC) from `from_day` on, counted in days since the Unix epoch.
+    pub fn count_active_authors(&self, from_day: u32) -> Result<usize> {
+        let mut authors = HashSet::new();
+
+        let start = Self::key_day(PREFIX_DAY_AUTHOR, from_day);
+        for item in self.range(start..vec![PREFIX_DAY_AUTHOR + 1]) {
+            let (key, _) = item?;
+            authors.insert(key[5..].to_vec());
+        }
+
+        Ok(authors.len())
+    }
+
+    /// Return the IDs of all messages which reference the given message as
+    /// the root of their thread.
+    pub fn get_thread(&self, root: &str) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+
+        for item in self.scan_prefix(Self::key(PREFIX_THREAD, &[root])) {
+            let (key, _) = item?;
+            ids.push(Self::last_component(&key));
+        }
+
+        Ok(ids)
+    }
+
+    /// Return up to `limit` messages whose expiry is at or before `now`,
+    /// soonest expired first. Messages authored by `except` (the local
+    /// identity) are left out, since the local feed is never pruned.
+    pub fn get_expired(&self, now: u64, except: &str, limit: usize) -> Result<Vec<Expiry>> {
+        let mut expired = Vec::new();
+
+        // The range ends before the first key expiring after `now`.
+        let mut end = vec![PREFIX_EXPIRY];
+        end.extend_from_slice(&now.saturating_add(1).to_be_bytes());
+        for item in self.range(vec![PREFIX_EXPIRY]..end) {
+            if expired.len() == limit {
+                break;
+            }
+            let (key, value) = item?;
+            let author = String::from_utf8_lossy(&value).to_string();
+            if author == except {
+                continue;
+            }
+            let mut u64_buffer = [0u8; 8];
+            u64_buffer.copy_from_slice(&key[1..9]);
+            expired.push(Expiry {
+                msg_ref: String::from_utf8_lossy(&key[9..]).to_string(),
+                author,
+                expires: u64::from_be_bytes(u64_buffer),
+            });
+        }
+
+        Ok(expired)
+    }
+
+    /// Return every current follow and block relationship.
+    pub fn get_contact_graph(&self) -> Result<ContactGraph> {
+        Ok(ContactGraph {
+            follows: self.scan_edges(PREFIX_CONTACT)?,
+            blocks: self.scan_edges(PREFIX_BLOCK)?,
+        })
+    }
+
+    /// Return the gatherings whose message has been indexed, soonest first;
+    /// gatherings without a start time come last. If `after` is given, only
+    /// gatherings starting at or after that time (in milliseconds since the
+    /// Unix epoch) are returned.
+    pub fn get_gatherings(&self, after: Option<u64>) -> Result<Vec<Gathering>> {
+        let mut gatherings = Vec::new();
+
+        for item in self.scan_prefix([PREFIX_GATHERING]) {
+            let (_, value) = item?;
+            let gathering: Gathering = serde_cbor::from_slice(&value)?;
+            let upcoming = match (after, gathering.start) {
+                (Some(after), Some(start)) => start >= after,
+                (Some(_), None) => false,
+                (None, _) => true,
+            };
+            if gathering.author.is_some() && upcoming {
+                gatherings.push(gathering);
+            }
+        }
+        gatherings.sort_by_key(|gathering| (gathering.start.is_none(), gathering.start));
+
+        Ok(gatherings)
+    }
+
+    /// Return the git-ssb repositories owned by the given feed.
+    pub fn get_git_repos(&self, owner: &str) -> Result<Vec<GitRepo>> {
+        let mut repos = Vec::new();
+
+        for item in self.scan_prefix(Self::key(PREFIX_GIT_OWNER, &[owner])) {
+            let (key, _) = item?;
+            let repo_id = Self::last_component(&key);
+            if let Some(raw) = self.get(&Self::key(PREFIX_GIT_REPO, &[&repo_id]))? {
+                repos.push(serde_cbor::from_slice(&raw)?);
+            }
+        }
+
+        Ok(repos)
+    }
+
+    /// Return the updates pushed to the given git-ssb repository, oldest
+    /// first. Updates are listed whoever pushed them; the author of each
+    /// is included.
+    pub fn get_git_updates(&self, repo_id: &str) -> Result<Vec<GitUpdate>> {
+        let mut updates = Vec::new();
+
+        for item in self.scan_prefix(Self::key(PREFIX_GIT_UPDATE, &[repo_id])) {
+            let (_, value) = item?;
+            updates.push(serde_cbor::from_slice(&value)?);
+        }
+
+        Ok(updates)
+    }
+
+    /// Return the IDs of up to `limit` posts in the given channel, newest
+    /// first.
+    pub fn get_channel(&self, channel: &str, limit: usize) -> Result<Vec<String>> {
+        let channel = match channel_name(channel) {
+            Some(channel) => channel,
+            None => return Ok(Vec::new()),
+        };
+        let prefix = Self::key(PREFIX_CHANNEL, &[&channel]);
+        let mut ids = Vec::new();
+
+        for item in self.scan_prefix(&prefix).rev().take(limit) {
+            let (key, _) = item?;
+            // The message ID follows the timestamp.
+            ids.push(String::from_utf8_lossy(&key[prefix.len() + 8..]).to_string());
+        }
+
+        Ok(ids)
+    }
+
+    /// Iterate over the feed messages whose indexed timestamp is at or after
+    /// `start` and before `end`, oldest first, as pairs of timestamp and
+    /// message ID. If `after` is given, the iteration continues after that
+    /// message, given with its indexed timestamp.
+    pub fn iter_between(
+        &self,
+        start: u64,
+        end: u64,
+        after: Option<(u64, &str)>,
+    ) -> Result<impl Iterator<Item = Result<(u64, String)>>> {
+        let from = match after {
+            // The smallest key following that of the message.
+            Some((timestamp, msg_ref)) => {
+                let mut key = Self::key_timestamp(timestamp, msg_ref);
+                key.push(0);
+                key
+            }
+            None => Self::key_timestamp(start, ""),
+        };
+        let to = Self::key_timestamp(end, "");
+
+        let from = from.min(to.clone());
+
+        Ok(self.range(from..to).map(|item| {
+            let (key, _) = item?;
+            let mut u64_buffer = [0u8; 8];
+            u64_buffer.copy_from_slice(&key[1..9]);
+            Ok((
+                u64::from_be_bytes(u64_buffer),
+                String::from_utf8_lossy(&key[9..]).to_string(),
+            ))
+        }))
+    }
+
+    /// Return the names of the channels with indexed posts, along with the
+    /// number of posts in each.
+    pub fn get_channels(&self) -> Result<Vec<(String, u64)>> {
+        let mut channels: Vec<(String, u64)> = Vec::new();
+
+        for item in self.scan_prefix([PREFIX_CHANNEL]) {
+            let (key, _) = item?;
+            let end = key[1..]
+                .iter()
+                .position(|byte| *byte == SEPARATOR)
+                .map_or(key.len(), |pos| pos + 1);
+            let channel = String::from_utf8_lossy(&key[1..end]);
+            match channels.last_mut() {
+                Some((name, count)) if *name == channel => *count += 1,
+                _ => channels.push((channel.to_string(), 1)),
+            }
+        }
+
+        Ok(channels)
+    }
+
+    /// Return the reason why the content of the given message violates the
+    /// schemas registered for its type, if it did when it was indexed.
+    pub fn get_invalid(&self, msg_ref: &str) -> Result<Option<String>> {
+        Ok(self
+            .get(&Self::key(PREFIX_INVALID, &[msg_ref]))?
+            .map(|reason| String::from_utf8_lossy(&reason).to_string()))
+    }
+
+    /// Return the flags and reports published about the given feed or
+    /// message.
+    pub fn get_reports(&self, target: &str) -> Result<Vec<Report>> {
+        let mut reports = Vec::new();
+
+        for item in self.scan_prefix(Self::key(PREFIX_REPORT, &[target])) {
+            let (_, value) = item?;
+            reports.push(serde_cbor::from_slice(&value)?);
+        }
+
+        Ok(reports)
+    }
+
+    /// Return the number of reports about each reported feed or message,
+    /// most reported first.
+    pub fn get_report_counts(&self) -> Result<Vec<ReportCount>> {
+        let mut reporters: HashMap<String, (usize, HashSet<String>)> = HashMap::new();
+
+        for item in self.scan_prefix([PREFIX_REPORT]) {
+            let (key, value) = item?;
+            // The target is the first component of the key.
+            let end = key
+                .iter()
+                .position(|byte| *byte == SEPARATOR)
+                .unwrap_or(key.len());
+            let target = String::from_utf8_lossy(&key[1..end]).to_string();
+            let report: Report = serde_cbor::from_slice(&value)?;
+
+            let entry = reporters.entry(target).or_default();
+            entry.0 += 1;
+            entry.1.insert(report.author);
+        }
+
+        let mut counts: Vec<ReportCount> = reporters
+            .into_iter()
+            .map(|(target, (reports, authors))| ReportCount {
+                target,
+                reports,
+                reporters: authors.len(),
+            })
+            .collect();
+        counts.sort_by(|a, b| b.reports.cmp(&a.reports).then(a.target.cmp(&b.target)));
+
+        Ok(counts)
+    }
+
+    /// Return the author and contact of each entry under the given prefix
+    /// whose state is `true`.
+    fn scan_edges(&self, prefix: u8) -> Result<Vec<(String, String)>> {
+        let mut edges = Vec::new();
+
+        for item in self.scan_prefix([prefix]) {
+            let (key, value) = item?;
+            if !serde_cbor::from_slice::<bool>(&value)? {
+                continue;
+            }
+            let mut ids = key[1..].chunks_exact(4).map(|raw| {
+                let mut u32_buffer = [0u8; 4];
+                u32_buffer.copy_from_slice(raw);
+                u32::from_be_bytes(u32_buffer)
+            });
+            if let (Some(author), Some(contact)) = (ids.next(), ids.next()) {
+                if let (Some(author), Some(contact)) = (
+                    self.authors.get_author(author)?,
+                    self.authors.get_author(contact)?,
+                ) {
+                    edges.push((author, contact));
+                }
+            }
+        }
+
+        Ok(edges)
+    }
+
+    /// Return the author of the final interned ID of each entry under the
+    /// given prefix whose follow state is `true`.
+    fn scan_following(&self, prefix: Vec<u8>) -> Result<Vec<String>> {
+        let mut ids = Vec::new();
+
+        for item in self.scan_prefix(prefix) {
+            let (key, value) = item?;
+            if serde_cbor::from_slice::<bool>(&value)? {
+                let mut u32_buffer = [0u8; 4];
+                u32_buffer.copy_from_slice(&key[key.len() - 4..]);
+                if let Some(author) = self.authors.get_author(u32::from_be_bytes(u32_buffer))? {
+                    ids.push(author);
+                }
+            }
+        }
+
+        Ok(ids)
+    }
+}
@@ -1,14 +1,51 @@
-use futures::SinkExt;
+use std::{
+    collections::HashSet,
+    fs,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use async_std::task;
+use futures::{channel::mpsc, SinkExt, Stream, StreamExt};
 use kuska_ssb::feed::{Feed as MessageKvt, Message as MessageValue};
-use log::warn;
+use log::{info, warn};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use sled::{
+    transaction::{ConflictableTransactionError, Transactional},
+    IVec,
+};
 
 use crate::{
     broker::{BrokerEvent, ChBrokerSend, Destination},
+    config::SECRET_CONFIG,
     error::Error,
-    Result,
+    metafeed::MetafeedTree,
+    storage::{
+        audit::AuditLog,
+        authors::Authors,
+        bans::Bans,
+        blob_wants::BlobWants,
+        clock_skew::{self, ClockSkew},
+        deliveries::Deliveries,
+        indexes::{self, ContactChange, Indexes},
+        mutes::Mutes,
+        outbox::Outbox,
+        room_keys::RoomKeys,
+    },
+    validation, Result,
 };
 
+// Databases of schema version 10 and earlier store every record type in the
+// default tree, under the following one-byte key prefixes. Each record type
+// now has a tree of its own (see `Trees`), keyed without a prefix, while
+// the single-entry records stay in the default tree.
+
 /// Prefix for a key to the latest sequence number for a stored feed.
 const PREFIX_LATEST_SEQ: u8 = 0u8;
 /// Prefix for a key to a message KVT (Key Value Timestamp).
@@ -19,28 +56,383 @@ const PREFIX_MSG_VAL: u8 = 2u8;
 const PREFIX_BLOB: u8 = 3u8;
 /// Prefix for a key to a peer.
 const PREFIX_PEER: u8 = 4u8;
+/// Key written when probing the database for writability.
+const KEY_HEALTH: u8 = 5u8;
+/// Prefix for a key to an out-of-order message value (a message fetched by
+/// ID rather than replicated as part of a feed).
+const PREFIX_OOO_MSG: u8 = 6u8;
+/// Prefix for a key marking a message as not fully verified.
+const PREFIX_UNVERIFIED: u8 = 7u8;
+/// Key to the schema version of the database.
+const KEY_SCHEMA_VERSION: u8 = 8u8;
+/// Prefix for a key to the ID of the message at a position (global sequence
+/// number) in the global order of received messages.
+const PREFIX_GLOBAL_ORDER: u8 = 9u8;
+/// Prefix for a key to the global sequence number of a message (the reverse
+/// of `PREFIX_GLOBAL_ORDER`).
+const PREFIX_GLOBAL_SEQ: u8 = 10u8;
+/// Key to the latest assigned global sequence number.
+const KEY_LATEST_GLOBAL_SEQ: u8 = 11u8;
+/// Prefix for a key marking a feed as pinned for replication.
+const PREFIX_PINNED: u8 = 12u8;
+/// Key to the global sequence number up to which notifications have been
+/// read.
+const KEY_NOTIFICATIONS_READ: u8 = 13u8;
+/// Prefix for a key to the measured connection latency of a peer address.
+const PREFIX_ADDRESS_LATENCY: u8 = 14u8;
+/// Key to the metafeed tree of the local identity.
+const KEY_METAFEED: u8 = 15u8;
+/// Key marking a restore of the local feed from peers as in progress.
+const KEY_RESTORE: u8 = 16u8;
+/// Key to the network key for which the database was created.
+const KEY_NETWORK_KEY: u8 = 17u8;
+
+/// Version of the database layout. Version 0 embeds author public keys in
+/// keys; version 1 replaces them with interned author IDs; versions 2 and 3
+/// add block states and reports to the indexes; version 4 orders message
+/// KVT keys by author, then sequence number; version 5 adds message expiry
+/// hints to the indexes; versions 6 to 10 add gatherings, git-ssb
+/// repositories, channels, message timestamps and daily activity to the
+/// indexes; version 11 moves each record type out of the default tree into
+/// a tree of its own.
+const SCHEMA_VERSION: u32 = 11;
+
+/// The trees holding each record type of the database, by key:
+///
+/// - `latest_seq`: interned author ID, to the latest sequence number of the
+///   feed;
+/// - `msg_kvt`: interned author ID and sequence number, to the message KVT
+///   (Key Value Timestamp);
+/// - `msg_val`: message ID, to the author and sequence number of the
+///   message (the 'V' in KVT);
+/// - `blobs`: blob ID, to the status of the blob;
+/// - `peers`: interned author ID, to the latest sequence number of the
+///   feed;
+/// - `ooo_msgs`: message ID, to an out-of-order message KVT (a message
+///   fetched by ID rather than replicated as part of a feed);
+/// - `unverified`: message ID, marking the message as not fully verified;
+/// - `global_order`: position (global sequence number) in the global order
+///   of received messages, to the ID of the message;
+/// - `global_seq`: message ID, to its global sequence number (the reverse
+///   of `global_order`);
+/// - `pinned`: interned author ID, marking the feed as pinned for
+///   replication;
+/// - `address_latency`: peer address, to its measured connection latency;
+/// - `tombstones`: message ID, to the record of the deletion of its
+///   content.
+#[derive(Clone)]
+struct Trees {
+    latest_seq: sled::Tree,
+    msg_kvt: sled::Tree,
+    msg_val: sled::Tree,
+    blobs: sled::Tree,
+    peers: sled::Tree,
+    ooo_msgs: sled::Tree,
+    unverified: sled::Tree,
+    global_order: sled::Tree,
+    global_seq: sled::Tree,
+    pinned: sled::Tree,
+    address_latency: sled::Tree,
+    tombstones: sled::Tree,
+}
+
+impl Trees {
+    /// Open the record trees of the given database.
+    fn open(db: &sled::Db) -> Result<Self> {
+        Ok(Self {
+            latest_seq: db.open_tree("latest_seq")?,
+            msg_kvt: db.open_tree("msg_kvt")?,
+            msg_val: db.open_tree("msg_val")?,
+            blobs: db.open_tree("blobs")?,
+            peers: db.open_tree("peers")?,
+            ooo_msgs: db.open_tree("ooo_msgs")?,
+            unverified: db.open_tree("unverified")?,
+            global_order: db.open_tree("global_order")?,
+            global_seq: db.open_tree("global_seq")?,
+            pinned: db.open_tree("pinned")?,
+            address_latency: db.open_tree("address_latency")?,
+            tombstones: db.open_tree("tombstones")?,
+        })
+    }
+
+    /// Return each tree along with the prefix under which databases of
+    /// schema version 10 and earlier store its records in the default tree.
+    /// Tombstones postdate these versions.
+    fn by_prefix(&self) -> [(u8, &sled::Tree); 11] {
+        [
+            (PREFIX_LATEST_SEQ, &self.latest_seq),
+            (PREFIX_MSG_KVT, &self.msg_kvt),
+            (PREFIX_MSG_VAL, &self.msg_val),
+            (PREFIX_BLOB, &self.blobs),
+            (PREFIX_PEER, &self.peers),
+            (PREFIX_OOO_MSG, &self.ooo_msgs),
+            (PREFIX_UNVERIFIED, &self.unverified),
+            (PREFIX_GLOBAL_ORDER, &self.global_order),
+            (PREFIX_GLOBAL_SEQ, &self.global_seq),
+            (PREFIX_PINNED, &self.pinned),
+            (PREFIX_ADDRESS_LATENCY, &self.address_latency),
+        ]
+    }
+
+    /// Whether no record of any type is stored, i.e. the database is new.
+    fn is_empty(&self) -> bool {
+        self.by_prefix().iter().all(|(_, tree)| tree.is_empty()) && self.tombstones.is_empty()
+    }
+
+    /// Read the message with the given ID, as referenced by the global
+    /// order index.
+    fn global_order_msg(&self, msg_id: &[u8]) -> Result<MessageKvt> {
+        let raw = self.msg_val.get(msg_id)?.ok_or(Error::IndexCorrupt {
+            index: "global order",
+        })?;
+        let msg_ref = serde_cbor::from_slice::<MsgRef>(&raw)?;
+        let raw = self
+            .msg_kvt
+            .get(KvStorage::key_msg_kvt(msg_ref.author, msg_ref.seq_num))?
+            .ok_or(Error::IndexCorrupt {
+                index: "global order",
+            })?;
+
+        Ok(MessageKvt::from_slice(&raw)?)
+    }
+}
+
+/// Number of attempts at opening the database once it has been compacted,
+/// and the delay between attempts.
+const REOPEN_ATTEMPTS: u32 = 50;
+const REOPEN_DELAY: Duration = Duration::from_millis(100);
+
+/// Set while the database is being migrated or reindexed. Messages published
+/// in the meantime are held in the outbox.
+static MAINTENANCE: AtomicUsize = AtomicUsize::new(0);
+
+/// Set from when the database is opened with missing or incomplete indexes
+/// until `KvStorage::build_indexes` has built them.
+static BUILD_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Error of the latest attempt at building the indexes, if it failed.
+static BUILD_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+/// Delay before retrying a failed index build, doubled after each further
+/// failure up to the maximum.
+pub const BUILD_RETRY_DELAY: Duration = Duration::from_secs(10);
+pub const BUILD_RETRY_MAX_DELAY: Duration = Duration::from_secs(600);
+
+/// Wakers of the streams returned by `KvStorage::tail_messages`, notified
+/// when a message is appended or the database is replaced. Each holds at most
+/// one pending notification, however slowly its stream is consumed.
+static TAIL_WAKERS: Mutex<Vec<mpsc::Sender<()>>> = Mutex::new(Vec::new());
+
+/// Set while the local feed is being restored from peers. Publishing before
+/// the restored feed is complete would fork it.
+static RESTORE_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the database is currently being migrated or reindexed, or the
+/// local feed restored.
+pub fn is_under_maintenance() -> bool {
+    MAINTENANCE.load(Ordering::SeqCst) > 0
+        || BUILD_PENDING.load(Ordering::SeqCst)
+        || RESTORE_PENDING.load(Ordering::SeqCst)
+}
+
+/// Whether the local feed is being restored from peers.
+pub fn is_restoring() -> bool {
+    RESTORE_PENDING.load(Ordering::SeqCst)
+}
+
+/// Whether all indexes have been built since the database was opened.
+pub fn indexes_ready() -> bool {
+    !BUILD_PENDING.load(Ordering::SeqCst)
+}
+
+/// Return the error of the latest attempt at building the indexes, if it
+/// failed. The build is retried until it succeeds.
+pub fn index_build_error() -> Option<String> {
+    BUILD_ERROR.lock().unwrap().clone()
+}
+
+/// Marks the database as under maintenance for as long as it is held.
+/// Guards may overlap, e.g. a reindex requested while the background build
+/// is migrating the database, and the maintenance ends when the last one is
+/// dropped.
+struct MaintenanceGuard;
+
+impl MaintenanceGuard {
+    fn new() -> Self {
+        MAINTENANCE.fetch_add(1, Ordering::SeqCst);
+        Self
+    }
+}
+
+impl Drop for MaintenanceGuard {
+    fn drop(&mut self) {
+        MAINTENANCE.fetch_sub(1, Ordering::SeqCst);
+    }
+}
 
 #[derive(Debug, Clone)]
 pub enum StoKvEvent {
     IdChanged(String),
+    /// A feed has followed, unfollowed, blocked or unblocked the local
+    /// identity.
+    FollowerChanged(ContactChange),
+    /// The database has been closed to be replaced by a compacted copy.
+    /// Streams returned by `tail_messages` end.
+    Replaced,
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct KvStorage {
+    config: Option<sled::Config>,
     db: Option<sled::Db>,
+    trees: Option<Trees>,
+    audit: Option<AuditLog>,
+    authors: Option<Authors>,
+    bans: Option<Bans>,
+    blob_wants: Option<BlobWants>,
+    clock_skew: Option<ClockSkew>,
+    deliveries: Option<Deliveries>,
+    indexes: Option<Indexes>,
+    mutes: Option<Mutes>,
+    outbox: Option<Outbox>,
+    room_keys: Option<RoomKeys>,
     ch_broker: Option<ChBrokerSend>,
+    /// Set once the database has been replaced by a compacted copy.
+    replaced: Arc<AtomicBool>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BlobStatus {
     retrieved: bool,
     users: Vec<String>,
+    /// The blob was not fetched automatically because it falls outside the
+    /// blob fetching policy. It may still be fetched on explicit request.
+    #[serde(default)]
+    skipped: bool,
 }
 
 /// The public key (ID) of a peer and a message sequence number.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PubKeyAndSeqNum {
-    pub_key: String,
+    pub pub_key: String,
+    pub seq_num: u64,
+    /// The feed is replicated regardless of the follow graph.
+    #[serde(default)]
+    pub pinned: bool,
+}
+
+/// A page of the public keys (IDs) of authors with a stored feed.
+#[derive(Debug, Serialize)]
+pub struct AuthorPage {
+    /// Total number of authors with a stored feed.
+    pub count: u64,
+    pub authors: Vec<String>,
+    /// Cursor from which to continue listing authors, if there may be more.
+    pub next_cursor: Option<u32>,
+}
+
+/// The reason a message is shown as a notification to the local identity.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationKind {
+    /// The message mentions the local identity.
+    Mention,
+    /// The message replies to a thread started by the local identity.
+    Reply,
+    /// The message votes on a message by the local identity.
+    Vote,
+    /// The message follows the local identity.
+    Follow,
+}
+
+/// A message of interest to the local identity.
+#[derive(Debug)]
+pub struct Notification {
+    /// Global sequence number of the message, which is also its cursor.
+    pub global_seq: u64,
+    pub kind: NotificationKind,
+    pub msg_kvt: MessageKvt,
+    /// The notification was received before the read marker.
+    pub read: bool,
+}
+
+/// A page of notifications, newest first.
+#[derive(Debug)]
+pub struct NotificationPage {
+    pub notifications: Vec<Notification>,
+    /// Cursor from which to continue with older notifications, if there may
+    /// be more.
+    pub next_cursor: Option<u64>,
+}
+
+/// A page of the timeline, newest message first.
+#[derive(Debug)]
+pub struct TimelinePage {
+    pub messages: Vec<MessageKvt>,
+    /// Cursor from which to continue with older messages, if there may be
+    /// more.
+    pub next_cursor: Option<u64>,
+}
+
+/// A page of the messages within a time range, oldest first.
+#[derive(Debug)]
+pub struct TimeRangePage {
+    pub messages: Vec<MessageKvt>,
+    /// ID of the message after which to continue, if there may be more.
+    pub next_cursor: Option<String>,
+}
+
+/// Result of a consistency check of the global order index.
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct GlobalOrderReport {
+    /// Number of feed messages in the database.
+    pub messages: u64,
+    /// Global sequence numbers, up to the latest assigned, with no message.
+    pub gaps: u64,
+    /// Messages assigned more than one global sequence number, or entries
+    /// which do not match the reverse index.
+    pub duplicates: u64,
+    /// Feed messages with no global sequence number.
+    pub missing: u64,
+}
+
+impl GlobalOrderReport {
+    /// Whether the check found no inconsistencies.
+    pub fn is_consistent(&self) -> bool {
+        self.gaps == 0 && self.duplicates == 0 && self.missing == 0
+    }
+}
+
+/// Size on disk of the database before and after compaction, in bytes.
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CompactionReport {
+    pub size_before: u64,
+    pub size_after: u64,
+}
+
+impl CompactionReport {
+    /// Number of bytes recovered by the compaction.
+    pub fn recovered(&self) -> u64 {
+        self.size_before.saturating_sub(self.size_after)
+    }
+}
+
+/// Record of the deletion of the content of a message.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tombstone {
+    /// ID of the `delete` message by which the author of the message
+    /// requested the deletion, or `None` if the content was deleted locally.
+    pub request: Option<String>,
+    pub reason: Option<String>,
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+}
+
+/// Reference from a message ID to the interned ID of its author and its
+/// sequence number.
+#[derive(Debug, Serialize, Deserialize)]
+struct MsgRef {
+    author: u32,
     seq_num: u64,
 }
 
@@ -49,58 +441,618 @@ impl KvStorage {
     /// the instance of `KvStorage` with the database and message-passing
     /// sender.
     pub fn open(&mut self, config: sled::Config, ch_broker: ChBrokerSend) -> Result<()> {
-        self.db = Some(config.open()?);
+        let start = Instant::now();
+        let db = config.open()?;
+        self.config = Some(config);
+        self.trees = Some(Trees::open(&db)?);
+        self.audit = Some(AuditLog::open(&db)?);
+        let authors = Authors::open(&db)?;
+        self.indexes = Some(Indexes::open(&db, authors.clone())?);
+        self.authors = Some(authors);
+        self.bans = Some(Bans::open(&db)?);
+        self.blob_wants = Some(BlobWants::open(&db)?);
+        self.clock_skew = Some(ClockSkew::open(&db)?);
+        self.deliveries = Some(Deliveries::open(&db)?);
+        self.mutes = Some(Mutes::open(&db)?);
+        self.outbox = Some(Outbox::open(&db)?);
+        self.room_keys = Some(RoomKeys::open(&db)?);
+        self.db = Some(db);
         self.ch_broker = Some(ch_broker);
+        self.upgrade_schema()?;
+
+        // Building the missing indexes is left to `build_indexes`, which may
+        // run in the background; until then the database is under
+        // maintenance.
+        BUILD_PENDING.store(self.needs_build()?, Ordering::SeqCst);
+        // A restore which was interrupted resumes.
+        if self.is_restore_pending()? {
+            RESTORE_PENDING.store(true, Ordering::SeqCst);
+        }
+        info!("Opened the database in {:?}", start.elapsed());
+
         Ok(())
     }
 
-    /// Generate a key for the latest sequence number of the feed authored by
-    /// the given public key.
-    fn key_latest_seq(user_id: &str) -> Vec<u8> {
-        let mut key = Vec::new();
-        key.push(PREFIX_LATEST_SEQ);
-        key.extend_from_slice(user_id.as_bytes());
-        key
+    /// Whether any index is missing or incomplete.
+    fn needs_build(&self) -> Result<bool> {
+        let db = self.db()?;
+
+        let missing = !self.trees()?.is_empty()
+            && (!db.contains_key([KEY_LATEST_GLOBAL_SEQ])?
+                || !self.author_index()?.has_feed_index()?);
+
+        Ok(missing || self.indexes().is_incomplete()?)
+    }
+
+    /// Build the indexes which are missing, e.g. on the first run after an
+    /// upgrade, or incomplete, e.g. after the process was killed. This may
+    /// take a long time on a large database, so the node runs it in the
+    /// background while serving connections. The error of a failed build is
+    /// kept for `index_build_error`.
+    pub fn build_indexes(&self) -> Result<()> {
+        let built = self.build_missing_indexes();
+        *BUILD_ERROR.lock().unwrap() = built.as_ref().err().map(|err| err.to_string());
+
+        built
+    }
+
+    fn build_missing_indexes(&self) -> Result<()> {
+        let db = self.db()?;
+        // A new database has nothing to index.
+        let is_new = self.trees()?.is_empty();
+
+        // Build the global order index if the database predates it.
+        if !db.contains_key([KEY_LATEST_GLOBAL_SEQ])? && !is_new {
+            info!("Building the global order index");
+            let start = Instant::now();
+            self.rebuild_global_order()?;
+            info!("Built the global order index in {:?}", start.elapsed());
+        }
+
+        // Likewise for the index of authors with a stored feed.
+        if !self.author_index()?.has_feed_index()? && !is_new {
+            info!("Building the author index");
+            let start = Instant::now();
+            self.rebuild_author_index()?;
+            info!("Built the author index in {:?}", start.elapsed());
+        }
+
+        // Replay the batched index writes which were lost, e.g. when the
+        // process was killed during a sync, or rebuild the indexes if they
+        // cannot be replayed.
+        if self.indexes().is_incomplete()? {
+            let start = Instant::now();
+            match self.indexes().replay_from()? {
+                Some(global_seq) => {
+                    info!("Replaying the index writes lost on an incomplete shutdown");
+                    self.replay_indexes(global_seq)?;
+                    info!("Replayed the index writes in {:?}", start.elapsed());
+                }
+                None => {
+                    info!("Rebuilding the indexes after an incomplete shutdown");
+                    self.reindex()?;
+                    info!("Rebuilt the indexes in {:?}", start.elapsed());
+                }
+            }
+        }
+
+        BUILD_PENDING.store(false, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Bring the database layout up to date with the current schema version.
+    fn upgrade_schema(&self) -> Result<()> {
+        let db = self.db()?;
+        let key: &[u8] = &[KEY_SCHEMA_VERSION];
+
+        let version = match db.get(key)? {
+            Some(raw) => {
+                let mut u32_buffer = [0u8; 4];
+                u32_buffer.copy_from_slice(&raw);
+                u32::from_be_bytes(u32_buffer)
+            }
+            // A new database uses the current layout from the start. The
+            // records of a version 0 database may already have been moved
+            // out of the default tree by an interrupted migration.
+            None if db.is_empty() && self.trees()?.is_empty() => SCHEMA_VERSION,
+            None => 0,
+        };
+
+        match version {
+            SCHEMA_VERSION => {}
+            0 => {
+                info!("Migrating database to schema version {}", SCHEMA_VERSION);
+                // Record the version being migrated from before any record
+                // is moved, so that an interrupted migration is resumed
+                // rather than taken for a new database.
+                db.insert(key, &0u32.to_be_bytes()[..])?;
+                db.flush()?;
+                self.migrate_split_trees()?;
+                self.migrate_intern_authors()?;
+            }
+            1..=10 => {
+                info!("Migrating database to schema version {}", SCHEMA_VERSION);
+                self.migrate_split_trees()?;
+                if version < 10 {
+                    self.reindex()?;
+                }
+                if version < 4 {
+                    self.migrate_feed_keys()?;
+                }
+            }
+            _ => return Err(Error::UnsupportedSchemaVersion(version)),
+        }
+        db.insert(key, &SCHEMA_VERSION.to_be_bytes()[..])?;
+        db.flush()?;
+
+        Ok(())
+    }
+
+    /// Move the records of each type out of the default tree, in which
+    /// databases of schema version 10 and earlier store them under a
+    /// one-byte prefix, into the tree of the type.
+    ///
+    /// The records of a type are written to their tree before they are
+    /// removed from the default tree, so an interrupted migration is simply
+    /// run again.
+    fn migrate_split_trees(&self) -> Result<()> {
+        let _maintenance = MaintenanceGuard::new();
+        let db = self.db()?;
+
+        for (prefix, tree) in self.trees()?.by_prefix() {
+            let mut copied = sled::Batch::default();
+            let mut removed = sled::Batch::default();
+            for item in db.scan_prefix([prefix]) {
+                let (key, value) = item?;
+                copied.insert(&key[1..], value);
+                removed.remove(key);
+            }
+            tree.apply_batch(copied)?;
+            db.flush()?;
+            db.apply_batch(removed)?;
+        }
+        db.flush()?;
+
+        Ok(())
+    }
+
+    /// Migrate a version 0 database, replacing author public keys in keys
+    /// with interned author IDs and rebuilding the indexes.
+    ///
+    /// The keys are rewritten in a single transaction together with the new
+    /// schema version, so an interrupted migration is simply run again.
+    fn migrate_intern_authors(&self) -> Result<()> {
+        let _maintenance = MaintenanceGuard::new();
+        let db = self.db()?;
+        let trees = self.trees()?;
+        let authors = self.author_index()?;
+        let indexes = self.indexes();
+        let mut kvt_batch = sled::Batch::default();
+
+        // Rebuild the indexes from scratch, in feed order, so that follow
+        // states are keyed by interned IDs.
+        indexes.clear()?;
+        for item in trees.msg_kvt.iter() {
+            let (key, value) = item?;
+            let msg_kvt = MessageKvt::from_slice(&value)?;
+            let received = msg_kvt.timestamp;
+            let msg = msg_kvt.into_message()?;
+            indexes.index_msg(&msg)?;
+            indexes.index_timestamp(&msg, received)?;
+
+            let author = authors.intern(&String::from_utf8_lossy(&key[8..]))?;
+            kvt_batch.remove(key.clone());
+            kvt_batch.insert(Self::key_msg_kvt(author, msg.sequence()), value);
+        }
+        for item in trees.ooo_msgs.iter() {
+            let (_, value) = item?;
+            indexes.index_msg(&MessageKvt::from_slice(&value)?.into_message()?)?;
+        }
+        indexes.commit()?;
+
+        let mut author_batches = Vec::new();
+        for tree in [&trees.latest_seq, &trees.peers] {
+            let mut batch = sled::Batch::default();
+            for item in tree.iter() {
+                let (key, value) = item?;
+                let author = authors.intern(&String::from_utf8_lossy(&key))?;
+                batch.remove(key.clone());
+                batch.insert(&author.to_be_bytes()[..], value);
+            }
+            author_batches.push(batch);
+        }
+
+        let mut val_batch = sled::Batch::default();
+        for item in trees.msg_val.iter() {
+            let (key, value) = item?;
+            let msg_ref = serde_cbor::from_slice::<PubKeyAndSeqNum>(&value)?;
+            let msg_ref = MsgRef {
+                author: authors.intern(&msg_ref.pub_key)?,
+                seq_num: msg_ref.seq_num,
+            };
+            val_batch.insert(key, serde_cbor::to_vec(&msg_ref)?);
+        }
+
+        (
+            &**db,
+            &trees.msg_kvt,
+            &trees.latest_seq,
+            &trees.peers,
+            &trees.msg_val,
+        )
+            .transaction(|(tx_db, tx_kvt, tx_latest_seq, tx_peers, tx_val)| {
+                tx_kvt.apply_batch(&kvt_batch)?;
+                tx_latest_seq.apply_batch(&author_batches[0])?;
+                tx_peers.apply_batch(&author_batches[1])?;
+                tx_val.apply_batch(&val_batch)?;
+                let version_key: &[u8] = &[KEY_SCHEMA_VERSION];
+                tx_db.insert(version_key, &SCHEMA_VERSION.to_be_bytes()[..])?;
+
+                Ok::<(), ConflictableTransactionError<sled::Error>>(())
+            })?;
+
+        Ok(())
+    }
+
+    /// Migrate message KVT keys from the version 3 layout, in which the
+    /// sequence number precedes the author, to the current layout, in which
+    /// each feed occupies a contiguous range of keys.
+    ///
+    /// The keys are rewritten in a single transaction together with the new
+    /// schema version, so an interrupted migration is simply run again.
+    fn migrate_feed_keys(&self) -> Result<()> {
+        let _maintenance = MaintenanceGuard::new();
+        let db = self.db()?;
+        let trees = self.trees()?;
+        let mut batch = sled::Batch::default();
+
+        // Both layouts have keys of the same length, so an old key may equal
+        // a new one; a later insert in the batch overrides an earlier remove
+        // of the same key.
+        let mut msgs = Vec::new();
+        for item in trees.msg_kvt.iter() {
+            let (key, value) = item?;
+            let msg_seq = u64_from_be(&key[..8]);
+            let author = u32_from_be(&key[8..12]);
+            batch.remove(key);
+            msgs.push((author, msg_seq, value));
+        }
+        for (author, msg_seq, value) in msgs {
+            batch.insert(Self::key_msg_kvt(author, msg_seq), value);
+        }
+
+        (&**db, &trees.msg_kvt).transaction(|(tx_db, tx_kvt)| {
+            tx_kvt.apply_batch(&batch)?;
+            let version_key: &[u8] = &[KEY_SCHEMA_VERSION];
+            tx_db.insert(version_key, &SCHEMA_VERSION.to_be_bytes()[..])?;
+
+            Ok::<(), ConflictableTransactionError<sled::Error>>(())
+        })?;
+
+        Ok(())
+    }
+
+    /// Rebuild the indexes from scratch from the stored messages.
+    pub fn reindex(&self) -> Result<()> {
+        let _maintenance = MaintenanceGuard::new();
+        let trees = self.trees()?;
+        let indexes = self.indexes();
+
+        indexes.clear()?;
+        self.clock_skew().clear()?;
+        for (tree, in_feed) in [(&trees.msg_kvt, true), (&trees.ooo_msgs, false)] {
+            for item in tree.iter() {
+                let (_, value) = item?;
+                let msg_kvt = MessageKvt::from_slice(&value)?;
+                // Tombstoned messages have no content left to index.
+                if trees.tombstones.contains_key(&msg_kvt.key)? {
+                    continue;
+                }
+                let received = msg_kvt.timestamp;
+                let msg = msg_kvt.into_message()?;
+                indexes.index_msg(&msg)?;
+                // Out-of-order messages are not part of a feed yet.
+                if in_feed {
+                    indexes.index_timestamp(&msg, received)?;
+                    self.clock_skew()
+                        .record(msg.author(), msg.timestamp(), received)?;
+                }
+            }
+        }
+        indexes.commit()?;
+
+        Ok(())
+    }
+
+    /// Index again the feed messages from the given position in the global
+    /// order on, whose index writes were lost.
+    fn replay_indexes(&self, from_global_seq: u64) -> Result<()> {
+        let trees = self.trees()?;
+        let indexes = self.indexes();
+
+        for item in trees.global_order.range(from_global_seq.to_be_bytes()..) {
+            let (key, msg_id) = item?;
+            let msg_id = String::from_utf8_lossy(&msg_id).to_string();
+            // Tombstoned messages have no content left to index.
+            if trees.tombstones.contains_key(&msg_id)? {
+                continue;
+            }
+            let msg_ref = match trees.msg_val.get(&msg_id)? {
+                Some(raw) => serde_cbor::from_slice::<MsgRef>(&raw)?,
+                None => continue,
+            };
+            if let Some(msg_kvt) = self.get_msg_kvt_by_id(msg_ref.author, msg_ref.seq_num)? {
+                let received = msg_kvt.timestamp;
+                let msg = msg_kvt.into_message()?;
+                indexes.replay_feed_msg(&msg, received, u64_from_be(&key))?;
+            }
+        }
+        indexes.commit()?;
+
+        Ok(())
+    }
+
+    /// Commit the pending index writes and flush the database to disk, e.g.
+    /// before acknowledging an append or once a burst of replicated messages
+    /// has been appended.
+    pub async fn commit_indexes(&self) -> Result<()> {
+        self.indexes().commit()?;
+        self.db()?.flush_async().await?;
+
+        Ok(())
+    }
+
+    /// Rebuild the index of authors with a stored feed from the peer
+    /// entries.
+    fn rebuild_author_index(&self) -> Result<()> {
+        let mut ids = Vec::new();
+        for item in self.trees()?.peers.iter() {
+            let (key, _) = item?;
+            ids.push(u32_from_be(&key));
+        }
+        self.author_index()?.rebuild_feed_index(&ids)
+    }
+
+    /// Write a consistent snapshot of the database to the given path.
+    ///
+    /// The snapshot is itself a sled database and can be opened in place of
+    /// the original. sled exports each tree in turn, so the storage is
+    /// borrowed mutably, i.e. under the write lock of `KV_STORAGE`, for
+    /// nothing to be written meanwhile. The copy runs on a blocking thread.
+    pub async fn backup(&mut self, path: &Path) -> Result<()> {
+        self.commit_indexes().await?;
+        let db = self.db()?.clone();
+        let path = path.to_path_buf();
+
+        task::spawn_blocking(move || {
+            let backup_db = sled::Config::new().path(path).open()?;
+            // sled panics on IO errors during the import.
+            panic::catch_unwind(AssertUnwindSafe(|| backup_db.import(db.export())))
+                .map_err(|_| Error::Other("failed to write the backup".to_string()))?;
+            backup_db.flush()?;
+
+            Ok(())
+        })
+        .await
+    }
+
+    /// Compact the database stored at `path`, which sled does not do by
+    /// itself, and return the space recovered. The live entries are copied
+    /// into a fresh database which then replaces the original, leaving
+    /// behind the space taken by deleted and overwritten entries.
+    ///
+    /// Streams returned by `tail_messages` end once the database has been
+    /// closed, and are to be tailed again from the position of their latest
+    /// message. If the compacted database cannot be opened, the original is
+    /// reopened before the error is returned.
+    pub async fn compact(&mut self, path: &Path) -> Result<CompactionReport> {
+        let _maintenance = MaintenanceGuard::new();
+        let config = self.config.clone().ok_or(Error::DbUninitialised)?;
+        let ch_broker = self.ch_broker.clone().ok_or(Error::DbUninitialised)?;
+
+        self.commit_indexes().await?;
+        let db = self.db()?;
+        let size_before = db.size_on_disk()?;
+
+        // Remove the leftovers of an interrupted compaction.
+        let sibling = |suffix: &str| {
+            let mut sibling = path.as_os_str().to_owned();
+            sibling.push(suffix);
+            PathBuf::from(sibling)
+        };
+        let compact_path = sibling(".compact");
+        let old_path = sibling(".old");
+        for leftover in [&compact_path, &old_path] {
+            if leftover.exists() {
+                fs::remove_dir_all(leftover)?;
+            }
+        }
+
+        let compact_db = sled::Config::new().path(&compact_path).open()?;
+        compact_db.import(db.export());
+        compact_db.flush_async().await?;
+        let size_after = compact_db.size_on_disk()?;
+        drop(compact_db);
+
+        // Close the original database, waking the parked tails so that they
+        // end, and open the compacted one in its place.
+        self.replaced.store(true, Ordering::SeqCst);
+        wake_tails();
+        let broker_msg = BrokerEvent::new(Destination::Broadcast, StoKvEvent::Replaced);
+        if let Err(err) = ch_broker.clone().send(broker_msg).await {
+            warn!("failed to notify broker of database replacement: {}", err)
+        }
+        *self = KvStorage::default();
+        let replaced = match fs::rename(path, &old_path) {
+            Ok(()) => match fs::rename(&compact_path, path) {
+                Ok(()) => self.reopen(&config, &ch_broker).await,
+                Err(err) => Err(err.into()),
+            },
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = replaced {
+            warn!("failed to replace the database by its compacted copy: {}", err);
+            *self = KvStorage::default();
+            if old_path.exists() {
+                if path.exists() {
+                    fs::remove_dir_all(path)?;
+                }
+                fs::rename(&old_path, path)?;
+            }
+            self.reopen(&config, &ch_broker).await?;
+            return Err(err);
+        }
+        fs::remove_dir_all(&old_path)?;
+
+        Ok(CompactionReport {
+            size_before,
+            size_after,
+        })
+    }
+
+    /// Open the database again after it was closed. sled releases the lock
+    /// on a dropped database lazily, so it may not be available straight
+    /// away.
+    async fn reopen(&mut self, config: &sled::Config, ch_broker: &ChBrokerSend) -> Result<()> {
+        let mut attempts = 0;
+        while let Err(err) = self.open(config.clone(), ch_broker.clone()) {
+            attempts += 1;
+            if attempts == REOPEN_ATTEMPTS || !matches!(err, Error::Database(sled::Error::Io(_))) {
+                return Err(err);
+            }
+            task::sleep(REOPEN_DELAY).await;
+        }
+
+        Ok(())
+    }
+
+    /// Return the database, failing if it has not been opened.
+    fn db(&self) -> Result<&sled::Db> {
+        self.db.as_ref().ok_or(Error::DbUninitialised)
+    }
+
+    /// Return the record trees, failing if the database has not been opened.
+    fn trees(&self) -> Result<&Trees> {
+        self.trees.as_ref().ok_or(Error::DbUninitialised)
+    }
+
+    /// Return the author index, failing if the database has not been opened.
+    fn author_index(&self) -> Result<&Authors> {
+        self.authors.as_ref().ok_or(Error::DbUninitialised)
+    }
+
+    /// Return the secondary indexes of the database.
+    pub fn indexes(&self) -> &Indexes {
+        self.indexes.as_ref().unwrap()
+    }
+
+    /// Return the temporary bans of peers.
+    pub fn bans(&self) -> &Bans {
+        self.bans.as_ref().unwrap()
+    }
+
+    /// Return the blobs to be fetched from peers.
+    pub fn blob_wants(&self) -> &BlobWants {
+        self.blob_wants.as_ref().unwrap()
     }
 
-    /// Generate a key for a message KVT authored by the given public key and
-    /// with the given message sequence number.
-    fn key_msg_kvt(user_id: &str, msg_seq: u64) -> Vec<u8> {
-        let mut key = Vec::new();
-        key.push(PREFIX_MSG_KVT);
+    /// Return the clock skew of the feeds whose messages were received.
+    pub fn clock_skew(&self) -> &ClockSkew {
+        self.clock_skew.as_ref().unwrap()
+    }
+
+    /// Return the peers to which messages and blobs are delivered whenever
+    /// they are seen.
+    pub fn deliveries(&self) -> &Deliveries {
+        self.deliveries.as_ref().unwrap()
+    }
+
+    /// Return the local list of muted feeds.
+    pub fn mutes(&self) -> &Mutes {
+        self.mutes.as_ref().unwrap()
+    }
+
+    /// Return the connection audit log.
+    pub fn audit(&self) -> &AuditLog {
+        self.audit.as_ref().unwrap()
+    }
+
+    /// Return the outbox of messages waiting to be published.
+    pub fn outbox(&self) -> &Outbox {
+        self.outbox.as_ref().unwrap()
+    }
+
+    /// Return the IDs of rooms pinned on first use.
+    pub fn room_keys(&self) -> &RoomKeys {
+        self.room_keys.as_ref().unwrap()
+    }
+
+    /// Check that the database accepts writes by inserting and removing a
+    /// probe key.
+    pub fn is_writable(&self) -> bool {
+        let db = match self.db.as_ref() {
+            Some(db) => db,
+            None => return false,
+        };
+        let key: &[u8] = &[KEY_HEALTH];
+
+        db.insert(key, &[]).and_then(|_| db.remove(key)).is_ok()
+    }
+
+    /// Generate a key for a message KVT authored by the given (interned)
+    /// author and with the given message sequence number. The messages of a
+    /// feed are stored in a contiguous range of keys, in sequence.
+    fn key_msg_kvt(author: u32, msg_seq: u64) -> Vec<u8> {
+        let mut key = author.to_be_bytes().to_vec();
         key.extend_from_slice(&msg_seq.to_be_bytes()[..]);
-        key.extend_from_slice(user_id.as_bytes());
         key
     }
 
-    /// Generate a key for a message value with the given ID (reference).
-    fn key_msg_val(msg_id: &str) -> Vec<u8> {
-        let mut key = Vec::new();
-        key.push(PREFIX_MSG_VAL);
-        key.extend_from_slice(msg_id.as_bytes());
-        key
+    /// Pin a feed, so that it is replicated and its blobs are fetched
+    /// regardless of the follow graph.
+    pub fn pin_feed(&self, pub_key: &str) -> Result<()> {
+        let author = self.author_index()?.intern(pub_key)?;
+        self.trees()?.pinned.insert(author.to_be_bytes(), &[])?;
+
+        Ok(())
     }
 
-    /// Generate a key for a blob with the given ID (reference).
-    fn key_blob(blob_id: &str) -> Vec<u8> {
-        let mut key = Vec::new();
-        key.push(PREFIX_BLOB);
-        key.extend_from_slice(blob_id.as_bytes());
-        key
+    /// Unpin a feed. Returns `false` if the feed was not pinned.
+    pub fn unpin_feed(&self, pub_key: &str) -> Result<bool> {
+        let pinned = &self.trees()?.pinned;
+        match self.author_index()?.get_id(pub_key)? {
+            Some(author) => Ok(pinned.remove(author.to_be_bytes())?.is_some()),
+            None => Ok(false),
+        }
     }
 
-    /// Generate a key for a peer with the given public key.
-    fn key_peer(user_id: &str) -> Vec<u8> {
-        let mut key = Vec::new();
-        key.push(PREFIX_PEER);
-        key.extend_from_slice(user_id.as_bytes());
-        key
+    /// Whether the given feed is pinned.
+    pub fn is_pinned(&self, pub_key: &str) -> Result<bool> {
+        let pinned = &self.trees()?.pinned;
+        match self.author_index()?.get_id(pub_key)? {
+            Some(author) => Ok(pinned.contains_key(author.to_be_bytes())?),
+            None => Ok(false),
+        }
+    }
+
+    /// Return the public keys of all pinned feeds.
+    pub fn get_pinned(&self) -> Result<Vec<String>> {
+        let mut pinned = Vec::new();
+
+        for item in self.trees()?.pinned.iter() {
+            let (key, _) = item?;
+            if let Some(pub_key) = self.author_index()?.get_author(u32_from_be(&key))? {
+                pinned.push(pub_key);
+            }
+        }
+
+        Ok(pinned)
     }
 
     /// Get the status of a blob with the given ID.
     pub fn get_blob(&self, blob_id: &str) -> Result<Option<BlobStatus>> {
-        let db = self.db.as_ref().unwrap();
-        if let Some(raw) = db.get(Self::key_blob(blob_id))? {
+        if let Some(raw) = self.trees()?.blobs.get(blob_id)? {
             Ok(serde_cbor::from_slice(&raw)?)
         } else {
             Ok(None)
@@ -109,9 +1061,38 @@ impl KvStorage {
 
     /// Set the status of a blob with the given ID.
     pub fn set_blob(&self, blob_id: &str, blob: &BlobStatus) -> Result<()> {
-        let db = self.db.as_ref().unwrap();
         let raw = serde_cbor::to_vec(blob)?;
-        db.insert(Self::key_blob(blob_id), raw)?;
+        self.trees()?.blobs.insert(blob_id, raw)?;
+
+        Ok(())
+    }
+
+    /// Record a blob as skipped by the blob fetching policy.
+    pub fn set_blob_skipped(&self, blob_id: &str) -> Result<()> {
+        let mut blob = self.get_blob(blob_id)?.unwrap_or_default();
+        blob.retrieved = false;
+        blob.skipped = true;
+
+        self.set_blob(blob_id, &blob)
+    }
+
+    /// Record whether the file of a blob is stored. A stored blob is no
+    /// longer skipped.
+    pub fn set_blob_retrieved(&self, blob_id: &str, retrieved: bool) -> Result<()> {
+        let mut blob = self.get_blob(blob_id)?.unwrap_or_default();
+        blob.retrieved = retrieved;
+        blob.skipped &= !retrieved;
+
+        self.set_blob(blob_id, &blob)
+    }
+
+    /// Clear the skipped flag of a blob, e.g. when it has been explicitly
+    /// requested.
+    pub fn clear_blob_skipped(&self, blob_id: &str) -> Result<()> {
+        if let Some(mut blob) = self.get_blob(blob_id)? {
+            blob.skipped = false;
+            self.set_blob(blob_id, &blob)?;
+        }
 
         Ok(())
     }
@@ -120,55 +1101,354 @@ impl KvStorage {
     pub fn get_pending_blobs(&self) -> Result<Vec<String>> {
         let mut list = Vec::new();
 
-        let db = self.db.as_ref().unwrap();
-        let scan_key: &[u8] = &[PREFIX_BLOB];
-        for item in db.range(scan_key..) {
+        for item in self.trees()?.blobs.iter() {
             let (k, v) = item?;
             let blob: BlobStatus = serde_cbor::from_slice(&v)?;
-            if !blob.retrieved {
-                list.push(String::from_utf8_lossy(&k[1..]).to_string());
+            if !blob.retrieved && !blob.skipped {
+                list.push(String::from_utf8_lossy(&k).to_string());
             }
         }
 
         Ok(list)
     }
 
-    /// Get the sequence number of the latest message in the feed authored by
-    /// the peer with the given public key.
-    pub fn get_latest_seq(&self, user_id: &str) -> Result<Option<u64>> {
-        let db = self.db.as_ref().unwrap();
-        let key = Self::key_latest_seq(user_id);
-        let seq = if let Some(value) = db.get(key)? {
-            let mut u64_buffer = [0u8; 8];
-            u64_buffer.copy_from_slice(&value);
-            Some(u64::from_be_bytes(u64_buffer))
-        } else {
-            None
-        };
-
-        Ok(seq)
-    }
+    /// Get a list of IDs for all blobs which have been skipped by the blob
+    /// fetching policy.
+    pub fn get_skipped_blobs(&self) -> Result<Vec<String>> {
+        let mut list = Vec::new();
 
-    /// Get the message KVT (Key Value Timestamp) for the given author and
-    /// message sequence number.
-    pub fn get_msg_kvt(&self, user_id: &str, msg_seq: u64) -> Result<Option<MessageKvt>> {
-        let db = self.db.as_ref().unwrap();
-        if let Some(raw) = db.get(Self::key_msg_kvt(user_id, msg_seq))? {
-            Ok(Some(MessageKvt::from_slice(&raw)?))
-        } else {
-            Ok(None)
+        for item in self.trees()?.blobs.iter() {
+            let (k, v) = item?;
+            let blob: BlobStatus = serde_cbor::from_slice(&v)?;
+            if blob.skipped {
+                list.push(String::from_utf8_lossy(&k).to_string());
+            }
         }
+
+        Ok(list)
     }
 
-    /// Get the message value for the given message ID (key).
-    pub fn get_msg_val(&self, msg_id: &str) -> Result<Option<MessageValue>> {
-        let db = self.db.as_ref().unwrap();
+    /// Get a list of IDs for all blobs recorded as stored.
+    pub fn get_retrieved_blobs(&self) -> Result<Vec<String>> {
+        let mut list = Vec::new();
+
+        for item in self.trees()?.blobs.iter() {
+            let (k, v) = item?;
+            let blob: BlobStatus = serde_cbor::from_slice(&v)?;
+            if blob.retrieved {
+                list.push(String::from_utf8_lossy(&k).to_string());
+            }
+        }
+
+        Ok(list)
+    }
+
+    /// Get the out-of-order message value with the given ID (key).
+    pub fn get_ooo_msg_val(&self, msg_id: &str) -> Result<Option<MessageValue>> {
+        if let Some(raw) = self.trees()?.ooo_msgs.get(msg_id)? {
+            Ok(Some(MessageKvt::from_slice(&raw)?.into_message()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Store a message value in the out-of-order store. Out-of-order messages
+    /// are not part of a replicated feed and are kept apart from feed
+    /// messages, but are indexed in the same way. They are recorded as
+    /// unverified until they are appended to a feed. Messages whose content
+    /// was deleted are not stored again.
+    pub fn set_ooo_msg_val(&self, msg_val: &MessageValue) -> Result<()> {
+        let msg_id = msg_val.id().to_string();
+        if self.trees()?.tombstones.contains_key(&msg_id)? {
+            return Ok(());
+        }
+        let msg_kvt = MessageKvt::new(msg_val.clone()).to_string();
+        self.trees()?.ooo_msgs.insert(&msg_id, msg_kvt.as_bytes())?;
+        self.set_msg_unverified(&msg_id)?;
+        // The writes are committed right away, since only those of feed
+        // messages are replayed if they are lost.
+        self.indexes().index_msg(msg_val)?;
+        self.indexes().commit()?;
+
+        Ok(())
+    }
+
+    /// Get the message value with the given ID (key) from either the feed
+    /// store or the out-of-order store.
+    pub fn get_any_msg_val(&self, msg_id: &str) -> Result<Option<MessageValue>> {
+        match self.get_msg_val(msg_id)? {
+            Some(msg) => Ok(Some(msg)),
+            None => self.get_ooo_msg_val(msg_id),
+        }
+    }
+
+    /// Delete the content of the message with the given ID and remove it
+    /// from the indexes. The message KVT of a feed message is kept with
+    /// `null` content, so that its key, sequence number and signature still
+    /// link the feed together; an out-of-order message is removed outright.
+    ///
+    /// If a `delete` request is given, the message is only tombstoned if it
+    /// was published by the author of the request. Returns `false` if no
+    /// such message is stored or its content was already deleted. The
+    /// latest message of a feed cannot be tombstoned, since the next message
+    /// is validated against it.
+    pub fn tombstone_msg(
+        &self,
+        msg_id: &str,
+        request: Option<&MessageValue>,
+        reason: Option<String>,
+    ) -> Result<bool> {
+        let trees = self.trees()?;
+        if trees.tombstones.contains_key(msg_id)? {
+            return Ok(false);
+        }
+        let (raw, msg_ref) = match trees.msg_val.get(msg_id)? {
+            Some(raw) => {
+                let msg_ref = serde_cbor::from_slice::<MsgRef>(&raw)?;
+                let raw = trees
+                    .msg_kvt
+                    .get(Self::key_msg_kvt(msg_ref.author, msg_ref.seq_num))?
+                    .ok_or(Error::IndexCorrupt { index: "message" })?;
+                (raw, Some(msg_ref))
+            }
+            None => match trees.ooo_msgs.get(msg_id)? {
+                Some(raw) => (raw, None),
+                None => return Ok(false),
+            },
+        };
+        let mut msg_kvt = MessageKvt::from_slice(&raw)?;
+        let received = msg_kvt.timestamp;
+        let msg = MessageKvt::from_slice(&raw)?.into_message()?;
+        if request.is_some_and(|request| request.author() != msg.author()) {
+            return Ok(false);
+        }
+
+        let tombstone = serde_cbor::to_vec(&Tombstone {
+            request: request.map(|request| request.id().to_string()),
+            reason,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or(0),
+        })?;
+        let in_feed = msg_ref.is_some();
+        match msg_ref {
+            Some(msg_ref) => {
+                if self.get_latest_seq_by_id(msg_ref.author)? == Some(msg_ref.seq_num) {
+                    return Err(Error::Other(format!(
+                        "{msg_id} is the latest message of its feed and cannot be tombstoned"
+                    )));
+                }
+                msg_kvt.value["content"] = serde_json::Value::Null;
+                let key = Self::key_msg_kvt(msg_ref.author, msg_ref.seq_num);
+                let msg_kvt = msg_kvt.to_string();
+                (&trees.msg_kvt, &trees.tombstones).transaction(
+                    |(tx_msg_kvt, tx_tombstones)| {
+                        tx_msg_kvt.insert(key.as_slice(), msg_kvt.as_bytes())?;
+                        tx_tombstones.insert(msg_id.as_bytes(), tombstone.clone())?;
+
+                        Ok::<(), ConflictableTransactionError<sled::Error>>(())
+                    },
+                )?;
+            }
+            None => {
+                (&trees.ooo_msgs, &trees.unverified, &trees.tombstones).transaction(
+                    |(tx_ooo_msgs, tx_unverified, tx_tombstones)| {
+                        tx_ooo_msgs.remove(msg_id.as_bytes())?;
+                        tx_unverified.remove(msg_id.as_bytes())?;
+                        tx_tombstones.insert(msg_id.as_bytes(), tombstone.clone())?;
+
+                        Ok::<(), ConflictableTransactionError<sled::Error>>(())
+                    },
+                )?;
+            }
+        }
+
+        // Entries which only concern the message are removed; state derived
+        // from several messages is recomputed from the remaining messages of
+        // its author, without reindexing the whole database.
+        let received = in_feed.then_some(received);
+        let author = msg.author().as_str();
+        let indexes = self.indexes();
+        indexes.unindex_msg(&msg, received)?;
+        let remaining = || -> Result<_> {
+            Ok(self.iter_feed(author)?.filter(|msg_kvt| {
+                msg_kvt
+                    .as_ref()
+                    .map_or(true, |msg_kvt| !msg_kvt.value["content"].is_null())
+            }))
+        };
+        indexes.rederive(&msg, received, remaining()?)?;
+        if received.is_some() {
+            let mut timestamps = Vec::new();
+            for msg_kvt in remaining()? {
+                let msg_kvt = msg_kvt?;
+                let claimed = msg_kvt.value["timestamp"].as_f64().unwrap_or(0.0);
+                timestamps.push((claimed, msg_kvt.timestamp));
+            }
+            self.clock_skew().recompute(author, timestamps)?;
+        }
+
+        Ok(true)
+    }
+
+    /// Return the record of the deletion of the content of the message with
+    /// the given ID, if it was deleted.
+    pub fn get_tombstone(&self, msg_id: &str) -> Result<Option<Tombstone>> {
+        match self.trees()?.tombstones.get(msg_id)? {
+            Some(raw) => Ok(Some(serde_cbor::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Return the sequence number of the first message of the feed authored
+    /// by the given public key, from `from_seq` on, whose content was
+    /// deleted. Such messages can no longer be verified, so the feed is only
+    /// served to peers up to the one before.
+    pub fn first_tombstoned_seq(&self, user_id: &str, from_seq: u64) -> Result<Option<u64>> {
+        let trees = self.trees()?;
+        if trees.tombstones.is_empty() {
+            return Ok(None);
+        }
+        let author = match self.author_index()?.get_id(user_id)? {
+            Some(author) => author,
+            None => return Ok(None),
+        };
+
+        // Tombstones are few, and keyed by message ID.
+        let mut first = None;
+        for item in trees.tombstones.iter() {
+            let (msg_id, _) = item?;
+            // Out-of-order messages are not part of a feed.
+            let msg_ref = match trees.msg_val.get(msg_id)? {
+                Some(raw) => serde_cbor::from_slice::<MsgRef>(&raw)?,
+                None => continue,
+            };
+            if msg_ref.author == author && msg_ref.seq_num >= from_seq {
+                first =
+                    Some(first.map_or(msg_ref.seq_num, |first: u64| first.min(msg_ref.seq_num)));
+            }
+        }
+
+        Ok(first)
+    }
+
+    /// Honour a `delete` message, by which an author requests the deletion
+    /// of the content of one of their earlier messages.
+    fn apply_delete_request(&self, msg_val: &MessageValue) -> Result<()> {
+        let content = msg_val.content();
+        let target = match content["link"].as_str() {
+            Some(target) => target,
+            None => return Ok(()),
+        };
+        let reason = content["reason"].as_str().map(String::from);
+        if self.tombstone_msg(target, Some(msg_val), reason)? {
+            info!("Deleted the content of {target} at the request of its author");
+        }
+
+        Ok(())
+    }
+
+    /// Return the timestamp by which the given message is ordered (see
+    /// [`clock_skew::ordering_timestamp`]). Messages which are not part of a
+    /// stored feed are taken to be received now.
+    pub fn ordering_timestamp(&self, msg: &MessageValue) -> Result<f64> {
+        let received = match self.get_msg_kvt(msg.author(), msg.sequence())? {
+            Some(msg_kvt) => msg_kvt.timestamp,
+            None => SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as f64)
+                .unwrap_or(0.0),
+        };
+
+        Ok(clock_skew::ordering_timestamp(msg.timestamp(), received))
+    }
 
-        if let Some(raw) = db.get(Self::key_msg_val(msg_id))? {
-            let msg_ref = serde_cbor::from_slice::<PubKeyAndSeqNum>(&raw)?;
+    /// Record that the message with the given ID was stored without being
+    /// fully verified, i.e. its signature was checked but not its position
+    /// in the feed of its author.
+    pub fn set_msg_unverified(&self, msg_id: &str) -> Result<()> {
+        self.trees()?.unverified.insert(msg_id, &[])?;
+
+        Ok(())
+    }
+
+    /// Whether the message with the given ID was fully verified when it was
+    /// stored. Messages are verified unless recorded otherwise.
+    pub fn is_msg_verified(&self, msg_id: &str) -> Result<bool> {
+        Ok(!self.trees()?.unverified.contains_key(msg_id)?)
+    }
+
+    /// Get the sequence number of the latest message in the feed authored by
+    /// the peer with the given public key.
+    pub fn get_latest_seq(&self, user_id: &str) -> Result<Option<u64>> {
+        match self.author_index()?.get_id(user_id)? {
+            Some(author) => self.get_latest_seq_by_id(author),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the sequence number of the latest message in the feed authored by
+    /// the given (interned) author.
+    fn get_latest_seq_by_id(&self, author: u32) -> Result<Option<u64>> {
+        let seq = if let Some(value) = self.trees()?.latest_seq.get(author.to_be_bytes())? {
+            let mut u64_buffer = [0u8; 8];
+            u64_buffer.copy_from_slice(&value);
+            Some(u64::from_be_bytes(u64_buffer))
+        } else {
+            None
+        };
+
+        Ok(seq)
+    }
+
+    /// Get the message KVT (Key Value Timestamp) for the given author and
+    /// message sequence number.
+    pub fn get_msg_kvt(&self, user_id: &str, msg_seq: u64) -> Result<Option<MessageKvt>> {
+        match self.author_index()?.get_id(user_id)? {
+            Some(author) => self.get_msg_kvt_by_id(author, msg_seq),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the message KVT for the given author and message sequence number
+    /// as the serialized bytes in which it is stored, so that it can be
+    /// forwarded without being parsed and serialized again.
+    pub fn get_msg_kvt_raw(&self, user_id: &str, msg_seq: u64) -> Result<Option<IVec>> {
+        let msg_kvt = &self.trees()?.msg_kvt;
+        match self.author_index()?.get_id(user_id)? {
+            Some(author) => Ok(msg_kvt.get(Self::key_msg_kvt(author, msg_seq))?),
+            None => Ok(None),
+        }
+    }
+
+    /// Get the message KVT for the given (interned) author and message
+    /// sequence number.
+    fn get_msg_kvt_by_id(&self, author: u32, msg_seq: u64) -> Result<Option<MessageKvt>> {
+        if let Some(raw) = self
+            .trees()?
+            .msg_kvt
+            .get(Self::key_msg_kvt(author, msg_seq))?
+        {
+            Ok(Some(MessageKvt::from_slice(&raw)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Get the message value for the given message ID (key). Returns `None`
+    /// if the content of the message was deleted, since its signature can
+    /// no longer be verified.
+    pub fn get_msg_val(&self, msg_id: &str) -> Result<Option<MessageValue>> {
+        let trees = self.trees()?;
+        if trees.tombstones.contains_key(msg_id)? {
+            return Ok(None);
+        }
+        if let Some(raw) = trees.msg_val.get(msg_id)? {
+            let msg_ref = serde_cbor::from_slice::<MsgRef>(&raw)?;
             let msg = self
-                .get_msg_kvt(&msg_ref.pub_key, msg_ref.seq_num)?
-                .unwrap()
+                .get_msg_kvt_by_id(msg_ref.author, msg_ref.seq_num)?
+                .ok_or(Error::IndexCorrupt { index: "message" })?
                 .into_message()?;
             Ok(Some(msg))
         } else {
@@ -181,318 +1461,2790 @@ impl KvStorage {
         let latest_msg = if let Some(last_id) = self.get_latest_seq(user_id)? {
             Some(
                 self.get_msg_kvt(user_id, last_id)?
-                    .unwrap()
+                    .ok_or_else(|| Error::MissingMessage {
+                        author: user_id.to_string(),
+                        seq: last_id,
+                    })?
                     .into_message()?,
             )
         } else {
             None
         };
 
-        Ok(latest_msg)
-    }
+        Ok(latest_msg)
+    }
+
+    /// Return the public key and latest sequence number for all peers in the
+    /// database, along with pinned feeds of which no message is stored yet.
+    pub async fn get_peers(&self) -> Result<Vec<PubKeyAndSeqNum>> {
+        let pinned_feeds = &self.trees()?.pinned;
+        let mut peers = Vec::new();
+
+        for (author, pub_key) in self.author_index()?.get_feeds(None, usize::MAX)? {
+            // Get the latest sequence number for the peer.
+            // Fallback to a value of 0 if a `None` value is returned.
+            let seq_num = self.get_latest_seq_by_id(author)?.unwrap_or(0);
+            let pinned = pinned_feeds.contains_key(author.to_be_bytes())?;
+            let peer_latest_sequence = PubKeyAndSeqNum {
+                pub_key,
+                seq_num,
+                pinned,
+            };
+            peers.push(peer_latest_sequence)
+        }
+
+        for pub_key in self.get_pinned()? {
+            if !peers.iter().any(|peer| peer.pub_key == pub_key) {
+                peers.push(PubKeyAndSeqNum {
+                    pub_key,
+                    seq_num: 0,
+                    pinned: true,
+                });
+            }
+        }
+
+        Ok(peers)
+    }
+
+    /// Return the number of authors with a stored feed.
+    pub fn author_count(&self) -> Result<u64> {
+        self.author_index()?.feed_count()
+    }
+
+    /// Return up to `limit` public keys of authors with a stored feed,
+    /// starting after the given cursor. Authors are listed in order of their
+    /// interned IDs, so pages stay stable as feeds are added.
+    pub fn authors(&self, cursor: Option<u32>, limit: usize) -> Result<AuthorPage> {
+        let feeds = self.author_index()?.get_feeds(cursor, limit)?;
+        let next_cursor = match feeds.last() {
+            Some((author, _)) if feeds.len() == limit => Some(*author),
+            _ => None,
+        };
+
+        Ok(AuthorPage {
+            count: self.author_count()?,
+            authors: feeds.into_iter().map(|(_, pub_key)| pub_key).collect(),
+            next_cursor,
+        })
+    }
+
+    /// Append a message value to a feed.
+    pub async fn append_feed(&self, msg_val: MessageValue) -> Result<u64> {
+        // Validation, the write transaction and indexing are run on the
+        // blocking pool, so that the executor threads stay free to serve
+        // connections and requests during heavy replication.
+        let kv = self.clone();
+        let author = msg_val.author().clone();
+        let (seq_num, contact_changes) =
+            task::spawn_blocking(move || kv.write_msg(&msg_val)).await?;
+
+        self.db()?.flush_async().await?;
+        wake_tails();
+
+        // Publish a notification that the feed belonging to the given public
+        // key has been updated.
+        let broker_msg = BrokerEvent::new(Destination::Broadcast, StoKvEvent::IdChanged(author));
+
+        // Matching on the error here (instead of unwrapping) allows us to
+        // write unit tests for `append_feed`; a case where we do not have
+        // a broker deployed to receive the event message.
+        if let Err(err) = self.ch_broker.as_ref().unwrap().send(broker_msg).await {
+            warn!(
+                "failed to notify broker of message appended to kv store: {}",
+                err
+            )
+        };
+
+        // Publish the changes in the relationships of other feeds with the
+        // local identity.
+        if let Some(secret_config) = SECRET_CONFIG.get() {
+            for change in contact_changes
+                .into_iter()
+                .filter(|change| change.contact == secret_config.id)
+            {
+                let broker_msg =
+                    BrokerEvent::new(Destination::Broadcast, StoKvEvent::FollowerChanged(change));
+                if let Err(err) = self.ch_broker.as_ref().unwrap().send(broker_msg).await {
+                    warn!("failed to notify broker of follower change: {}", err)
+                }
+            }
+        }
+
+        Ok(seq_num)
+    }
+
+    /// Validate a message value and write it to its feed, updating the
+    /// indexes. Returns the sequence number of the message and the changes
+    /// in relationships it makes.
+    fn write_msg(&self, msg_val: &MessageValue) -> Result<(u64, Vec<ContactChange>)> {
+        // Check the sequence number and previous message reference
+        // according to the feed format of the author.
+        let last_msg = self.get_latest_msg_val(msg_val.author())?;
+        validation::validate_msg(last_msg.as_ref(), msg_val)?;
+        let seq_num = msg_val.sequence();
+
+        let author = self.author_index()?.intern(msg_val.author())?;
+        let db = self.db()?;
+        let trees = self.trees()?;
+
+        let msg_ref = serde_cbor::to_vec(&MsgRef { author, seq_num })?;
+        let msg_kvt = MessageKvt::new(msg_val.clone());
+        let received = msg_kvt.timestamp;
+        let msg_kvt = msg_kvt.to_string();
+
+        // Write the message value reference, message KVT, latest sequence
+        // number, peer entry and author index entry in a single transaction.
+        // This ensures the store is never left in an inconsistent state if
+        // the process is interrupted partway through an append.
+        let msg_id = msg_val.id().to_string();
+        let is_first = last_msg.is_none();
+        let global_seq = (
+            &**db,
+            &trees.msg_val,
+            &trees.msg_kvt,
+            &trees.latest_seq,
+            &trees.peers,
+            &trees.unverified,
+            &trees.global_order,
+            &trees.global_seq,
+            self.author_index()?.tree(),
+        )
+            .transaction(
+                |(
+                    tx_db,
+                    tx_msg_val,
+                    tx_msg_kvt,
+                    tx_latest_seq,
+                    tx_peers,
+                    tx_unverified,
+                    tx_global_order,
+                    tx_global_seq,
+                    tx_authors,
+                )| {
+                    tx_msg_val.insert(msg_id.as_bytes(), msg_ref.clone())?;
+                    tx_msg_kvt.insert(Self::key_msg_kvt(author, seq_num), msg_kvt.as_bytes())?;
+                    tx_latest_seq.insert(&author.to_be_bytes()[..], &seq_num.to_be_bytes()[..])?;
+                    // Add the public key and latest sequence number for this
+                    // peer to the list of peers.
+                    tx_peers.insert(&author.to_be_bytes()[..], &seq_num.to_be_bytes()[..])?;
+                    // The message has been fully verified, even if it was
+                    // previously stored out-of-order.
+                    tx_unverified.remove(msg_id.as_bytes())?;
+                    // Assign the message the next position in the global
+                    // order.
+                    let latest_global_seq_key: &[u8] = &[KEY_LATEST_GLOBAL_SEQ];
+                    let global_seq = tx_db
+                        .get(latest_global_seq_key)?
+                        .map_or(0, |raw| u64_from_be(&raw))
+                        + 1;
+                    tx_global_order.insert(&global_seq.to_be_bytes()[..], msg_id.as_bytes())?;
+                    tx_global_seq.insert(msg_id.as_bytes(), &global_seq.to_be_bytes()[..])?;
+                    tx_db.insert(latest_global_seq_key, &global_seq.to_be_bytes()[..])?;
+                    // Record the author on the first message of their feed.
+                    if is_first {
+                        Authors::tx_add_feed(tx_authors, author)?;
+                    }
+
+                    Ok::<u64, ConflictableTransactionError<sled::Error>>(global_seq)
+                },
+            )?;
+
+        // Update the secondary indexes with the content of the message.
+        let contact_changes = self
+            .indexes()
+            .index_feed_msg(msg_val, received, global_seq)?;
+        self.clock_skew()
+            .record(msg_val.author(), msg_val.timestamp(), received)?;
+
+        // The message is stored by now, so a request which cannot be
+        // honoured does not fail the append.
+        if msg_val.content()["type"].as_str() == Some("delete") {
+            if let Err(err) = self.apply_delete_request(msg_val) {
+                warn!("Failed to apply delete request {msg_id}: {err}");
+            }
+        }
+
+        Ok((seq_num, contact_changes))
+    }
+
+    /// Get all messages comprising the feed authored by the given public key.
+    pub fn get_feed(&self, user_id: &str) -> Result<Vec<MessageKvt>> {
+        self.iter_feed(user_id)?.collect()
+    }
+
+    /// Iterate over the messages of the feed authored by the given public
+    /// key, in sequence, with a single range scan.
+    pub fn iter_feed(&self, user_id: &str) -> Result<impl Iterator<Item = Result<MessageKvt>>> {
+        let msg_kvt = &self.trees()?.msg_kvt;
+        // No messages are stored for an author without an interned ID.
+        let author = self.author_index()?.get_id(user_id)?;
+
+        Ok(author
+            .map(|author| msg_kvt.scan_prefix(author.to_be_bytes()))
+            .into_iter()
+            .flatten()
+            .map(|item| {
+                let (_, value) = item?;
+                Ok(MessageKvt::from_slice(&value)?)
+            }))
+    }
+
+    /// Stream the messages of the feed authored by the given public key, in
+    /// sequence. Messages are read as the stream is polled, so that whole
+    /// feeds are not buffered.
+    pub fn feed_stream(&self, user_id: &str) -> Result<impl Stream<Item = Result<MessageKvt>>> {
+        Ok(futures::stream::iter(self.iter_feed(user_id)?))
+    }
+
+    /// Get up to `limit` messages of the feed authored by the given public
+    /// key, in sequence, starting at sequence number `start_seq`. Messages
+    /// are read with a single range scan, so long feeds can be paged through
+    /// without loading them whole.
+    pub fn get_feed_range(
+        &self,
+        user_id: &str,
+        start_seq: u64,
+        limit: usize,
+    ) -> Result<Vec<MessageKvt>> {
+        let msg_kvt = &self.trees()?.msg_kvt;
+        let author = match self.author_index()?.get_id(user_id)? {
+            Some(author) => author,
+            None => return Ok(Vec::new()),
+        };
+
+        msg_kvt
+            .range(Self::key_msg_kvt(author, start_seq)..=Self::key_msg_kvt(author, u64::MAX))
+            .take(limit)
+            .map(|item| {
+                let (_, value) = item?;
+                Ok(MessageKvt::from_slice(&value)?)
+            })
+            .collect()
+    }
+
+    /// Get the global sequence number (position in the global order of
+    /// received messages) of the message with the given ID. Returns `None`
+    /// if the message is not part of a stored feed.
+    pub fn get_global_seq(&self, msg_id: &str) -> Result<Option<u64>> {
+        Ok(self
+            .trees()?
+            .global_seq
+            .get(msg_id)?
+            .map(|raw| u64_from_be(&raw)))
+    }
+
+    /// Get the latest assigned global sequence number, or 0 if no message
+    /// has been appended yet.
+    pub fn get_latest_global_seq(&self) -> Result<u64> {
+        let db = self.db()?;
+        let key: &[u8] = &[KEY_LATEST_GLOBAL_SEQ];
+
+        Ok(db.get(key)?.map_or(0, |raw| u64_from_be(&raw)))
+    }
+
+    /// Iterate over the message references in the global order, most
+    /// recently received first, starting before the given global sequence
+    /// number (or from the latest message).
+    fn iter_received_rev(
+        &self,
+        before: Option<u64>,
+    ) -> Result<impl Iterator<Item = Result<(u64, MsgRef)>> + '_> {
+        let trees = self.trees()?;
+        let global_order = match before {
+            Some(global_seq) => trees.global_order.range(..global_seq.to_be_bytes()),
+            None => trees.global_order.iter(),
+        };
+
+        Ok(global_order.rev().filter_map(move |item| {
+            let msg_ref = (|| {
+                let (key, msg_id) = item?;
+                let msg_ref = match trees.msg_val.get(msg_id)? {
+                    Some(raw) => serde_cbor::from_slice::<MsgRef>(&raw)?,
+                    None => return Ok(None),
+                };
+                Ok(Some((u64_from_be(&key), msg_ref)))
+            })();
+            msg_ref.transpose()
+        }))
+    }
+
+    /// Determine whether the given message notifies `local_id`, and why.
+    fn notification_kind(
+        &self,
+        local_id: &str,
+        msg_kvt: &MessageKvt,
+    ) -> Result<Option<NotificationKind>> {
+        let content = &msg_kvt.value["content"];
+        let is_own_msg = |msg_id: Option<&str>| -> Result<bool> {
+            match msg_id {
+                Some(msg_id) => Ok(self
+                    .get_msg_val(msg_id)?
+                    .is_some_and(|msg| msg.author() == local_id)),
+                None => Ok(false),
+            }
+        };
+
+        let kind = match content["type"].as_str() {
+            Some("contact")
+                if content["contact"].as_str() == Some(local_id)
+                    && content["following"].as_bool() == Some(true) =>
+            {
+                Some(NotificationKind::Follow)
+            }
+            Some("vote") if is_own_msg(content["vote"]["link"].as_str())? => {
+                Some(NotificationKind::Vote)
+            }
+            _ if content["mentions"].as_array().is_some_and(|mentions| {
+                mentions
+                    .iter()
+                    .any(|mention| mention["link"].as_str() == Some(local_id))
+            }) =>
+            {
+                Some(NotificationKind::Mention)
+            }
+            _ if is_own_msg(content["root"].as_str())? => Some(NotificationKind::Reply),
+            _ => None,
+        };
+
+        Ok(kind)
+    }
+
+    /// Return the notification for `local_id` raised by the given message,
+    /// received at the given position in the global order, if any. Messages
+    /// by the local identity and by muted feeds raise none.
+    pub fn notification(
+        &self,
+        local_id: &str,
+        global_seq: u64,
+        msg_kvt: MessageKvt,
+    ) -> Result<Option<Notification>> {
+        let author = msg_kvt.value["author"].as_str().unwrap_or_default();
+        if author == local_id || self.mutes().is_muted(author)? {
+            return Ok(None);
+        }
+        let kind = match self.notification_kind(local_id, &msg_kvt)? {
+            Some(kind) => kind,
+            None => return Ok(None),
+        };
+
+        Ok(Some(Notification {
+            global_seq,
+            kind,
+            msg_kvt,
+            read: global_seq <= self.get_notifications_read()?,
+        }))
+    }
+
+    /// Return up to `limit` notifications for `local_id`, newest first:
+    /// mentions, replies to its threads, votes on its messages and new
+    /// followers. Messages by the local identity and by muted feeds are left
+    /// out. The cursor is the global sequence number before which to
+    /// continue.
+    pub fn notifications(
+        &self,
+        local_id: &str,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> Result<NotificationPage> {
+        let read_up_to = self.get_notifications_read()?;
+        let local_author = self.author_index()?.get_id(local_id)?;
+
+        let mut notifications = Vec::new();
+        let mut next_cursor = None;
+        for item in self.iter_received_rev(cursor)? {
+            if notifications.len() == limit {
+                break;
+            }
+            let (global_seq, msg_ref) = item?;
+            if Some(msg_ref.author) == local_author {
+                continue;
+            }
+            let msg_kvt = match self.get_msg_kvt_by_id(msg_ref.author, msg_ref.seq_num)? {
+                Some(msg_kvt) => msg_kvt,
+                None => continue,
+            };
+            if let Some(notification) = self.notification(local_id, global_seq, msg_kvt)? {
+                notifications.push(Notification {
+                    read: global_seq <= read_up_to,
+                    ..notification
+                });
+                next_cursor = Some(global_seq);
+            }
+        }
+        if notifications.len() < limit {
+            next_cursor = None;
+        }
+
+        Ok(NotificationPage {
+            notifications,
+            next_cursor,
+        })
+    }
+
+    /// Get the connection latency in milliseconds measured for the given
+    /// peer address, if it has been reached before.
+    pub fn get_address_latency(&self, address: &str) -> Result<Option<u64>> {
+        Ok(self
+            .trees()?
+            .address_latency
+            .get(address)?
+            .map(|raw| u64_from_be(&raw)))
+    }
+
+    /// Record a connection latency in milliseconds measured for the given
+    /// peer address. The stored value is a moving average, so that a single
+    /// slow connection does not outweigh earlier measurements.
+    pub fn record_address_latency(&self, address: &str, latency_ms: u64) -> Result<()> {
+        let address_latency = &self.trees()?.address_latency;
+
+        address_latency.fetch_and_update(address, |old| {
+            let latency = match old.map(u64_from_be) {
+                Some(old) => (old * 3 + latency_ms) / 4,
+                None => latency_ms,
+            };
+            Some(latency.to_be_bytes().to_vec())
+        })?;
+
+        Ok(())
+    }
+
+    /// Get the global sequence number up to which notifications have been
+    /// read, or 0 if none have.
+    pub fn get_notifications_read(&self) -> Result<u64> {
+        let db = self.db()?;
+        let key: &[u8] = &[KEY_NOTIFICATIONS_READ];
+
+        Ok(db.get(key)?.map_or(0, |raw| u64_from_be(&raw)))
+    }
+
+    /// Mark all notifications up to and including the given global sequence
+    /// number as read. The marker never moves backwards.
+    pub fn set_notifications_read(&self, global_seq: u64) -> Result<()> {
+        let db = self.db()?;
+        let key: &[u8] = &[KEY_NOTIFICATIONS_READ];
+
+        db.fetch_and_update(key, |old| {
+            let old = old.map_or(0, u64_from_be);
+            Some(old.max(global_seq).to_be_bytes().to_vec())
+        })?;
+
+        Ok(())
+    }
+
+    /// Get the metafeed tree of the local identity, if it has been created.
+    pub fn get_metafeed(&self) -> Result<Option<MetafeedTree>> {
+        let db = self.db()?;
+        let key: &[u8] = &[KEY_METAFEED];
+
+        match db.get(key)? {
+            Some(raw) => Ok(Some(serde_cbor::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Store the metafeed tree of the local identity.
+    pub fn set_metafeed(&self, tree: &MetafeedTree) -> Result<()> {
+        let db = self.db()?;
+        let key: &[u8] = &[KEY_METAFEED];
+        db.insert(key, serde_cbor::to_vec(tree)?)?;
+
+        Ok(())
+    }
+
+    /// Check that the database belongs to the network with the given key,
+    /// so that feeds of different networks are never mixed. Databases
+    /// written by earlier versions are assigned to the network they are
+    /// first opened with.
+    ///
+    /// Only a digest of the key is stored, since the key is secret on
+    /// private networks. A key stored as is by an earlier version is
+    /// replaced by its digest.
+    pub fn check_network_key(&self, network_key: &[u8]) -> Result<()> {
+        let db = self.db()?;
+        let key: &[u8] = &[KEY_NETWORK_KEY];
+        let digest = Sha256::digest(network_key);
+
+        match db.get(key)? {
+            Some(stored) if stored == digest.as_slice() => Ok(()),
+            Some(stored) if stored != network_key => Err(Error::NetworkKeyMismatch),
+            _ => {
+                db.insert(key, digest.as_slice())?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Whether a restore of the local feed from peers is in progress.
+    pub fn is_restore_pending(&self) -> Result<bool> {
+        let db = self.db()?;
+        let key: &[u8] = &[KEY_RESTORE];
+
+        Ok(db.contains_key(key)?)
+    }
+
+    /// Start restoring the local feed from peers. Publishing is held until
+    /// `finish_restore` is called, including after a restart.
+    pub fn begin_restore(&self) -> Result<()> {
+        let db = self.db()?;
+        let key: &[u8] = &[KEY_RESTORE];
+        db.insert(key, &[])?;
+        db.flush()?;
+        RESTORE_PENDING.store(true, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Mark the local feed as restored, allowing publishing to resume.
+    pub fn finish_restore(&self) -> Result<()> {
+        let db = self.db()?;
+        let key: &[u8] = &[KEY_RESTORE];
+        db.remove(key)?;
+        db.flush()?;
+        RESTORE_PENDING.store(false, Ordering::SeqCst);
+
+        Ok(())
+    }
+
+    /// Return up to `limit` public messages by feeds within `max_hops` of
+    /// `local_id` in the follow graph, most recently received first. Feeds
+    /// which are muted or blocked by `local_id` are left out, as are expired
+    /// messages by other feeds. The cursor is the global sequence number
+    /// before which to continue.
+    pub fn timeline(
+        &self,
+        local_id: &str,
+        max_hops: usize,
+        cursor: Option<u64>,
+        limit: usize,
+    ) -> Result<TimelinePage> {
+        let mut authors = HashSet::new();
+        for pub_key in self.indexes().get_within_hops(local_id, max_hops)? {
+            if pub_key != local_id
+                && (self.indexes().is_blocking(local_id, &pub_key)?
+                    || self.mutes().is_muted(&pub_key)?)
+            {
+                continue;
+            }
+            if let Some(author) = self.author_index()?.get_id(&pub_key)? {
+                authors.insert(author);
+            }
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_millis() as u64)
+            .unwrap_or(0);
+
+        let mut messages = Vec::new();
+        let mut next_cursor = None;
+        for item in self.iter_received_rev(cursor)? {
+            if messages.len() == limit {
+                break;
+            }
+            let (global_seq, msg_ref) = item?;
+            // Check the author before reading the message itself.
+            if !authors.contains(&msg_ref.author) {
+                continue;
+            }
+            let msg_kvt = match self.get_msg_kvt_by_id(msg_ref.author, msg_ref.seq_num)? {
+                Some(msg_kvt) => msg_kvt,
+                None => continue,
+            };
+            // Private messages have encrypted (string) content.
+            if !msg_kvt.value["content"].is_object() {
+                continue;
+            }
+            if indexes::expires_at(&msg_kvt.value["content"]).is_some_and(|expires| expires <= now)
+                && msg_kvt.value["author"] != local_id
+            {
+                continue;
+            }
+            messages.push(msg_kvt);
+            next_cursor = Some(global_seq);
+        }
+        if messages.len() < limit {
+            next_cursor = None;
+        }
+
+        Ok(TimelinePage {
+            messages,
+            next_cursor,
+        })
+    }
+
+    /// Return up to `limit` public messages by feeds which are not muted,
+    /// whose timestamp is at or after `start` and before `end` (in
+    /// milliseconds since the Unix epoch), oldest first. Messages are
+    /// ordered by the timestamp claimed by their author, or by the time they
+    /// were received if the claimed timestamp is later. The cursor is the
+    /// ID of the message after which to continue.
+    pub fn messages_between(
+        &self,
+        start: u64,
+        end: u64,
+        cursor: Option<&str>,
+        limit: usize,
+    ) -> Result<TimeRangePage> {
+        // Locate the cursor in the index from the message itself.
+        let after = match cursor {
+            Some(msg_ref) => match self.get_msg_val(msg_ref)? {
+                Some(msg) => {
+                    let msg_kvt = self.get_msg_kvt(msg.author(), msg.sequence())?;
+                    let received = msg_kvt.map_or(msg.timestamp(), |msg_kvt| msg_kvt.timestamp);
+                    Some((
+                        indexes::indexed_timestamp(msg.timestamp(), received),
+                        msg_ref,
+                    ))
+                }
+                None => return Err(Error::Other(format!("unknown cursor {msg_ref}"))),
+            },
+            None => None,
+        };
+
+        let mut messages = Vec::new();
+        let mut next_cursor = None;
+        for item in self.indexes().iter_between(start, end, after)? {
+            if messages.len() == limit {
+                break;
+            }
+            let (_, msg_ref) = item?;
+            let msg = match self.get_msg_val(&msg_ref)? {
+                Some(msg) => msg,
+                None => continue,
+            };
+            // Private messages have encrypted (string) content.
+            if !msg.content().is_object() || self.mutes().is_muted(msg.author())? {
+                continue;
+            }
+            if let Some(msg_kvt) = self.get_msg_kvt(msg.author(), msg.sequence())? {
+                messages.push(msg_kvt);
+                next_cursor = Some(msg_ref);
+            }
+        }
+        if messages.len() < limit {
+            next_cursor = None;
+        }
+
+        Ok(TimeRangePage {
+            messages,
+            next_cursor,
+        })
+    }
+
+    /// Stream the stored messages which follow the given position in the
+    /// global order, paired with their global sequence numbers, e.g. to fill
+    /// a client cache. Unlike `tail_messages`, the stream ends after the
+    /// latest message. Pass 0 to start from the first message.
+    pub fn global_order_stream(
+        &self,
+        after_global_seq: u64,
+    ) -> Result<impl Stream<Item = Result<(u64, MessageKvt)>>> {
+        let trees = self.trees()?.clone();
+        let start = after_global_seq.saturating_add(1).to_be_bytes();
+        let entries = trees.global_order.range(start..);
+
+        Ok(futures::stream::iter(entries.map(move |item| {
+            let (key, msg_id) = item?;
+            Ok((u64_from_be(&key), trees.global_order_msg(&msg_id)?))
+        })))
+    }
+
+    /// Return a stream of the messages which follow the given position in
+    /// the global order, paired with their global sequence numbers. The
+    /// stream first yields the stored messages and then waits for new
+    /// messages to be appended. Pass 0 to start from the first message.
+    /// Messages which cannot be read are skipped, their errors yielded in
+    /// their place.
+    pub async fn tail_messages(
+        &self,
+        after_global_seq: u64,
+    ) -> Result<impl Stream<Item = Result<(u64, MessageKvt)>>> {
+        // Register to be woken when messages are appended. Messages are read
+        // from the database by cursor rather than passed along, so that no
+        // backlog builds up behind a slow consumer.
+        let (waker, wakeups) = mpsc::channel(0);
+        TAIL_WAKERS.lock().unwrap().push(waker);
+
+        let tail = Tail {
+            trees: self.trees()?.clone(),
+            replaced: self.replaced.clone(),
+            cursor: after_global_seq,
+            stalled: false,
+            wakeups,
+        };
+
+        Ok(futures::stream::unfold(tail, |mut tail| async move {
+            loop {
+                if tail.replaced.load(Ordering::SeqCst) {
+                    return None;
+                }
+                if !tail.stalled {
+                    if let Some(item) = tail.next_stored() {
+                        return Some((item, tail));
+                    }
+                }
+                // Wait for a message to be appended.
+                tail.wakeups.next().await?;
+                tail.stalled = false;
+            }
+        }))
+    }
+
+    /// Check the global order index against the stored feeds, counting gaps,
+    /// duplicate assignments and feed messages missing from the index.
+    pub fn check_global_order(&self) -> Result<GlobalOrderReport> {
+        let trees = self.trees()?;
+        let mut report = GlobalOrderReport::default();
+
+        let mut indexed = HashSet::new();
+        let mut latest_global_seq = 0;
+        for item in trees.global_order.iter() {
+            let (key, value) = item?;
+            let global_seq = u64_from_be(&key);
+            latest_global_seq = global_seq;
+            let msg_id = String::from_utf8_lossy(&value).to_string();
+            let reverse = trees.global_seq.get(&msg_id)?.map(|raw| u64_from_be(&raw));
+            if reverse != Some(global_seq) || !indexed.insert(msg_id) {
+                report.duplicates += 1;
+            }
+        }
+        report.gaps = latest_global_seq.saturating_sub(indexed.len() as u64 + report.duplicates);
+
+        for item in trees.msg_kvt.iter() {
+            let (_, value) = item?;
+            report.messages += 1;
+            if !indexed.contains(&MessageKvt::from_slice(&value)?.key) {
+                report.missing += 1;
+            }
+        }
+
+        Ok(report)
+    }
+
+    /// Drop the global order index and reconstruct it from the stored feeds,
+    /// ordering messages by the time they were received. Returns the result
+    /// of checking the index before it was rebuilt.
+    pub fn rebuild_global_order(&self) -> Result<GlobalOrderReport> {
+        let _maintenance = MaintenanceGuard::new();
+        let db = self.db()?;
+        let trees = self.trees()?;
+        let report = self.check_global_order()?;
+
+        let mut order_batch = sled::Batch::default();
+        let mut seq_batch = sled::Batch::default();
+        for (tree, batch) in [
+            (&trees.global_order, &mut order_batch),
+            (&trees.global_seq, &mut seq_batch),
+        ] {
+            for item in tree.iter() {
+                let (key, _) = item?;
+                batch.remove(key);
+            }
+        }
+
+        // Messages of a single feed are received in sequence; the sequence
+        // number breaks ties between messages received at the same time.
+        let mut msgs = Vec::new();
+        for item in trees.msg_kvt.iter() {
+            let (key, value) = item?;
+            let msg_kvt = MessageKvt::from_slice(&value)?;
+            let msg_seq = u64_from_be(&key[4..12]);
+            msgs.push((msg_kvt.timestamp, msg_seq, msg_kvt.key));
+        }
+        msgs.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+
+        let mut global_seq = 0u64;
+        for (_, _, msg_id) in msgs {
+            global_seq += 1;
+            order_batch.insert(&global_seq.to_be_bytes()[..], msg_id.as_bytes());
+            seq_batch.insert(msg_id.as_bytes(), &global_seq.to_be_bytes()[..]);
+        }
+        (&**db, &trees.global_order, &trees.global_seq).transaction(
+            |(tx_db, tx_global_order, tx_global_seq)| {
+                tx_global_order.apply_batch(&order_batch)?;
+                tx_global_seq.apply_batch(&seq_batch)?;
+                let latest_global_seq_key: &[u8] = &[KEY_LATEST_GLOBAL_SEQ];
+                tx_db.insert(latest_global_seq_key, &global_seq.to_be_bytes()[..])?;
+
+                Ok::<(), ConflictableTransactionError<sled::Error>>(())
+            },
+        )?;
+        db.flush()?;
+
+        let rebuilt = self.check_global_order()?;
+        if !rebuilt.is_consistent() {
+            warn!("global order index is inconsistent after rebuild: {rebuilt:?}");
+            return Err(Error::IndexCorrupt {
+                index: "global order",
+            });
+        }
+
+        Ok(report)
+    }
+}
+
+/// State of a stream returned by `KvStorage::tail_messages`.
+struct Tail {
+    trees: Trees,
+    /// Set once the database has been replaced by a compacted copy.
+    replaced: Arc<AtomicBool>,
+    /// Global sequence number of the most recently yielded message.
+    cursor: u64,
+    /// Set when the global order index could not be read, so that it is read
+    /// again once a message is appended rather than right away.
+    stalled: bool,
+    wakeups: mpsc::Receiver<()>,
+}
+
+impl Tail {
+    /// Read the first stored message after the cursor, if any, and move the
+    /// cursor past it. The cursor also moves past a message which cannot be
+    /// read, so that it is skipped.
+    fn next_stored(&mut self) -> Option<Result<(u64, MessageKvt)>> {
+        let start = (self.cursor + 1).to_be_bytes();
+
+        let (key, msg_id) = match self.trees.global_order.range(start..).next()? {
+            Ok(item) => item,
+            Err(err) => {
+                self.stalled = true;
+                return Some(Err(err.into()));
+            }
+        };
+        self.cursor = u64_from_be(&key);
+
+        Some(
+            self.trees
+                .global_order_msg(&msg_id)
+                .map(|msg_kvt| (self.cursor, msg_kvt)),
+        )
+    }
+}
+
+/// Wake the streams returned by `KvStorage::tail_messages`, forgetting those
+/// which were dropped.
+fn wake_tails() {
+    TAIL_WAKERS
+        .lock()
+        .unwrap()
+        .retain_mut(|waker| match waker.try_send(()) {
+            Ok(()) => true,
+            // A notification is already pending.
+            Err(err) => err.is_full(),
+        });
+}
+
+/// Decode a big-endian u64 from the given bytes.
+fn u64_from_be(raw: &[u8]) -> u64 {
+    let mut u64_buffer = [0u8; 8];
+    u64_buffer.copy_from_slice(raw);
+    u64::from_be_bytes(u64_buffer)
+}
+
+/// Decode a big-endian u32 from the given bytes.
+fn u32_from_be(raw: &[u8]) -> u32 {
+    let mut u32_buffer = [0u8; 4];
+    u32_buffer.copy_from_slice(raw);
+    u32::from_be_bytes(u32_buffer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::TryStreamExt;
+    use kuska_ssb::{api::dto::content::TypedMessage, keystore::OwnedIdentity};
+    use serde_json::json;
+    use sled::Config as KvConfig;
+
+    use crate::{
+        api::schema::{Schema, SCHEMAS},
+        config::SecretConfig,
+        metafeed,
+        storage::{
+            audit::{AuditEntry, AuditEvent, Direction},
+            indexes::{
+                ContactChangeKind, ContactGraph, Gathering, GitCommit, GitRepo, ReportCount,
+            },
+            mutes::Mute,
+            outbox::OutboxStatus,
+        },
+    };
+
+    fn open_temporary_kv() -> KvStorage {
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        let path = tempdir::TempDir::new("solardb").unwrap();
+        let config = KvConfig::new().path(path.path());
+        kv.open(config, sender).unwrap();
+        kv
+    }
+
+    #[test]
+    fn test_uninitialised_db() {
+        // Queries fail rather than panic before the database is opened.
+        let kv = KvStorage::default();
+        assert!(matches!(
+            kv.get_latest_seq("@abc=.ed25519"),
+            Err(Error::DbUninitialised)
+        ));
+        assert!(matches!(kv.author_count(), Err(Error::DbUninitialised)));
+        assert!(!kv.is_writable());
+    }
+
+    #[async_std::test]
+    async fn test_feed_length() -> Result<()> {
+        use kuska_ssb::feed::Message;
+        // Create a unique keypair to sign messages.
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+
+        // Open a temporary key-value store.
+        let kv = open_temporary_kv();
+
+        let mut last_msg: Option<Message> = None;
+        for i in 1..=4 {
+            // Create a post-type message.
+            let msg_content = TypedMessage::Post {
+                text: format!("Important announcement #{i}"),
+                mentions: None,
+            };
+
+            let msg = MessageValue::sign(last_msg.as_ref(), &keypair, json!(msg_content)).unwrap();
+
+            // Append the signed message to the feed. Returns the sequence number
+            // of the appended message.
+            let seq = kv.append_feed(msg).await.unwrap();
+            assert_eq!(seq, i);
+
+            last_msg = kv.get_latest_msg_val(&keypair.id).unwrap();
+
+            let feed = kv.get_feed(&keypair.id).unwrap();
+            assert_eq!(feed.len(), i as usize);
+        }
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_feed_range() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
+        let kv = open_temporary_kv();
+
+        // Interleave two feeds, so that ranges must stop at the end of one.
+        let mut last_msgs = (None, None);
+        for _ in 0..5 {
+            for (keypair, last_msg) in [(&alice, &mut last_msgs.0), (&bob, &mut last_msgs.1)] {
+                let msg = MessageValue::sign(last_msg.as_ref(), keypair, json!({ "type": "post" }))
+                    .unwrap();
+                kv.append_feed(msg.clone()).await?;
+                *last_msg = Some(msg);
+            }
+        }
+
+        let sequences = |page: Vec<MessageKvt>| -> Vec<u64> {
+            page.iter()
+                .map(|msg_kvt| msg_kvt.value["sequence"].as_u64().unwrap())
+                .collect()
+        };
+        assert_eq!(sequences(kv.get_feed_range(&alice.id, 1, 2)?), vec![1, 2]);
+        assert_eq!(sequences(kv.get_feed_range(&alice.id, 3, 2)?), vec![3, 4]);
+        assert_eq!(sequences(kv.get_feed_range(&alice.id, 5, 2)?), vec![5]);
+        assert!(kv.get_feed_range(&alice.id, 6, 2)?.is_empty());
+        assert_eq!(
+            sequences(kv.get_feed_range(&bob.id, 0, usize::MAX)?),
+            vec![1, 2, 3, 4, 5]
+        );
+        assert!(kv.get_feed_range("@unknown.ed25519", 1, 2)?.is_empty());
+
+        // Streams yield the same messages lazily.
+        let feed: Vec<MessageKvt> = kv.feed_stream(&bob.id)?.try_collect().await?;
+        assert_eq!(sequences(feed), vec![1, 2, 3, 4, 5]);
+        assert_eq!(kv.feed_stream("@unknown.ed25519")?.count().await, 0);
+        let global: Vec<(u64, MessageKvt)> = kv.global_order_stream(8)?.try_collect().await?;
+        assert_eq!(
+            global
+                .iter()
+                .map(|(global_seq, msg_kvt)| (*global_seq, msg_kvt.value["author"].clone()))
+                .collect::<Vec<_>>(),
+            vec![(9, json!(alice.id)), (10, json!(bob.id))]
+        );
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_single_message_content_matches() -> Result<()> {
+        // Create a unique keypair to sign messages.
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+
+        // Open a temporary key-value store.
+        let kv = open_temporary_kv();
+
+        // Create a post-type message.
+        let msg_content = TypedMessage::Post {
+            text: "A strange rambling expiration of my own conscious".to_string(),
+            mentions: None,
+        };
+
+        let last_msg = kv.get_latest_msg_val(&keypair.id).unwrap();
+        let msg = MessageValue::sign(last_msg.as_ref(), &keypair, json!(msg_content)).unwrap();
+
+        // Append the signed message to the feed. Returns the sequence number
+        // of the appended message.
+        let seq = kv.append_feed(msg).await.unwrap();
+        assert_eq!(seq, 1);
+
+        let latest_seq = kv.get_latest_seq(&keypair.id).unwrap();
+        assert_eq!(latest_seq, Some(1));
+
+        // Lookup the value of the previous message. This will be `None`
+        let last_msg = kv.get_latest_msg_val(&keypair.id).unwrap();
+        assert!(last_msg.is_some());
+        let expected = serde_json::value::to_value(msg_content).unwrap();
+        let last_msg = last_msg.unwrap().content().clone();
+
+        assert_eq!(last_msg, expected);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_new_feed_is_empty() -> Result<()> {
+        // Create a unique keypair to sign messages.
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+
+        // Open a temporary key-value store.
+        let kv = open_temporary_kv();
+
+        // Lookup the value of the previous message. This will be `None`
+        let last_msg = kv.get_latest_msg_val(&keypair.id).unwrap();
+        assert!(last_msg.is_none());
+
+        let latest_seq = kv.get_latest_seq(&keypair.id).unwrap();
+        assert!(latest_seq.is_none());
+
+        Ok(())
+    }
+
+    // In reality this test covers more than just the append method.
+    // It tests multiple methods exposed by the kv database.
+    // The main reason for combining the tests is the cost of setting up
+    // testable conditions (ie. creating the keypair and database and
+    // it with messages). Perhaps this could be broken up in the future.
+    #[async_std::test]
+    async fn test_append_feed() -> Result<()> {
+        // Create a unique keypair to sign messages.
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+
+        // Open a temporary key-value store.
+        let kv = open_temporary_kv();
+
+        // Create a post-type message.
+        let msg_content = TypedMessage::Post {
+            text: "A solar flare is an intense localized eruption of electromagnetic radiation."
+                .to_string(),
+            mentions: None,
+        };
+
+        // Lookup the value of the previous message. This will be `None`.
+        let last_msg = kv.get_latest_msg_val(&keypair.id).unwrap();
+
+        // Sign the message content using the temporary keypair and value of
+        // the previous message.
+        let msg = MessageValue::sign(last_msg.as_ref(), &keypair, json!(msg_content)).unwrap();
+
+        // Append the signed message to the feed. Returns the sequence number
+        // of the appended message.
+        let seq = kv.append_feed(msg).await.unwrap();
+
+        // Ensure that the message is the first in the feed.
+        assert_eq!(seq, 1);
+
+        // Get the latest sequence number.
+        let latest_seq = kv.get_latest_seq(&keypair.id).unwrap();
+
+        // Ensure the stored sequence number matches that of the appended
+        // message.
+        assert!(latest_seq.is_some());
+        assert_eq!(latest_seq.unwrap(), seq);
+
+        // Get a list of all replicated peers and their latest sequence
+        // numbers. This list is expected to contain an entry for the
+        // local keypair.
+        let peers = kv.get_peers().await.unwrap();
+
+        // Ensure there is only one entry in the peers list.
+        assert_eq!(peers.len(), 1);
+        // Ensure the public key of the peer matches expectations and that
+        // the sequence number is correct.
+        assert_eq!(peers[0].pub_key, keypair.id);
+        assert_eq!(peers[0].seq_num, 1);
+
+        // Create, sign and append a second post-type message.
+        let msg_content_2 = TypedMessage::Post {
+            text: "When the sun shone upon her.".to_string(),
+            mentions: None,
+        };
+        let last_msg_2 = kv.get_latest_msg_val(&keypair.id).unwrap();
+        let msg_2 =
+            MessageValue::sign(last_msg_2.as_ref(), &keypair, json!(msg_content_2)).unwrap();
+        let msg_2_clone = msg_2.clone();
+        let seq_2 = kv.append_feed(msg_2).await.unwrap();
+
+        // Ensure that the message is the second in the feed.
+        assert_eq!(seq_2, 2);
+
+        // Get the second message in the key-value store in the form of a KVT.
+        let msg_kvt = kv.get_msg_kvt(&keypair.id, 2).unwrap();
+        assert!(msg_kvt.is_some());
+
+        // Retrieve the key from the KVT.
+        let msg_kvt_key = msg_kvt.unwrap().key;
+
+        // Get the second message in the key-value store in the form of a value.
+        let msg_val = kv.get_msg_val(&msg_kvt_key).unwrap();
+
+        assert!(msg_val.is_some());
+        // Ensure the retrieved message value matches the previously created
+        // and signed message.
+        assert_eq!(msg_val.unwrap(), msg_2_clone);
+
+        // Get all messages comprising the feed.
+        let feed = kv.get_feed(&keypair.id).unwrap();
+
+        // Ensure that two messages are returned.
+        assert_eq!(feed.len(), 2);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_msg_kvt_raw() -> Result<()> {
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+        let kv = open_temporary_kv();
+
+        let msg_content = TypedMessage::Post {
+            text: "Served as stored.".to_string(),
+            mentions: None,
+        };
+        let msg = MessageValue::sign(None, &keypair, json!(msg_content)).unwrap();
+        kv.append_feed(msg).await.unwrap();
+
+        // The raw KVT is the serialized form of the parsed KVT.
+        let msg_kvt = kv.get_msg_kvt(&keypair.id, 1)?.unwrap();
+        let msg_kvt_raw = kv.get_msg_kvt_raw(&keypair.id, 1)?.unwrap();
+        assert_eq!(MessageKvt::from_slice(&msg_kvt_raw)?.key, msg_kvt.key);
+        assert!(kv.get_msg_kvt_raw(&keypair.id, 2)?.is_none());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_backup() -> Result<()> {
+        // Create a unique keypair to sign messages.
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+
+        // Open a temporary key-value store and append a single message.
+        let mut kv = open_temporary_kv();
+        let msg_content = TypedMessage::Post {
+            text: "Backed up and ready to go.".to_string(),
+            mentions: None,
+        };
+        let msg = MessageValue::sign(None, &keypair, json!(msg_content)).unwrap();
+        kv.append_feed(msg).await?;
+
+        // Write a snapshot to a temporary directory.
+        let backup_path = tempdir::TempDir::new("solarbackup").unwrap();
+        kv.backup(backup_path.path()).await?;
+
+        // Open the snapshot and ensure the feed is intact.
+        let mut restored = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        restored.open(KvConfig::new().path(backup_path.path()), sender)?;
+        assert_eq!(restored.get_latest_seq(&keypair.id)?, Some(1));
+        assert_eq!(restored.get_feed(&keypair.id)?.len(), 1);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_contact_index() -> Result<()> {
+        // Create unique keypairs for the author and the followed contact.
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+        let contact = SecretConfig::create().owned_identity().unwrap();
+
+        let kv = open_temporary_kv();
+        assert_eq!(kv.indexes().get_contact(&keypair.id, &contact.id)?, None);
+
+        // Follow the contact.
+        let msg_content = json!({ "type": "contact", "contact": contact.id, "following": true });
+        let msg = MessageValue::sign(None, &keypair, msg_content).unwrap();
+        kv.append_feed(msg.clone()).await?;
+
+        assert_eq!(
+            kv.indexes().get_contact(&keypair.id, &contact.id)?,
+            Some(true)
+        );
+        assert_eq!(kv.indexes().get_follows(&keypair.id)?, vec![contact.id.clone()]);
+        assert_eq!(kv.indexes().get_followers(&contact.id)?, vec![keypair.id.clone()]);
+
+        // Unfollow and block the contact.
+        let msg_content = json!({
+            "type": "contact",
+            "contact": contact.id,
+            "following": false,
+            "blocking": true
+        });
+        let msg = MessageValue::sign(Some(&msg), &keypair, msg_content).unwrap();
+        kv.append_feed(msg).await?;
+
+        assert_eq!(
+            kv.indexes().get_contact(&keypair.id, &contact.id)?,
+            Some(false)
+        );
+        assert!(kv.indexes().get_follows(&keypair.id)?.is_empty());
+        assert!(kv.indexes().get_followers(&contact.id)?.is_empty());
+        assert!(kv.indexes().is_blocking(&keypair.id, &contact.id)?);
+        assert!(!kv.indexes().is_blocking(&contact.id, &keypair.id)?);
+        assert_eq!(
+            kv.indexes().get_contact_graph()?,
+            ContactGraph {
+                follows: vec![],
+                blocks: vec![(keypair.id.clone(), contact.id.clone())],
+            }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_contact_changes() -> Result<()> {
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+        let contact = SecretConfig::create().owned_identity().unwrap();
+
+        let kv = open_temporary_kv();
+        let mut last_msg = None;
+        let mut index = |content: serde_json::Value| -> Result<Vec<ContactChangeKind>> {
+            let msg = MessageValue::sign(last_msg.as_ref(), &keypair, content).unwrap();
+            let changes = kv.indexes().index_msg(&msg)?;
+            last_msg = Some(msg);
+            Ok(changes.into_iter().map(|change| change.kind).collect())
+        };
+
+        // Changes are detected whether or not the previous state has been
+        // committed, and repeated states are not changes.
+        let follow = json!({ "type": "contact", "contact": contact.id, "following": true });
+        assert_eq!(index(follow.clone())?, vec![ContactChangeKind::Follow]);
+        assert!(index(follow.clone())?.is_empty());
+        kv.indexes().commit()?;
+        assert!(index(follow)?.is_empty());
+
+        let unfollow_and_block = json!({
+            "type": "contact",
+            "contact": contact.id,
+            "following": false,
+            "blocking": true
+        });
+        assert_eq!(
+            index(unfollow_and_block)?,
+            vec![ContactChangeKind::Unfollow, ContactChangeKind::Block]
+        );
+        let unblock = json!({ "type": "contact", "contact": contact.id, "blocking": false });
+        assert_eq!(index(unblock.clone())?, vec![ContactChangeKind::Unblock]);
+        assert!(index(unblock)?.is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_index_write_batching() -> Result<()> {
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+        let contact = SecretConfig::create().owned_identity().unwrap();
+
+        let path = tempdir::TempDir::new("solardb").unwrap();
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        kv.open(KvConfig::new().path(path.path()), sender)?;
+
+        let msg_content = json!({ "type": "contact", "contact": contact.id, "following": true });
+        let msg = MessageValue::sign(None, &keypair, msg_content).unwrap();
+        kv.append_feed(msg).await?;
+
+        // The index update is pending until it is committed.
+        assert!(kv.indexes().is_incomplete()?);
+        kv.commit_indexes().await?;
+        assert!(!kv.indexes().is_incomplete()?);
+
+        // Reads see the pending updates, which are left pending.
+        let msg_content = json!({ "type": "contact", "contact": contact.id, "blocking": true });
+        let msg = MessageValue::sign(
+            kv.get_latest_msg_val(&keypair.id)?.as_ref(),
+            &keypair,
+            msg_content,
+        )
+        .unwrap();
+        kv.append_feed(msg).await?;
+        assert!(kv.indexes().is_blocking(&keypair.id, &contact.id)?);
+        assert!(kv.indexes().is_incomplete()?);
+        assert_eq!(kv.indexes().get_daily_counts(0)?.values().sum::<u64>(), 2);
+
+        // Pending updates lost on shutdown are recovered by replaying the
+        // messages which staged them.
+        let msg_content = json!({ "type": "contact", "contact": contact.id, "blocking": false });
+        let msg = MessageValue::sign(
+            kv.get_latest_msg_val(&keypair.id)?.as_ref(),
+            &keypair,
+            msg_content,
+        )
+        .unwrap();
+        kv.append_feed(msg).await?;
+        drop(kv);
+
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        kv.open(KvConfig::new().path(path.path()), sender)?;
+        assert!(kv.needs_build()?);
+        assert_eq!(kv.indexes().replay_from()?, Some(2));
+        kv.build_indexes()?;
+        assert!(!kv.needs_build()?);
+        assert!(!kv.indexes().is_blocking(&keypair.id, &contact.id)?);
+        assert_eq!(
+            kv.indexes().get_contact(&keypair.id, &contact.id)?,
+            Some(true)
+        );
+        // The messages whose updates were committed are not counted twice.
+        assert_eq!(kv.indexes().get_daily_counts(0)?.values().sum::<u64>(), 3);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_address_latency() -> Result<()> {
+        let kv = open_temporary_kv();
+        let address = "192.168.1.20:8008";
+        assert_eq!(kv.get_address_latency(address)?, None);
+
+        kv.record_address_latency(address, 100)?;
+        assert_eq!(kv.get_address_latency(address)?, Some(100));
+        // A single slow connection only moves the average part of the way.
+        kv.record_address_latency(address, 500)?;
+        assert_eq!(kv.get_address_latency(address)?, Some(200));
+        assert_eq!(kv.get_address_latency("example.org:8008")?, None);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_audit_log() -> Result<()> {
+        let kv = open_temporary_kv();
+        let audit = kv.audit();
+        let address = Some("10.0.0.1:8008".to_string());
+        let peer = Some("@peer.ed25519".to_string());
+
+        audit.record(&AuditEntry::new(
+            Direction::Inbound,
+            address.clone(),
+            None,
+            AuditEvent::Attempt,
+        ))?;
+        let rejected = AuditEntry::new(
+            Direction::Inbound,
+            address,
+            peer.clone(),
+            AuditEvent::Rejected {
+                reason: "not in replication list".to_string(),
+            },
+        );
+        audit.record(&rejected)?;
+
+        let entries = audit.get_entries(None, 10)?;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0], rejected);
+        assert_eq!(entries[1].event, AuditEvent::Attempt);
+        assert_eq!(audit.get_entries(None, 1)?, vec![rejected.clone()]);
+        assert_eq!(audit.get_entries(peer.as_deref(), 10)?, vec![rejected]);
+        assert!(audit.get_entries(Some("@other.ed25519"), 10)?.is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_author_index() -> Result<()> {
+        let kv = open_temporary_kv();
+        assert_eq!(kv.author_count()?, 0);
+
+        let mut keypairs = Vec::new();
+        for _ in 0..3 {
+            let keypair = SecretConfig::create().owned_identity().unwrap();
+            let mut last_msg = None;
+            for _ in 0..2 {
+                let msg =
+                    MessageValue::sign(last_msg.as_ref(), &keypair, json!({ "type": "post" }))
+                        .unwrap();
+                kv.append_feed(msg.clone()).await?;
+                last_msg = Some(msg);
+            }
+            keypairs.push(keypair);
+        }
+        // Pinning a feed interns its author without storing a feed.
+        kv.pin_feed("@unknown.ed25519")?;
+        assert_eq!(kv.author_count()?, 3);
+
+        let page = kv.authors(None, 2)?;
+        assert_eq!(page.count, 3);
+        assert_eq!(
+            page.authors,
+            vec![keypairs[0].id.clone(), keypairs[1].id.clone()]
+        );
+        let page = kv.authors(page.next_cursor, 2)?;
+        assert_eq!(page.authors, vec![keypairs[2].id.clone()]);
+        assert_eq!(page.next_cursor, None);
+
+        // The index is rebuilt from the peer entries.
+        kv.authors.as_ref().unwrap().rebuild_feed_index(&[])?;
+        assert_eq!(kv.author_count()?, 0);
+        kv.rebuild_author_index()?;
+        assert_eq!(kv.authors(None, 10)?.authors.len(), 3);
+        assert_eq!(kv.get_peers().await?.len(), 4);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_timeline() -> Result<()> {
+        let local = SecretConfig::create().owned_identity().unwrap();
+        let friend = SecretConfig::create().owned_identity().unwrap();
+        let stranger = SecretConfig::create().owned_identity().unwrap();
+
+        let kv = open_temporary_kv();
+
+        let msg_content = json!({ "type": "contact", "contact": friend.id, "following": true });
+        let follow = MessageValue::sign(None, &local, msg_content).unwrap();
+        kv.append_feed(follow.clone()).await?;
+
+        // Interleave posts by the followed feed and a stranger, with a
+        // private message in between.
+        let mut last_msgs = (None, None);
+        for i in 0..3 {
+            for (keypair, last_msg) in [(&friend, &mut last_msgs.0), (&stranger, &mut last_msgs.1)]
+            {
+                let msg_content = json!({ "type": "post", "text": i.to_string() });
+                let msg = MessageValue::sign(last_msg.as_ref(), keypair, msg_content).unwrap();
+                kv.append_feed(msg.clone()).await?;
+                *last_msg = Some(msg);
+            }
+        }
+        let msg = MessageValue::sign(last_msgs.0.as_ref(), &friend, json!("private.box")).unwrap();
+        kv.append_feed(msg).await?;
+
+        let page = kv.timeline(&local.id, 1, None, 2)?;
+        let texts: Vec<&str> = page
+            .messages
+            .iter()
+            .map(|msg_kvt| msg_kvt.value["content"]["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(texts, vec!["2", "1"]);
+        assert!(page
+            .messages
+            .iter()
+            .all(|msg_kvt| msg_kvt.value["author"] == friend.id.as_str()));
+
+        // The first post and the contact message, then nothing more.
+        let page = kv.timeline(&local.id, 1, page.next_cursor, 2)?;
+        assert_eq!(page.messages.len(), 2);
+        assert_eq!(page.messages[1].key, follow.id().to_string());
+        let page = kv.timeline(&local.id, 1, page.next_cursor, 2)?;
+        assert!(page.messages.is_empty());
+        assert_eq!(page.next_cursor, None);
+
+        // Muted feeds are left out.
+        kv.mutes().mute(&friend.id, false)?;
+        assert_eq!(kv.timeline(&local.id, 1, None, 10)?.messages.len(), 1);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_messages_between() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
+
+        let kv = open_temporary_kv();
+
+        let mut last_msg = None;
+        let mut timestamps = Vec::new();
+        for i in 0..4 {
+            task::sleep(Duration::from_millis(2)).await;
+            let msg_content = json!({ "type": "post", "text": i.to_string() });
+            let msg = MessageValue::sign(last_msg.as_ref(), &alice, msg_content).unwrap();
+            kv.append_feed(msg.clone()).await?;
+            timestamps.push(msg.timestamp() as u64);
+            last_msg = Some(msg);
+        }
+        // Messages claiming the same timestamp would be ordered by key.
+        task::sleep(Duration::from_millis(2)).await;
+        let msg = MessageValue::sign(None, &bob, json!({ "type": "post", "text": "bob" })).unwrap();
+        kv.append_feed(msg).await?;
+
+        let texts = |page: &TimeRangePage| -> Vec<String> {
+            page.messages
+                .iter()
+                .map(|msg_kvt| {
+                    msg_kvt.value["content"]["text"]
+                        .as_str()
+                        .unwrap()
+                        .to_string()
+                })
+                .collect()
+        };
+
+        // The range includes its start and excludes its end.
+        let page = kv.messages_between(timestamps[1], timestamps[3], None, 10)?;
+        assert_eq!(texts(&page), vec!["1", "2"]);
+        assert_eq!(page.next_cursor, None);
+
+        let page = kv.messages_between(timestamps[0], u64::MAX, None, 3)?;
+        assert_eq!(texts(&page), vec!["0", "1", "2"]);
+        let cursor = page.next_cursor.unwrap();
+        let page = kv.messages_between(timestamps[0], u64::MAX, Some(&cursor), 3)?;
+        assert_eq!(texts(&page), vec!["3", "bob"]);
+        assert!(kv.messages_between(10, 0, None, 3)?.messages.is_empty());
+
+        // Muted feeds are left out.
+        kv.mutes().mute(&bob.id, false)?;
+        assert_eq!(
+            kv.messages_between(0, u64::MAX, None, 10)?.messages.len(),
+            4
+        );
+
+        // Claimed timestamps later than the time of receipt are replaced.
+        assert_eq!(indexes::indexed_timestamp(1000.0, 2000.0), 1000);
+        assert_eq!(indexes::indexed_timestamp(3000.0, 2000.0), 2000);
+        assert_eq!(indexes::indexed_timestamp(-1.0, 2000.0), 2000);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_expiry_index() -> Result<()> {
+        let local = SecretConfig::create().owned_identity().unwrap();
+        let friend = SecretConfig::create().owned_identity().unwrap();
+
+        let kv = open_temporary_kv();
+
+        let msg_content = json!({ "type": "contact", "contact": friend.id, "following": true });
+        let follow = MessageValue::sign(None, &local, msg_content).unwrap();
+        kv.append_feed(follow.clone()).await?;
+        let msg_content = json!({ "type": "post", "text": "mine", "expires": 1000 });
+        let own = MessageValue::sign(Some(&follow), &local, msg_content).unwrap();
+        kv.append_feed(own).await?;
+
+        // An expired post, a post expiring in the far future and a post
+        // without a hint.
+        let mut last_msg = None;
+        for (text, expires) in [
+            ("expired", json!(2000)),
+            ("later", json!(u64::MAX / 2)),
+            ("kept", json!(null)),
+        ] {
+            let msg_content = json!({ "type": "post", "text": text, "expires": expires });
+            let msg = MessageValue::sign(last_msg.as_ref(), &friend, msg_content).unwrap();
+            kv.append_feed(msg.clone()).await?;
+            last_msg = Some(msg);
+        }
+        let expired_ref = kv.get_msg_kvt(&friend.id, 1)?.unwrap().key;
+
+        // The local feed never expires.
+        let expired = kv.indexes().get_expired(5000, &local.id, 10)?;
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].msg_ref, expired_ref);
+        assert_eq!(expired[0].author, friend.id);
+        assert_eq!(expired[0].expires, 2000);
+        assert!(kv.indexes().get_expired(1999, &local.id, 10)?.is_empty());
+
+        let page = kv.timeline(&local.id, 1, None, 10)?;
+        let texts: Vec<&str> = page
+            .messages
+            .iter()
+            .filter_map(|msg_kvt| msg_kvt.value["content"]["text"].as_str())
+            .collect();
+        assert_eq!(texts, vec!["kept", "later", "mine"]);
+
+        // The index is rebuilt along with the others.
+        kv.reindex()?;
+        assert_eq!(kv.indexes().get_expired(5000, &local.id, 10)?, expired);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_index_feeds() -> Result<()> {
+        let local = SecretConfig::create().owned_identity().unwrap();
+        let friend = SecretConfig::create().owned_identity().unwrap();
+
+        let kv = open_temporary_kv();
+
+        let mut last_msg = None;
+        for msg_content in [
+            json!({ "type": "about", "about": local.id, "name": "solar" }),
+            json!({ "type": "post", "text": "hello" }),
+            json!({ "type": "contact", "contact": friend.id, "following": true }),
+        ] {
+            let msg = MessageValue::sign(last_msg.as_ref(), &local, msg_content).unwrap();
+            kv.append_feed(msg.clone()).await?;
+            last_msg = Some(msg);
+        }
+
+        // Nothing is indexed until the metafeed is created.
+        let seed = metafeed::generate_seed();
+        metafeed::update_indexes(&kv, &seed).await?;
+        let tree = MetafeedTree::new(&seed, &local.id)?;
+        kv.set_metafeed(&tree)?;
+        assert_eq!(kv.get_metafeed()?, Some(tree.clone()));
+
+        let about_index = &tree.get("index:about").unwrap().feed_id;
+        let contact_index = &tree.get("index:contact").unwrap().feed_id;
+        assert_eq!(kv.get_latest_seq(about_index)?, None);
+
+        metafeed::update_indexes(&kv, &seed).await?;
+        let indexed = kv.get_msg_kvt(contact_index, 1)?.unwrap().into_message()?;
+        assert_eq!(indexed.content()["type"], "metafeed/index");
+        assert_eq!(indexed.content()["indexed"]["sequence"], 3);
+        assert_eq!(
+            indexed.content()["indexed"]["key"],
+            kv.get_msg_kvt(&local.id, 3)?.unwrap().key.as_str()
+        );
+        assert_eq!(kv.get_latest_seq(about_index)?, Some(1));
+
+        // Index feeds resume after the last message they reference.
+        let msg_content = json!({ "type": "about", "about": local.id, "name": "sol" });
+        let msg = MessageValue::sign(last_msg.as_ref(), &local, msg_content).unwrap();
+        kv.append_feed(msg).await?;
+        metafeed::update_indexes(&kv, &seed).await?;
+        metafeed::update_indexes(&kv, &seed).await?;
+        assert_eq!(kv.get_latest_seq(about_index)?, Some(2));
+        assert_eq!(kv.get_latest_seq(contact_index)?, Some(1));
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_notifications() -> Result<()> {
+        let local = SecretConfig::create().owned_identity().unwrap();
+        let other = SecretConfig::create().owned_identity().unwrap();
+
+        let kv = open_temporary_kv();
+
+        let post =
+            MessageValue::sign(None, &local, json!({ "type": "post", "text": "hi" })).unwrap();
+        kv.append_feed(post.clone()).await?;
+
+        let contents = vec![
+            json!({ "type": "contact", "contact": local.id, "following": true }),
+            json!({ "type": "vote", "vote": { "link": post.id().to_string(), "value": 1 } }),
+            json!({ "type": "post", "text": "unrelated" }),
+            json!({ "type": "post", "text": "hey", "mentions": [{ "link": local.id }] }),
+            json!({ "type": "post", "text": "re", "root": post.id().to_string() }),
+        ];
+        let mut last_msg = None;
+        for content in contents {
+            let msg = MessageValue::sign(last_msg.as_ref(), &other, content).unwrap();
+            kv.append_feed(msg.clone()).await?;
+            last_msg = Some(msg);
+        }
+
+        let page = kv.notifications(&local.id, None, 10)?;
+        let kinds: Vec<NotificationKind> = page
+            .notifications
+            .iter()
+            .map(|notification| notification.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                NotificationKind::Reply,
+                NotificationKind::Mention,
+                NotificationKind::Vote,
+                NotificationKind::Follow,
+            ]
+        );
+        assert!(page.notifications.iter().all(|n| !n.read));
+        assert_eq!(page.next_cursor, None);
+
+        // Mark the follow and the vote as read.
+        kv.set_notifications_read(page.notifications[2].global_seq)?;
+        let page = kv.notifications(&local.id, None, 2)?;
+        assert!(page.notifications.iter().all(|n| !n.read));
+        let page = kv.notifications(&local.id, page.next_cursor, 2)?;
+        assert!(page.notifications.iter().all(|n| n.read));
+
+        // Single messages are checked as they are received.
+        let msg_kvt = kv.get_msg_kvt(&local.id, 1)?.unwrap();
+        assert!(kv.notification(&local.id, 1, msg_kvt)?.is_none());
+        let msg_kvt = kv.get_msg_kvt(&other.id, 5)?.unwrap();
+        let notification = kv.notification(&local.id, 6, msg_kvt)?.unwrap();
+        assert_eq!(notification.kind, NotificationKind::Reply);
+        assert!(!notification.read);
+
+        // The read marker never moves backwards.
+        kv.set_notifications_read(1)?;
+        assert_eq!(
+            kv.get_notifications_read()?,
+            page.notifications[0].global_seq
+        );
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_compact() -> Result<()> {
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+
+        let path = tempdir::TempDir::new("solardb").unwrap();
+        let feeds_path = path.path().join("feeds");
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        kv.open(KvConfig::new().path(&feeds_path), sender)?;
+
+        let mut last_msg = None;
+        for i in 0..10 {
+            let content = json!({ "type": "post", "text": "x".repeat(500 * i) });
+            let msg = MessageValue::sign(last_msg.as_ref(), &keypair, content).unwrap();
+            kv.append_feed(msg.clone()).await?;
+            last_msg = Some(msg);
+        }
+        let mut tail = Box::pin(kv.tail_messages(kv.get_latest_global_seq()?).await?);
+
+        let report = kv.compact(&feeds_path).await?;
+        assert!(report.size_after > 0);
+        assert_eq!(
+            report.recovered(),
+            report.size_before.saturating_sub(report.size_after)
+        );
+
+        // The compacted database holds the same data and accepts writes.
+        assert_eq!(kv.get_latest_seq(&keypair.id)?, Some(10));
+        assert!(kv.check_global_order()?.is_consistent());
+        let msg =
+            MessageValue::sign(last_msg.as_ref(), &keypair, json!({ "type": "post" })).unwrap();
+        assert_eq!(kv.append_feed(msg).await?, 11);
+        assert!(!path.path().join("feeds.compact").exists());
+        assert!(!path.path().join("feeds.old").exists());
+
+        // Streams reading the replaced database end.
+        assert!(tail.next().await.is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_network_key() -> Result<()> {
+        let kv = open_temporary_kv();
+
+        // The database is assigned to the first network key it is checked
+        // against.
+        kv.check_network_key(&[1; 32])?;
+        kv.check_network_key(&[1; 32])?;
+        assert!(matches!(
+            kv.check_network_key(&[2; 32]),
+            Err(Error::NetworkKeyMismatch)
+        ));
+
+        // Only a digest of the key is stored, including in place of a key
+        // stored as is by an earlier version.
+        let key: &[u8] = &[KEY_NETWORK_KEY];
+        let db = kv.db()?;
+        assert_eq!(db.get(key)?.unwrap(), Sha256::digest([1; 32]).as_slice());
+        db.insert(key, &[2; 32])?;
+        kv.check_network_key(&[2; 32])?;
+        assert_eq!(db.get(key)?.unwrap(), Sha256::digest([2; 32]).as_slice());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_restore_marker() -> Result<()> {
+        let path = tempdir::TempDir::new("solardb").unwrap();
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        kv.open(KvConfig::new().path(path.path()), sender.clone())?;
+        assert!(!kv.is_restore_pending()?);
+
+        // An interrupted restore is still pending once the database is
+        // reopened.
+        kv.begin_restore()?;
+        kv = KvStorage::default();
+        kv.open(KvConfig::new().path(path.path()), sender)?;
+        assert!(kv.is_restore_pending()?);
+
+        kv.finish_restore()?;
+        assert!(!kv.is_restore_pending()?);
+
+        Ok(())
+    }
+
+    /// Write a feed of the given message using the version 0 layout, with
+    /// public keys in keys. If `split`, the records are written to their
+    /// trees instead of the default tree, as left by a migration interrupted
+    /// after `migrate_split_trees`.
+    fn write_version_0_feed(
+        path: &Path,
+        keypair: &OwnedIdentity,
+        msg: &MessageValue,
+        split: bool,
+    ) -> Result<()> {
+        let db = KvConfig::new().path(path).open()?;
+        let trees = Trees::open(&db)?;
+        let by_prefix = trees.by_prefix();
+        let insert = |prefix: u8, key: &[u8], value: &[u8]| -> Result<()> {
+            if split {
+                let (_, tree) = by_prefix
+                    .iter()
+                    .find(|(tree_prefix, _)| *tree_prefix == prefix)
+                    .unwrap();
+                tree.insert(key, value)?;
+            } else {
+                let mut legacy_key = vec![prefix];
+                legacy_key.extend_from_slice(key);
+                db.insert(legacy_key, value)?;
+            }
+            Ok(())
+        };
+
+        let mut key_kvt = 1u64.to_be_bytes().to_vec();
+        key_kvt.extend_from_slice(keypair.id.as_bytes());
+        let kvt = MessageKvt::new(msg.clone()).to_string();
+        insert(PREFIX_MSG_KVT, &key_kvt, kvt.as_bytes())?;
+        let msg_ref = PubKeyAndSeqNum {
+            pub_key: keypair.id.clone(),
+            seq_num: 1,
+            pinned: false,
+        };
+        let msg_id = msg.id().to_string();
+        insert(
+            PREFIX_MSG_VAL,
+            msg_id.as_bytes(),
+            &serde_cbor::to_vec(&msg_ref)?,
+        )?;
+        for prefix in [PREFIX_LATEST_SEQ, PREFIX_PEER] {
+            insert(prefix, keypair.id.as_bytes(), &1u64.to_be_bytes())?;
+        }
+        db.flush()?;
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_migrate_intern_authors() -> Result<()> {
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+        let contact = SecretConfig::create().owned_identity().unwrap();
+
+        let msg_content = json!({ "type": "contact", "contact": contact.id, "following": true });
+        let msg = MessageValue::sign(None, &keypair, msg_content).unwrap();
+        let msg_id = msg.id().to_string();
+
+        let path = tempdir::TempDir::new("solardb").unwrap();
+        write_version_0_feed(path.path(), &keypair, &msg, false)?;
+
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        kv.open(KvConfig::new().path(path.path()), sender)?;
+        kv.build_indexes()?;
+
+        assert_eq!(kv.get_latest_seq(&keypair.id)?, Some(1));
+        assert_eq!(kv.get_msg_val(&msg_id)?, Some(msg.clone()));
+        assert_eq!(kv.get_feed(&keypair.id)?.len(), 1);
+        let peers = kv.get_peers().await?;
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].pub_key, keypair.id);
+        assert_eq!(
+            kv.indexes().get_contact(&keypair.id, &contact.id)?,
+            Some(true)
+        );
+
+        // The migrated feed can be appended to.
+        let msg_2 = MessageValue::sign(Some(&msg), &keypair, json!({ "type": "post" })).unwrap();
+        assert_eq!(kv.append_feed(msg_2).await?, 2);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_migrate_interrupted() -> Result<()> {
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+        let contact = SecretConfig::create().owned_identity().unwrap();
+
+        let msg_content = json!({ "type": "contact", "contact": contact.id, "following": true });
+        let msg = MessageValue::sign(None, &keypair, msg_content).unwrap();
+
+        // The migration was interrupted once the records were moved out of
+        // the default tree, with or without the version being migrated from
+        // recorded, and before the author public keys were interned.
+        for marker in [false, true] {
+            let path = tempdir::TempDir::new("solardb").unwrap();
+            write_version_0_feed(path.path(), &keypair, &msg, true)?;
+            if marker {
+                let db = KvConfig::new().path(path.path()).open()?;
+                let version_key: &[u8] = &[KEY_SCHEMA_VERSION];
+                db.insert(version_key, &0u32.to_be_bytes()[..])?;
+                db.flush()?;
+            }
+
+            let mut kv = KvStorage::default();
+            let (sender, _) = futures::channel::mpsc::unbounded();
+            kv.open(KvConfig::new().path(path.path()), sender)?;
+            kv.build_indexes()?;
+
+            // The migration is resumed rather than the database taken for a
+            // new one.
+            assert_eq!(kv.get_latest_seq(&keypair.id)?, Some(1));
+            assert_eq!(kv.get_feed(&keypair.id)?.len(), 1);
+            assert_eq!(
+                kv.indexes().get_contact(&keypair.id, &contact.id)?,
+                Some(true)
+            );
+            assert_eq!(kv.get_latest_global_seq()?, 1);
+        }
+
+        Ok(())
+    }
+
+    /// Move the records of each type back into the default tree, under
+    /// their prefix, as stored by schema version 10 and earlier.
+    fn move_to_default_tree(kv: &KvStorage) -> Result<()> {
+        let db = kv.db()?;
+        for (prefix, tree) in kv.trees()?.by_prefix() {
+            for item in tree.iter() {
+                let (key, value) = item?;
+                let mut legacy_key = vec![prefix];
+                legacy_key.extend_from_slice(&key);
+                db.insert(legacy_key, value)?;
+            }
+            tree.clear()?;
+        }
+        let version_key: &[u8] = &[KEY_SCHEMA_VERSION];
+        db.insert(version_key, &10u32.to_be_bytes()[..])?;
+        db.flush()?;
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_migrate_split_trees() -> Result<()> {
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+        let path = tempdir::TempDir::new("solardb").unwrap();
+
+        let mut msg_ids = Vec::new();
+        {
+            // Without a background flusher, the database is unlocked as soon
+            // as it is dropped.
+            let config = KvConfig::new().path(path.path()).flush_every_ms(None);
+            let mut kv = KvStorage::default();
+            let (sender, _) = futures::channel::mpsc::unbounded();
+            kv.open(config, sender)?;
+            let mut last_msg = None;
+            for _ in 0..3 {
+                let msg =
+                    MessageValue::sign(last_msg.as_ref(), &keypair, json!({ "type": "post" }))
+                        .unwrap();
+                msg_ids.push(msg.id().to_string());
+                kv.append_feed(msg.clone()).await?;
+                last_msg = Some(msg);
+            }
+            kv.set_blob_skipped("&blob.sha256")?;
+            kv.pin_feed("@pinned.ed25519")?;
+            kv.record_address_latency("127.0.0.1:8008", 20)?;
+            kv.set_notifications_read(2)?;
+            move_to_default_tree(&kv)?;
+        }
+
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        kv.open(KvConfig::new().path(path.path()), sender)?;
+
+        assert_eq!(kv.get_latest_seq(&keypair.id)?, Some(3));
+        assert_eq!(kv.get_feed(&keypair.id)?.len(), 3);
+        assert_eq!(kv.get_global_seq(&msg_ids[2])?, Some(3));
+        assert!(kv.check_global_order()?.is_consistent());
+        assert_eq!(
+            kv.get_skipped_blobs()?,
+            ["&blob.sha256".to_string()].to_vec()
+        );
+        assert!(kv.is_pinned("@pinned.ed25519")?);
+        assert_eq!(kv.get_address_latency("127.0.0.1:8008")?, Some(20));
+        assert_eq!(kv.get_notifications_read()?, 2);
+        // Only the single-entry records are left in the default tree.
+        let db = kv.db()?;
+        assert!(db.iter().keys().all(|key| key.unwrap().len() == 1));
+
+        // The migrated feed can be appended to.
+        let msg = kv.get_latest_msg_val(&keypair.id)?;
+        let msg = MessageValue::sign(msg.as_ref(), &keypair, json!({ "type": "post" })).unwrap();
+        assert_eq!(kv.append_feed(msg).await?, 4);
+        assert_eq!(kv.get_latest_global_seq()?, 4);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_migrate_feed_keys() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
+        let path = tempdir::TempDir::new("solardb").unwrap();
+
+        // Write two interleaved feeds, then move their KVTs to the version 3
+        // layout, with the sequence number before the author.
+        {
+            let config = KvConfig::new().path(path.path()).flush_every_ms(None);
+            let mut kv = KvStorage::default();
+            let (sender, _) = futures::channel::mpsc::unbounded();
+            kv.open(config, sender)?;
+            let mut last_msgs = (None, None);
+            for _ in 0..2 {
+                for (keypair, last_msg) in [(&alice, &mut last_msgs.0), (&bob, &mut last_msgs.1)] {
+                    let msg =
+                        MessageValue::sign(last_msg.as_ref(), keypair, json!({ "type": "post" }))
+                            .unwrap();
+                    kv.append_feed(msg.clone()).await?;
+                    *last_msg = Some(msg);
+                }
+            }
+
+            move_to_default_tree(&kv)?;
+            let db = kv.db.as_ref().unwrap();
+            let mut batch = sled::Batch::default();
+            for item in db.scan_prefix([PREFIX_MSG_KVT]) {
+                let (key, value) = item?;
+                let mut old_key = vec![PREFIX_MSG_KVT];
+                old_key.extend_from_slice(&key[5..13]);
+                old_key.extend_from_slice(&key[1..5]);
+                batch.remove(key);
+                batch.insert(old_key, value);
+            }
+            let version_key: &[u8] = &[KEY_SCHEMA_VERSION];
+            batch.insert(version_key, &3u32.to_be_bytes()[..]);
+            db.apply_batch(batch)?;
+            db.flush()?;
+        }
+
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        kv.open(KvConfig::new().path(path.path()), sender)?;
+
+        for keypair in [&alice, &bob] {
+            let feed = kv.get_feed(&keypair.id)?;
+            assert_eq!(feed.len(), 2);
+            assert!(feed
+                .iter()
+                .enumerate()
+                .all(|(i, msg_kvt)| msg_kvt.value["sequence"] == i as u64 + 1
+                    && msg_kvt.value["author"] == keypair.id.as_str()));
+        }
+        assert_eq!(kv.iter_feed("@unknown.ed25519")?.count(), 0);
+        assert!(kv.check_global_order()?.is_consistent());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_rebuild_global_order() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
+
+        let kv = open_temporary_kv();
+        let mut msg_ids = Vec::new();
+        let mut last_msgs = (None, None);
+        for i in 0..3 {
+            for (keypair, last_msg) in [(&alice, &mut last_msgs.0), (&bob, &mut last_msgs.1)] {
+                let content = json!({ "type": "post", "text": format!("{i}") });
+                let msg = MessageValue::sign(last_msg.as_ref(), keypair, content).unwrap();
+                msg_ids.push(msg.id().to_string());
+                kv.append_feed(msg.clone()).await?;
+                *last_msg = Some(msg);
+            }
+        }
+        assert!(kv.check_global_order()?.is_consistent());
+
+        // Drop one message from the global order.
+        let trees = kv.trees()?;
+        trees.global_order.remove(2u64.to_be_bytes())?;
+        trees.global_seq.remove(&msg_ids[1])?;
+
+        let report = kv.rebuild_global_order()?;
+        assert_eq!(
+            report,
+            GlobalOrderReport {
+                messages: 6,
+                gaps: 1,
+                duplicates: 0,
+                missing: 1
+            }
+        );
+        assert!(kv.check_global_order()?.is_consistent());
+        assert_eq!(kv.get_global_seq(&msg_ids[0])?, Some(1));
+        assert_eq!(kv.get_global_seq(&msg_ids[5])?, Some(6));
+        assert_eq!(kv.get_global_seq("%unknown.sha256")?, None);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_outbox() -> Result<()> {
+        let kv = open_temporary_kv();
+        let outbox = kv.outbox();
+
+        let first = outbox.push(&json!({ "type": "post", "text": "first" }))?;
+        let second = outbox.push(&json!({ "type": "post", "text": "second" }))?;
+        assert!(outbox.has_queued()?);
+
+        let queued = outbox.get_queued()?;
+        assert_eq!(queued.len(), 2);
+        assert_eq!(queued[0].0, first);
+        assert_eq!(queued[1].1["text"], "second");
+
+        let published = OutboxStatus::Published {
+            msg_ref: "%first.sha256".to_string(),
+            seq_num: 1,
+        };
+        outbox.set_status(first, published.clone())?;
+        assert_eq!(outbox.get_status(first)?, Some(published));
+        assert_eq!(outbox.get_status(second)?, Some(OutboxStatus::Queued));
+        assert_eq!(outbox.get_queued()?.len(), 1);
+        assert_eq!(outbox.get_status(second + 1)?, None);
+
+        outbox.set_status(second, OutboxStatus::Failed { error: "failed".to_string() })?;
+        assert!(!outbox.has_queued()?);
+        assert!(outbox.get_queued()?.is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_tail_messages() -> Result<()> {
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+
+        let kv = open_temporary_kv();
+        let mut last_msg = None;
+        for i in 0..3 {
+            let content = json!({ "type": "post", "text": format!("{i}") });
+            let msg = MessageValue::sign(last_msg.as_ref(), &keypair, content).unwrap();
+            kv.append_feed(msg.clone()).await?;
+            last_msg = Some(msg);
+        }
+        assert_eq!(kv.get_latest_global_seq()?, 3);
+
+        // Stored messages after the cursor are yielded in order.
+        let mut tail = Box::pin(kv.tail_messages(1).await?);
+        let (global_seq, msg_kvt) = tail.next().await.unwrap()?;
+        assert_eq!(global_seq, 2);
+        assert_eq!(msg_kvt.value["sequence"], 2);
+        let (global_seq, msg_kvt) = tail.next().await.unwrap()?;
+        assert_eq!(global_seq, 3);
+        assert_eq!(msg_kvt.key, last_msg.as_ref().unwrap().id().to_string());
+
+        // Appended messages are yielded once the stored ones are consumed.
+        let content = json!({ "type": "post", "text": "3" });
+        let msg = MessageValue::sign(last_msg.as_ref(), &keypair, content).unwrap();
+        kv.append_feed(msg).await?;
+        let (global_seq, _) = tail.next().await.unwrap()?;
+        assert_eq!(global_seq, 4);
+
+        // A message which cannot be read is reported and skipped.
+        let author = kv.author_index()?.get_id(&keypair.id)?.unwrap();
+        kv.trees()?.msg_kvt.remove(KvStorage::key_msg_kvt(author, 2))?;
+        let mut tail = Box::pin(kv.tail_messages(1).await?);
+        assert!(tail.next().await.unwrap().is_err());
+        let (global_seq, _) = tail.next().await.unwrap()?;
+        assert_eq!(global_seq, 3);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_ooo_store() -> Result<()> {
+        // Create a unique keypair to sign messages.
+        let keypair = SecretConfig::create().owned_identity().unwrap();
+
+        let kv = open_temporary_kv();
+        let msg_content = TypedMessage::Post {
+            text: "Found my way here out of order.".to_string(),
+            mentions: None,
+        };
+        let msg = MessageValue::sign(None, &keypair, json!(msg_content)).unwrap();
+        let msg_id = msg.id().to_string();
+
+        kv.set_ooo_msg_val(&msg)?;
 
-    /// Add the public key and latest sequence number of a peer to the list of
-    /// peers.
-    pub async fn set_peer(&self, user_id: &str, latest_seq: u64) -> Result<()> {
-        let db = self.db.as_ref().unwrap();
-        db.insert(Self::key_peer(user_id), &latest_seq.to_be_bytes()[..])?;
+        // The message is retrievable by ID but is not part of the feed.
+        assert_eq!(kv.get_any_msg_val(&msg_id)?, Some(msg.clone()));
+        assert!(kv.get_msg_val(&msg_id)?.is_none());
+        assert!(kv.get_latest_seq(&keypair.id)?.is_none());
+        assert!(!kv.is_msg_verified(&msg_id)?);
 
-        // TODO: Should we be flushing here?
-        // Flush may have a performance impact. It may also be unnecessary
-        // depending on where / when this method is called.
+        // Appending the message to its feed verifies it.
+        kv.append_feed(msg).await?;
+        assert!(kv.is_msg_verified(&msg_id)?);
 
         Ok(())
     }
 
-    /// Return the public key and latest sequence number for all peers in the
-    /// database.
-    pub async fn get_peers(&self) -> Result<Vec<PubKeyAndSeqNum>> {
-        let db = self.db.as_ref().unwrap();
-        let mut peers = Vec::new();
-
-        // Use the generic peer prefix to return an iterator over all peers.
-        let scan_peer_key: &[u8] = &[PREFIX_PEER];
-        for peer in db.range(scan_peer_key..) {
-            let (peer_key, _) = peer?;
-            // Drop the prefix byte and convert the remaining bytes to
-            // a string.
-            let pub_key = String::from_utf8_lossy(&peer_key[1..]).to_string();
-            // Get the latest sequence number for the peer.
-            // Fallback to a value of 0 if a `None` value is returned.
-            let seq_num = self.get_latest_seq(&pub_key)?.unwrap_or(0);
-            let peer_latest_sequence = PubKeyAndSeqNum { pub_key, seq_num };
-            peers.push(peer_latest_sequence)
-        }
+    #[async_std::test]
+    async fn test_pinned_feeds() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
 
-        Ok(peers)
-    }
+        let kv = open_temporary_kv();
+        let msg = MessageValue::sign(None, &alice, json!({ "type": "post" })).unwrap();
+        kv.append_feed(msg).await?;
+        kv.pin_feed(&alice.id)?;
+        kv.pin_feed(&bob.id)?;
+        assert!(kv.is_pinned(&bob.id)?);
 
-    /// Append a message value to a feed.
-    pub async fn append_feed(&self, msg_val: MessageValue) -> Result<u64> {
-        let seq_num = self.get_latest_seq(msg_val.author())?.map_or(0, |num| num) + 1;
+        // Pinned feeds are listed as peers even if none of their messages
+        // are stored.
+        let peers = kv.get_peers().await?;
+        assert_eq!(peers.len(), 2);
+        assert!(peers.iter().all(|peer| peer.pinned));
+        assert!(peers
+            .iter()
+            .any(|peer| peer.pub_key == bob.id && peer.seq_num == 0));
 
-        // TODO: We should really be performing more comprehensive validation.
-        // Are there other checks in place behind the scenes?
-        if msg_val.sequence() != seq_num {
-            return Err(Error::InvalidSequence);
-        }
+        assert!(kv.unpin_feed(&bob.id)?);
+        assert!(!kv.unpin_feed(&bob.id)?);
+        assert_eq!(kv.get_pinned()?, vec![alice.id.clone()]);
+        assert_eq!(kv.get_peers().await?.len(), 1);
 
-        let author = msg_val.author().to_owned();
-        let db = self.db.as_ref().unwrap();
+        Ok(())
+    }
 
-        let msg_ref = serde_cbor::to_vec(&PubKeyAndSeqNum {
-            pub_key: author.clone(),
-            seq_num,
-        })?;
-        db.insert(Self::key_msg_val(&msg_val.id().to_string()), msg_ref)?;
+    #[async_std::test]
+    async fn test_clock_skew() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
 
-        let msg_kvt = MessageKvt::new(msg_val.clone());
-        db.insert(
-            Self::key_msg_kvt(&author, seq_num),
-            msg_kvt.to_string().as_bytes(),
-        )?;
-        db.insert(Self::key_latest_seq(&author), &seq_num.to_be_bytes()[..])?;
+        let kv = open_temporary_kv();
+        let msg =
+            MessageValue::sign(None, &alice, json!({ "type": "post", "text": "hi" })).unwrap();
+        kv.append_feed(msg).await?;
 
-        // Add the public key and latest sequence number for this peer to the
-        // list of peers.
-        self.set_peer(&author, seq_num).await?;
+        let skew = kv.clock_skew().get(&alice.id)?.unwrap();
+        assert_eq!(skew.messages, 1);
+        assert!(skew.latest_skew <= 0);
 
-        db.flush_async().await?;
+        // Bob's clock runs a day ahead, then is set right.
+        let max_skew = clock_skew::max_clock_skew();
+        let day = 24.0 * 60.0 * 60.0 * 1000.0;
+        kv.clock_skew().record(&bob.id, 2.0 * day, day)?;
+        kv.clock_skew().record(&bob.id, day, day)?;
+        let skewed = kv.clock_skew().get_skewed(max_skew as i64)?;
+        assert_eq!(skewed.len(), 1);
+        assert_eq!(skewed[0].feed_id, bob.id);
+        assert_eq!((skewed[0].latest_skew, skewed[0].max_skew), (0, day as i64));
 
-        // Publish a notification that the feed belonging to the given public
-        // key has been updated.
-        let broker_msg = BrokerEvent::new(
-            Destination::Broadcast,
-            StoKvEvent::IdChanged(msg_val.author().clone()),
+        assert_eq!(clock_skew::ordering_timestamp(day, 2.0 * day), day);
+        assert_eq!(
+            clock_skew::ordering_timestamp(day + max_skew, day),
+            day + max_skew
         );
+        assert_eq!(clock_skew::ordering_timestamp(2.0 * day, day), day);
 
-        // Matching on the error here (instead of unwrapping) allows us to
-        // write unit tests for `append_feed`; a case where we do not have
-        // a broker deployed to receive the event message.
-        if let Err(err) = self.ch_broker.as_ref().unwrap().send(broker_msg).await {
-            warn!(
-                "failed to notify broker of message appended to kv store: {}",
-                err
-            )
-        };
+        // The clock skew is recorded again from the stored messages.
+        kv.reindex()?;
+        assert_eq!(kv.clock_skew().get(&alice.id)?.unwrap().messages, 1);
+        assert_eq!(kv.clock_skew().get(&bob.id)?, None);
 
-        Ok(seq_num)
+        Ok(())
     }
 
-    /// Get all messages comprising the feed authored by the given public key.
-    pub fn get_feed(&self, user_id: &str) -> Result<Vec<MessageKvt>> {
-        let mut feed = Vec::new();
+    #[async_std::test]
+    async fn test_mutes() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
 
-        // Lookup the latest sequence number for the given peer.
-        if let Some(latest_seq) = self.get_latest_seq(user_id)? {
-            // Iterate through the messages in the feed.
-            for msg_seq in 1..=latest_seq {
-                // Get the message KVT for the given author and message
-                // sequence number and add it to the feed vector.
-                // TODO: consider handling the `None` case instead of
-                // unwrapping.
-                feed.push(self.get_msg_kvt(user_id, msg_seq)?.unwrap())
-            }
-        }
+        let kv = open_temporary_kv();
+        let mutes = kv.mutes();
+        mutes.mute(&alice.id, false)?;
+        mutes.mute(&bob.id, true)?;
+        assert!(mutes.is_muted(&alice.id)?);
+        assert!(!mutes.is_replication_stopped(&alice.id)?);
+        assert!(mutes.is_replication_stopped(&bob.id)?);
+        assert_eq!(mutes.get_mutes()?.len(), 2);
 
-        Ok(feed)
+        assert!(mutes.unmute(&bob.id)?);
+        assert!(!mutes.unmute(&bob.id)?);
+        assert!(!mutes.is_muted(&bob.id)?);
+        assert!(!mutes.is_replication_stopped(&bob.id)?);
+        assert_eq!(
+            mutes.get_mutes()?,
+            vec![Mute {
+                pub_key: alice.id.clone(),
+                stop_replication: false,
+            }]
+        );
+
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::*;
+    #[test]
+    fn test_bans() -> Result<()> {
+        let kv = open_temporary_kv();
+        let bans = kv.bans();
 
-    use kuska_ssb::api::dto::content::TypedMessage;
-    use serde_json::json;
-    use sled::Config as KvConfig;
+        // Peer IDs from handshakes have no leading `@`.
+        bans.ban("peer=.ed25519", 2000, Some("flooding".to_string()))?;
+        bans.ban("@other=.ed25519", 1000, None)?;
+        assert!(bans.is_banned("@peer=.ed25519", 1500)?);
+        let listed: Vec<String> = bans
+            .get_all(0)?
+            .into_iter()
+            .map(|ban| ban.pub_key)
+            .collect();
+        assert_eq!(listed, vec!["@other=.ed25519", "@peer=.ed25519"]);
 
-    use crate::config::SecretConfig;
+        // Bans expire on their own.
+        assert!(!bans.is_banned("other=.ed25519", 1000)?);
+        assert!(!bans.unban("@other=.ed25519")?);
+        assert_eq!(bans.get_all(1500)?.len(), 1);
+        assert!(bans.unban("peer=.ed25519")?);
+        assert!(!bans.is_banned("@peer=.ed25519", 1500)?);
 
-    fn open_temporary_kv() -> KvStorage {
-        let mut kv = KvStorage::default();
-        let (sender, _) = futures::channel::mpsc::unbounded();
-        let path = tempdir::TempDir::new("solardb").unwrap();
-        let config = KvConfig::new().path(path.path());
-        kv.open(config, sender).unwrap();
-        kv
+        Ok(())
     }
 
     #[async_std::test]
-    async fn test_feed_length() -> Result<()> {
-        use kuska_ssb::feed::Message;
-        // Create a unique keypair to sign messages.
-        let keypair = SecretConfig::create().owned_identity().unwrap();
+    async fn test_deliveries() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
+        let blob_ids = vec!["&kZpHNwxz0bZTkm7sEzlO9HAXGWLfTnoHP8tiL1EeIWI=.sha256".to_string()];
 
-        // Open a temporary key-value store.
         let kv = open_temporary_kv();
+        let deliveries = kv.deliveries();
+        // Peer IDs from handshakes have no leading `@`.
+        let peer_id = alice.id.trim_start_matches('@');
+        assert!(deliveries.add(peer_id)?);
+        assert!(!deliveries.add(&alice.id)?);
+        assert!(deliveries.contains(&alice.id)?);
 
-        let mut last_msg: Option<Message> = None;
-        for i in 1..=4 {
-            // Create a post-type message.
-            let msg_content = TypedMessage::Post {
-                text: format!("Important announcement #{i}"),
-                mentions: None,
-            };
+        // Only feeds requested by the peer are tracked, and the known
+        // sequence number never decreases.
+        deliveries.record_held(&bob.id, &alice.id, 1)?;
+        deliveries.record_held(peer_id, &bob.id, 3)?;
+        deliveries.record_held(peer_id, &bob.id, 2)?;
+        deliveries.queue_blobs(&bob.id, &blob_ids)?;
+        deliveries.queue_blobs(&alice.id, &blob_ids)?;
+        assert!(deliveries.get(&bob.id)?.is_none());
+        let queue = deliveries.get(&alice.id)?.unwrap();
+        assert_eq!(queue.feeds.get(&bob.id), Some(&3));
+        assert_eq!(queue.blobs.len(), 1);
 
-            let msg = MessageValue::sign(last_msg.as_ref(), &keypair, json!(msg_content)).unwrap();
+        deliveries.remove_blob(peer_id, &blob_ids[0])?;
+        assert!(deliveries.get(&alice.id)?.unwrap().blobs.is_empty());
+        assert!(deliveries.remove(peer_id)?);
+        assert!(deliveries.get_all()?.is_empty());
 
-            // Append the signed message to the feed. Returns the sequence number
-            // of the appended message.
-            let seq = kv.append_feed(msg).await.unwrap();
-            assert_eq!(seq, i);
+        Ok(())
+    }
 
-            last_msg = kv.get_latest_msg_val(&keypair.id).unwrap();
+    #[async_std::test]
+    async fn test_report_index() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
+        let mallory = SecretConfig::create().owned_identity().unwrap();
 
-            let feed = kv.get_feed(&keypair.id).unwrap();
-            assert_eq!(feed.len(), i as usize);
-        }
+        let kv = open_temporary_kv();
+        let flag = json!({ "type": "flag", "link": mallory.id, "reason": "spam" });
+        kv.append_feed(MessageValue::sign(None, &alice, flag.clone()).unwrap())
+            .await?;
+        let msg = MessageValue::sign(None, &bob, flag).unwrap();
+        kv.append_feed(msg.clone()).await?;
+        let report = json!({ "type": "report", "flag": { "link": mallory.id } });
+        kv.append_feed(MessageValue::sign(Some(&msg), &bob, report).unwrap())
+            .await?;
+
+        let reports = kv.indexes().get_reports(&mallory.id)?;
+        assert_eq!(reports.len(), 3);
+        assert!(reports
+            .iter()
+            .any(|report| report.author == alice.id && report.reason.as_deref() == Some("spam")));
+        assert!(kv.indexes().get_reports(&alice.id)?.is_empty());
+
+        let counts = kv.indexes().get_report_counts()?;
+        assert_eq!(
+            counts,
+            vec![ReportCount {
+                target: mallory.id.clone(),
+                reports: 3,
+                reporters: 2,
+            }]
+        );
 
         Ok(())
     }
 
     #[async_std::test]
-    async fn test_single_message_content_matches() -> Result<()> {
-        // Create a unique keypair to sign messages.
-        let keypair = SecretConfig::create().owned_identity().unwrap();
+    async fn test_invalid_content_index() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        // The registry is global; the type is unique to this test.
+        SCHEMAS.register(
+            "test-invalid-content",
+            Arc::new(Schema::new(json!({ "required": ["title"] }))),
+        );
 
-        // Open a temporary key-value store.
         let kv = open_temporary_kv();
+        let valid = MessageValue::sign(
+            None,
+            &alice,
+            json!({ "type": "test-invalid-content", "title": "ok" }),
+        )
+        .unwrap();
+        kv.append_feed(valid.clone()).await?;
+        let invalid = MessageValue::sign(
+            Some(&valid),
+            &alice,
+            json!({ "type": "test-invalid-content" }),
+        )
+        .unwrap();
+        kv.append_feed(invalid.clone()).await?;
 
-        // Create a post-type message.
-        let msg_content = TypedMessage::Post {
-            text: "A strange rambling expiration of my own conscious".to_string(),
-            mentions: None,
-        };
+        assert_eq!(kv.indexes().get_invalid(&valid.id().to_string())?, None);
+        assert_eq!(
+            kv.indexes()
+                .get_invalid(&invalid.id().to_string())?
+                .as_deref(),
+            Some("test-invalid-content message: content.title is missing")
+        );
 
-        let last_msg = kv.get_latest_msg_val(&keypair.id).unwrap();
-        let msg = MessageValue::sign(last_msg.as_ref(), &keypair, json!(msg_content)).unwrap();
+        Ok(())
+    }
 
-        // Append the signed message to the feed. Returns the sequence number
-        // of the appended message.
-        let seq = kv.append_feed(msg).await.unwrap();
-        assert_eq!(seq, 1);
+    #[async_std::test]
+    async fn test_gathering_index() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
+        let kv = open_temporary_kv();
 
-        let latest_seq = kv.get_latest_seq(&keypair.id).unwrap();
-        assert_eq!(latest_seq, Some(1));
+        let picnic = MessageValue::sign(None, &alice, json!({ "type": "gathering" })).unwrap();
+        kv.append_feed(picnic.clone()).await?;
+        let picnic_ref = picnic.id().to_string();
+        let details = json!({
+            "type": "about",
+            "about": picnic_ref,
+            "title": "picnic",
+            "startDateTime": { "epoch": 2000, "tz": "utc" },
+            "image": { "link": "&image.sha256" },
+        });
+        let msg = MessageValue::sign(Some(&picnic), &alice, details).unwrap();
+        kv.append_feed(msg.clone()).await?;
+        let attend =
+            json!({ "type": "about", "about": picnic_ref, "attendee": { "link": alice.id } });
+        let past = MessageValue::sign(Some(&msg), &alice, attend).unwrap();
+        kv.append_feed(past.clone()).await?;
 
-        // Lookup the value of the previous message. This will be `None`
-        let last_msg = kv.get_latest_msg_val(&keypair.id).unwrap();
-        assert!(last_msg.is_some());
-        let expected = serde_json::value::to_value(msg_content).unwrap();
-        let last_msg = last_msg.unwrap().content().clone();
+        // Only the author of the gathering sets its details, and feeds only
+        // announce their own attendance.
+        let retitle = json!({ "type": "about", "about": picnic_ref, "title": "party" });
+        let msg = MessageValue::sign(None, &bob, retitle).unwrap();
+        kv.append_feed(msg.clone()).await?;
+        let attend = json!({ "type": "gathering-update", "about": picnic_ref, "attendee": { "link": bob.id } });
+        let msg = MessageValue::sign(Some(&msg), &bob, attend).unwrap();
+        kv.append_feed(msg.clone()).await?;
+        let unattend = json!({ "type": "about", "about": picnic_ref, "attendee": { "link": alice.id, "remove": true } });
+        kv.append_feed(MessageValue::sign(Some(&msg), &bob, unattend).unwrap())
+            .await?;
 
-        assert_eq!(last_msg, expected);
+        // A gathering without a start time.
+        let gathering = json!({ "type": "gathering" });
+        let meetup = MessageValue::sign(Some(&past), &alice, gathering).unwrap();
+        kv.append_feed(meetup.clone()).await?;
+
+        let gatherings = kv.indexes().get_gatherings(None)?;
+        assert_eq!(
+            gatherings[0],
+            Gathering {
+                msg_ref: picnic_ref,
+                author: Some(alice.id.clone()),
+                title: Some("picnic".to_string()),
+                image: Some("&image.sha256".to_string()),
+                start: Some(2000),
+                attendees: vec![alice.id.clone(), bob.id.clone()].into_iter().collect(),
+                ..Default::default()
+            }
+        );
+        assert_eq!(gatherings[1].msg_ref, meetup.id().to_string());
+        assert_eq!(gatherings.len(), 2);
+
+        assert_eq!(kv.indexes().get_gatherings(Some(2000))?.len(), 1);
+        assert!(kv.indexes().get_gatherings(Some(2001))?.is_empty());
 
         Ok(())
     }
 
     #[async_std::test]
-    async fn test_new_feed_is_empty() -> Result<()> {
-        // Create a unique keypair to sign messages.
-        let keypair = SecretConfig::create().owned_identity().unwrap();
+    async fn test_git_index() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
+        let kv = open_temporary_kv();
 
-        // Open a temporary key-value store.
+        let repo = MessageValue::sign(None, &alice, json!({ "type": "git-repo" })).unwrap();
+        kv.append_feed(repo.clone()).await?;
+        let repo_id = repo.id().to_string();
+        let name = json!({ "type": "about", "about": repo_id, "name": "solar" });
+        let msg = MessageValue::sign(Some(&repo), &alice, name).unwrap();
+        kv.append_feed(msg.clone()).await?;
+        let update = json!({
+            "type": "git-update",
+            "repo": repo_id,
+            "refs": { "refs/heads/main": "abc", "refs/heads/old": null },
+            "commits": [{ "sha1": "abc", "title": "Initial commit" }],
+        });
+        kv.append_feed(MessageValue::sign(Some(&msg), &alice, update).unwrap())
+            .await?;
+
+        // Only the owner names the repository.
+        let rename = json!({ "type": "about", "about": repo_id, "name": "lunar" });
+        kv.append_feed(MessageValue::sign(None, &bob, rename).unwrap())
+            .await?;
+
+        assert_eq!(
+            kv.indexes().get_git_repos(&alice.id)?,
+            vec![GitRepo {
+                repo_id: repo_id.clone(),
+                owner: alice.id.clone(),
+                name: Some("solar".to_string()),
+            }]
+        );
+        assert!(kv.indexes().get_git_repos(&bob.id)?.is_empty());
+
+        let updates = kv.indexes().get_git_updates(&repo_id)?;
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].author, alice.id);
+        assert_eq!(
+            updates[0].refs.get("refs/heads/main"),
+            Some(&Some("abc".to_string()))
+        );
+        assert_eq!(updates[0].refs.get("refs/heads/old"), Some(&None));
+        assert_eq!(
+            updates[0].commits,
+            vec![GitCommit {
+                sha1: "abc".to_string(),
+                title: Some("Initial commit".to_string()),
+            }]
+        );
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_index_overlay() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
         let kv = open_temporary_kv();
 
-        // Lookup the value of the previous message. This will be `None`
-        let last_msg = kv.get_latest_msg_val(&keypair.id).unwrap();
-        assert!(last_msg.is_none());
+        let mut last_msg = None;
+        let mut ids = Vec::new();
+        for _ in 0..4 {
+            task::sleep(Duration::from_millis(2)).await;
+            let post = json!({ "type": "post", "text": "hi", "channel": "solar" });
+            let msg = MessageValue::sign(last_msg.as_ref(), &alice, post).unwrap();
+            kv.append_feed(msg.clone()).await?;
+            ids.push(msg.id().to_string());
+            last_msg = Some(msg);
+            // The first and third posts are committed, the others staged.
+            if ids.len() % 2 == 1 {
+                kv.commit_indexes().await?;
+            }
+        }
+        assert!(kv.indexes().is_incomplete()?);
 
-        let latest_seq = kv.get_latest_seq(&keypair.id).unwrap();
-        assert!(latest_seq.is_none());
+        // Reads merge the staged posts with the stored ones, in either
+        // direction.
+        let newest_first: Vec<String> = ids.iter().rev().cloned().collect();
+        assert_eq!(kv.indexes().get_channel("solar", 10)?, newest_first);
+        assert_eq!(kv.indexes().get_channels()?, vec![("solar".to_string(), 4)]);
 
         Ok(())
     }
 
-    // In reality this test covers more than just the append method.
-    // It tests multiple methods exposed by the kv database.
-    // The main reason for combining the tests is the cost of setting up
-    // testable conditions (ie. creating the keypair and database and
-    // it with messages). Perhaps this could be broken up in the future.
     #[async_std::test]
-    async fn test_append_feed() -> Result<()> {
-        // Create a unique keypair to sign messages.
+    async fn test_channel_index() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let kv = open_temporary_kv();
+
+        let mut last_msg = None;
+        let mut ids = Vec::new();
+        for channel in &["#Solar", "solar", "", "lunar"] {
+            // Posts in a channel are ordered by timestamp.
+            task::sleep(Duration::from_millis(2)).await;
+            let post = json!({ "type": "post", "text": "hi", "channel": channel });
+            let msg = MessageValue::sign(last_msg.as_ref(), &alice, post).unwrap();
+            kv.append_feed(msg.clone()).await?;
+            ids.push(msg.id().to_string());
+            last_msg = Some(msg);
+        }
+
+        assert_eq!(
+            kv.indexes().get_channels()?,
+            vec![("lunar".to_string(), 1), ("solar".to_string(), 2)]
+        );
+        assert_eq!(
+            kv.indexes().get_channel("#solar", 10)?,
+            vec![ids[1].clone(), ids[0].clone()]
+        );
+        assert_eq!(kv.indexes().get_channel("SOLAR", 1)?, vec![ids[1].clone()]);
+        assert!(kv.indexes().get_channel("", 10)?.is_empty());
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_thread_index() -> Result<()> {
         let keypair = SecretConfig::create().owned_identity().unwrap();
+        let stranger = SecretConfig::create().owned_identity().unwrap();
 
-        // Open a temporary key-value store.
         let kv = open_temporary_kv();
 
-        // Create a post-type message.
-        let msg_content = TypedMessage::Post {
-            text: "A solar flare is an intense localized eruption of electromagnetic radiation."
-                .to_string(),
-            mentions: None,
-        };
-
-        // Lookup the value of the previous message. This will be `None`.
-        let last_msg = kv.get_latest_msg_val(&keypair.id).unwrap();
+        // A root post in the local feed.
+        let root_content = json!({ "type": "post", "text": "What's the weather like?" });
+        let root = MessageValue::sign(None, &keypair, root_content).unwrap();
+        let root_id = root.id().to_string();
+        kv.append_feed(root).await?;
 
-        // Sign the message content using the temporary keypair and value of
-        // the previous message.
-        let msg = MessageValue::sign(last_msg.as_ref(), &keypair, json!(msg_content)).unwrap();
+        // A reply from a feed which is not replicated.
+        let reply_content = json!({ "type": "post", "text": "Sunny!", "root": root_id, "branch": root_id });
+        let reply = MessageValue::sign(None, &stranger, reply_content).unwrap();
+        kv.set_ooo_msg_val(&reply)?;
 
-        // Append the signed message to the feed. Returns the sequence number
-        // of the appended message.
-        let seq = kv.append_feed(msg).await.unwrap();
+        assert_eq!(
+            kv.indexes().get_thread(&root_id)?,
+            vec![reply.id().to_string()]
+        );
 
-        // Ensure that the message is the first in the feed.
-        assert_eq!(seq, 1);
+        Ok(())
+    }
 
-        // Get the latest sequence number.
-        let latest_seq = kv.get_latest_seq(&keypair.id).unwrap();
+    #[async_std::test]
+    async fn test_tombstone() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
+        let kv = open_temporary_kv();
 
-        // Ensure the stored sequence number matches that of the appended
-        // message.
-        assert!(latest_seq.is_some());
-        assert_eq!(latest_seq.unwrap(), seq);
+        let mut last_msg = None;
+        let mut msgs = Vec::new();
+        for content in [
+            json!({ "type": "post", "text": "oops", "channel": "solar" }),
+            json!({ "type": "contact", "contact": bob.id, "following": true }),
+            json!({ "type": "post", "text": "hi" }),
+        ] {
+            let msg = MessageValue::sign(last_msg.as_ref(), &alice, content).unwrap();
+            kv.append_feed(msg.clone()).await?;
+            msgs.push(msg.clone());
+            last_msg = Some(msg);
+        }
+        let post_id = msgs[0].id().to_string();
 
-        // Get a list of all replicated peers and their latest sequence
-        // numbers. This list is expected to contain an entry for the
-        // local keypair.
-        let peers = kv.get_peers().await.unwrap();
+        // The content is deleted, while the message still links the feed.
+        assert!(kv.tombstone_msg(&post_id, None, Some("typo".to_string()))?);
+        assert!(!kv.tombstone_msg(&post_id, None, None)?);
+        assert!(kv.get_msg_val(&post_id)?.is_none());
+        let msg_kvt = kv.get_msg_kvt(&alice.id, 1)?.unwrap();
+        assert_eq!(msg_kvt.key, post_id);
+        assert!(msg_kvt.value["content"].is_null());
+        assert!(msg_kvt.value["signature"].is_string());
+        assert_eq!(
+            kv.get_tombstone(&post_id)?.unwrap().reason.as_deref(),
+            Some("typo")
+        );
+        assert!(kv.indexes().get_channel("solar", 10)?.is_empty());
+        // Nor can the message be stored again out-of-order.
+        kv.set_ooo_msg_val(&msgs[0])?;
+        assert!(kv.get_any_msg_val(&post_id)?.is_none());
 
-        // Ensure there is only one entry in the peers list.
-        assert_eq!(peers.len(), 1);
-        // Ensure the public key of the peer matches expectations and that
-        // the sequence number is correct.
-        assert_eq!(peers[0].pub_key, keypair.id);
-        assert_eq!(peers[0].seq_num, 1);
+        // The latest message of a feed is kept.
+        assert!(kv
+            .tombstone_msg(&msgs[2].id().to_string(), None, None)
+            .is_err());
 
-        // Create, sign and append a second post-type message.
-        let msg_content_2 = TypedMessage::Post {
-            text: "When the sun shone upon her.".to_string(),
-            mentions: None,
-        };
-        let last_msg_2 = kv.get_latest_msg_val(&keypair.id).unwrap();
-        let msg_2 =
-            MessageValue::sign(last_msg_2.as_ref(), &keypair, json!(msg_content_2)).unwrap();
-        let msg_2_clone = msg_2.clone();
-        let seq_2 = kv.append_feed(msg_2).await.unwrap();
+        // Delete requests are only honoured for messages of their author.
+        let contact_id = msgs[1].id().to_string();
+        let request = json!({ "type": "delete", "link": contact_id });
+        let bob_request = MessageValue::sign(None, &bob, request.clone()).unwrap();
+        kv.append_feed(bob_request).await?;
+        assert!(kv.get_tombstone(&contact_id)?.is_none());
+        let alice_request = MessageValue::sign(last_msg.as_ref(), &alice, request).unwrap();
+        kv.append_feed(alice_request.clone()).await?;
+        assert_eq!(
+            kv.get_tombstone(&contact_id)?.unwrap().request,
+            Some(alice_request.id().to_string())
+        );
+        // The follow state is recomputed without the deleted message.
+        assert!(kv.indexes().get_follows(&alice.id)?.is_empty());
+        // The feed is only served up to the first deleted message.
+        assert_eq!(kv.first_tombstoned_seq(&alice.id, 1)?, Some(1));
+        assert_eq!(kv.first_tombstoned_seq(&alice.id, 2)?, Some(2));
+        assert_eq!(kv.first_tombstoned_seq(&alice.id, 3)?, None);
+        assert_eq!(kv.first_tombstoned_seq(&bob.id, 1)?, None);
 
-        // Ensure that the message is the second in the feed.
-        assert_eq!(seq_2, 2);
+        // The feed can still be appended to and reindexed.
+        let msg =
+            MessageValue::sign(Some(&alice_request), &alice, json!({ "type": "post" })).unwrap();
+        assert_eq!(kv.append_feed(msg).await?, 5);
+        kv.reindex()?;
+        assert!(kv.indexes().get_channel("solar", 10)?.is_empty());
+        assert_eq!(kv.get_feed(&alice.id)?.len(), 5);
 
-        // Get the second message in the key-value store in the form of a KVT.
-        let msg_kvt = kv.get_msg_kvt(&keypair.id, 2).unwrap();
-        assert!(msg_kvt.is_some());
+        Ok(())
+    }
 
-        // Retrieve the key from the KVT.
-        let msg_kvt_key = msg_kvt.unwrap().key;
+    #[async_std::test]
+    async fn test_tombstone_rederive() -> Result<()> {
+        let alice = SecretConfig::create().owned_identity().unwrap();
+        let bob = SecretConfig::create().owned_identity().unwrap();
+        let kv = open_temporary_kv();
 
-        // Get the second message in the key-value store in the form of a value.
-        let msg_val = kv.get_msg_val(&msg_kvt_key).unwrap();
+        let picnic = MessageValue::sign(None, &alice, json!({ "type": "gathering" })).unwrap();
+        let picnic_ref = picnic.id().to_string();
+        let mut last_msg = Some(picnic.clone());
+        let mut msgs = vec![picnic.clone()];
+        kv.append_feed(picnic).await?;
+        for content in [
+            json!({ "type": "about", "about": picnic_ref, "title": "picnic" }),
+            json!({ "type": "about", "about": picnic_ref, "attendee": { "link": alice.id } }),
+            json!({ "type": "about", "about": picnic_ref, "title": "party" }),
+            json!({ "type": "contact", "contact": bob.id, "following": true }),
+            json!({ "type": "contact", "contact": bob.id, "following": false }),
+            json!({ "type": "post", "text": "hi" }),
+        ] {
+            let msg = MessageValue::sign(last_msg.as_ref(), &alice, content).unwrap();
+            kv.append_feed(msg.clone()).await?;
+            msgs.push(msg.clone());
+            last_msg = Some(msg);
+        }
+        let msg = MessageValue::sign(None, &bob, json!({ "type": "post" })).unwrap();
+        kv.append_feed(msg).await?;
 
-        assert!(msg_val.is_some());
-        // Ensure the retrieved message value matches the previously created
-        // and signed message.
-        assert_eq!(msg_val.unwrap(), msg_2_clone);
+        let count = |kv: &KvStorage| -> Result<u64> {
+            Ok(kv.indexes().get_daily_counts(0)?.values().sum())
+        };
+        assert_eq!(count(&kv)?, 8);
+        for msg in [&msgs[3], &msgs[2], &msgs[5]] {
+            assert!(kv.tombstone_msg(&msg.id().to_string(), None, None)?);
+        }
 
-        // Get all messages comprising the feed.
-        let feed = kv.get_feed(&keypair.id).unwrap();
+        // The state derived from the deleted messages is recomputed from the
+        // remaining ones, as a reindex would.
+        let gatherings = kv.indexes().get_gatherings(None)?;
+        assert_eq!(gatherings[0].title.as_deref(), Some("picnic"));
+        assert!(gatherings[0].attendees.is_empty());
+        assert_eq!(kv.indexes().get_follows(&alice.id)?, vec![bob.id.clone()]);
+        assert_eq!(count(&kv)?, 5);
+        assert_eq!(kv.indexes().count_active_authors(0)?, 2);
+        let skew = kv.clock_skew().get(&alice.id)?.unwrap();
+        assert_eq!(skew.messages, 4);
 
-        // Ensure that two messages are returned.
-        assert_eq!(feed.len(), 2);
+        kv.reindex()?;
+        assert_eq!(kv.indexes().get_gatherings(None)?, gatherings);
+        assert_eq!(kv.indexes().get_follows(&alice.id)?, vec![bob.id.clone()]);
+        assert_eq!(count(&kv)?, 5);
+        assert_eq!(kv.clock_skew().get(&alice.id)?, Some(skew));
 
         Ok(())
     }
@@ -508,6 +4260,7 @@ mod test {
             &BlobStatus {
                 retrieved: true,
                 users: ["u1".to_string()].to_vec(),
+                skipped: false,
             },
         )?;
 
@@ -516,6 +4269,7 @@ mod test {
             &BlobStatus {
                 retrieved: false,
                 users: ["u2".to_string()].to_vec(),
+                skipped: false,
             },
         )?;
 
@@ -530,6 +4284,7 @@ mod test {
             &BlobStatus {
                 retrieved: false,
                 users: ["u7".to_string()].to_vec(),
+                skipped: false,
             },
         )?;
 
@@ -542,6 +4297,19 @@ mod test {
             ["b1".to_string(), "b2".to_string()].to_vec()
         );
 
+        // Skipped blobs are no longer pending until explicitly requested.
+        kv.set_blob_skipped("b2")?;
+        assert_eq!(kv.get_pending_blobs()?, ["b1".to_string()].to_vec());
+        assert_eq!(kv.get_skipped_blobs()?, ["b2".to_string()].to_vec());
+
+        kv.clear_blob_skipped("b2")?;
+        assert!(kv.get_skipped_blobs()?.is_empty());
+
+        kv.set_blob_skipped("b3")?;
+        kv.set_blob_retrieved("b3", true)?;
+        assert!(kv.get_skipped_blobs()?.is_empty());
+        assert_eq!(kv.get_retrieved_blobs()?, ["b3".to_string()].to_vec());
+
         Ok(())
     }
 }
@@ -1,2 +1,14 @@
+pub mod audit;
+pub mod authors;
+pub mod bans;
 pub mod blob;
+pub mod blob_fsck;
+pub mod blob_import;
+pub mod blob_wants;
+pub mod clock_skew;
+pub mod deliveries;
+pub mod indexes;
 pub mod kv;
+pub mod mutes;
+pub mod outbox;
+pub mod room_keys;
@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Name of the sled tree in which the mute list is stored.
+const MUTES_TREE: &str = "mutes";
+
+/// A muted feed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Mute {
+    pub pub_key: String,
+    /// Whether replication of the feed is also stopped.
+    pub stop_replication: bool,
+}
+
+/// Local list of muted feeds.
+///
+/// Muting is private to this node: no contact message is published. Muted
+/// feeds are hidden from query results and, optionally, no longer
+/// replicated.
+#[derive(Clone)]
+pub struct Mutes {
+    tree: sled::Tree,
+}
+
+impl Mutes {
+    /// Open the mutes tree of the given database.
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let tree = db.open_tree(MUTES_TREE)?;
+
+        Ok(Self { tree })
+    }
+
+    /// Mute the given feed, replacing any previous mute of it.
+    pub fn mute(&self, pub_key: &str, stop_replication: bool) -> Result<()> {
+        self.tree
+            .insert(pub_key.as_bytes(), serde_cbor::to_vec(&stop_replication)?)?;
+        self.tree.flush()?;
+
+        Ok(())
+    }
+
+    /// Unmute the given feed. Returns `false` if the feed was not muted.
+    pub fn unmute(&self, pub_key: &str) -> Result<bool> {
+        let removed = self.tree.remove(pub_key.as_bytes())?.is_some();
+        self.tree.flush()?;
+
+        Ok(removed)
+    }
+
+    /// Whether the given feed is muted.
+    pub fn is_muted(&self, pub_key: &str) -> Result<bool> {
+        Ok(self.tree.contains_key(pub_key.as_bytes())?)
+    }
+
+    /// Whether replication of the given feed has been stopped by muting it.
+    pub fn is_replication_stopped(&self, pub_key: &str) -> Result<bool> {
+        match self.tree.get(pub_key.as_bytes())? {
+            Some(raw) => Ok(serde_cbor::from_slice(&raw)?),
+            None => Ok(false),
+        }
+    }
+
+    /// Return all muted feeds.
+    pub fn get_mutes(&self) -> Result<Vec<Mute>> {
+        let mut mutes = Vec::new();
+
+        for item in self.tree.iter() {
+            let (key, value) = item?;
+            mutes.push(Mute {
+                pub_key: String::from_utf8_lossy(&key).to_string(),
+                stop_replication: serde_cbor::from_slice(&value)?,
+            });
+        }
+
+        Ok(mutes)
+    }
+}
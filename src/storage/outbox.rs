@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sled::transaction::{ConflictableTransactionError, Transactional};
+
+use crate::Result;
+
+/// Name of the sled tree in which the outbox is stored.
+const OUTBOX_TREE: &str = "outbox";
+
+/// Name of the sled tree holding the IDs of the entries waiting to be
+/// published, so that they are found without reading the whole outbox.
+const QUEUED_TREE: &str = "outbox_queued";
+
+/// Status of a message in the outbox.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum OutboxStatus {
+    /// Waiting to be published.
+    Queued,
+    /// Published on the local feed.
+    Published { msg_ref: String, seq_num: u64 },
+    /// Publishing failed and will not be retried.
+    Failed { error: String },
+}
+
+/// A message in the outbox.
+#[derive(Debug, Serialize, Deserialize)]
+struct OutboxEntry {
+    /// Message content, serialized as JSON.
+    content: String,
+    status: OutboxStatus,
+}
+
+/// Durable queue of messages accepted for publishing while the database is
+/// undergoing maintenance. Entries are kept after publishing so that their
+/// status can be queried.
+#[derive(Clone)]
+pub struct Outbox {
+    db: sled::Db,
+    tree: sled::Tree,
+    queued: sled::Tree,
+}
+
+impl Outbox {
+    /// Open the outbox trees of the given database.
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let has_queued_tree = db
+            .tree_names()
+            .iter()
+            .any(|name| name == QUEUED_TREE.as_bytes());
+        let outbox = Self {
+            db: db.clone(),
+            tree: db.open_tree(OUTBOX_TREE)?,
+            queued: db.open_tree(QUEUED_TREE)?,
+        };
+
+        // Outboxes which predate the tree of queued entries are scanned
+        // once to fill it.
+        if !has_queued_tree {
+            for item in outbox.tree.iter() {
+                let (key, value) = item?;
+                let entry: OutboxEntry = serde_cbor::from_slice(&value)?;
+                if entry.status == OutboxStatus::Queued {
+                    outbox.queued.insert(key, &[])?;
+                }
+            }
+        }
+
+        Ok(outbox)
+    }
+
+    /// Add the given content to the outbox. Returns the ID of the entry.
+    pub fn push(&self, content: &Value) -> Result<u64> {
+        // IDs generated by the database are unique and increasing, so the
+        // entries are stored in the order in which they were submitted.
+        let id = self.db.generate_id()?;
+        let entry = OutboxEntry {
+            content: content.to_string(),
+            status: OutboxStatus::Queued,
+        };
+        let raw = serde_cbor::to_vec(&entry)?;
+        (&self.tree, &self.queued).transaction(|(tx_tree, tx_queued)| {
+            tx_tree.insert(&id.to_be_bytes(), raw.as_slice())?;
+            tx_queued.insert(&id.to_be_bytes(), &[])?;
+            Ok::<(), ConflictableTransactionError<sled::Error>>(())
+        })?;
+        self.tree.flush()?;
+
+        Ok(id)
+    }
+
+    /// Return the ID and content of all queued entries, oldest first.
+    pub fn get_queued(&self) -> Result<Vec<(u64, Value)>> {
+        let mut queued = Vec::new();
+
+        for item in self.queued.iter() {
+            let (key, _) = item?;
+            if let Some(raw) = self.tree.get(&key)? {
+                let entry: OutboxEntry = serde_cbor::from_slice(&raw)?;
+                let mut u64_buffer = [0u8; 8];
+                u64_buffer.copy_from_slice(&key);
+                queued.push((
+                    u64::from_be_bytes(u64_buffer),
+                    serde_json::from_str(&entry.content)?,
+                ));
+            }
+        }
+
+        Ok(queued)
+    }
+
+    /// Whether any entry is waiting to be published.
+    pub fn has_queued(&self) -> Result<bool> {
+        Ok(!self.queued.is_empty())
+    }
+
+    /// Get the status of the entry with the given ID.
+    pub fn get_status(&self, id: u64) -> Result<Option<OutboxStatus>> {
+        match self.tree.get(id.to_be_bytes())? {
+            Some(raw) => Ok(Some(serde_cbor::from_slice::<OutboxEntry>(&raw)?.status)),
+            None => Ok(None),
+        }
+    }
+
+    /// Update the status of the entry with the given ID.
+    pub fn set_status(&self, id: u64, status: OutboxStatus) -> Result<()> {
+        if let Some(raw) = self.tree.get(id.to_be_bytes())? {
+            let mut entry: OutboxEntry = serde_cbor::from_slice(&raw)?;
+            let is_queued = status == OutboxStatus::Queued;
+            entry.status = status;
+            let raw = serde_cbor::to_vec(&entry)?;
+            (&self.tree, &self.queued).transaction(|(tx_tree, tx_queued)| {
+                tx_tree.insert(&id.to_be_bytes(), raw.as_slice())?;
+                if is_queued {
+                    tx_queued.insert(&id.to_be_bytes(), &[])?;
+                } else {
+                    tx_queued.remove(&id.to_be_bytes())?;
+                }
+                Ok::<(), ConflictableTransactionError<sled::Error>>(())
+            })?;
+        }
+
+        Ok(())
+    }
+}
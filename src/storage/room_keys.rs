@@ -0,0 +1,90 @@
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Name of the sled tree in which the room keys are stored.
+const ROOM_KEYS_TREE: &str = "room_keys";
+
+/// The ID of a room, pinned the first time an alias was looked up on it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomKey {
+    /// Host name of the room (e.g. `room.example`).
+    pub host: String,
+    /// ID (public key) of the room.
+    pub room_id: String,
+    /// Milliseconds since the Unix epoch at which the ID was pinned.
+    pub pinned_at: u64,
+}
+
+/// IDs of the rooms on which aliases have been looked up, by host name.
+///
+/// Alias lookups go over plain HTTP, so the registration returned could be
+/// that of a man in the middle. Pinning the ID of each room on first use
+/// (trust on first use) means that later lookups are only trusted if the
+/// room presents the same ID.
+#[derive(Clone)]
+pub struct RoomKeys {
+    tree: sled::Tree,
+}
+
+impl RoomKeys {
+    /// Open the room keys tree of the given database.
+    pub fn open(db: &sled::Db) -> Result<Self> {
+        let tree = db.open_tree(ROOM_KEYS_TREE)?;
+
+        Ok(Self { tree })
+    }
+
+    /// Pin the given ID for the room at `host` at `now`, unless an ID is
+    /// already pinned for it. Returns the pinned key.
+    pub fn pin(&self, host: &str, room_id: &str, now: u64) -> Result<RoomKey> {
+        let key = RoomKey {
+            host: host.to_string(),
+            room_id: room_id.to_string(),
+            pinned_at: now,
+        };
+        let pinned = self.tree.compare_and_swap(
+            host,
+            None as Option<&[u8]>,
+            Some(serde_cbor::to_vec(&key)?),
+        )?;
+        match pinned {
+            Ok(()) => {
+                self.tree.flush()?;
+                Ok(key)
+            }
+            Err(err) => {
+                let raw = err.current.unwrap_or_default();
+                Ok(serde_cbor::from_slice(&raw)?)
+            }
+        }
+    }
+
+    /// Return the key pinned for the room at `host`, if any.
+    pub fn get(&self, host: &str) -> Result<Option<RoomKey>> {
+        match self.tree.get(host)? {
+            Some(raw) => Ok(Some(serde_cbor::from_slice(&raw)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Forget the key pinned for the room at `host`, so that the next
+    /// lookup pins the ID it returns. Returns `false` if no key was pinned.
+    pub fn forget(&self, host: &str) -> Result<bool> {
+        let removed = self.tree.remove(host)?.is_some();
+        self.tree.flush()?;
+
+        Ok(removed)
+    }
+
+    /// Return the pinned keys, in order of host name.
+    pub fn get_all(&self) -> Result<Vec<RoomKey>> {
+        let mut keys = Vec::new();
+        for item in self.tree.iter() {
+            let (_, raw) = item?;
+            keys.push(serde_cbor::from_slice(&raw)?);
+        }
+
+        Ok(keys)
+    }
+}
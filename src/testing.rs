@@ -0,0 +1,400 @@
+//! Harness for tests involving several nodes in the same process.
+//!
+//! The actors of a running node share process-wide state (the broker and the
+//! key-value store), so a `TestNode` consists of an identity and a database
+//! only. Nodes exchange messages directly between their stores, as a history
+//! stream would: for every feed the receiving node replicates, the messages
+//! following its latest sequence number are appended in order, and are
+//! validated on the way in.
+//!
+//! A `Simulation` drives a set of nodes through a sequence of events drawn
+//! from a seeded random number generator, on a virtual clock, so that a run
+//! which fails to converge can be reproduced exactly from its seed.
+//!
+//! `arbitrary_content` and `check_storage_invariants` support randomized
+//! tests of the store itself.
+
+use kuska_ssb::{feed::Message as MessageValue, keystore::OwnedIdentity};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde_json::{json, Value};
+use sled::Config as KvConfig;
+use tempdir::TempDir;
+
+use crate::{config::SecretConfig, error::Error, storage::kv::KvStorage, Result};
+
+/// A node with its own identity and temporary database.
+pub struct TestNode {
+    pub id: OwnedIdentity,
+    pub kv: KvStorage,
+    // Removed once the node is dropped.
+    _path: TempDir,
+}
+
+impl TestNode {
+    /// Create a node with a new identity and an empty database.
+    pub fn new() -> Result<Self> {
+        let id = SecretConfig::create().owned_identity()?;
+        let path = TempDir::new("solardb")?;
+        let mut kv = KvStorage::default();
+        let (sender, _) = futures::channel::mpsc::unbounded();
+        kv.open(KvConfig::new().path(path.path()), sender)?;
+
+        Ok(Self {
+            id,
+            kv,
+            _path: path,
+        })
+    }
+
+    /// Sign the given content as the next message of the local feed and
+    /// append it.
+    pub async fn publish(&self, content: Value) -> Result<MessageValue> {
+        let last_msg = self.kv.get_latest_msg_val(&self.id.id)?;
+        let msg =
+            MessageValue::sign(last_msg.as_ref(), &self.id, content).map_err(Error::Validation)?;
+        self.kv.append_feed(msg.clone()).await?;
+
+        Ok(msg)
+    }
+
+    /// The feeds this node replicates: its own, those it follows and those
+    /// of which it already stores messages.
+    pub async fn replicated_feeds(&self) -> Result<Vec<String>> {
+        let mut feeds = vec![self.id.id.clone()];
+        let stored = self.kv.get_peers().await?;
+        for pub_key in self
+            .kv
+            .indexes()
+            .get_follows(&self.id.id)?
+            .into_iter()
+            .chain(stored.into_iter().map(|peer| peer.pub_key))
+        {
+            if !feeds.contains(&pub_key) {
+                feeds.push(pub_key);
+            }
+        }
+
+        Ok(feeds)
+    }
+
+    /// Fetch the messages of the replicated feeds which `peer` has and this
+    /// node lacks. Returns the number of messages appended.
+    pub async fn replicate_from(&self, peer: &TestNode) -> Result<usize> {
+        let mut appended = 0;
+
+        for pub_key in self.replicated_feeds().await? {
+            let latest_seq = self.kv.get_latest_seq(&pub_key)?.unwrap_or(0);
+            for msg_kvt in peer.kv.iter_feed(&pub_key)? {
+                let msg = msg_kvt?.into_message()?;
+                if msg.sequence() > latest_seq {
+                    self.kv.append_feed(msg).await?;
+                    appended += 1;
+                }
+            }
+        }
+
+        Ok(appended)
+    }
+}
+
+/// Replicate between every ordered pair of the given nodes, in order, until
+/// no node receives any new message.
+pub async fn replicate_all(nodes: &[TestNode]) -> Result<()> {
+    loop {
+        let mut appended = 0;
+        for node in nodes {
+            for peer in nodes {
+                if !std::ptr::eq(node, peer) {
+                    appended += node.replicate_from(peer).await?;
+                }
+            }
+        }
+        if appended == 0 {
+            return Ok(());
+        }
+    }
+}
+
+/// An event in a simulated run, referring to nodes by index.
+#[derive(Debug, Clone, PartialEq)]
+pub enum SimEvent {
+    /// The node publishes a post.
+    Publish(usize),
+    /// The first node follows the second.
+    Follow(usize, usize),
+    /// The first node replicates from the second.
+    Connect(usize, usize),
+}
+
+/// A deterministic run of events over a set of nodes.
+pub struct Simulation {
+    pub nodes: Vec<TestNode>,
+    rng: StdRng,
+    /// Virtual time in milliseconds.
+    now: u64,
+    /// The events applied so far, with the virtual time at which each
+    /// occurred.
+    pub log: Vec<(u64, SimEvent)>,
+}
+
+impl Simulation {
+    /// Create a simulation of the given number of nodes, with events drawn
+    /// from the given seed.
+    pub fn new(seed: u64, nodes: usize) -> Result<Self> {
+        Ok(Self {
+            nodes: (0..nodes).map(|_| TestNode::new()).collect::<Result<_>>()?,
+            rng: StdRng::seed_from_u64(seed),
+            now: 0,
+            log: Vec::new(),
+        })
+    }
+
+    /// Advance the virtual clock, then draw and apply the next event.
+    pub async fn step(&mut self) -> Result<SimEvent> {
+        self.now += self.rng.gen_range(1..1_000);
+
+        let count = self.nodes.len();
+        let node = self.rng.gen_range(0..count);
+        // Any other node; the same node when there is only one.
+        let other = (node + self.rng.gen_range(1..count.max(2))) % count;
+        let event = match self.rng.gen_range(0..4) {
+            0 => SimEvent::Follow(node, other),
+            1 => SimEvent::Publish(node),
+            _ => SimEvent::Connect(node, other),
+        };
+
+        match event {
+            SimEvent::Publish(node) => {
+                let text = format!("post at {}", self.now);
+                self.nodes[node]
+                    .publish(json!({ "type": "post", "text": text }))
+                    .await?;
+            }
+            SimEvent::Follow(node, other) => {
+                let contact = self.nodes[other].id.id.clone();
+                self.nodes[node]
+                    .publish(json!({ "type": "contact", "contact": contact, "following": true }))
+                    .await?;
+            }
+            SimEvent::Connect(node, other) => {
+                self.nodes[node].replicate_from(&self.nodes[other]).await?;
+            }
+        }
+        self.log.push((self.now, event.clone()));
+
+        Ok(event)
+    }
+
+    /// Apply the given number of events.
+    pub async fn run(&mut self, steps: usize) -> Result<()> {
+        for _ in 0..steps {
+            self.step().await?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that every node has the latest message of every feed it
+    /// follows. Returns the pairs of nodes (by index) for which it does not.
+    pub fn check_converged(&self) -> Result<Vec<(usize, usize)>> {
+        let mut lagging = Vec::new();
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            let follows = node.kv.indexes().get_follows(&node.id.id)?;
+            for (j, other) in self.nodes.iter().enumerate() {
+                if follows.contains(&other.id.id)
+                    && node.kv.get_latest_seq(&other.id.id)?
+                        != other.kv.get_latest_seq(&other.id.id)?
+                {
+                    lagging.push((i, j));
+                }
+            }
+        }
+
+        Ok(lagging)
+    }
+}
+
+/// Generate valid message content of a random type, linking to the given
+/// feeds and messages where the type calls for it.
+pub fn arbitrary_content(rng: &mut impl Rng, feeds: &[String], msgs: &[String]) -> Value {
+    let feed = &feeds[rng.gen_range(0..feeds.len())];
+    let msg = if msgs.is_empty() {
+        None
+    } else {
+        Some(&msgs[rng.gen_range(0..msgs.len())])
+    };
+
+    match (rng.gen_range(0..5), msg) {
+        (0, _) => json!({
+            "type": "contact",
+            "contact": feed,
+            "following": rng.gen::<bool>(),
+            "blocking": rng.gen::<bool>(),
+        }),
+        (1, Some(msg)) => json!({
+            "type": "vote",
+            "vote": { "link": msg, "value": 1, "expression": "Like" },
+        }),
+        (2, Some(msg)) => json!({ "type": "flag", "link": msg, "reason": "spam" }),
+        (3, _) => json!({ "type": "about", "about": feed, "name": "someone" }),
+        (_, msg) => {
+            let mut post = json!({
+                "type": "post",
+                "text": "hello",
+                "mentions": [{ "link": feed }],
+            });
+            if let Some(msg) = msg {
+                post["root"] = json!(msg);
+            }
+            post
+        }
+    }
+}
+
+/// Check the consistency of the given store, returning a description of
+/// each violation found: the global order must cover every feed message,
+/// every feed must run from sequence number 1 to its latest without gaps,
+/// and every message must be reachable by its key.
+pub fn check_storage_invariants(kv: &KvStorage) -> Result<Vec<String>> {
+    let mut violations = Vec::new();
+
+    let report = kv.check_global_order()?;
+    if !report.is_consistent() {
+        violations.push(format!("inconsistent global order: {report:?}"));
+    }
+
+    let authors = kv.authors(None, usize::MAX)?;
+    if authors.count != authors.authors.len() as u64 {
+        violations.push(format!(
+            "author count {} but {} authors listed",
+            authors.count,
+            authors.authors.len()
+        ));
+    }
+
+    for author in authors.authors {
+        let mut expected_seq = 1;
+        for msg_kvt in kv.iter_feed(&author)? {
+            let msg_kvt = msg_kvt?;
+            if msg_kvt.value["sequence"] != expected_seq {
+                violations.push(format!(
+                    "feed {author} has sequence {} in place of {expected_seq}",
+                    msg_kvt.value["sequence"]
+                ));
+            }
+            if kv.get_msg_val(&msg_kvt.key)?.is_none() {
+                violations.push(format!("message {} not found by key", msg_kvt.key));
+            }
+            expected_seq += 1;
+        }
+        if kv.get_latest_seq(&author)? != Some(expected_seq - 1) {
+            violations.push(format!("latest sequence of feed {author} is out of date"));
+        }
+    }
+
+    Ok(violations)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    #[async_std::test]
+    async fn test_replicate_follows() -> Result<()> {
+        let nodes = vec![TestNode::new()?, TestNode::new()?, TestNode::new()?];
+        let (alice, bob, carol) = (&nodes[0], &nodes[1], &nodes[2]);
+
+        alice
+            .publish(json!({ "type": "contact", "contact": bob.id.id, "following": true }))
+            .await?;
+        bob.publish(json!({ "type": "contact", "contact": carol.id.id, "following": true }))
+            .await?;
+        for node in &nodes {
+            node.publish(json!({ "type": "post", "text": "hello" }))
+                .await?;
+        }
+
+        replicate_all(&nodes).await?;
+
+        // Each node has the feeds it follows, but not those further away.
+        assert_eq!(alice.kv.get_latest_seq(&bob.id.id)?, Some(2));
+        assert_eq!(bob.kv.get_latest_seq(&carol.id.id)?, Some(1));
+        assert_eq!(alice.kv.get_latest_seq(&carol.id.id)?, None);
+        assert_eq!(carol.kv.get_latest_seq(&alice.id.id)?, None);
+
+        // New messages are picked up in the next round.
+        carol
+            .publish(json!({ "type": "post", "text": "again" }))
+            .await?;
+        replicate_all(&nodes).await?;
+        assert_eq!(bob.kv.get_feed(&carol.id.id)?.len(), 2);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_simulation_is_reproducible() -> Result<()> {
+        let mut first = Simulation::new(42, 4)?;
+        first.run(100).await?;
+        let mut second = Simulation::new(42, 4)?;
+        second.run(100).await?;
+        assert_eq!(first.log, second.log);
+
+        // Once all nodes have exchanged messages, every node is up to date
+        // with the feeds it follows.
+        replicate_all(&first.nodes).await?;
+        assert_eq!(first.check_converged()?, vec![]);
+
+        Ok(())
+    }
+
+    #[async_std::test]
+    async fn test_storage_operations() -> Result<()> {
+        for seed in 0..4 {
+            let mut rng = StdRng::seed_from_u64(seed);
+            let nodes = [TestNode::new()?, TestNode::new()?, TestNode::new()?];
+            let feeds: Vec<String> = nodes.iter().map(|node| node.id.id.clone()).collect();
+            // All messages are appended to the store of the first node.
+            let kv = &nodes[0].kv;
+            let mut msgs = Vec::new();
+
+            for _ in 0..60 {
+                match rng.gen_range(0..10) {
+                    0 => {
+                        let graph = kv.indexes().get_contact_graph()?;
+                        let report_counts = kv.indexes().get_report_counts()?;
+                        kv.reindex()?;
+                        assert_eq!(kv.indexes().get_contact_graph()?, graph, "seed {seed}");
+                        assert_eq!(
+                            kv.indexes().get_report_counts()?,
+                            report_counts,
+                            "seed {seed}"
+                        );
+                    }
+                    1 => {
+                        kv.rebuild_global_order()?;
+                    }
+                    _ => {
+                        let author = &nodes[rng.gen_range(0..nodes.len())].id;
+                        let content = arbitrary_content(&mut rng, &feeds, &msgs);
+                        let last_msg = kv.get_latest_msg_val(&author.id)?;
+                        let msg = MessageValue::sign(last_msg.as_ref(), author, content)
+                            .map_err(Error::Validation)?;
+                        kv.append_feed(msg.clone()).await?;
+                        msgs.push(msg.id().to_string());
+                    }
+                }
+                assert_eq!(
+                    check_storage_invariants(kv)?,
+                    Vec::<String>::new(),
+                    "seed {seed}"
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+//! SSB URIs (`ssb:feed/classic/...`, `ssb:message/classic/...` and
+//! `ssb:blob/classic/...`), as used by newer clients and rooms.
+//!
+//! IDs are stored and processed in their sigil encoding (e.g.
+//! `@...=.ed25519`); URIs are translated at the edges.
+
+use serde::{Deserialize, Deserializer};
+
+/// Sigil, suffix and accepted URI prefixes of each kind of ID. The `classic`
+/// prefixes are the current form; the others are older aliases.
+const KINDS: [(char, &str, [&str; 2]); 3] = [
+    ('@', ".ed25519", ["ssb:feed/classic/", "ssb:feed/ed25519/"]),
+    (
+        '%',
+        ".sha256",
+        ["ssb:message/classic/", "ssb:message/sha256/"],
+    ),
+    ('&', ".sha256", ["ssb:blob/classic/", "ssb:blob/sha256/"]),
+];
+
+/// Translate an SSB URI to the sigil encoding of the ID it refers to.
+/// Returns `None` if the string is not a supported SSB URI.
+pub fn to_sigil(uri: &str) -> Option<String> {
+    for (sigil, suffix, prefixes) in KINDS {
+        if let Some(data) = prefixes.iter().find_map(|prefix| uri.strip_prefix(prefix)) {
+            if data.is_empty() {
+                return None;
+            }
+            // The data is URL-safe base64, with or without padding.
+            let mut data = data.replace("%3D", "=").replace('-', "+").replace('_', "/");
+            while data.len() % 4 != 0 {
+                data.push('=');
+            }
+            return Some(format!("{sigil}{data}{suffix}"));
+        }
+    }
+
+    None
+}
+
+/// Translate a sigil-encoded ID to an SSB URI. Returns `None` if the ID has
+/// no URI form.
+pub fn from_sigil(id: &str) -> Option<String> {
+    for (sigil, suffix, prefixes) in KINDS {
+        if let Some(data) = id
+            .strip_prefix(sigil)
+            .and_then(|data| data.strip_suffix(suffix))
+        {
+            let data = data.replace('+', "-").replace('/', "_");
+            return Some(format!("{}{data}", prefixes[0]));
+        }
+    }
+
+    None
+}
+
+/// Return the sigil encoding of an ID given either as a sigil-encoded ID or
+/// as an SSB URI. Other strings are returned unchanged.
+pub fn normalize(id: &str) -> String {
+    to_sigil(id).unwrap_or_else(|| id.to_string())
+}
+
+/// Deserialize an ID given either as a sigil-encoded ID or as an SSB URI.
+pub fn deserialize_ref<'de, D>(deserializer: D) -> std::result::Result<String, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer).map(|id| normalize(&id))
+}
+
+/// Deserialize an optional ID given either as a sigil-encoded ID or as an
+/// SSB URI.
+pub fn deserialize_opt_ref<'de, D>(deserializer: D) -> std::result::Result<Option<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer).map(|id| id.map(|id| normalize(&id)))
+}
+
+/// Deserialize a list of IDs given either as sigil-encoded IDs or as SSB
+/// URIs.
+pub fn deserialize_refs<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer).map(|ids| ids.iter().map(|id| normalize(id)).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_uri_translation() {
+        let feed = "@+oaWWDs8g73EZFUMfW37R/ULtFEjwKN/DczvdYI/8TA=.ed25519";
+        let feed_uri = "ssb:feed/classic/-oaWWDs8g73EZFUMfW37R_ULtFEjwKN_DczvdYI_8TA=";
+        assert_eq!(from_sigil(feed).as_deref(), Some(feed_uri));
+        assert_eq!(to_sigil(feed_uri).as_deref(), Some(feed));
+        // Older aliases and unpadded data are accepted.
+        assert_eq!(
+            to_sigil("ssb:feed/ed25519/-oaWWDs8g73EZFUMfW37R_ULtFEjwKN_DczvdYI_8TA").as_deref(),
+            Some(feed)
+        );
+
+        let msg = "%g3hPVPDEO1Aj/uPl0+J2NlhFB2bbFLIHlty+YuqFZ3w=.sha256";
+        let msg_uri = "ssb:message/classic/g3hPVPDEO1Aj_uPl0-J2NlhFB2bbFLIHlty-YuqFZ3w=";
+        assert_eq!(from_sigil(msg).as_deref(), Some(msg_uri));
+        assert_eq!(normalize(msg_uri), msg);
+
+        let blob = "&S7+CwHM6dZ9si5Vn4ftpk/l/ldbRMqzzJos+spZbWf4=.sha256";
+        assert_eq!(normalize(&from_sigil(blob).unwrap()), blob);
+
+        // Sigil-encoded IDs and other strings pass through unchanged.
+        assert_eq!(normalize(feed), feed);
+        assert_eq!(normalize("connect"), "connect");
+        assert_eq!(to_sigil("ssb:feed/classic/"), None);
+        assert_eq!(from_sigil("@abc=.bbfeed-v1"), None);
+    }
+}
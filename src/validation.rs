@@ -0,0 +1,349 @@
+//! Feed format aware message validation.
+//!
+//! Each supported feed format implements [`FeedFormat`], covering decoding
+//! (including message ID computation and signature verification) and
+//! sequencing checks. Storage and replication go through [`decode_msg`] and
+//! [`validate_msg`] so that adding a format only requires a new
+//! implementation here.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use kuska_ssb::feed::{Feed as MessageKvt, Message as MessageValue};
+use once_cell::sync::Lazy;
+
+use crate::{config::MSG_SIZE_CONFIG, error::Error, Result};
+
+/// Number of canonical encodings kept in the cache.
+const CANONICAL_CACHE_SIZE: usize = 1024;
+
+/// Canonical encodings of the latest classic messages handled, by message
+/// ID. A message received from a peer is measured before it is verified for
+/// storage and again when it is appended; several peers may also send the
+/// same message.
+static CANONICAL_CACHE: Lazy<Mutex<CanonicalCache>> =
+    Lazy::new(|| Mutex::new(CanonicalCache::default()));
+
+/// Encodings by message ID, evicted in insertion order.
+#[derive(Default)]
+struct CanonicalCache {
+    encodings: HashMap<String, Arc<str>>,
+    order: VecDeque<String>,
+}
+
+impl CanonicalCache {
+    fn get(&self, msg_id: &str) -> Option<Arc<str>> {
+        self.encodings.get(msg_id).cloned()
+    }
+
+    fn insert(&mut self, msg_id: &str, encoding: Arc<str>) {
+        if self.encodings.contains_key(msg_id) {
+            return;
+        }
+        if self.order.len() >= CANONICAL_CACHE_SIZE {
+            if let Some(evicted) = self.order.pop_front() {
+                self.encodings.remove(&evicted);
+            }
+        }
+        self.order.push_back(msg_id.to_string());
+        self.encodings.insert(msg_id.to_string(), encoding);
+    }
+}
+
+/// Return the legacy canonical JSON encoding of a classic message: its value
+/// serialized with an indentation of two spaces, as hashed into its ID.
+pub fn canonical_json(msg: &MessageValue) -> Result<Arc<str>> {
+    let msg_id = msg.id();
+    if let Some(encoding) = CANONICAL_CACHE.lock().unwrap().get(msg_id) {
+        return Ok(encoding);
+    }
+
+    let value = MessageKvt::new(msg.clone()).value;
+    let encoding: Arc<str> = serde_json::to_string_pretty(&value)?.into();
+    CANONICAL_CACHE
+        .lock()
+        .unwrap()
+        .insert(msg_id, encoding.clone());
+
+    Ok(encoding)
+}
+
+/// A feed format, identified by the suffix of its feed IDs.
+pub trait FeedFormat: Sync {
+    /// Name of the format.
+    fn name(&self) -> &'static str;
+
+    /// Suffix of the feed IDs of the format (e.g. `.ed25519`).
+    fn feed_suffix(&self) -> &'static str;
+
+    /// Default maximum size of a message in bytes, as measured by `size`.
+    fn default_max_size(&self) -> usize;
+
+    /// Size of a message, as limited by the format.
+    fn size(&self, msg: &MessageValue) -> usize;
+
+    /// Whether the given bytes look like a message in this format.
+    fn detect(&self, bytes: &[u8]) -> bool;
+
+    /// Decode a message, computing its ID and verifying its signature.
+    fn decode(&self, bytes: &[u8]) -> Result<MessageValue>;
+
+    /// Check that a message directly follows the previous message of the
+    /// feed, or is the first message of the feed if there is none.
+    fn validate(&self, prev: Option<&MessageValue>, msg: &MessageValue) -> Result<()>;
+}
+
+/// Classic SSB messages: signed JSON with an ed25519 author.
+pub struct Classic;
+
+impl FeedFormat for Classic {
+    fn name(&self) -> &'static str {
+        "classic"
+    }
+
+    fn feed_suffix(&self) -> &'static str {
+        ".ed25519"
+    }
+
+    fn default_max_size(&self) -> usize {
+        8192
+    }
+
+    fn size(&self, msg: &MessageValue) -> usize {
+        // As in the reference implementation, the size is the length of the
+        // canonical encoding of the message, in UTF-16 code units.
+        canonical_json(msg)
+            .map(|json| json.encode_utf16().count())
+            .unwrap_or(usize::MAX)
+    }
+
+    fn detect(&self, bytes: &[u8]) -> bool {
+        bytes
+            .iter()
+            .find(|byte| !byte.is_ascii_whitespace())
+            .is_some_and(|byte| *byte == b'{')
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<MessageValue> {
+        // Messages may be sent either as values or as KVTs. Deserializing
+        // the message verifies its signature.
+        let msg = match MessageValue::from_slice(bytes) {
+            Ok(msg) => msg,
+            Err(_) => MessageKvt::from_slice(bytes)?.into_message()?,
+        };
+
+        Ok(msg)
+    }
+
+    fn validate(&self, prev: Option<&MessageValue>, msg: &MessageValue) -> Result<()> {
+        let previous = MessageKvt::new(msg.clone()).value["previous"].clone();
+
+        match prev {
+            Some(prev) => {
+                if msg.sequence() != prev.sequence() + 1 {
+                    return Err(Error::InvalidSequence);
+                }
+                if msg.author() != prev.author()
+                    || previous.as_str() != Some(prev.id().to_string().as_str())
+                {
+                    return Err(Error::InvalidPrevious);
+                }
+            }
+            None => {
+                if msg.sequence() != 1 {
+                    return Err(Error::InvalidSequence);
+                }
+                if !previous.is_null() {
+                    return Err(Error::InvalidPrevious);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Bendy butt messages: bencoded, used for metafeeds.
+///
+/// Feed IDs are recognised but messages cannot yet be decoded.
+pub struct BendyButt;
+
+impl FeedFormat for BendyButt {
+    fn name(&self) -> &'static str {
+        "bendy-butt"
+    }
+
+    fn feed_suffix(&self) -> &'static str {
+        ".bbfeed-v1"
+    }
+
+    fn default_max_size(&self) -> usize {
+        8192
+    }
+
+    fn size(&self, _msg: &MessageValue) -> usize {
+        usize::MAX
+    }
+
+    fn detect(&self, bytes: &[u8]) -> bool {
+        // Messages are bencoded lists.
+        bytes.first() == Some(&b'l')
+    }
+
+    fn decode(&self, _bytes: &[u8]) -> Result<MessageValue> {
+        Err(Error::UnsupportedFeedFormat(self.name().to_string()))
+    }
+
+    fn validate(&self, _prev: Option<&MessageValue>, _msg: &MessageValue) -> Result<()> {
+        Err(Error::UnsupportedFeedFormat(self.name().to_string()))
+    }
+}
+
+/// Buttwoo messages: BIPF encoded.
+///
+/// Feed IDs are recognised but messages cannot yet be decoded.
+pub struct Buttwoo;
+
+impl FeedFormat for Buttwoo {
+    fn name(&self) -> &'static str {
+        "buttwoo"
+    }
+
+    fn feed_suffix(&self) -> &'static str {
+        ".buttwoo-v1"
+    }
+
+    fn default_max_size(&self) -> usize {
+        16384
+    }
+
+    fn size(&self, _msg: &MessageValue) -> usize {
+        usize::MAX
+    }
+
+    fn detect(&self, bytes: &[u8]) -> bool {
+        // Messages are BIPF arrays; the low three bits of the first byte
+        // hold the BIPF type, which is 4 for arrays.
+        bytes.first().is_some_and(|byte| byte & 7 == 4)
+    }
+
+    fn decode(&self, _bytes: &[u8]) -> Result<MessageValue> {
+        Err(Error::UnsupportedFeedFormat(self.name().to_string()))
+    }
+
+    fn validate(&self, _prev: Option<&MessageValue>, _msg: &MessageValue) -> Result<()> {
+        Err(Error::UnsupportedFeedFormat(self.name().to_string()))
+    }
+}
+
+/// Supported feed formats, in the order in which they are tried when
+/// detecting the format of an encoded message.
+pub static FORMATS: &[&dyn FeedFormat] = &[&Classic, &BendyButt, &Buttwoo];
+
+/// Look up the format of the feed with the given ID.
+pub fn format_for(feed_id: &str) -> Result<&'static dyn FeedFormat> {
+    FORMATS
+        .iter()
+        .find(|format| feed_id.ends_with(format.feed_suffix()))
+        .copied()
+        .ok_or_else(|| Error::UnsupportedFeedFormat(feed_id.to_string()))
+}
+
+/// Decode a message received from a peer, detecting its format.
+pub fn decode_msg(bytes: &[u8]) -> Result<MessageValue> {
+    let format = FORMATS
+        .iter()
+        .find(|format| format.detect(bytes))
+        .ok_or_else(|| Error::UnsupportedFeedFormat("unknown".to_string()))?;
+
+    format.decode(bytes)
+}
+
+/// Maximum size of a message of the given format, either configured with
+/// `SOLAR_MAX_MSG_SIZE` or the default of the format.
+pub fn max_size(format: &dyn FeedFormat) -> usize {
+    MSG_SIZE_CONFIG
+        .get()
+        .and_then(|sizes| sizes.get(format.name()))
+        .copied()
+        .unwrap_or_else(|| format.default_max_size())
+}
+
+/// Check that a message does not exceed the maximum size of its format.
+pub fn check_size(msg: &MessageValue) -> Result<()> {
+    let format = format_for(msg.author())?;
+    let size = format.size(msg);
+    let max_size = max_size(format);
+    if size > max_size {
+        return Err(Error::MessageTooLarge {
+            format: format.name(),
+            size,
+            max_size,
+        });
+    }
+
+    Ok(())
+}
+
+/// Check that a message may be appended to its feed, given the latest
+/// stored message of the feed.
+pub fn validate_msg(prev: Option<&MessageValue>, msg: &MessageValue) -> Result<()> {
+    format_for(msg.author())?.validate(prev, msg)?;
+    check_size(msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use serde_json::json;
+
+    use crate::config::SecretConfig;
+
+    #[test]
+    fn test_validate_classic() -> Result<()> {
+        let keypair = SecretConfig::create().owned_identity()?;
+        let content = json!({ "type": "post", "text": "sunrise" });
+
+        let msg_1 =
+            MessageValue::sign(None, &keypair, content.clone()).map_err(Error::Validation)?;
+        let msg_2 = MessageValue::sign(Some(&msg_1), &keypair, content.clone())
+            .map_err(Error::Validation)?;
+        let forked = MessageValue::sign(None, &keypair, content).map_err(Error::Validation)?;
+
+        validate_msg(None, &msg_1)?;
+        validate_msg(Some(&msg_1), &msg_2)?;
+        assert!(validate_msg(None, &msg_2).is_err());
+        assert!(validate_msg(Some(&msg_1), &forked).is_err());
+
+        let encoded = MessageKvt::new(msg_2.clone()).to_string();
+        assert_eq!(decode_msg(encoded.as_bytes())?, msg_2);
+
+        // Messages over the maximum size of the format are rejected.
+        let text = "x".repeat(Classic.default_max_size());
+        let content = json!({ "type": "post", "text": text });
+        let oversized = MessageValue::sign(None, &keypair, content).map_err(Error::Validation)?;
+        assert!(matches!(
+            validate_msg(None, &oversized),
+            Err(Error::MessageTooLarge {
+                format: "classic",
+                ..
+            })
+        ));
+
+        // Canonical encodings are cached by message ID.
+        let encoding = canonical_json(&msg_2)?;
+        assert_eq!(
+            *encoding,
+            serde_json::to_string_pretty(&MessageKvt::new(msg_2.clone()).value)?
+        );
+        assert!(Arc::ptr_eq(&encoding, &canonical_json(&msg_2)?));
+
+        assert_eq!(format_for("@abc=.bbfeed-v1")?.name(), "bendy-butt");
+        assert!(format_for("@abc=.unknown").is_err());
+
+        Ok(())
+    }
+}